@@ -0,0 +1,599 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, Mesh, RAY_EPSILON, pair_crossings, basis, line_box};
+use crate::math::vector::{Vector2, Vector3};
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde_derive::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+/// Control points of a bicubic patch, row by row: point `4 * j + i` weighs
+/// in along u by the i-th and along v by the j-th Bernstein polynomial.
+pub type ControlPoints = [Vector3; 16];
+
+pub fn bernstein(t: f32) -> [f32; 4] {
+    let s = 1.0 - t;
+    [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t]
+}
+
+pub fn bernstein_derivative(t: f32) -> [f32; 4] {
+    let s = 1.0 - t;
+    [-3.0 * s * s, 3.0 * s * s - 6.0 * t * s, 6.0 * t * s - 3.0 * t * t, 3.0 * t * t]
+}
+
+fn lerp(a: Vector3, b: Vector3, t: f32) -> Vector3 {
+    a + (b - a) * t
+}
+
+// De Casteljau split of a cubic at `t`, returning both halves.
+pub fn split(c: [Vector3; 4], t: f32) -> ([Vector3; 4], [Vector3; 4]) {
+    let ab = lerp(c[0], c[1], t);
+    let bc = lerp(c[1], c[2], t);
+    let cd = lerp(c[2], c[3], t);
+    let abc = lerp(ab, bc, t);
+    let bcd = lerp(bc, cd, t);
+    let mid = lerp(abc, bcd, t);
+    ([c[0], ab, abc, mid], [mid, bcd, cd, c[3]])
+}
+
+// Control points of the part of a cubic over [a, b].
+fn sub_curve(c: [Vector3; 4], a: f32, b: f32) -> [Vector3; 4] {
+    let (left, _) = split(c, b);
+    if b > 0.0 { split(left, a / b).1 } else { left }
+}
+
+/// Position and partial derivatives of the patch at (u, v).
+pub fn evaluate(points: &ControlPoints, u: f32, v: f32) -> (Vector3, Vector3, Vector3) {
+    let (bu, bv) = (bernstein(u), bernstein(v));
+    let (du, dv) = (bernstein_derivative(u), bernstein_derivative(v));
+    let mut p = Vector3::zero();
+    let mut dpdu = Vector3::zero();
+    let mut dpdv = Vector3::zero();
+    for j in 0..4 {
+        for i in 0..4 {
+            let c = points[4 * j + i];
+            p = p + c * (bu[i] * bv[j]);
+            dpdu = dpdu + c * (du[i] * bv[j]);
+            dpdv = dpdv + c * (bu[i] * dv[j]);
+        }
+    }
+    (p, dpdu, dpdv)
+}
+
+// Unit normal at (u, v). Where an edge collapses to a point, as at the
+// teapot's lid, the derivatives vanish and the normal is taken from just
+// inside the patch instead.
+fn normal_at(points: &ControlPoints, u: f32, v: f32) -> Vector3 {
+    let (_, dpdu, dpdv) = evaluate(points, u, v);
+    let n = dpdu.cross(&dpdv);
+    if n.size_squared() > 1e-12 {
+        return n.normalize();
+    }
+    let (u, v) = (u + (0.5 - u) * 1e-3, v + (0.5 - v) * 1e-3);
+    let (_, dpdu, dpdv) = evaluate(points, u, v);
+    let n = dpdu.cross(&dpdv);
+    if n.size_squared() > 0.0 { n.normalize() } else { Vector3 { x: 0.0, y: 1.0, z: 0.0 } }
+}
+
+fn bounds_of(points: &[Vector3]) -> (Vector3, Vector3) {
+    let mut min = Vector3::from_one(f32::INFINITY);
+    let mut max = Vector3::from_one(f32::NEG_INFINITY);
+    for p in points {
+        min = Vector3 { x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z) };
+        max = Vector3 { x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z) };
+    }
+    (min, max)
+}
+
+// Control points of the piece of a patch over [u0, u1] x [v0, v1].
+fn sub_patch(points: &ControlPoints, u: (f32, f32), v: (f32, f32)) -> ControlPoints {
+    let mut rows = [[Vector3::zero(); 4]; 4];
+    for (j, row) in rows.iter_mut().enumerate() {
+        *row = sub_curve([points[4 * j], points[4 * j + 1], points[4 * j + 2], points[4 * j + 3]], u.0, u.1);
+    }
+    let mut piece = [Vector3::zero(); 16];
+    for i in 0..4 {
+        for (j, p) in sub_curve([rows[0][i], rows[1][i], rows[2][i], rows[3][i]], v.0, v.1).iter().enumerate() {
+            piece[4 * j + i] = *p;
+        }
+    }
+    piece
+}
+
+// Node of a patch's bounding hierarchy over the parameter rectangle
+// [u0, u1] x [v0, v1], bounded by the control hull of that piece.
+struct PatchNode {
+    min: Vector3,
+    max: Vector3,
+    u: (f32, f32),
+    v: (f32, f32),
+    children: Vec<PatchNode>,
+}
+
+// Levels of four-way splits below each patch; the leaves seed Newton.
+const TREE_DEPTH: u32 = 4;
+const NEWTON_ITERATIONS: usize = 12;
+// How far outside its leaf a converged root may land, in parameter space.
+const LEAF_SLACK: f32 = 1e-4;
+
+impl PatchNode {
+    fn build(points: &ControlPoints, u: (f32, f32), v: (f32, f32), depth: u32) -> PatchNode {
+        let (min, max) = bounds_of(&sub_patch(points, u, v));
+
+        let children = if depth == 0 {
+            Vec::new()
+        } else {
+            let (um, vm) = (0.5 * (u.0 + u.1), 0.5 * (v.0 + v.1));
+            vec![
+                PatchNode::build(points, (u.0, um), (v.0, vm), depth - 1),
+                PatchNode::build(points, (um, u.1), (v.0, vm), depth - 1),
+                PatchNode::build(points, (u.0, um), (vm, v.1), depth - 1),
+                PatchNode::build(points, (um, u.1), (vm, v.1), depth - 1),
+            ]
+        };
+        PatchNode { min, max, u, v, children }
+    }
+}
+
+struct Patch {
+    points: ControlPoints,
+    root: PatchNode,
+}
+
+impl Patch {
+    fn new(points: ControlPoints) -> Patch {
+        Patch { root: PatchNode::build(&points, (0.0, 1.0), (0.0, 1.0), TREE_DEPTH), points }
+    }
+
+    // Newton iteration on the distances from two planes that meet along the
+    // ray's line, started from the middle of a leaf.
+    fn newton(&self, planes: &[(Vector3, f32); 2], u: f32, v: f32) -> Option<(f32, f32)> {
+        let (mut u, mut v) = (u, v);
+        for _ in 0..NEWTON_ITERATIONS {
+            let (p, dpdu, dpdv) = evaluate(&self.points, u, v);
+            let f = [planes[0].0.dot(&p) + planes[0].1, planes[1].0.dot(&p) + planes[1].1];
+            // relative to the point's magnitude, for the sake of f32
+            let tolerance = 1e-6 * (1.0 + p.length());
+            if f[0].abs() < tolerance && f[1].abs() < tolerance {
+                return Some((u, v));
+            }
+            let (a, b) = (planes[0].0.dot(&dpdu), planes[0].0.dot(&dpdv));
+            let (c, d) = (planes[1].0.dot(&dpdu), planes[1].0.dot(&dpdv));
+            let det = a * d - b * c;
+            if det.abs() < 1e-12 {
+                return None;
+            }
+            u -= (d * f[0] - b * f[1]) / det;
+            v -= (a * f[1] - c * f[0]) / det;
+            if !(-0.5..=1.5).contains(&u) || !(-0.5..=1.5).contains(&v) {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn crossings(&self, ray: &Ray, planes: &[(Vector3, f32); 2], node: &PatchNode, found: &mut Vec<(f32, f32, f32)>) {
+        if line_box(ray, node.min, node.max).is_none() {
+            return;
+        }
+        if !node.children.is_empty() {
+            for child in &node.children {
+                self.crossings(ray, planes, child, found);
+            }
+            return;
+        }
+
+        let root = self.newton(planes, 0.5 * (node.u.0 + node.u.1), 0.5 * (node.v.0 + node.v.1));
+        let (u, v) = match root {
+            Some(root) => root,
+            None => return,
+        };
+        let inside = |x: f32, range: (f32, f32)| x >= range.0 - LEAF_SLACK && x <= range.1 + LEAF_SLACK;
+        if !inside(u, node.u) || !inside(v, node.v) {
+            return;
+        }
+        let (u, v) = (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0));
+        let (p, _, _) = evaluate(&self.points, u, v);
+        let t = (p - ray.origin).dot(&ray.direction) / ray.direction.size_squared();
+        // neighbouring leaves can converge on the same root
+        if !found.iter().any(|&(s, fu, fv)| (s - t).abs() < 1e-4 && (fu - u).abs() < 1e-3 && (fv - v).abs() < 1e-3) {
+            found.push((t, u, v));
+        }
+    }
+
+    fn surface(&self, ray: &Ray, t: f32, u: f32, v: f32) -> Hit {
+        let (_, dpdu, _) = evaluate(&self.points, u, v);
+        let normal = normal_at(&self.points, u, v);
+        let tangent = dpdu - normal * normal.dot(&dpdu);
+        let (tangent, bitangent) = if tangent.size_squared() > 1e-12 {
+            let tangent = tangent.normalize();
+            (tangent, normal.cross(&tangent))
+        } else {
+            basis(normal)
+        };
+
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = normal;
+        hit.geometric_normal = normal;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit.uv = Vector2 { x: u, y: v };
+        hit
+    }
+}
+
+/// Set of bicubic Bézier patches, intersected directly by Newton iteration
+/// over a bounding hierarchy per patch, or through a triangle mesh when
+/// tessellated. Faces are oriented by dP/du x dP/dv.
+#[derive(Deserialize)]
+#[serde(from = "PatchDescription")]
+pub struct BezierPatch {
+    patches: Vec<Patch>,
+    pub color: Color,
+    pub refractive_index: f32,
+    pub detail_map: Option<DetailMap>,
+    // stands in for the patches when set
+    mesh: Option<Mesh>,
+}
+
+/// Patches given inline as `control_points`, loaded from a `.bpt` file at
+/// `path`, or both. With `tessellate` set, the patches are rendered as
+/// triangles within that distance of the surface instead.
+#[derive(Deserialize)]
+struct PatchDescription {
+    #[serde(default)]
+    control_points: Vec<ControlPoints>,
+    #[serde(default, deserialize_with = "deserialize_bpt")]
+    path: Vec<ControlPoints>,
+    color: Color,
+    refractive_index: f32,
+    #[serde(default)]
+    detail_map: Option<DetailMap>,
+    #[serde(default)]
+    tessellate: Option<f32>,
+}
+
+fn deserialize_bpt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ControlPoints>, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    load_bpt(&path).map_err(D::Error::custom)
+}
+
+impl From<PatchDescription> for BezierPatch {
+    fn from(description: PatchDescription) -> BezierPatch {
+        let mut points = description.control_points;
+        points.extend(description.path);
+        let mut shape = BezierPatch::new(points, description.color, description.refractive_index, description.detail_map);
+        if let Some(tolerance) = description.tessellate {
+            shape.mesh = Some(shape.tessellate(tolerance));
+        }
+        shape
+    }
+}
+
+impl BezierPatch {
+    pub fn new(control_points: Vec<ControlPoints>, color: Color, refractive_index: f32, detail_map: Option<DetailMap>) -> BezierPatch {
+        BezierPatch { patches: control_points.into_iter().map(Patch::new).collect(), color, refractive_index, detail_map, mesh: None }
+    }
+
+    /// Triangle mesh within about `tolerance` of the surface. Each patch is
+    /// split where it bends until every piece is flat to within `tolerance`,
+    /// so flat stretches take few triangles and tight curves many. Where
+    /// neighbouring pieces are split to different depths, gaps up to
+    /// `tolerance` wide can open between them.
+    pub fn tessellate(&self, tolerance: f32) -> Mesh {
+        let mut mesh = Mesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: Vec::new(),
+            color: self.color,
+            texture: None,
+            refractive_index: self.refractive_index,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        };
+        for patch in &self.patches {
+            let mut pieces = Vec::new();
+            dice(&patch.points, (0.0, 1.0), (0.0, 1.0), tolerance.max(1e-6), DICE_DEPTH, &mut pieces);
+            // corners shared by neighbouring pieces are shared by the mesh
+            let mut corners: HashMap<(u32, u32), u32> = HashMap::new();
+            let mut corner = |mesh: &mut Mesh, u: f32, v: f32| *corners.entry((u.to_bits(), v.to_bits())).or_insert_with(|| {
+                mesh.positions.push(evaluate(&patch.points, u, v).0);
+                mesh.normals.push(normal_at(&patch.points, u, v));
+                mesh.uvs.push(Vector2 { x: u, y: v });
+                mesh.positions.len() as u32 - 1
+            });
+            for (u, v) in pieces {
+                let (a, b) = (corner(&mut mesh, u.0, v.0), corner(&mut mesh, u.1, v.0));
+                let (c, d) = (corner(&mut mesh, u.1, v.1), corner(&mut mesh, u.0, v.1));
+                // a collapsed edge turns one of the pair into a sliver
+                for tri in [[a, b, c], [a, c, d]] {
+                    let (p0, p1, p2) = (mesh.positions[tri[0] as usize], mesh.positions[tri[1] as usize], mesh.positions[tri[2] as usize]);
+                    if (p1 - p0).cross(&(p2 - p0)).size_squared() > 1e-14 {
+                        mesh.indices.push(tri);
+                    }
+                }
+            }
+        }
+        mesh
+    }
+}
+
+// Halvings of a patch's parameter rectangle allowed while tessellating;
+// twelve reach a 64 x 64 grid.
+const DICE_DEPTH: u32 = 12;
+
+// Largest second differences of the control points along u and along v. A
+// cubic strays from its chord by at most 3/4 of its largest one.
+fn bending(points: &ControlPoints) -> (f32, f32) {
+    let mut along_u: f32 = 0.0;
+    let mut along_v: f32 = 0.0;
+    for j in 0..4 {
+        for i in 0..2 {
+            along_u = along_u.max((points[4 * j + i] - points[4 * j + i + 1] * 2.0 + points[4 * j + i + 2]).length());
+            along_v = along_v.max((points[4 * i + j] - points[4 * (i + 1) + j] * 2.0 + points[4 * (i + 2) + j]).length());
+        }
+    }
+    (along_u, along_v)
+}
+
+// A parameter rectangle, as its spans along u and v.
+type Piece = ((f32, f32), (f32, f32));
+
+// Collects pieces of the patch, starting from the one over `u` x `v`, each
+// flat to within `tolerance`, halving a piece across the direction it bends
+// the more.
+fn dice(points: &ControlPoints, u: (f32, f32), v: (f32, f32), tolerance: f32, depth: u32, pieces: &mut Vec<Piece>) {
+    let (along_u, along_v) = bending(&sub_patch(points, u, v));
+    if depth == 0 || 0.75 * (along_u + along_v) <= tolerance {
+        pieces.push((u, v));
+    } else if along_u >= along_v {
+        let middle = 0.5 * (u.0 + u.1);
+        dice(points, (u.0, middle), v, tolerance, depth - 1, pieces);
+        dice(points, (middle, u.1), v, tolerance, depth - 1, pieces);
+    } else {
+        let middle = 0.5 * (v.0 + v.1);
+        dice(points, u, (v.0, middle), tolerance, depth - 1, pieces);
+        dice(points, u, (middle, v.1), tolerance, depth - 1, pieces);
+    }
+}
+
+/// Reads patches in the `.bpt` text format: a patch count, then for each
+/// patch its u and v degrees followed by its control points, row by row.
+/// Only bicubic patches are accepted.
+pub fn parse_bpt(text: &str) -> Result<Vec<ControlPoints>, String> {
+    let mut tokens = text.split_whitespace();
+    let mut next = |what: &str| tokens.next().ok_or_else(|| format!("unexpected end of file reading {}", what));
+
+    let count: usize = next("patch count")?.parse().map_err(|e| format!("patch count: {}", e))?;
+    // the count is not trusted for allocation; the patches must be there
+    let mut patches = Vec::new();
+    for patch in 0..count {
+        let degree_u = next("degree").map_err(|_| format!("the file claims {} patches but holds {}", count, patch))?;
+        let degree_v = next("degree")?;
+        if degree_u != "3" || degree_v != "3" {
+            return Err(format!("patch {} has degrees {} x {}; only bicubic patches are supported", patch, degree_u, degree_v));
+        }
+        let mut points = [Vector3::zero(); 16];
+        for point in points.iter_mut() {
+            let mut coordinate = || -> Result<f32, String> {
+                next("control point")?.parse().map_err(|e| format!("patch {}: {}", patch, e))
+            };
+            *point = Vector3 { x: coordinate()?, y: coordinate()?, z: coordinate()? };
+        }
+        patches.push(points);
+    }
+    Ok(patches)
+}
+
+pub fn load_bpt(path: &str) -> Result<Vec<ControlPoints>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_bpt(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+impl BezierPatch {
+    fn crossings(&self, ray: &Ray) -> Vec<(f32, Hit)> {
+        // two planes through the ray's line
+        let d = ray.direction;
+        let n1 = if d.x.abs() > d.y.abs() && d.x.abs() > d.z.abs() {
+            Vector3 { x: d.y, y: -d.x, z: 0.0 }
+        } else {
+            Vector3 { x: 0.0, y: d.z, z: -d.y }
+        }.normalize();
+        let n2 = n1.cross(&d).normalize();
+        let planes = [(n1, -n1.dot(&ray.origin)), (n2, -n2.dot(&ray.origin))];
+
+        let mut crossings = Vec::new();
+        for patch in &self.patches {
+            let mut found = Vec::new();
+            patch.crossings(ray, &planes, &patch.root, &mut found);
+            crossings.extend(found.into_iter().map(|(t, u, v)| (t, patch.surface(ray, t, u, v))));
+        }
+        crossings
+    }
+}
+
+impl Shape for BezierPatch {
+    fn location(&self) -> Vector3 {
+        let all: Vec<Vector3> = self.patches.iter().flat_map(|p| p.points.iter().copied()).collect();
+        let (min, max) = bounds_of(&all);
+        (min + max) / 2.0
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for BezierPatch {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        if let Some(mesh) = &self.mesh {
+            return mesh.intersect(ray, hit);
+        }
+        let nearest = self.crossings(ray).into_iter()
+            .filter(|c| c.0 > RAY_EPSILON)
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        match nearest {
+            Some((_, nearest)) => {
+                *hit = nearest;
+                if ray.direction.dot(&hit.geometric_normal) < 0.0 { 2 } else { 1 }
+            }
+            None => 0,
+        }
+    }
+
+    /// Treats the patches as the skin of a closed solid.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        match &self.mesh {
+            Some(mesh) => mesh.intervals(ray, intervals),
+            None => pair_crossings(ray, self.crossings(ray), intervals),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A dome over [-1, 1]^2 in x and z, peaking at the middle.
+    fn dome() -> ControlPoints {
+        let mut points = [Vector3::zero(); 16];
+        for j in 0..4 {
+            for i in 0..4 {
+                let inner = |k: usize| k == 1 || k == 2;
+                let y = if inner(i) && inner(j) { 2.0 } else { 0.0 };
+                points[4 * j + i] = Vector3 { x: -1.0 + i as f32 * 2.0 / 3.0, y, z: 1.0 - j as f32 * 2.0 / 3.0 };
+            }
+        }
+        points
+    }
+
+    fn patch(points: Vec<ControlPoints>) -> BezierPatch {
+        BezierPatch::new(points, Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }, 1.0, None)
+    }
+
+    #[test]
+    fn direct_intersection() {
+        let shape = patch(vec![dome()]);
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 5.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        assert_eq!(shape.intersect(&ray, &mut hit), 2);
+        // the peak of the dome is (3/4)^2 of its inner control points' height
+        assert!((hit.point.y - 1.125).abs() < 1e-4);
+        assert!((hit.normal.y - 1.0).abs() < 1e-4);
+        assert!((hit.uv.x - 0.5).abs() < 1e-4 && (hit.uv.y - 0.5).abs() < 1e-4);
+
+        let ray = Ray { origin: Vector3 { x: 0.4, y: 5.0, z: -0.3 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        assert_eq!(shape.intersect(&ray, &mut hit), 2);
+        let (p, _, _) = evaluate(&dome(), hit.uv.x, hit.uv.y);
+        assert!((p - hit.point).length() < 1e-4);
+        assert!(hit.normal.x > 0.0 && hit.normal.z < 0.0);
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+
+        // from below, the dome is left through its back face
+        let ray = Ray { origin: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 1.0, z: 0.0 } };
+        assert_eq!(shape.intersect(&ray, &mut hit), 1);
+
+        let ray = Ray { origin: Vector3 { x: 1.5, y: 5.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        assert_eq!(shape.intersect(&ray, &mut hit), 0);
+    }
+
+    #[test]
+    fn grazing_the_side() {
+        // a horizontal line through the dome crosses its surface twice
+        let shape = patch(vec![dome()]);
+        let ray = Ray { origin: Vector3 { x: -3.0, y: 0.5, z: 0.0 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+        let mut intervals = Vec::new();
+        shape.intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        let (t_in, t_out) = (intervals[0].t_in, intervals[0].t_out);
+        assert!(t_in > 2.0 && t_out < 4.0 && (t_in + t_out - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tessellation() {
+        let shape = patch(vec![dome()]);
+        let coarse = shape.tessellate(0.1);
+        let fine = shape.tessellate(0.001);
+        assert!(fine.indices.len() > coarse.indices.len());
+        assert_eq!(fine.positions.len(), fine.normals.len());
+
+        // every vertex lies on the patch and the mesh agrees with the patch
+        let ray = Ray { origin: Vector3 { x: 0.3, y: 5.0, z: 0.2 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        let (mut exact, mut approximate) = (Hit::new(), Hit::new());
+        assert_eq!(shape.intersect(&ray, &mut exact), 2);
+        assert_eq!(fine.intersect(&ray, &mut approximate), 2);
+        assert!((exact.point - approximate.point).length() < 2e-3);
+        assert!((exact.normal - approximate.normal).length() < 1e-2);
+
+        // a flat patch needs no splitting however fine the tolerance
+        let mut flat = dome();
+        for p in flat.iter_mut() {
+            p.y = 0.0;
+        }
+        assert_eq!(patch(vec![flat]).tessellate(1e-4).indices.len(), 2);
+        // the dome is split more across its curved middle than at its edges
+        let mut pieces = Vec::new();
+        dice(&dome(), (0.0, 1.0), (0.0, 1.0), 0.01, DICE_DEPTH, &mut pieces);
+        let size = |(u, v): &Piece| (u.1 - u.0) * (v.1 - v.0);
+        let sizes: Vec<f32> = pieces.iter().map(size).collect();
+        assert!(sizes.iter().cloned().fold(0.0, f32::max) > sizes.iter().cloned().fold(1.0, f32::min));
+    }
+
+    #[test]
+    fn tessellated_from_scene() {
+        let points: Vec<serde_json::Value> = dome().iter().map(|p| serde_json::json!({ "x": p.x, "y": p.y, "z": p.z })).collect();
+        let description = serde_json::json!({
+            "control_points": [points],
+            "color": {"r": 1, "g": 1, "b": 1, "a": 1},
+            "refractive_index": 1,
+            "tessellate": 0.001,
+        });
+        let tessellated: BezierPatch = serde_json::from_value(description.clone()).unwrap();
+        assert!(tessellated.mesh.is_some());
+        let mut direct = description;
+        direct.as_object_mut().unwrap().remove("tessellate");
+        let direct: BezierPatch = serde_json::from_value(direct).unwrap();
+        assert!(direct.mesh.is_none());
+
+        let ray = Ray { origin: Vector3 { x: 0.3, y: 5.0, z: 0.2 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        let (mut exact, mut approximate) = (Hit::new(), Hit::new());
+        assert_eq!(direct.intersect(&ray, &mut exact), 2);
+        assert_eq!(tessellated.intersect(&ray, &mut approximate), 2);
+        assert!((exact.point - approximate.point).length() < 2e-3);
+        assert!((exact.uv.x - approximate.uv.x).abs() < 1e-2 && (exact.uv.y - approximate.uv.y).abs() < 1e-2);
+    }
+
+    #[test]
+    fn bpt() {
+        let mut text = String::from("2\n");
+        for offset in [0.0, 10.0] {
+            text.push_str("3 3\n");
+            for p in dome().iter() {
+                text.push_str(&format!("{} {} {}\n", p.x + offset, p.y, p.z));
+            }
+        }
+        let patches = parse_bpt(&text).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert!((patches[1][5].x - dome()[5].x - 10.0).abs() < 1e-6);
+
+        assert!(parse_bpt("1\n3 3\n0 0 0").is_err());
+        assert!(parse_bpt("1\n2 2\n").unwrap_err().contains("bicubic"));
+        let error = parse_bpt("18446744073709551615 3 3").unwrap_err();
+        assert!(error.contains("unexpected end of file"), "{}", error);
+        let error = parse_bpt(&text.replacen("2\n", "3\n", 1)).unwrap_err();
+        assert_eq!(error, "the file claims 3 patches but holds 2");
+    }
+}
@@ -0,0 +1,495 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, nearest_boundary, basis};
+use crate::math::clamp;
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::quaternion::Quat;
+use crate::math::polynomial::solve_quadratic;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+use std::f32::consts::PI;
+
+fn default_rotation() -> Quat {
+    Quat::identity()
+}
+
+/// Offset of an animated source at `time`.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Keyframe {
+    pub time: f32,
+    pub offset: Vector3,
+}
+
+/// Field contributor. Each falls off smoothly from `strength` at its core
+/// to nothing at `radius` from it; a negative strength carves the blob.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum BlobSource {
+    Point {
+        center: Vector3,
+        radius: f32,
+        strength: f32,
+        #[serde(default)]
+        path: Vec<Keyframe>,
+    },
+    /// Segment from `start` to `end`.
+    Line {
+        start: Vector3,
+        end: Vector3,
+        radius: f32,
+        strength: f32,
+        #[serde(default)]
+        path: Vec<Keyframe>,
+    },
+    Ellipsoid {
+        center: Vector3,
+        radii: Vector3,
+        #[serde(default = "default_rotation")]
+        rotation: Quat,
+        strength: f32,
+        #[serde(default)]
+        path: Vec<Keyframe>,
+    },
+}
+
+// Wyvill's kernel: 1 at the core, 0 with zero slope at q = 1, where q is the
+// squared distance relative to the support radius.
+fn kernel(q: f32) -> f32 {
+    if q < 1.0 { (1.0 - q).powi(3) } else { 0.0 }
+}
+
+fn kernel_slope(q: f32) -> f32 {
+    if q < 1.0 { -3.0 * (1.0 - q).powi(2) } else { 0.0 }
+}
+
+// Line parameters of `o + t d` inside the unit sphere, if any.
+fn unit_sphere_span(o: Vector3, d: Vector3) -> Option<(f32, f32)> {
+    solve_quadratic(d.dot(&d), 2.0 * o.dot(&d), o.dot(&o) - 1.0)
+}
+
+impl BlobSource {
+    fn path(&self) -> &[Keyframe] {
+        match self {
+            BlobSource::Point { path, .. } | BlobSource::Line { path, .. } | BlobSource::Ellipsoid { path, .. } => path,
+        }
+    }
+
+    fn strength(&self) -> f32 {
+        match self {
+            BlobSource::Point { strength, .. } | BlobSource::Line { strength, .. } | BlobSource::Ellipsoid { strength, .. } => *strength,
+        }
+    }
+
+    /// Displacement along the source's path at `time`, held at the first
+    /// and last keyframes outside their range.
+    pub fn offset(&self, time: f32) -> Vector3 {
+        let path = self.path();
+        match path.iter().position(|k| k.time > time) {
+            None => path.last().map_or(Vector3::zero(), |k| k.offset),
+            Some(0) => path[0].offset,
+            Some(i) => {
+                let (a, b) = (path[i - 1], path[i]);
+                let s = (time - a.time) / (b.time - a.time);
+                a.offset + (b.offset - a.offset) * s
+            }
+        }
+    }
+
+    // Squared distance relative to the support and its gradient at `p`, for
+    // the source displaced by `offset`.
+    fn falloff(&self, p: Vector3, offset: Vector3) -> (f32, Vector3) {
+        match self {
+            BlobSource::Point { center, radius, .. } => {
+                let v = p - (*center + offset);
+                let r2 = radius * radius;
+                (v.size_squared() / r2, v * (2.0 / r2))
+            }
+            BlobSource::Line { start, end, radius, .. } => {
+                let a = *start + offset;
+                let ba = *end - *start;
+                let length2 = ba.size_squared();
+                let h = if length2 > 0.0 { clamp((p - a).dot(&ba) / length2, 0.0, 1.0) } else { 0.0 };
+                let v = p - (a + ba * h);
+                let r2 = radius * radius;
+                (v.size_squared() / r2, v * (2.0 / r2))
+            }
+            BlobSource::Ellipsoid { center, radii, rotation, .. } => {
+                let mut inverse = *rotation;
+                inverse.conjugate();
+                let l = inverse.rotate(p - (*center + offset));
+                let s = Vector3 { x: l.x / radii.x, y: l.y / radii.y, z: l.z / radii.z };
+                let gradient = Vector3 { x: 2.0 * s.x / radii.x, y: 2.0 * s.y / radii.y, z: 2.0 * s.z / radii.z };
+                (s.size_squared(), rotation.rotate(gradient))
+            }
+        }
+    }
+
+    // Span of the line where the source contributes, possibly generously.
+    fn support(&self, o: Vector3, d: Vector3, offset: Vector3) -> Option<(f32, f32)> {
+        match self {
+            BlobSource::Point { center, radius, .. } => {
+                unit_sphere_span((o - (*center + offset)) / *radius, d / *radius)
+            }
+            BlobSource::Line { start, end, radius, .. } => {
+                let middle = (*start + *end) / 2.0 + offset;
+                let reach = (*end - *start).length() / 2.0 + radius;
+                unit_sphere_span((o - middle) / reach, d / reach)
+            }
+            BlobSource::Ellipsoid { center, radii, rotation, .. } => {
+                let mut inverse = *rotation;
+                inverse.conjugate();
+                let lo = inverse.rotate(o - (*center + offset));
+                let ld = inverse.rotate(d);
+                let scale = |v: Vector3| Vector3 { x: v.x / radii.x, y: v.y / radii.y, z: v.z / radii.z };
+                unit_sphere_span(scale(lo), scale(ld))
+            }
+        }
+    }
+
+    fn min_radius(&self) -> f32 {
+        match self {
+            BlobSource::Point { radius, .. } | BlobSource::Line { radius, .. } => *radius,
+            BlobSource::Ellipsoid { radii, .. } => radii.x.min(radii.y.min(radii.z)),
+        }
+    }
+
+    // Corners of the box the source's support fits in.
+    fn bounds(&self, offset: Vector3) -> (Vector3, Vector3) {
+        match self {
+            BlobSource::Point { center, radius, .. } => {
+                let c = *center + offset;
+                (c - Vector3::from_one(*radius), c + Vector3::from_one(*radius))
+            }
+            BlobSource::Line { start, end, radius, .. } => {
+                let (a, b) = (*start + offset, *end + offset);
+                let r = Vector3::from_one(*radius);
+                (
+                    Vector3 { x: a.x.min(b.x), y: a.y.min(b.y), z: a.z.min(b.z) } - r,
+                    Vector3 { x: a.x.max(b.x), y: a.y.max(b.y), z: a.z.max(b.z) } + r,
+                )
+            }
+            BlobSource::Ellipsoid { center, radii, rotation, .. } => {
+                // the half width along a world axis is the length of that axis
+                // mapped into the scaled frame
+                let mut inverse = *rotation;
+                inverse.conjugate();
+                let half = |axis: Vector3| {
+                    let l = inverse.rotate(axis);
+                    Vector3 { x: l.x * radii.x, y: l.y * radii.y, z: l.z * radii.z }.length()
+                };
+                let extent = Vector3 {
+                    x: half(Vector3 { x: 1.0, y: 0.0, z: 0.0 }),
+                    y: half(Vector3 { x: 0.0, y: 1.0, z: 0.0 }),
+                    z: half(Vector3 { x: 0.0, y: 0.0, z: 1.0 }),
+                };
+                let c = *center + offset;
+                (c - extent, c + extent)
+            }
+        }
+    }
+}
+
+/// Implicit surface where the summed field of `sources` reaches `threshold`.
+/// Sources follow their keyframed paths, evaluated at `time`.
+#[derive(Deserialize)]
+pub struct Blob {
+    pub sources: Vec<BlobSource>,
+    pub threshold: f32,
+    #[serde(default)]
+    pub time: f32,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+// Field samples per unit of the smallest source radius while bracketing.
+const SAMPLES_PER_RADIUS: f32 = 8.0;
+const REFINE_ITERATIONS: usize = 32;
+
+impl Blob {
+    /// Box around every point the blob can cover at the current time. Rays
+    /// that miss it are turned away before the field is sampled.
+    pub fn bounds(&self) -> (Vector3, Vector3) {
+        self.bounds_at(&self.offsets())
+    }
+
+    fn bounds_at(&self, offsets: &[Vector3]) -> (Vector3, Vector3) {
+        let mut min = Vector3::from_one(f32::INFINITY);
+        let mut max = Vector3::from_one(f32::NEG_INFINITY);
+        for (source, offset) in self.sources.iter().zip(offsets).filter(|(s, _)| s.strength() > 0.0) {
+            let (lo, hi) = source.bounds(*offset);
+            min = Vector3 { x: min.x.min(lo.x), y: min.y.min(lo.y), z: min.z.min(lo.z) };
+            max = Vector3 { x: max.x.max(hi.x), y: max.y.max(hi.y), z: max.z.max(hi.z) };
+        }
+        (min, max)
+    }
+
+    fn offsets(&self) -> Vec<Vector3> {
+        self.sources.iter().map(|s| s.offset(self.time)).collect()
+    }
+
+    // Field minus threshold, positive inside.
+    fn field(&self, p: Vector3, offsets: &[Vector3]) -> f32 {
+        let sum: f32 = self.sources.iter().zip(offsets)
+            .map(|(source, offset)| source.strength() * kernel(source.falloff(p, *offset).0))
+            .sum();
+        sum - self.threshold
+    }
+
+    fn surface(&self, ray: &Ray, t: f32, offsets: &[Vector3]) -> Hit {
+        let p = ray.origin + t * ray.direction;
+        let mut gradient = Vector3::zero();
+        for (source, offset) in self.sources.iter().zip(offsets) {
+            let (q, dq) = source.falloff(p, *offset);
+            gradient = gradient + dq * (source.strength() * kernel_slope(q));
+        }
+        // the field falls off outward
+        let n = if gradient.size_squared() > 0.0 { -gradient.normalize() } else { Vector3 { x: 0.0, y: 1.0, z: 0.0 } };
+        let (tangent, bitangent) = basis(n);
+
+        let mut hit = Hit::new();
+        hit.point = p;
+        hit.normal = n;
+        hit.geometric_normal = n;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit.uv = Vector2 {
+            x: 0.5 + n.x.atan2(n.z) / (2.0 * PI),
+            y: 0.5 + clamp(n.y, -1.0, 1.0).asin() / PI,
+        };
+        hit
+    }
+
+    // Narrows a sign change of the field between `a` and `b` with the
+    // Illinois variant of regula falsi.
+    fn refine(&self, ray: &Ray, offsets: &[Vector3], mut a: f32, mut fa: f32, mut b: f32, mut fb: f32) -> f32 {
+        let mut side = 0;
+        for _ in 0..REFINE_ITERATIONS {
+            let t = (a * fb - b * fa) / (fb - fa);
+            if !(t > a && t < b) || b - a < 1e-6 {
+                break;
+            }
+            let ft = self.field(ray.origin + t * ray.direction, offsets);
+            if (ft > 0.0) == (fb > 0.0) {
+                b = t;
+                fb = ft;
+                if side == -1 { fa /= 2.0; }
+                side = -1;
+            } else {
+                a = t;
+                fa = ft;
+                if side == 1 { fb /= 2.0; }
+                side = 1;
+            }
+        }
+        (a * fb - b * fa) / (fb - fa)
+    }
+}
+
+// Whether the ray's line passes through the box, by the slab method.
+fn crosses_box(ray: &Ray, (min, max): (Vector3, Vector3)) -> bool {
+    let (mut near, mut far) = (f32::NEG_INFINITY, f32::INFINITY);
+    for (o, d, lo, hi) in [
+        (ray.origin.x, ray.direction.x, min.x, max.x),
+        (ray.origin.y, ray.direction.y, min.y, max.y),
+        (ray.origin.z, ray.direction.z, min.z, max.z),
+    ] {
+        if lo > hi {
+            // no positive sources, so nothing to hit
+            return false;
+        }
+        if d == 0.0 {
+            if o < lo || o > hi {
+                return false;
+            }
+            continue;
+        }
+        let (a, b) = ((lo - o) / d, (hi - o) / d);
+        near = near.max(a.min(b));
+        far = far.min(a.max(b));
+    }
+    near <= far
+}
+
+impl Shape for Blob {
+    fn location(&self) -> Vector3 {
+        let (min, max) = self.bounds();
+        (min + max) / 2.0
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Blob {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::new();
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let offsets = self.offsets();
+        if !crosses_box(ray, self.bounds_at(&offsets)) {
+            return;
+        }
+
+        // Only positive sources can lift the field over the threshold, so the
+        // line is outside everywhere but the union of their supports.
+        let mut spans: Vec<(f32, f32)> = self.sources.iter().zip(&offsets)
+            .filter(|(source, _)| source.strength() > 0.0)
+            .filter_map(|(source, offset)| source.support(ray.origin, ray.direction, *offset))
+            .collect();
+        spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f32, f32)> = Vec::with_capacity(spans.len());
+        for (t0, t1) in spans {
+            match merged.last_mut() {
+                Some(last) if t0 <= last.1 => last.1 = last.1.max(t1),
+                _ => merged.push((t0, t1)),
+            }
+        }
+
+        let min_radius = self.sources.iter().map(|s| s.min_radius()).fold(f32::INFINITY, f32::min);
+        let step = min_radius / SAMPLES_PER_RADIUS / ray.direction.length();
+
+        for (t0, t1) in merged {
+            let samples = ((t1 - t0) / step).ceil().max(1.0) as usize;
+            let mut t = t0;
+            let mut f = self.field(ray.origin + t * ray.direction, &offsets);
+            let mut open: Option<(f32, Hit)> = None;
+            for i in 1..=samples {
+                let next = t0 + (t1 - t0) * i as f32 / samples as f32;
+                let f_next = self.field(ray.origin + next * ray.direction, &offsets);
+                if (f > 0.0) != (f_next > 0.0) {
+                    let root = self.refine(ray, &offsets, t, f, next, f_next);
+                    let hit = self.surface(ray, root, &offsets);
+                    match open.take() {
+                        Some((t_in, enter)) => intervals.push(Interval { t_in, t_out: root, enter, exit: hit }),
+                        None => open = Some((root, hit)),
+                    }
+                }
+                t = next;
+                f = f_next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(sources: Vec<BlobSource>) -> Blob {
+        Blob {
+            sources,
+            threshold: 0.125,
+            time: 0.0,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        }
+    }
+
+    fn point(x: f32, path: Vec<Keyframe>) -> BlobSource {
+        BlobSource::Point { center: Vector3 { x, y: 0.0, z: -5.0 }, radius: 2.0, strength: 1.0, path }
+    }
+
+    fn forward(x: f32) -> Ray {
+        Ray { origin: Vector3 { x, y: 0.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } }
+    }
+
+    #[test]
+    fn single_point() {
+        // (1 - q)^3 = 1/8 at q = 1/2, a sphere of radius 2 / sqrt(2)
+        let shape = blob(vec![point(0.0, Vec::new())]);
+        let r = 2.0_f32.sqrt();
+        let mut hit = Hit::new();
+        assert_eq!(shape.intersect(&forward(0.0), &mut hit), 2);
+        assert!((hit.point.z + 5.0 - r).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+
+        let mut intervals = Vec::new();
+        shape.intervals(&forward(0.0), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_out - 5.0 - r).abs() < 1e-4);
+
+        assert_eq!(shape.intersect(&forward(1.5), &mut hit), 0);
+    }
+
+    #[test]
+    fn sources_merge() {
+        // apart the spheres leave a gap at x = 0, close together they fuse
+        let ray = Ray { origin: Vector3 { x: -5.0, y: 0.0, z: -5.0 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+        let mut intervals = Vec::new();
+        blob(vec![point(-1.6, Vec::new()), point(1.6, Vec::new())]).intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 2);
+
+        intervals.clear();
+        blob(vec![point(-1.0, Vec::new()), point(1.0, Vec::new())]).intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 1);
+
+        let line = BlobSource::Line {
+            start: Vector3 { x: -1.0, y: 0.0, z: -5.0 },
+            end: Vector3 { x: 1.0, y: 0.0, z: -5.0 },
+            radius: 2.0,
+            strength: 1.0,
+            path: Vec::new(),
+        };
+        let mut hit = Hit::new();
+        assert_eq!(blob(vec![line]).intersect(&forward(0.9), &mut hit), 2);
+        assert!((hit.point.z + 5.0 - 2.0_f32.sqrt()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ellipsoid_and_bounds() {
+        let shape = blob(vec![BlobSource::Ellipsoid {
+            center: Vector3 { x: 0.0, y: 0.0, z: -5.0 },
+            radii: Vector3 { x: 1.0, y: 1.0, z: 4.0 },
+            rotation: Quat::from_axis_angle(Vector3 { x: 0.0, y: 1.0, z: 0.0 }, PI / 2.0),
+            strength: 1.0,
+            path: Vec::new(),
+        }]);
+        // turned so its long axis lies along x
+        let (min, max) = shape.bounds();
+        assert!((max.x - 4.0).abs() < 1e-4 && (min.x + 4.0).abs() < 1e-4);
+        assert!((max.z + 4.0).abs() < 1e-4);
+
+        let mut hit = Hit::new();
+        assert_eq!(shape.intersect(&forward(2.0), &mut hit), 2);
+        assert_eq!(shape.intersect(&forward(3.0), &mut hit), 0);
+        // rays clear of the box are turned away
+        assert!(crosses_box(&forward(3.5), (min, max)));
+        assert!(!crosses_box(&forward(4.5), (min, max)));
+        let sideways = Ray { origin: Vector3 { x: 0.0, y: 2.0, z: -5.0 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+        assert!(!crosses_box(&sideways, (min, max)));
+    }
+
+    #[test]
+    fn animated_source() {
+        let path = vec![
+            Keyframe { time: 0.0, offset: Vector3::zero() },
+            Keyframe { time: 1.0, offset: Vector3 { x: 4.0, y: 0.0, z: 0.0 } },
+        ];
+        let mut shape = blob(vec![point(0.0, path)]);
+        let mut hit = Hit::new();
+        assert_eq!(shape.intersect(&forward(0.0), &mut hit), 2);
+        assert_eq!(shape.intersect(&forward(2.0), &mut hit), 0);
+
+        shape.time = 0.5;
+        assert_eq!(shape.intersect(&forward(0.0), &mut hit), 0);
+        assert_eq!(shape.intersect(&forward(2.0), &mut hit), 2);
+        assert!((shape.bounds().0.x).abs() < 1e-5);
+
+        shape.time = 3.0;
+        assert_eq!(shape.intersect(&forward(4.0), &mut hit), 2);
+    }
+}
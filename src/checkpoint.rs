@@ -0,0 +1,157 @@
+use crate::progressive::{Accumulator, Progress, Settings};
+use crate::math::random::Rng;
+use crate::scene::Scene;
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RTCKPT01";
+const HEADER: usize = 8 + 8 + 4 + 4 + 4 + 8;
+const PIXEL: usize = 3 * 8 + 8 + 4;
+
+// FNV-1a, which unlike std's hasher stays the same across releases
+fn fnv(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// A fingerprint of what a render's result depends on: the contents of the
+/// files the scene is read from, the camera and the sampling settings. The
+/// sample count and the time and noise limits are left out, so a resumed
+/// render may be given new ones.
+pub fn scene_hash(files: &[PathBuf], scene: &Scene, settings: &Settings) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for file in files {
+        hash = fnv(hash, &fs::read(file).unwrap_or_default());
+    }
+    hash = fnv(hash, &scene.width.to_le_bytes());
+    hash = fnv(hash, &scene.height.to_le_bytes());
+    hash = fnv(hash, &scene.fov.to_le_bytes());
+    hash = fnv(hash, &settings.seed.to_le_bytes());
+    hash = fnv(hash, &settings.min_samples.to_le_bytes());
+    fnv(hash, &settings.adaptive_threshold.map_or(u64::MAX, f64::to_bits).to_le_bytes())
+}
+
+pub fn encode(hash: u64, progress: &Progress) -> Vec<u8> {
+    let accumulator = &progress.accumulator;
+    let mut bytes = Vec::with_capacity(HEADER + PIXEL * accumulator.counts.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&hash.to_le_bytes());
+    bytes.extend_from_slice(&accumulator.width.to_le_bytes());
+    bytes.extend_from_slice(&accumulator.height.to_le_bytes());
+    bytes.extend_from_slice(&progress.pass.to_le_bytes());
+    bytes.extend_from_slice(&progress.rng.state.to_le_bytes());
+    for ((sum, square), count) in accumulator.sums.iter().zip(&accumulator.squares).zip(&accumulator.counts) {
+        for channel in sum {
+            bytes.extend_from_slice(&channel.to_le_bytes());
+        }
+        bytes.extend_from_slice(&square.to_le_bytes());
+        bytes.extend_from_slice(&count.to_le_bytes());
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8], hash: u64) -> Result<Progress, String> {
+    if bytes.len() < HEADER || &bytes[..8] != MAGIC {
+        return Err("not a render checkpoint".to_string());
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    if u64_at(8) != hash {
+        return Err("checkpoint was made for a different scene or sampling settings".to_string());
+    }
+    let (width, height) = (u32_at(16), u32_at(20));
+    let pixels = width as usize * height as usize;
+    if bytes.len() != HEADER + PIXEL * pixels {
+        return Err("checkpoint is truncated".to_string());
+    }
+    let mut accumulator = Accumulator::new(width, height);
+    for i in 0..pixels {
+        let at = HEADER + PIXEL * i;
+        let f64_at = |offset: usize| f64::from_bits(u64_at(at + offset));
+        accumulator.sums[i] = [f64_at(0), f64_at(8), f64_at(16)];
+        accumulator.squares[i] = f64_at(24);
+        accumulator.counts[i] = u32_at(at + 32);
+    }
+    Ok(Progress { accumulator, pass: u32_at(24), rng: Rng { state: u64_at(28) } })
+}
+
+/// Writes a checkpoint, replacing any earlier one only once it is complete.
+pub fn save(path: &Path, hash: u64, progress: &Progress) -> Result<(), String> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, encode(hash, progress))
+        .and_then(|_| fs::rename(&partial, path))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn load(path: &Path, hash: u64) -> Result<Progress, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    decode(&bytes, hash).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::math::vector::Vector3;
+    use crate::progressive::{render, resume};
+    use crate::renderer::CancelToken;
+    use crate::shape::Sphere;
+
+    fn scene() -> Scene {
+        Scene { width: 8, height: 6, fov: 90.0, lights: Vec::new(), shapes: vec![Box::new(Sphere {
+            center: Vector3 { x: 0.0, y: 0.0, z: -3.0 },
+            radius: 2.0,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.5,
+            detail_map: None,
+        })] }
+    }
+
+    #[test]
+    fn resume_matches() {
+        let scene = scene();
+        let settings = Settings {
+            samples: 24,
+            adaptive_threshold: Some(1e-3),
+            min_samples: 4,
+            update_interval: std::time::Duration::from_secs(0),
+            seed: 3,
+            ..Settings::default()
+        };
+        let hash = scene_hash(&[], &scene, &settings);
+        let (whole, _) = render(&scene, &settings, &CancelToken::new(), &mut |_| ());
+
+        // keep the checkpoint from pass 9, as though the render died during pass 10
+        let mut saved = None;
+        render(&scene, &settings, &CancelToken::new(), &mut |progress| if progress.pass == 9 {
+            saved = Some(encode(hash, progress));
+        });
+        let restored = decode(&saved.unwrap(), hash).unwrap();
+        assert_eq!(restored.pass, 9);
+        let (resumed, _) = resume(&scene, &settings, restored, &CancelToken::new(), &mut |_| ());
+        assert_eq!(encode(hash, &resumed), encode(hash, &whole));
+        assert_eq!(resumed.accumulator.to_image().as_bytes(), whole.accumulator.to_image().as_bytes());
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let scene = scene();
+        let settings = Settings { samples: 2, ..Settings::default() };
+        let hash = scene_hash(&[], &scene, &settings);
+        let (progress, _) = render(&scene, &settings, &CancelToken::new(), &mut |_| ());
+        let bytes = encode(hash, &progress);
+
+        let reseeded = Settings { seed: 1, ..Settings::default() };
+        assert!(decode(&bytes, scene_hash(&[], &scene, &reseeded)).err().unwrap().contains("different scene"));
+        assert!(decode(&bytes[..bytes.len() - 1], hash).err().unwrap().contains("truncated"));
+        assert!(decode(b"not a checkpoint", hash).is_err());
+        // more samples may be asked for on resuming
+        assert_eq!(scene_hash(&[], &scene, &Settings { samples: 100, ..settings }), hash);
+    }
+}
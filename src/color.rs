@@ -1,4 +1,4 @@
-use image::{Rgba, Pixel};
+use image::Rgba;
 use serde_derive::Deserialize;
 use crate::math::*;
 use std::ops::{Mul, Add};
@@ -22,11 +22,16 @@ pub struct Color {
 }
 
 impl Color{
-    pub fn to_rgba(&self) -> Rgba<u8> {
-        Rgba::from_channels((gamma_encode(self.r) * 255.0) as u8,
-                            (gamma_encode(self.g) * 255.0) as u8,
-                            (gamma_encode(self.b) * 255.0) as u8,
-                            (gamma_encode(self.a) * 255.0) as u8)
+    pub fn to_rgba(self) -> Rgba<u8> {
+        Rgba([(gamma_encode(self.r) * 255.0) as u8,
+              (gamma_encode(self.g) * 255.0) as u8,
+              (gamma_encode(self.b) * 255.0) as u8,
+              (gamma_encode(self.a) * 255.0) as u8])
+    }
+
+    /// Linear color from gamma-encoded channels; alpha is left as is.
+    pub fn decode(self) -> Color {
+        Color { r: gamma_decode(self.r), g: gamma_decode(self.g), b: gamma_decode(self.b), a: self.a }
     }
 
     pub fn from_rgba(rgba: Rgba<u8>) -> Color {
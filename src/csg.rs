@@ -0,0 +1,192 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, nearest_boundary};
+use crate::math::vector::Vector3;
+use crate::ray::Ray;
+use crate::color::Color;
+use serde_derive::Deserialize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            CsgOperation::Union => left || right,
+            CsgOperation::Intersection => left && right,
+            CsgOperation::Difference => left && !right,
+        }
+    }
+}
+
+/// Boolean combination of two solids, shaded as a single object.
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Box<dyn Shape>,
+    pub right: Box<dyn Shape>,
+    pub color: Color,
+    pub refractive_index: f32,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: Box<dyn Shape>, right: Box<dyn Shape>, color: Color, refractive_index: f32) -> Csg {
+        Csg { operation, left, right, color, refractive_index }
+    }
+}
+
+impl Shape for Csg {
+    fn location(&self) -> Vector3 {
+        (self.left.location() + self.right.location()) / 2.0
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+}
+
+// Sorted, non-overlapping intervals of one operand.
+fn merged(mut intervals: Vec<Interval>) -> Vec<Interval> {
+    intervals.sort_by(|a, b| a.t_in.total_cmp(&b.t_in));
+    let mut result: Vec<Interval> = Vec::with_capacity(intervals.len());
+    for interval in intervals {
+        match result.last_mut() {
+            Some(last) if interval.t_in <= last.t_out => {
+                if interval.t_out > last.t_out {
+                    last.t_out = interval.t_out;
+                    last.exit = interval.exit;
+                }
+            }
+            _ => result.push(interval),
+        }
+    }
+    result
+}
+
+fn flipped(hit: &Hit) -> Hit {
+    let mut hit = *hit;
+    hit.normal = -hit.normal;
+    hit.geometric_normal = -hit.geometric_normal;
+    hit.bitangent = -hit.bitangent;
+    hit
+}
+
+/// Applies `operation` to two sets of intervals along the same ray.
+pub fn combine(operation: CsgOperation, left: Vec<Interval>, right: Vec<Interval>) -> Vec<Interval> {
+    // (distance, from the right operand, entering, surface)
+    let mut events: Vec<(f32, bool, bool, Hit)> = Vec::new();
+    for (is_right, intervals) in [(false, merged(left)), (true, merged(right))] {
+        for interval in intervals {
+            events.push((interval.t_in, is_right, true, interval.enter));
+            events.push((interval.t_out, is_right, false, interval.exit));
+        }
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut result = Vec::new();
+    let (mut in_left, mut in_right) = (false, false);
+    let mut open: Option<(f32, Hit)> = None;
+    for (t, is_right, entering, hit) in events {
+        if is_right { in_right = entering; } else { in_left = entering; }
+        // the subtracted solid's surface faces the other way in the result
+        let hit = if is_right && operation == CsgOperation::Difference { flipped(&hit) } else { hit };
+
+        let inside = operation.inside(in_left, in_right);
+        match open {
+            None if inside => open = Some((t, hit)),
+            Some((t_in, enter)) if !inside => {
+                if t_in < t {
+                    result.push(Interval { t_in, t_out: t, enter, exit: hit });
+                }
+                open = None;
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+impl Intersectable for Csg {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::new();
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        self.left.intervals(ray, &mut left);
+        self.right.intervals(ray, &mut right);
+        intervals.extend(combine(self.operation, left, right));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Sphere;
+
+    fn sphere(z: f32, radius: f32) -> Box<dyn Shape> {
+        Box::new(Sphere {
+            center: Vector3 { x: 0.0, y: 0.0, z },
+            radius,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        })
+    }
+
+    fn csg(operation: CsgOperation, left: Box<dyn Shape>, right: Box<dyn Shape>) -> Csg {
+        Csg::new(operation, left, right, Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }, 1.5)
+    }
+
+    fn forward(z: f32) -> Ray {
+        Ray { origin: Vector3 { x: 0.0, y: 0.0, z }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } }
+    }
+
+    #[test]
+    fn lens() {
+        // two overlapping spheres leave a lens between z = -4.5 and z = -5.5
+        let lens = csg(CsgOperation::Intersection, sphere(-4.0, 1.5), sphere(-6.0, 1.5));
+        let mut hit = Hit::new();
+        assert_eq!(lens.intersect(&forward(0.0), &mut hit), 2);
+        assert!((hit.point.z + 4.5).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+
+        assert_eq!(lens.intersect(&forward(-5.0), &mut hit), 1);
+        assert!((hit.point.z + 5.5).abs() < 1e-4);
+        assert!((hit.normal.z + 1.0).abs() < 1e-4);
+
+        let ray = Ray { origin: Vector3 { x: 1.4, y: 0.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(lens.intersect(&ray, &mut hit), 0);
+    }
+
+    #[test]
+    fn hollow_shell() {
+        let shell = csg(CsgOperation::Difference, sphere(-5.0, 2.0), sphere(-5.0, 1.0));
+        let mut intervals = Vec::new();
+        shell.intervals(&forward(0.0), &mut intervals);
+        assert_eq!(intervals.len(), 2);
+
+        // from the cavity the ray meets the inner wall, entering the shell
+        // through a normal that points into the cavity
+        let mut hit = Hit::new();
+        assert_eq!(shell.intersect(&forward(-5.0), &mut hit), 2);
+        assert!((hit.point.z + 6.0).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn union() {
+        let pair = csg(CsgOperation::Union, sphere(-4.0, 1.5), sphere(-6.0, 1.5));
+        let mut intervals = Vec::new();
+        pair.intervals(&forward(0.0), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_in - 2.5).abs() < 1e-4);
+        assert!((intervals[0].t_out - 7.5).abs() < 1e-4);
+    }
+}
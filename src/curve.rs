@@ -0,0 +1,320 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, nearest_boundary, basis};
+use crate::bezier::{bernstein, bernstein_derivative, split};
+use crate::math::clamp;
+use crate::math::vector::{Vector2, Vector3};
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::light::Hair;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+/// How `Curve::control_points` are read.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum CurveBasis {
+    /// Cubic segments sharing end points: 3n + 1 points make n segments.
+    Bezier,
+    /// Uniform cubic B-spline: n + 3 points make n segments.
+    BSpline,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum CurveType {
+    /// Flat strip that always faces the ray, for fine hair and grass.
+    Ribbon,
+    /// Round tube, for strands seen up close.
+    Cylinder,
+}
+
+/// Strand swept along a cubic spline, tapering linearly from `start_width`
+/// to `end_width`. The tangent of a hit runs along the strand, so it suits
+/// the `hair` shading model.
+#[derive(Deserialize)]
+pub struct Curve {
+    pub control_points: Vec<Vector3>,
+    pub basis: CurveBasis,
+    pub curve_type: CurveType,
+    pub start_width: f32,
+    pub end_width: f32,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub hair: Option<Hair>,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+fn cubic(c: &[Vector3; 4], t: f32) -> (Vector3, Vector3) {
+    let (b, db) = (bernstein(t), bernstein_derivative(t));
+    let mut p = Vector3::zero();
+    let mut dp = Vector3::zero();
+    for i in 0..4 {
+        p = p + c[i] * b[i];
+        dp = dp + c[i] * db[i];
+    }
+    (p, dp)
+}
+
+// A crossing found in the ray's frame: the strand's center line passes
+// `offset` from the line at depth `depth`, at curve parameter `u`.
+struct Crossing {
+    u: f32,
+    depth: f32,
+    offset: Vector2,
+    width: f32,
+}
+
+// Subdivision stops once the control polygon is within this fraction of
+// the width of its chords.
+const FLATNESS: f32 = 0.05;
+const MAX_DEPTH: i32 = 10;
+
+impl Curve {
+    /// Bézier control points of every segment.
+    pub fn segments(&self) -> Vec<[Vector3; 4]> {
+        let p = &self.control_points;
+        match self.basis {
+            CurveBasis::Bezier => p.windows(4).step_by(3).map(|w| [w[0], w[1], w[2], w[3]]).collect(),
+            CurveBasis::BSpline => p.windows(4).map(|w| [
+                (w[0] + w[1] * 4.0 + w[2]) / 6.0,
+                (w[1] * 2.0 + w[2]) / 3.0,
+                (w[1] + w[2] * 2.0) / 3.0,
+                (w[1] + w[2] * 4.0 + w[3]) / 6.0,
+            ]).collect(),
+        }
+    }
+
+    fn width(&self, u: f32) -> f32 {
+        self.start_width + (self.end_width - self.start_width) * u
+    }
+
+    // Subdivides the segment, held in the ray's frame, until its pieces are
+    // nearly straight, then tests the line against each piece.
+    fn subdivide(&self, c: [Vector3; 4], u: (f32, f32), depth: i32, last: bool, found: &mut Vec<Crossing>) {
+        let half_width = 0.5 * self.width(u.0).max(self.width(u.1));
+        let (mut lo, mut hi) = (Vector2 { x: f32::INFINITY, y: f32::INFINITY }, Vector2 { x: f32::NEG_INFINITY, y: f32::NEG_INFINITY });
+        for p in &c {
+            lo = Vector2 { x: lo.x.min(p.x), y: lo.y.min(p.y) };
+            hi = Vector2 { x: hi.x.max(p.x), y: hi.y.max(p.y) };
+        }
+        if lo.x > half_width || hi.x < -half_width || lo.y > half_width || hi.y < -half_width {
+            return;
+        }
+
+        if depth > 0 {
+            let (left, right) = split(c, 0.5);
+            let mid = 0.5 * (u.0 + u.1);
+            self.subdivide(left, (u.0, mid), depth - 1, false, found);
+            self.subdivide(right, (mid, u.1), depth - 1, last, found);
+            return;
+        }
+
+        // the piece covers the band between the normals to its end tangents;
+        // it owns its start edge but not its end, bar the very last piece
+        let ahead = |a: Vector3, b: Vector3| (b.x - a.x) * -a.x + (b.y - a.y) * -a.y;
+        let (start, end) = (ahead(c[0], c[1]), ahead(c[3], c[2]));
+        if start < 0.0 || end < 0.0 || (end == 0.0 && !last) {
+            return;
+        }
+        // closest approach of the chord to the line, in the ray's xy plane
+        let chord = Vector2 { x: c[3].x - c[0].x, y: c[3].y - c[0].y };
+        let length2 = chord.x * chord.x + chord.y * chord.y;
+        let w = if length2 > 0.0 { clamp(-(c[0].x * chord.x + c[0].y * chord.y) / length2, 0.0, 1.0) } else { 0.0 };
+        let (p, _) = cubic(&c, w);
+        let u = u.0 + (u.1 - u.0) * w;
+        let width = self.width(u);
+        if p.x * p.x + p.y * p.y > 0.25 * width * width {
+            return;
+        }
+        found.push(Crossing { u, depth: p.z, offset: Vector2 { x: p.x, y: p.y }, width });
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<(Crossing, usize)> {
+        let length = ray.direction.length();
+        let z = ray.direction / length;
+        let (x, y) = basis(z);
+        let to_ray = |p: Vector3| {
+            let q = p - ray.origin;
+            Vector3 { x: q.dot(&x), y: q.dot(&y), z: q.dot(&z) }
+        };
+
+        let segments = self.segments();
+        let count = segments.len();
+        let max_width = self.start_width.max(self.end_width);
+        let mut found = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let c = [to_ray(segment[0]), to_ray(segment[1]), to_ray(segment[2]), to_ray(segment[3])];
+            let mut bend: f32 = 0.0;
+            for k in 0..2 {
+                bend = bend.max((c[k] - c[k + 1] * 2.0 + c[k + 2]).length());
+            }
+            // pieces deviate from their chords by bend / (8 * 4^depth)
+            let depth = ((bend / (8.0 * FLATNESS * max_width)).max(1.0).log2() / 2.0).ceil() as i32;
+
+            let u = (i as f32 / count as f32, (i + 1) as f32 / count as f32);
+            let mut crossings = Vec::new();
+            self.subdivide(c, u, depth.clamp(0, MAX_DEPTH), i + 1 == count, &mut crossings);
+            found.extend(crossings.into_iter().map(|crossing| (crossing, i)));
+        }
+        found
+    }
+
+    // The surface where the line crosses segment `index` of `segments`,
+    // `side` being -1 on the near side and 1 on the far side.
+    fn surface(&self, ray: &Ray, crossing: &Crossing, segments: &[[Vector3; 4]], index: usize, side: f32) -> (f32, Hit) {
+        let length = ray.direction.length();
+        let z = ray.direction / length;
+        let (x, y) = basis(z);
+        let local = crossing.u * segments.len() as f32 - index as f32;
+        let (_, derivative) = cubic(&segments[index], local);
+        let tangent = if derivative.size_squared() > 0.0 { derivative.normalize() } else { z };
+
+        let radius = 0.5 * crossing.width;
+        let lateral = x * -crossing.offset.x + y * -crossing.offset.y;
+        let (depth, outward) = match self.curve_type {
+            CurveType::Ribbon => (crossing.depth, z * side),
+            CurveType::Cylinder => {
+                let chord = (radius * radius - lateral.size_squared()).max(0.0).sqrt();
+                (crossing.depth + side * chord, lateral + z * (side * chord))
+            }
+        };
+        let normal = outward - tangent * tangent.dot(&outward);
+        let normal = if normal.size_squared() > 1e-12 { normal.normalize() } else { basis(tangent).0 };
+
+        let mut hit = Hit::new();
+        let t = depth / length;
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = normal;
+        hit.geometric_normal = normal;
+        hit.tangent = tangent;
+        hit.bitangent = normal.cross(&tangent);
+        // across the strand, from one edge to the other
+        let across = tangent.cross(&z);
+        let side_offset = if across.size_squared() > 0.0 { -lateral.dot(&across.normalize()) } else { 0.0 };
+        hit.uv = Vector2 { x: crossing.u, y: clamp(0.5 + side_offset / crossing.width.max(1e-12), 0.0, 1.0) };
+        (t, hit)
+    }
+}
+
+impl Shape for Curve {
+    fn location(&self) -> Vector3 {
+        let sum = self.control_points.iter().fold(Vector3::zero(), |acc, p| acc + *p);
+        sum / self.control_points.len().max(1) as f32
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+    fn hair(&self) -> Option<&Hair> {
+        self.hair.as_ref()
+    }
+}
+
+impl Intersectable for Curve {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::new();
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    /// A tube is crossed between its near and far walls; a ribbon has no
+    /// thickness and gives an empty interval at the strip.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let segments = self.segments();
+        for (crossing, i) in self.crossings(ray) {
+            let (t_in, enter) = self.surface(ray, &crossing, &segments, i, -1.0);
+            let (t_out, exit) = self.surface(ray, &crossing, &segments, i, 1.0);
+            intervals.push(Interval { t_in, t_out, enter, exit });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A strand arching over the origin along x, 5 units in front of the eye.
+    fn strand(curve_type: CurveType) -> Curve {
+        Curve {
+            control_points: vec![
+                Vector3 { x: -2.0, y: 0.0, z: -5.0 },
+                Vector3 { x: -1.0, y: 1.0, z: -5.0 },
+                Vector3 { x: 1.0, y: 1.0, z: -5.0 },
+                Vector3 { x: 2.0, y: 0.0, z: -5.0 },
+            ],
+            basis: CurveBasis::Bezier,
+            curve_type,
+            start_width: 0.2,
+            end_width: 0.2,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            hair: None,
+            detail_map: None,
+        }
+    }
+
+    fn forward(x: f32, y: f32) -> Ray {
+        Ray { origin: Vector3 { x, y, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } }
+    }
+
+    #[test]
+    fn ribbon() {
+        let curve = strand(CurveType::Ribbon);
+        let mut hit = Hit::new();
+        // the arch peaks at y = 0.75 in the middle
+        assert_eq!(curve.intersect(&forward(0.0, 0.75), &mut hit), 2);
+        assert!((hit.point.z + 5.0).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+        assert!((hit.tangent.x - 1.0).abs() < 1e-3);
+        assert!((hit.uv.x - 0.5).abs() < 1e-3);
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+
+        assert_eq!(curve.intersect(&forward(0.0, 0.84), &mut hit), 2);
+        assert_eq!(curve.intersect(&forward(0.0, 0.86), &mut hit), 0);
+        assert_eq!(curve.intersect(&forward(0.0, 0.5), &mut hit), 0);
+        assert_eq!(curve.intersect(&forward(2.5, 0.0), &mut hit), 0);
+    }
+
+    #[test]
+    fn cylinder() {
+        let curve = strand(CurveType::Cylinder);
+        let mut intervals = Vec::new();
+        curve.intervals(&forward(0.0, 0.75), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_in - 4.9).abs() < 1e-3);
+        assert!((intervals[0].t_out - 5.1).abs() < 1e-3);
+
+        // off center, the normal leans up towards the ray
+        let mut hit = Hit::new();
+        assert_eq!(curve.intersect(&forward(0.0, 0.8), &mut hit), 2);
+        assert!(hit.normal.y > 0.4 && hit.normal.z > 0.0);
+        assert!(hit.normal.dot(&hit.tangent).abs() < 1e-3);
+    }
+
+    #[test]
+    fn spline_and_taper() {
+        // a straight B-spline along x, tapering to a point
+        let mut curve = strand(CurveType::Ribbon);
+        curve.basis = CurveBasis::BSpline;
+        curve.control_points = (0..6).map(|i| Vector3 { x: i as f32 - 2.5, y: 0.0, z: -5.0 }).collect();
+        curve.start_width = 0.4;
+        curve.end_width = 0.0;
+        assert_eq!(curve.segments().len(), 3);
+
+        let mut hit = Hit::new();
+        // the spline spans x from -1.5 to 1.5
+        assert_eq!(curve.intersect(&forward(-1.4, 0.15), &mut hit), 2);
+        assert_eq!(curve.intersect(&forward(1.0, 0.15), &mut hit), 0);
+        assert_eq!(curve.intersect(&forward(1.0, 0.0), &mut hit), 2);
+        assert_eq!(curve.intersect(&forward(-1.6, 0.0), &mut hit), 0);
+
+        // crossings on segment joins are counted once
+        let mut intervals = Vec::new();
+        curve.intervals(&forward(-0.5, 0.0), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::shape::Mesh;
+use crate::math::clamp;
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::noise::fbm;
+use crate::texture::Texture;
+use serde_derive::Deserialize;
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Measures lengths as they appear on screen, for the camera the renderer
+/// shoots primary rays from.
+pub struct ScreenSpace {
+    pub eye: Vector3,
+    pub fov: f32,
+    pub height: u32,
+}
+
+impl ScreenSpace {
+    pub fn new(fov: f32, height: u32) -> ScreenSpace {
+        ScreenSpace { eye: Vector3::zero(), fov, height }
+    }
+
+    /// Approximate length in pixels of the edge from `a` to `b`.
+    pub fn pixels(&self, a: Vector3, b: Vector3) -> f32 {
+        let distance = ((a + b) / 2.0 - self.eye).length().max(1e-6);
+        let pixel = 2.0 * distance * (self.fov.to_radians() / 2.0).tan() / self.height as f32;
+        (b - a).length() / pixel
+    }
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+/// Where displacement heights come from, in [0, 1].
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum DisplacementSource {
+    /// Grayscale texture looked up by the mesh's texture coordinates.
+    Texture { texture: Texture },
+    /// Fractal noise over object space.
+    Noise {
+        frequency: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u32,
+    },
+}
+
+fn default_max_edge_pixels() -> f32 {
+    2.0
+}
+
+/// Geometric displacement of a mesh along its normals by
+/// `scale * (height - midlevel)`. The mesh is first split until no edge
+/// spans more than `max_edge_pixels` on screen.
+#[derive(Deserialize)]
+pub struct Displacement {
+    pub source: DisplacementSource,
+    pub scale: f32,
+    #[serde(default)]
+    pub midlevel: f32,
+    #[serde(default = "default_max_edge_pixels")]
+    pub max_edge_pixels: f32,
+}
+
+// Rounds of edge splitting, and a stop on runaway triangle counts: past
+// about a million triangles the mesh and its tree outgrow what a scene
+// can spend on one shape.
+const MAX_LEVELS: u32 = 12;
+const MAX_TRIANGLES: usize = 1 << 20;
+
+impl Displacement {
+    pub fn height(&self, p: Vector3, uv: Vector2) -> f32 {
+        match &self.source {
+            DisplacementSource::Texture { texture } => {
+                let c = texture.sample(uv);
+                (c.r + c.g + c.b) / 3.0
+            }
+            DisplacementSource::Noise { frequency, octaves, seed } => {
+                clamp(0.5 + 0.5 * fbm(p * *frequency, *octaves, *seed), 0.0, 1.0)
+            }
+        }
+    }
+
+    /// `mesh` tessellated and displaced, with recomputed normals. Texture
+    /// sources need the mesh to carry texture coordinates. Vertices repeated
+    /// at one position, for hard edges or texture seams, move as one, along
+    /// their averaged normal by their averaged height, so no cracks open
+    /// between them. The mesh's tree is built afterwards, around the
+    /// displaced vertices.
+    pub fn apply(&self, mesh: Mesh, screen: &ScreenSpace) -> Mesh {
+        let mut mesh = tessellate(mesh, screen, self.max_edge_pixels);
+        let key = |p: Vector3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+        let mut shared: HashMap<[u32; 3], (Vector3, f32, f32)> = HashMap::new();
+        for (i, &p) in mesh.positions.iter().enumerate() {
+            let uv = mesh.uvs.get(i).copied().unwrap_or_else(Vector2::zero);
+            let (normal, height, count) = shared.entry(key(p)).or_insert((Vector3::zero(), 0.0, 0.0));
+            *normal = *normal + mesh.normals[i];
+            *height += self.height(p, uv);
+            *count += 1.0;
+        }
+        for i in 0..mesh.positions.len() {
+            let p = mesh.positions[i];
+            let (normal, height, count) = shared[&key(p)];
+            let normal = if normal.size_squared() > 0.0 { normal.normalize() } else { mesh.normals[i] };
+            mesh.positions[i] = p + normal * (self.scale * (height / count - self.midlevel));
+        }
+        mesh.smooth_normals();
+        mesh
+    }
+}
+
+/// `mesh` with edges longer than `max_edge_pixels` on screen split
+/// in half until none are left. Splits are decided per edge from its end
+/// positions, so the triangles on both sides agree and no cracks open,
+/// even where vertices are repeated at one position. Normals, texture
+/// coordinates and colors are interpolated; missing normals are made
+/// smooth first.
+pub fn tessellate(mesh: Mesh, screen: &ScreenSpace, max_edge_pixels: f32) -> Mesh {
+    let mut result = Mesh { tree: OnceLock::new(), ..mesh };
+    if result.normals.len() != result.positions.len() {
+        result.smooth_normals();
+    }
+    let has_uvs = result.uvs.len() == result.positions.len();
+    let has_colors = result.colors.len() == result.positions.len();
+
+    for _ in 0..MAX_LEVELS {
+        if result.indices.len() * 4 > MAX_TRIANGLES {
+            break;
+        }
+        let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+        for tri in &result.indices {
+            for k in 0..3 {
+                let (a, b) = (tri[k], tri[(k + 1) % 3]);
+                let key = if a < b { (a, b) } else { (b, a) };
+                if midpoints.contains_key(&key) {
+                    continue;
+                }
+                let (pa, pb) = (result.positions[a as usize], result.positions[b as usize]);
+                if screen.pixels(pa, pb) > max_edge_pixels {
+                    midpoints.insert(key, result.positions.len() as u32);
+                    result.positions.push((pa + pb) / 2.0);
+                    let n = result.normals[a as usize] + result.normals[b as usize];
+                    result.normals.push(if n.size_squared() > 0.0 { n.normalize() } else { n });
+                    if has_uvs {
+                        result.uvs.push((result.uvs[a as usize] + result.uvs[b as usize]) * 0.5);
+                    }
+                    if has_colors {
+                        result.colors.push((result.colors[a as usize] + result.colors[b as usize]) * 0.5);
+                    }
+                }
+            }
+        }
+        if midpoints.is_empty() {
+            break;
+        }
+
+        let midpoint = |a: u32, b: u32| midpoints.get(&if a < b { (a, b) } else { (b, a) }).copied();
+        let mut indices = Vec::with_capacity(result.indices.len() * 2);
+        for tri in &result.indices {
+            // turn the triangle so its split edges come first
+            let split = [midpoint(tri[0], tri[1]), midpoint(tri[1], tri[2]), midpoint(tri[2], tri[0])];
+            let count = split.iter().filter(|m| m.is_some()).count();
+            let turn = match count {
+                1 => split.iter().position(|m| m.is_some()).unwrap_or(0),
+                2 => split.iter().position(|m| m.is_none()).map_or(0, |k| (k + 1) % 3),
+                _ => 0,
+            };
+            let (v0, v1, v2) = (tri[turn], tri[(turn + 1) % 3], tri[(turn + 2) % 3]);
+            let (m01, m12, m20) = (split[turn], split[(turn + 1) % 3], split[(turn + 2) % 3]);
+            match (m01, m12, m20) {
+                (Some(m01), Some(m12), Some(m20)) => {
+                    indices.extend_from_slice(&[[v0, m01, m20], [v1, m12, m01], [v2, m20, m12], [m01, m12, m20]]);
+                }
+                (Some(m01), Some(m12), None) => {
+                    indices.extend_from_slice(&[[m01, v1, m12], [v0, m01, m12], [v0, m12, v2]]);
+                }
+                (Some(m01), None, None) => {
+                    indices.extend_from_slice(&[[v0, m01, v2], [m01, v1, v2]]);
+                }
+                _ => indices.push(*tri),
+            }
+        }
+        result.indices = indices;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    // A 2 x 2 square facing the camera at depth `z`, as two triangles.
+    fn square(z: f32) -> Mesh {
+        Mesh {
+            positions: vec![
+                Vector3 { x: -1.0, y: -1.0, z },
+                Vector3 { x: 1.0, y: -1.0, z },
+                Vector3 { x: 1.0, y: 1.0, z },
+                Vector3 { x: -1.0, y: 1.0, z },
+            ],
+            normals: Vec::new(),
+            uvs: vec![Vector2 { x: 0.0, y: 0.0 }, Vector2 { x: 1.0, y: 0.0 }, Vector2 { x: 1.0, y: 1.0 }, Vector2 { x: 0.0, y: 1.0 }],
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            texture: None,
+            refractive_index: 1.0,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        }
+    }
+
+    fn screen() -> ScreenSpace {
+        ScreenSpace { eye: Vector3::zero(), fov: 90.0, height: 100 }
+    }
+
+    fn longest_edge(mesh: &Mesh) -> f32 {
+        mesh.indices.iter().flat_map(|t| (0..3).map(move |k| (t[k], t[(k + 1) % 3])))
+            .map(|(a, b)| screen().pixels(mesh.positions[a as usize], mesh.positions[b as usize]))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn edge_length_in_pixels() {
+        let near = tessellate(square(-5.0), &screen(), 4.0);
+        let far = tessellate(square(-50.0), &screen(), 4.0);
+        assert!(longest_edge(&near) <= 4.0);
+        assert!(longest_edge(&far) <= 4.0);
+        assert!(near.indices.len() > 10 * far.indices.len());
+
+        // every vertex keeps the interpolated frame of the flat square
+        assert!(near.normals.iter().all(|n| (n.z - 1.0).abs() < 1e-5));
+        assert!(near.positions.iter().zip(&near.uvs).all(|(p, uv)| ((p.x + 1.0) / 2.0 - uv.x).abs() < 1e-5));
+    }
+
+    #[test]
+    fn no_cracks() {
+        // every edge inside the square borders exactly two triangles
+        let mesh = tessellate(square(-5.0), &screen(), 7.0);
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+        for t in &mesh.indices {
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k + 1) % 3]);
+                *edges.entry(if a < b { (a, b) } else { (b, a) }).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            let (pa, pb) = (mesh.positions[a as usize], mesh.positions[b as usize]);
+            let on_border = |f: fn(&Vector3) -> f32| (f(&pa).abs() - 1.0).abs() < 1e-6 && (f(&pb).abs() - 1.0).abs() < 1e-6 && (f(&pa) - f(&pb)).abs() < 1e-6;
+            let border = on_border(|p| p.x) || on_border(|p| p.y);
+            assert_eq!(count, if border { 1 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn displaced() {
+        let gray = Texture::from_image(DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]))));
+        let lift = Displacement { source: DisplacementSource::Texture { texture: gray }, scale: 0.5, midlevel: 0.0, max_edge_pixels: 8.0 };
+        let mesh = lift.apply(square(-5.0), &screen());
+        assert!(mesh.positions.iter().all(|p| (p.z + 4.5).abs() < 1e-5));
+        assert!(mesh.normals.iter().all(|n| (n.z - 1.0).abs() < 1e-5));
+
+        let rough = Displacement {
+            source: DisplacementSource::Noise { frequency: 3.0, octaves: 3, seed: 1 },
+            scale: 0.2,
+            midlevel: 0.5,
+            max_edge_pixels: 4.0,
+        };
+        let mesh = rough.apply(square(-5.0), &screen());
+        let offsets: Vec<f32> = mesh.positions.iter().map(|p| p.z + 5.0).collect();
+        assert!(offsets.iter().all(|o| o.abs() <= 0.1 + 1e-5));
+        assert!(offsets.iter().any(|o| o.abs() > 0.01));
+        assert!(mesh.normals.iter().any(|n| n.z < 0.99));
+    }
+
+    // The square with each triangle on its own vertices, their normals
+    // leaning apart across the diagonal.
+    fn hard_square() -> Mesh {
+        let square = square(-5.0);
+        let corners = [0, 1, 2, 0, 2, 3];
+        let lean = |x: f32| Vector3 { x, y: 0.0, z: 1.0 }.normalize();
+        Mesh {
+            positions: corners.iter().map(|&i| square.positions[i]).collect(),
+            normals: vec![lean(0.5), lean(0.5), lean(0.5), lean(-0.5), lean(-0.5), lean(-0.5)],
+            uvs: corners.iter().map(|&i| square.uvs[i]).collect(),
+            indices: vec![[0, 1, 2], [3, 4, 5]],
+            ..square
+        }
+    }
+
+    #[test]
+    fn hard_edges_stay_closed() {
+        let rough = Displacement {
+            source: DisplacementSource::Noise { frequency: 3.0, octaves: 3, seed: 1 },
+            scale: 0.2,
+            midlevel: 0.5,
+            max_edge_pixels: 8.0,
+        };
+        let flat = tessellate(hard_square(), &screen(), 8.0);
+        let displaced = rough.apply(hard_square(), &screen());
+        // vertices that start together, on the diagonal, end together
+        let mut shared = 0;
+        for i in 0..flat.positions.len() {
+            for j in 0..i {
+                if (flat.positions[i] - flat.positions[j]).length() == 0.0 {
+                    assert!((displaced.positions[i] - displaced.positions[j]).length() < 1e-6);
+                    shared += 1;
+                }
+            }
+        }
+        assert!(shared > 2);
+    }
+}
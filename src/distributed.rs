@@ -0,0 +1,562 @@
+use crate::scene::Scene;
+use crate::renderer::{CancelToken, Rendered, Report, Tile, tiles, render_tile, place_tile};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, RgbImage};
+use serde_json::Value;
+
+/// What a coordinator sends its workers to render.
+pub enum Source {
+    /// The built-in demo scene.
+    Demo,
+    /// A scene file, flattened so it reads without its includes.
+    Json(String),
+    /// A glTF or GLB file; external buffers are looked up in `base`.
+    Gltf { base: String, bytes: Vec<u8> },
+}
+
+/// Builds the scene a worker is sent.
+pub type Build = fn(&Source) -> Result<Scene, String>;
+
+// Each message is a kind byte, a little-endian payload length and the
+// payload. A coordinator sends the files the scene reads, then one scene,
+// then tiles one at a time, each answered with its pixels, then DONE.
+const FILE: u8 = b'R';
+const DEMO: u8 = b'D';
+const JSON: u8 = b'J';
+const GLTF: u8 = b'G';
+const TILE: u8 = b'T';
+const PIXELS: u8 = b'P';
+const FAILED: u8 = b'F';
+const DONE: u8 = b'Q';
+
+const LARGEST_MESSAGE: usize = 1 << 30;
+
+fn send(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn receive(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+    if length > LARGEST_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+    }
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+fn words(payload: &[u8]) -> Vec<u32> {
+    payload.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// A name and bytes, as the payload of a glTF scene or a file.
+fn encode_named(name: &str, bytes: &[u8]) -> Vec<u8> {
+    let mut payload = (name.len() as u32).to_le_bytes().to_vec();
+    payload.extend_from_slice(name.as_bytes());
+    payload.extend_from_slice(bytes);
+    payload
+}
+
+fn decode_named(payload: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    if payload.len() < 4 {
+        return Err(invalid("truncated message"));
+    }
+    let length = words(&payload[..4])[0] as usize;
+    if payload.len() < 4 + length {
+        return Err(invalid("truncated message"));
+    }
+    let name = String::from_utf8(payload[4..4 + length].to_vec()).map_err(|_| invalid("name is not UTF-8"))?;
+    Ok((name, payload[4 + length..].to_vec()))
+}
+
+fn encode_source(source: &Source) -> (u8, Vec<u8>) {
+    match source {
+        Source::Demo => (DEMO, Vec::new()),
+        Source::Json(text) => (JSON, text.as_bytes().to_vec()),
+        Source::Gltf { base, bytes } => (GLTF, encode_named(base, bytes)),
+    }
+}
+
+fn decode_source(kind: u8, payload: Vec<u8>) -> io::Result<Source> {
+    match kind {
+        DEMO => Ok(Source::Demo),
+        JSON => Ok(Source::Json(String::from_utf8(payload).map_err(|_| invalid("scene is not UTF-8"))?)),
+        GLTF => {
+            let (base, bytes) = decode_named(&payload)?;
+            Ok(Source::Gltf { base, bytes })
+        }
+        _ => Err(invalid("expected a scene")),
+    }
+}
+
+// Where a file named `name` on the coordinator is kept under `root`. `..`
+// is resolved by name and never climbs out of `root`.
+fn local_path(root: &Path, name: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    let mut depth = 0;
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => {
+                path.push(part);
+                depth += 1;
+            }
+            Component::ParentDir if depth > 0 => {
+                path.pop();
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    path
+}
+
+// Files a coordinator sent with its scene, kept in a directory of the
+// worker's own that goes away with them.
+struct Received {
+    root: PathBuf,
+    files: HashMap<String, PathBuf>,
+}
+
+impl Received {
+    fn new() -> Received {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!("ray-tracer-worker-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        Received { root, files: HashMap::new() }
+    }
+
+    fn add(&mut self, payload: &[u8]) -> io::Result<()> {
+        let (name, bytes) = decode_named(payload)?;
+        let path = local_path(&self.root, &name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, bytes)?;
+        self.files.insert(name, path);
+        Ok(())
+    }
+
+    // `source` naming the files where they were received instead.
+    fn relocate(&self, source: Source) -> io::Result<Source> {
+        if self.files.is_empty() {
+            return Ok(source);
+        }
+        Ok(match source {
+            Source::Json(text) => match serde_json::from_str::<Value>(&text) {
+                Ok(mut scene) => {
+                    self.rename(&mut scene);
+                    Source::Json(scene.to_string())
+                }
+                // left for the build to report
+                Err(_) => Source::Json(text),
+            },
+            Source::Gltf { base, bytes } => {
+                // relative uris may climb out of the base, so it must exist
+                let base = local_path(&self.root, &base);
+                fs::create_dir_all(&base)?;
+                Source::Gltf { base: base.to_string_lossy().into_owned(), bytes }
+            }
+            Source::Demo => Source::Demo,
+        })
+    }
+
+    fn rename(&self, value: &mut Value) {
+        match value {
+            Value::String(name) => {
+                if let Some(path) = self.files.get(name.as_str()) {
+                    *name = path.to_string_lossy().into_owned();
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.rename(item)),
+            Value::Object(fields) => fields.values_mut().for_each(|field| self.rename(field)),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Received {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+// Serves one coordinator: builds its scene, then renders tiles until told
+// to stop.
+fn work(mut stream: TcpStream, build: Build) -> io::Result<()> {
+    let mut received = Received::new();
+    let (kind, payload) = loop {
+        let (kind, payload) = receive(&mut stream)?;
+        if kind != FILE {
+            break (kind, payload);
+        }
+        received.add(&payload)?;
+    };
+    let source = received.relocate(decode_source(kind, payload)?)?;
+    let scene = match build(&source) {
+        Ok(scene) => scene,
+        Err(message) => return send(&mut stream, FAILED, message.as_bytes()),
+    };
+    loop {
+        let (kind, payload) = receive(&mut stream)?;
+        match (kind, words(&payload).as_slice()) {
+            (TILE, &[id, x, y, width, height]) => {
+                let tile = Tile { x, y, width, height };
+                if x.checked_add(width).is_none_or(|right| right > scene.width) || y.checked_add(height).is_none_or(|bottom| bottom > scene.height) {
+                    return send(&mut stream, FAILED, format!("tile {:?} lies outside the image", tile).as_bytes());
+                }
+                let mut reply = id.to_le_bytes().to_vec();
+                reply.extend(render_tile(&scene, tile));
+                send(&mut stream, PIXELS, &reply)?;
+            }
+            (DONE, _) => return Ok(()),
+            _ => return Err(invalid("expected a tile")),
+        }
+    }
+}
+
+/// Runs a worker: each coordinator that connects is served on its own
+/// thread, for as long as the listener lasts.
+pub fn serve(listener: TcpListener, build: Build) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map_or_else(|_| "coordinator".to_string(), |a| a.to_string());
+                    if let Err(e) = work(stream, build) {
+                        eprintln!("{}: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("accepting a coordinator: {}", e),
+        }
+    }
+}
+
+// How often a coordinator waiting on its workers looks for a cancel.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+pub struct Settings {
+    pub tile_size: u32,
+    /// How long a worker may take to connect or to answer before it is
+    /// taken for dead.
+    pub timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { tile_size: 64, timeout: Duration::from_secs(120) }
+    }
+}
+
+// Tiles waiting for a worker, and how many are not yet rendered. Once
+// `stopped`, no more tiles are handed out.
+struct Queue {
+    pending: VecDeque<usize>,
+    remaining: usize,
+    stopped: bool,
+}
+
+struct Shared {
+    tiles: Vec<Tile>,
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last = invalid("address does not resolve");
+    for socket in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+fn request(stream: &mut TcpStream, id: usize, tile: Tile) -> io::Result<Vec<u8>> {
+    let fields = [id as u32, tile.x, tile.y, tile.width, tile.height];
+    send(stream, TILE, &fields.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>())?;
+    match receive(stream)? {
+        (PIXELS, reply) if reply.len() == 4 + (tile.width * tile.height * 3) as usize && words(&reply[..4])[0] == id as u32 => Ok(reply[4..].to_vec()),
+        (FAILED, message) => Err(invalid(&String::from_utf8_lossy(&message))),
+        _ => Err(invalid("unexpected reply to a tile")),
+    }
+}
+
+// Feeds tiles to one worker until none are left. A tile it fails on goes
+// back on the queue for the others.
+fn drive(address: &str, opening: &[(u8, Vec<u8>)], shared: &Shared, results: &mpsc::Sender<(usize, Vec<u8>)>, timeout: Duration) -> io::Result<()> {
+    let mut stream = connect(address, timeout)?;
+    for (kind, payload) in opening {
+        send(&mut stream, *kind, payload)?;
+    }
+    loop {
+        let id = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.pending.is_empty() && queue.remaining > 0 && !queue.stopped {
+                queue = shared.changed.wait(queue).unwrap();
+            }
+            if queue.stopped {
+                break;
+            }
+            match queue.pending.pop_front() {
+                Some(id) => id,
+                None => break,
+            }
+        };
+        match request(&mut stream, id, shared.tiles[id]) {
+            Ok(pixels) => {
+                shared.queue.lock().unwrap().remaining -= 1;
+                shared.changed.notify_all();
+                let _ = results.send((id, pixels));
+            }
+            Err(e) => {
+                shared.queue.lock().unwrap().pending.push_front(id);
+                shared.changed.notify_all();
+                return Err(e);
+            }
+        }
+    }
+    send(&mut stream, DONE, &[])
+}
+
+/// Renders a `width` by `height` image of `source` on the workers listening
+/// at `workers`, a tile at a time. `files` are the files the scene reads;
+/// each worker is sent a copy, so it needs no access to the coordinator's
+/// disk. Tiles from workers that fail or stop answering go to the others;
+/// it fails only if every worker does. Once `cancel` is set, no more tiles
+/// are handed out and the image is returned with what has arrived.
+pub fn coordinate(source: &Source, files: &[PathBuf], width: u32, height: u32, workers: &[String], settings: &Settings, cancel: &CancelToken) -> Result<Rendered, String> {
+    let start = Instant::now();
+    let mut opening = Vec::with_capacity(files.len() + 1);
+    for path in files {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        opening.push((FILE, encode_named(&path.to_string_lossy(), &bytes)));
+    }
+    opening.push(encode_source(source));
+    let tiles = tiles(width, height, settings.tile_size.max(1));
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue { pending: (0..tiles.len()).collect(), remaining: tiles.len(), stopped: false }),
+        tiles,
+        changed: Condvar::new(),
+    });
+    let opening = Arc::new(opening);
+    let (sender, results) = mpsc::channel();
+    let handles: Vec<_> = workers.iter().map(|address| {
+        let (address, opening, shared, sender, timeout) = (address.clone(), opening.clone(), shared.clone(), sender.clone(), settings.timeout);
+        thread::spawn(move || drive(&address, &opening, &shared, &sender, timeout).map_err(|e| {
+            eprintln!("worker {} failed: {}", address, e);
+            format!("{}: {}", address, e)
+        }))
+    }).collect();
+    drop(sender);
+
+    let mut image = RgbImage::new(width, height);
+    let (mut rendered, mut samples) = (0, 0);
+    let report = |rendered: usize, samples: u64| Report::new(rendered as u32, shared.tiles.len() as u32, samples, start.elapsed());
+    loop {
+        let (id, pixels) = if cancel.is_cancelled() {
+            // keep the tiles that have arrived; workers finish the one they
+            // are on, but nobody waits for them
+            shared.queue.lock().unwrap().stopped = true;
+            shared.changed.notify_all();
+            match results.try_recv() {
+                Ok(result) => result,
+                Err(_) => return Ok(Rendered { image: DynamicImage::ImageRgb8(image), cancelled: true, report: report(rendered, samples) }),
+            }
+        } else {
+            match results.recv_timeout(CANCEL_POLL) {
+                Ok(result) => result,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        };
+        let tile = shared.tiles[id];
+        place_tile(&mut image, tile, &pixels);
+        rendered += 1;
+        samples += u64::from(tile.width * tile.height);
+    }
+    let failures: Vec<String> = handles.into_iter().filter_map(|h| h.join().unwrap().err()).collect();
+    if rendered < shared.tiles.len() {
+        return Err(format!("{} of {} tiles rendered before every worker failed ({})", rendered, shared.tiles.len(), failures.join("; ")));
+    }
+    Ok(Rendered { image: DynamicImage::ImageRgb8(image), cancelled: false, report: report(rendered, samples) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenefile::read_scene_text;
+
+    const SCENE: &str = r#"{
+        "width": 40, "height": 24, "fov": 70,
+        "shapes": [
+            {"type": "Sphere", "center": {"x": 0, "y": 0, "z": -4}, "radius": 1.5,
+             "color": {"r": 0.9, "g": 0.4, "b": 0.2, "a": 1}, "refractive_index": 1.3},
+            {"type": "Plane", "location": {"x": 0, "y": -1.5, "z": 0}, "normal": {"x": 0, "y": 1, "z": 0},
+             "color": {"r": 0.5, "g": 0.5, "b": 0.5, "a": 1}, "uv_scale": 1, "refractive_index": 1}
+        ],
+        "lights": [{"light_type": "Directional", "direction": {"x": -1, "y": -1, "z": -1},
+                    "diffuse_color": {"r": 1, "g": 1, "b": 1, "a": 1}, "specular_color": {"r": 1, "g": 1, "b": 1, "a": 1}}]
+    }"#;
+
+    fn build(source: &Source) -> Result<Scene, String> {
+        match source {
+            Source::Json(text) => read_scene_text(text, "scene.json").0.ok_or_else(|| "bad scene".to_string()),
+            _ => Err("only JSON scenes here".to_string()),
+        }
+    }
+
+    fn worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, build));
+        address
+    }
+
+    fn local(source: &Source) -> DynamicImage {
+        crate::renderer::render(&build(source).unwrap(), 8, &CancelToken::new(), &mut |_| ()).image
+    }
+
+    // A worker that renders `tiles` tiles, then drops the connection.
+    fn dying_worker(tiles: usize) -> String {
+        partial_worker(tiles, |stream| {
+            let _ = receive(stream);
+        })
+    }
+
+    // A worker that renders `tiles` tiles and hands the connection to `then`.
+    fn partial_worker(tiles: usize, then: impl FnOnce(&mut TcpStream) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (kind, payload) = receive(&mut stream).unwrap();
+            let scene = build(&decode_source(kind, payload).unwrap()).unwrap();
+            for _ in 0..tiles {
+                let (kind, payload) = receive(&mut stream).unwrap();
+                if kind != TILE {
+                    return;
+                }
+                let w = words(&payload);
+                let mut reply = w[0].to_le_bytes().to_vec();
+                reply.extend(render_tile(&scene, Tile { x: w[1], y: w[2], width: w[3], height: w[4] }));
+                send(&mut stream, PIXELS, &reply).unwrap();
+            }
+            then(&mut stream);
+        });
+        address
+    }
+
+    #[test]
+    fn matches_local_render() {
+        let expected = local(&Source::Json(SCENE.to_string()));
+        // one worker that dies after a tile and one that never answers
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let workers = vec![dying_worker(1), worker(), unreachable, worker()];
+        let settings = Settings { tile_size: 8, timeout: Duration::from_secs(10) };
+        let rendered = coordinate(&Source::Json(SCENE.to_string()), &[], 40, 24, &workers, &settings, &CancelToken::new()).unwrap();
+        assert!(!rendered.cancelled);
+        assert_eq!(rendered.report.done, 15);
+        assert_eq!(rendered.image.as_bytes(), expected.as_bytes());
+    }
+
+    #[test]
+    fn cancels() {
+        // the worker cancels when asked for its fourth tile, then never answers
+        let cancel = CancelToken::new();
+        let watcher = cancel.clone();
+        let stalling = partial_worker(3, move |stream| {
+            let _ = receive(stream);
+            watcher.cancel();
+            while receive(stream).is_ok() {}
+        });
+        let settings = Settings { tile_size: 8, timeout: Duration::from_secs(10) };
+        let start = Instant::now();
+        let rendered = coordinate(&Source::Json(SCENE.to_string()), &[], 40, 24, &[stalling], &settings, &cancel).unwrap();
+        assert!(start.elapsed() < settings.timeout);
+        assert!(rendered.cancelled);
+        assert_eq!((rendered.report.done, rendered.report.total, rendered.report.samples), (3, 15, 3 * 64));
+        // the first three tiles are there; the rest is black
+        let (expected, image) = (local(&Source::Json(SCENE.to_string())).to_rgb8(), rendered.image.to_rgb8());
+        assert_eq!(image.get_pixel(20, 4), expected.get_pixel(20, 4));
+        assert_eq!(image.get_pixel(28, 4), &image::Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn received_files() {
+        let mut received = Received::new();
+        received.add(&encode_named("/models/../models/tri.stl", b"solid")).unwrap();
+        received.add(&encode_named("../../escape.txt", b"out")).unwrap();
+        let root = received.root.clone();
+        assert_eq!(fs::read(root.join("models").join("tri.stl")).unwrap(), b"solid");
+        // nothing lands outside the worker's directory
+        assert_eq!(fs::read(root.join("escape.txt")).unwrap(), b"out");
+
+        let source = Source::Json(r#"{"shapes": [{"path": "/models/../models/tri.stl", "name": "tri.stl"}]}"#.to_string());
+        let text = match received.relocate(source).unwrap() {
+            Source::Json(text) => text,
+            _ => unreachable!(),
+        };
+        let scene: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(scene["shapes"][0]["path"], root.join("models").join("tri.stl").to_string_lossy().as_ref());
+        assert_eq!(scene["shapes"][0]["name"], "tri.stl");
+        match received.relocate(Source::Gltf { base: "/models".to_string(), bytes: Vec::new() }).unwrap() {
+            Source::Gltf { base, .. } => assert_eq!(Path::new(&base), root.join("models")),
+            _ => unreachable!(),
+        }
+        drop(received);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn ships_files() {
+        let directory = std::env::temp_dir().join(format!("ray-tracer-distributed-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let stl = directory.join("tri.stl");
+        fs::write(&stl, "solid t\nfacet normal 0 0 1\nouter loop\nvertex -1 -1 -3\nvertex 1 -1 -3\nvertex 0 1 -3\nendloop\nendfacet\nendsolid t\n").unwrap();
+        let text = SCENE.replacen(r#""shapes": ["#, &format!(r#""shapes": [{{"type": "Mesh", "path": {:?}, "color": {{"r": 1, "g": 1, "b": 1, "a": 1}}, "refractive_index": 1}},"#, stl.to_str().unwrap()), 1);
+        let expected = local(&Source::Json(text.clone()));
+        let settings = Settings { tile_size: 8, timeout: Duration::from_secs(10) };
+        let rendered = coordinate(&Source::Json(text), &[stl], 40, 24, &[worker()], &settings, &CancelToken::new()).unwrap();
+        assert_eq!(rendered.image.as_bytes(), expected.as_bytes());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn every_worker_failing() {
+        let settings = Settings { tile_size: 8, timeout: Duration::from_secs(10) };
+        let error = coordinate(&Source::Json(SCENE.to_string()), &[], 40, 24, &[dying_worker(2)], &settings, &CancelToken::new()).err().unwrap();
+        assert!(error.starts_with("2 of 15 tiles rendered"), "{}", error);
+        // a worker that cannot build the scene fails too
+        let error = coordinate(&Source::Demo, &[], 40, 24, &[worker()], &settings, &CancelToken::new()).err().unwrap();
+        assert!(error.contains("only JSON scenes here"), "{}", error);
+    }
+}
@@ -0,0 +1,853 @@
+use crate::scene::Scene;
+use crate::shape::{Shape, Mesh};
+use crate::light::{Light, LightType};
+use crate::color::Color;
+use crate::math::matrix::Matrix;
+use crate::math::quaternion::Quat;
+use crate::math::vector::{Vector2, Vector3, Vector4};
+use crate::texture::{DetailMap, NormalMap, Texture};
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// The parts of a glTF 2.0 document the importer reads.
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct Document {
+    scene: Option<usize>,
+    scenes: Vec<SceneEntry>,
+    nodes: Vec<Node>,
+    meshes: Vec<MeshEntry>,
+    materials: Vec<MaterialEntry>,
+    textures: Vec<TextureEntry>,
+    images: Vec<ImageEntry>,
+    accessors: Vec<Accessor>,
+    buffer_views: Vec<BufferView>,
+    buffers: Vec<Buffer>,
+    cameras: Vec<CameraEntry>,
+    extensions_used: Vec<String>,
+    extensions_required: Vec<String>,
+    extensions: DocumentExtensions,
+    skins: Vec<Value>,
+    animations: Vec<Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SceneEntry {
+    nodes: Vec<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Node {
+    children: Vec<usize>,
+    matrix: Option<[f32; 16]>,
+    translation: Option<[f32; 3]>,
+    rotation: Option<[f32; 4]>,
+    scale: Option<[f32; 3]>,
+    mesh: Option<usize>,
+    camera: Option<usize>,
+    extensions: NodeExtensions,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct NodeExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    light: Option<LightRef>,
+}
+
+#[derive(Deserialize)]
+struct LightRef {
+    light: usize,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MeshEntry {
+    primitives: Vec<Primitive>,
+}
+
+fn default_mode() -> u32 {
+    4
+}
+
+#[derive(Deserialize)]
+struct Primitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "default_mode")]
+    mode: u32,
+    #[serde(default)]
+    targets: Vec<Value>,
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct MaterialEntry {
+    pbr_metallic_roughness: Pbr,
+    normal_texture: Option<TextureRef>,
+    extensions: MaterialExtensions,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MaterialExtensions {
+    #[serde(rename = "KHR_materials_ior")]
+    ior: Option<Ior>,
+}
+
+#[derive(Deserialize)]
+struct Ior {
+    #[serde(default = "default_ior")]
+    ior: f32,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn white() -> [f32; 4] {
+    [1.0; 4]
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pbr {
+    #[serde(default = "white")]
+    base_color_factor: [f32; 4],
+    base_color_texture: Option<TextureRef>,
+    #[serde(default = "one")]
+    metallic_factor: f32,
+    metallic_roughness_texture: Option<TextureRef>,
+}
+
+impl Default for Pbr {
+    fn default() -> Pbr {
+        Pbr { base_color_factor: white(), base_color_texture: None, metallic_factor: 1.0, metallic_roughness_texture: None }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextureRef {
+    index: usize,
+    #[serde(default)]
+    tex_coord: u32,
+    #[serde(default = "one")]
+    scale: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TextureEntry {
+    source: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ImageEntry {
+    uri: Option<String>,
+    buffer_view: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Buffer {
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CameraEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    perspective: Option<Perspective>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Perspective {
+    yfov: f32,
+    aspect_ratio: Option<f32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DocumentExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    lights: Option<Lights>,
+}
+
+#[derive(Deserialize)]
+struct Lights {
+    lights: Vec<LightEntry>,
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1.0; 3]
+}
+
+#[derive(Deserialize)]
+struct LightEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default = "default_light_color")]
+    color: [f32; 3],
+    #[serde(default = "one")]
+    intensity: f32,
+}
+
+const SUPPORTED_EXTENSIONS: [&str; 2] = ["KHR_lights_punctual", "KHR_materials_ior"];
+
+/// A scene read from glTF, with what could not be carried over.
+pub struct Import {
+    pub scene: Scene,
+    pub warnings: Vec<String>,
+}
+
+/// Loads a `.gltf` (with its `.bin` and image files) or `.glb` file.
+pub fn load_gltf(path: &str, width: u32, height: u32) -> Result<Import, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse_gltf(&bytes, base, width, height).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Builds a scene from glTF JSON or GLB bytes, resolving relative URIs
+/// against `base`.
+///
+/// The renderer's camera sits at the origin looking down -z, as a glTF
+/// camera does in its own space, so everything is moved into the space of
+/// the first camera in the scene. Its vertical field of view becomes
+/// `fov` and its aspect ratio, if given, sets the width for `height`.
+///
+/// Each triangle primitive becomes a `Mesh`. The base color factor, the
+/// vertex colors and the base color texture multiply together, and normal
+/// textures become normal maps. Shading here has no metalness or
+/// roughness; metallic surfaces are instead given the refractive index
+/// whose Fresnel reflectance matches theirs at normal incidence.
+/// KHR_lights_punctual point and directional lights carry over; spot
+/// lights become point lights.
+pub fn parse_gltf(bytes: &[u8], base: &Path, width: u32, height: u32) -> Result<Import, String> {
+    let (json, binary) = if bytes.starts_with(b"glTF") {
+        split_glb(bytes)?
+    } else {
+        (bytes, None)
+    };
+    let document: Document = serde_json::from_slice(json).map_err(|e| format!("invalid glTF: {}", e))?;
+
+    let mut warnings = Vec::new();
+    for extension in &document.extensions_used {
+        if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+            let required = if document.extensions_required.contains(extension) { "required " } else { "" };
+            warnings.push(format!("{}extension {} is not supported and was ignored", required, extension));
+        }
+    }
+    if !document.skins.is_empty() {
+        warnings.push("skins are not supported; meshes are left in their bind pose".to_string());
+    }
+    if !document.animations.is_empty() {
+        warnings.push("animations are not supported".to_string());
+    }
+
+    let buffers = document.buffers.iter().enumerate().map(|(i, buffer)| match &buffer.uri {
+        Some(uri) => read_uri(uri, base),
+        None if i == 0 => binary.map(<[u8]>::to_vec).ok_or_else(|| "buffer 0 has no uri and there is no GLB binary chunk".to_string()),
+        None => Err(format!("buffer {} has no uri", i)),
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    let mut import = Importer { document: &document, base, buffers, warnings, textures: HashMap::new() };
+    import.build(width, height)
+}
+
+/// Files a glTF or GLB file reads besides itself: the buffers and images
+/// it names by uri, relative to `base`.
+pub fn external_files(bytes: &[u8], base: &Path) -> Result<Vec<PathBuf>, String> {
+    let json = if bytes.starts_with(b"glTF") { split_glb(bytes)?.0 } else { bytes };
+    let document: Document = serde_json::from_slice(json).map_err(|e| format!("invalid glTF: {}", e))?;
+    let uris = document.buffers.iter().filter_map(|b| b.uri.as_ref())
+        .chain(document.images.iter().filter_map(|i| i.uri.as_ref()));
+    Ok(uris.filter(|uri| !uri.starts_with("data:")).map(|uri| base.join(uri.replace("%20", " "))).collect())
+}
+
+fn split_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>), String> {
+    let word = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| "truncated GLB file".to_string());
+    if word(4)? != 2 {
+        return Err(format!("GLB version {} is not supported", word(4)?));
+    }
+    let end = word(8)?.min(bytes.len());
+    let (mut json, mut binary) = (None, None);
+    let mut at = 12;
+    while at + 8 <= end {
+        let length = word(at)?;
+        let chunk = bytes.get(at + 8..at + 8 + length).ok_or("truncated GLB chunk")?;
+        match &bytes[at + 4..at + 8] {
+            b"JSON" if json.is_none() => json = Some(chunk),
+            b"BIN\0" if binary.is_none() => binary = Some(chunk),
+            _ => {}
+        }
+        at += 8 + length;
+    }
+    Ok((json.ok_or("GLB file has no JSON chunk")?, binary))
+}
+
+fn read_uri(uri: &str, base: &Path) -> Result<Vec<u8>, String> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (header, payload) = data.split_once(',').ok_or("malformed data uri")?;
+        if !header.ends_with(";base64") {
+            return Err("data uris must be base64 encoded".to_string());
+        }
+        return decode_base64(payload);
+    }
+    let path = base.join(uri.replace("%20", " "));
+    fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(format!("invalid base64 character {:?}", c as char)),
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+struct Importer<'a> {
+    document: &'a Document,
+    base: &'a Path,
+    buffers: Vec<Vec<u8>>,
+    warnings: Vec<String>,
+    textures: HashMap<usize, Option<Texture>>,
+}
+
+impl Importer<'_> {
+    fn build(&mut self, width: u32, height: u32) -> Result<Import, String> {
+        let document = self.document;
+        let roots = match document.scenes.get(document.scene.unwrap_or(0)) {
+            Some(scene) => scene.nodes.clone(),
+            None => {
+                // without scenes, every node that is nobody's child is a root
+                let children: Vec<usize> = document.nodes.iter().flat_map(|n| n.children.iter().copied()).collect();
+                (0..document.nodes.len()).filter(|i| !children.contains(i)).collect()
+            }
+        };
+
+        let mut placed = Vec::new();
+        for root in roots {
+            self.walk(root, Matrix::identity(), 0, &mut placed)?;
+        }
+
+        let mut fov = 90.0;
+        let mut width = width;
+        let mut view = Matrix::identity();
+        let cameras: Vec<&(usize, Matrix)> = placed.iter().filter(|(n, _)| document.nodes[*n].camera.is_some()).collect();
+        match cameras.first() {
+            Some((node, world)) => {
+                let camera = document.cameras.get(document.nodes[*node].camera.unwrap_or(0)).ok_or("missing camera")?;
+                match (&camera.perspective, camera.kind.as_str()) {
+                    (Some(perspective), "perspective") => {
+                        fov = perspective.yfov.to_degrees();
+                        if let Some(aspect) = perspective.aspect_ratio {
+                            width = (height as f32 * aspect).round() as u32;
+                        }
+                    }
+                    _ => self.warnings.push(format!("{} cameras are not supported; using the default field of view", camera.kind)),
+                }
+                view = world.inverse();
+                if cameras.len() > 1 {
+                    self.warnings.push(format!("the scene has {} cameras; using the first", cameras.len()));
+                }
+            }
+            None => self.warnings.push("the scene has no camera; viewing from the origin down -z".to_string()),
+        }
+
+        let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
+        let mut lights = Vec::new();
+        for (node, world) in &placed {
+            let node = &document.nodes[*node];
+            let transform = *world * view;
+            if let Some(mesh) = node.mesh {
+                let mesh = document.meshes.get(mesh).ok_or_else(|| format!("missing mesh {}", mesh))?;
+                for primitive in &mesh.primitives {
+                    if let Some(mesh) = self.primitive(primitive, &transform)? {
+                        shapes.push(Box::new(mesh));
+                    }
+                }
+            }
+            if let Some(light) = &node.extensions.light {
+                lights.push(self.light(light.light, &transform)?);
+            }
+        }
+
+        Ok(Import {
+            scene: Scene { width, height, fov, lights, shapes },
+            warnings: std::mem::take(&mut self.warnings),
+        })
+    }
+
+    // Collects every node under `index` with its world transform, parents
+    // before children.
+    fn walk(&self, index: usize, parent: Matrix, depth: usize, placed: &mut Vec<(usize, Matrix)>) -> Result<(), String> {
+        let node = self.document.nodes.get(index).ok_or_else(|| format!("missing node {}", index))?;
+        if depth > self.document.nodes.len() {
+            return Err("the node hierarchy has a cycle".to_string());
+        }
+        let local = match (node.matrix, node.translation, node.rotation, node.scale) {
+            // column-major for column vectors reads row by row as the transpose
+            (Some(m), ..) => Matrix::from_vector(
+                Vector4 { x: m[0], y: m[1], z: m[2], w: m[3] },
+                Vector4 { x: m[4], y: m[5], z: m[6], w: m[7] },
+                Vector4 { x: m[8], y: m[9], z: m[10], w: m[11] },
+                Vector4 { x: m[12], y: m[13], z: m[14], w: m[15] }),
+            (None, t, r, s) => {
+                let [tx, ty, tz] = t.unwrap_or([0.0; 3]);
+                let [x, y, z, w] = r.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+                let [sx, sy, sz] = s.unwrap_or([1.0; 3]);
+                Matrix::scale(sx, sy, sz) * Quat::new(x, y, z, w).to_matrix() * Matrix::translate(tx, ty, tz)
+            }
+        };
+        let world = local * parent;
+        placed.push((index, world));
+        for &child in &node.children {
+            self.walk(child, world, depth + 1, placed)?;
+        }
+        Ok(())
+    }
+
+    // Values of an accessor, flattened, with integer types scaled to
+    // [0, 1] or [-1, 1] when `normalize` is set.
+    fn read(&mut self, index: usize, normalize: bool) -> Result<(usize, Vec<f64>), String> {
+        let accessor = self.document.accessors.get(index).ok_or_else(|| format!("missing accessor {}", index))?;
+        let components = match accessor.kind.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" | "MAT2" => 4,
+            "MAT3" => 9,
+            "MAT4" => 16,
+            other => return Err(format!("accessor {} has unknown type {}", index, other)),
+        };
+        let size = match accessor.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            other => return Err(format!("accessor {} has unknown component type {}", index, other)),
+        };
+        if accessor.sparse.is_some() {
+            self.warnings.push(format!("sparse accessor {} was read without its sparse values", index));
+        }
+        let view = match accessor.buffer_view {
+            Some(view) => self.document.buffer_views.get(view).ok_or_else(|| format!("missing buffer view {}", view))?,
+            None if accessor.count == 0 => return Ok((components, Vec::new())),
+            None => return Err(format!("accessor {} has {} values but no buffer view", index, accessor.count)),
+        };
+        let buffer = self.buffers.get(view.buffer).ok_or_else(|| format!("missing buffer {}", view.buffer))?;
+        let stride = view.byte_stride.unwrap_or(components * size);
+        if stride < components * size {
+            return Err(format!("accessor {} has values of {} bytes in a stride of {}", index, components * size, stride));
+        }
+        let data = bytes(buffer, view.byte_offset, view.byte_length).ok_or("buffer view runs past its buffer")?;
+        // the last value must end inside the view before anything is allocated
+        let end = accessor.count.checked_sub(1).map_or(Some(0), |last| {
+            last.checked_mul(stride)?.checked_add(accessor.byte_offset)?.checked_add(components * size)
+        });
+        if end.is_none_or(|end| end > data.len()) {
+            return Err(format!("accessor {} has {} values, more than its buffer view holds", index, accessor.count));
+        }
+
+        let normalize = normalize || accessor.normalized;
+        let mut values = Vec::with_capacity(components * accessor.count);
+        for i in 0..accessor.count {
+            for c in 0..components {
+                let at = accessor.byte_offset + i * stride + c * size;
+                let b = data.get(at..at + size).ok_or_else(|| format!("accessor {} runs past its buffer view", index))?;
+                let (value, scale) = match accessor.component_type {
+                    5120 => (f64::from(b[0] as i8), 127.0),
+                    5121 => (f64::from(b[0]), 255.0),
+                    5122 => (f64::from(i16::from_le_bytes([b[0], b[1]])), 32767.0),
+                    5123 => (f64::from(u16::from_le_bytes([b[0], b[1]])), 65535.0),
+                    5125 => (f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])), 1.0),
+                    _ => (f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])), 1.0),
+                };
+                values.push(if normalize && scale > 1.0 { (value / scale).max(-1.0) } else { value });
+            }
+        }
+        Ok((components, values))
+    }
+
+    fn vectors(&mut self, index: usize) -> Result<Vec<Vector3>, String> {
+        let (n, values) = self.read(index, false)?;
+        if n != 3 {
+            return Err(format!("accessor {} should hold 3D vectors", index));
+        }
+        Ok(values.chunks(3).map(|v| Vector3 { x: v[0] as f32, y: v[1] as f32, z: v[2] as f32 }).collect())
+    }
+
+    fn texture(&mut self, reference: &TextureRef) -> Option<Texture> {
+        if reference.tex_coord != 0 {
+            self.warnings.push(format!("texture {} uses texture coordinate set {}; only set 0 is supported", reference.index, reference.tex_coord));
+        }
+        if let Some(texture) = self.textures.get(&reference.index) {
+            return texture.clone();
+        }
+        let texture = self.load_texture(reference.index).map_err(|e| self.warnings.push(e)).ok();
+        self.textures.insert(reference.index, texture.clone());
+        texture
+    }
+
+    fn load_texture(&self, index: usize) -> Result<Texture, String> {
+        let document = self.document;
+        let source = document.textures.get(index).and_then(|t| t.source)
+            .ok_or_else(|| format!("texture {} has no image it can use", index))?;
+        let image = document.images.get(source).ok_or_else(|| format!("missing image {}", source))?;
+        let bytes = match (&image.uri, image.buffer_view) {
+            (Some(uri), _) => read_uri(uri, self.base)?,
+            (None, Some(view)) => {
+                let view = document.buffer_views.get(view).ok_or_else(|| format!("missing buffer view {}", view))?;
+                self.buffers.get(view.buffer).and_then(|b| bytes(b, view.byte_offset, view.byte_length))
+                    .ok_or_else(|| format!("image {} runs past its buffer", source))?.to_vec()
+            }
+            (None, None) => return Err(format!("image {} has no data", source)),
+        };
+        image::load_from_memory(&bytes).map(Texture::from_image).map_err(|e| format!("image {}: {}", source, e))
+    }
+
+    fn primitive(&mut self, primitive: &Primitive, transform: &Matrix) -> Result<Option<Mesh>, String> {
+        if !matches!(primitive.mode, 4..=6) {
+            self.warnings.push(format!("primitives of mode {} are not triangles and were skipped", primitive.mode));
+            return Ok(None);
+        }
+        let position = *primitive.attributes.get("POSITION").ok_or("primitive has no POSITION")?;
+        let positions: Vec<Vector3> = self.vectors(position)?.into_iter().map(|p| transform.transform_point(p)).collect();
+        let corners: Vec<u32> = match primitive.indices {
+            Some(index) => self.read(index, false)?.1.into_iter().map(|i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        let mut indices: Vec<[u32; 3]> = match primitive.mode {
+            4 => corners.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+            5 => (2..corners.len()).map(|k| {
+                let t = [corners[k - 2], corners[k - 1], corners[k]];
+                if k % 2 == 0 { t } else { [t[1], t[0], t[2]] }
+            }).collect(),
+            _ => (2..corners.len()).map(|k| [corners[0], corners[k - 1], corners[k]]).collect(),
+        };
+        if !primitive.targets.is_empty() {
+            self.warnings.push("morph targets are not supported".to_string());
+        }
+        if let Some(bad) = indices.iter().flatten().find(|&&i| i as usize >= positions.len()) {
+            return Err(format!("primitive refers to missing vertex {}", bad));
+        }
+        let normal_transform = transform.inverse().transpose();
+        let normals = match primitive.attributes.get("NORMAL") {
+            Some(&normal) => self.vectors(normal)?.into_iter().map(|n| normal_transform.transform_vector(n).normalize()).collect(),
+            None => Vec::new(),
+        };
+        if transform.determinant() < 0.0 {
+            // mirrored; keep the front faces counter-clockwise
+            for t in &mut indices {
+                t.swap(1, 2);
+            }
+        }
+        let uvs = match primitive.attributes.get("TEXCOORD_0") {
+            // glTF's v runs down the image
+            Some(&uv) => {
+                let (n, values) = self.read(uv, false)?;
+                if n != 2 {
+                    return Err(format!("accessor {} should hold 2D texture coordinates", uv));
+                }
+                values.chunks(2).map(|v| Vector2 { x: v[0] as f32, y: 1.0 - v[1] as f32 }).collect()
+            }
+            None => Vec::new(),
+        };
+        let colors: Vec<Color> = match primitive.attributes.get("COLOR_0") {
+            Some(&color) => {
+                let (n, values) = self.read(color, true)?;
+                if n != 3 && n != 4 {
+                    return Err(format!("accessor {} should hold RGB or RGBA colors", color));
+                }
+                values.chunks(n).map(|c| Color { r: c[0] as f32, g: c[1] as f32, b: c[2] as f32, a: c.get(3).map_or(1.0, |&a| a as f32) }).collect()
+            }
+            None => Vec::new(),
+        };
+        for (name, count) in [("NORMAL", normals.len()), ("TEXCOORD_0", uvs.len()), ("COLOR_0", colors.len())] {
+            if primitive.attributes.contains_key(name) && count != positions.len() {
+                return Err(format!("{} has {} values for {} positions", name, count, positions.len()));
+            }
+        }
+
+        let default = MaterialEntry::default();
+        let material = match primitive.material {
+            Some(m) => self.document.materials.get(m).ok_or_else(|| format!("missing material {}", m))?,
+            None => &default,
+        };
+        let pbr = &material.pbr_metallic_roughness;
+        let [r, g, b, a] = pbr.base_color_factor;
+        let color = Color { r, g, b, a };
+        let texture = pbr.base_color_texture.as_ref().and_then(|t| self.texture(t));
+        let detail_map = material.normal_texture.as_ref().and_then(|t| self.texture(t))
+            .map(|texture| DetailMap::Normal(NormalMap { texture, strength: material.normal_texture.as_ref().map_or(1.0, |t| t.scale), flip_green: false }));
+        if pbr.metallic_roughness_texture.is_some() {
+            self.warnings.push("metallic-roughness textures are not supported; their factors are used".to_string());
+        }
+        let ior = material.extensions.ior.as_ref().map_or(default_ior(), |i| i.ior);
+
+        Ok(Some(Mesh {
+            positions,
+            normals,
+            uvs,
+            colors: colors.into_iter().map(|c| c * color).collect(),
+            indices,
+            color,
+            texture,
+            refractive_index: refractive_index(ior, pbr.metallic_factor, color),
+            detail_map,
+            displacement: None,
+            tree: OnceLock::new(),
+        }))
+    }
+
+    fn light(&mut self, index: usize, transform: &Matrix) -> Result<Light, String> {
+        let entry = self.document.extensions.lights.as_ref().and_then(|l| l.lights.get(index))
+            .ok_or_else(|| format!("missing light {}", index))?;
+        let [r, g, b] = entry.color;
+        let color = Color { r, g, b, a: 1.0 } * entry.intensity;
+        let light_type = match entry.kind.as_str() {
+            "directional" => LightType::Directional,
+            "point" => LightType::Point,
+            other => {
+                self.warnings.push(format!("{} light {} was imported as a point light", other, index));
+                LightType::Point
+            }
+        };
+        Ok(Light {
+            location: transform.transform_point(Vector3::zero()),
+            direction: transform.transform_vector(Vector3 { x: 0.0, y: 0.0, z: -1.0 }).normalize(),
+            light_type,
+            diffuse_color: color,
+            specular_color: color,
+        })
+    }
+}
+
+// The `length` bytes of `buffer` from `offset`, if they are all there.
+fn bytes(buffer: &[u8], offset: usize, length: usize) -> Option<&[u8]> {
+    buffer.get(offset..offset.checked_add(length)?)
+}
+
+// Refractive index whose reflectance at normal incidence blends from the
+// dielectric's towards the base color's brightness as `metallic` rises.
+fn refractive_index(ior: f32, metallic: f32, base: Color) -> f32 {
+    let dielectric = ((ior - 1.0) / (ior + 1.0)).powi(2);
+    let brightness = 0.2126 * base.r + 0.7152 * base.g + 0.0722 * base.b;
+    let f0 = (dielectric + (brightness - dielectric) * metallic).clamp(0.0, 0.98);
+    (1.0 + f0.sqrt()) / (1.0 - f0.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Little-endian floats and shorts packed into one buffer.
+    fn pack(floats: &[f32], shorts: &[u16]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = floats.iter().flat_map(|f| f.to_le_bytes()).collect();
+        bytes.extend(shorts.iter().flat_map(|s| s.to_le_bytes()));
+        bytes
+    }
+
+    fn glb(json: &str, binary: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        while !json.len().is_multiple_of(4) {
+            json.push(b' ');
+        }
+        let mut bytes = b"glTF".to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&((12 + 8 + json.len() + 8 + binary.len()) as u32).to_le_bytes());
+        bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"JSON");
+        bytes.extend_from_slice(&json);
+        bytes.extend_from_slice(&(binary.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"BIN\0");
+        bytes.extend_from_slice(binary);
+        bytes
+    }
+
+    #[test]
+    fn binary_scene() {
+        // a triangle one unit down, under a parent scaled by 2, seen by a
+        // camera at z = 10 with a light pointing straight down
+        let binary = pack(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0], &[0, 1, 2, 0]);
+        let json = r#"{
+            "asset": {"version": "2.0"},
+            "extensionsUsed": ["KHR_lights_punctual", "KHR_materials_clearcoat"],
+            "scene": 0,
+            "scenes": [{"nodes": [0, 2, 3]}],
+            "nodes": [
+                {"scale": [2, 2, 2], "children": [1]},
+                {"translation": [0, -1, 0], "mesh": 0},
+                {"translation": [0, 0, 10], "camera": 0},
+                {"rotation": [-0.7071068, 0, 0, 0.7071068], "extensions": {"KHR_lights_punctual": {"light": 0}}}
+            ],
+            "cameras": [{"type": "perspective", "perspective": {"yfov": 0.7853982, "aspectRatio": 2.0, "znear": 0.1}}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+            "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1, 0.5, 0, 1], "metallicFactor": 0}}],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+                {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+            ],
+            "bufferViews": [{"buffer": 0, "byteLength": 36}, {"buffer": 0, "byteOffset": 36, "byteLength": 6}],
+            "buffers": [{"byteLength": 44}],
+            "extensions": {"KHR_lights_punctual": {"lights": [{"type": "directional", "intensity": 0.5}]}}
+        }"#;
+        let import = parse_gltf(&glb(json, &binary), Path::new(""), 100, 100).unwrap();
+        let scene = &import.scene;
+        assert_eq!((scene.width, scene.height), (200, 100));
+        assert!((scene.fov - 45.0).abs() < 1e-3);
+        assert_eq!(import.warnings.len(), 1);
+        assert!(import.warnings[0].contains("KHR_materials_clearcoat"));
+
+        assert_eq!(scene.shapes.len(), 1);
+        assert!((scene.shapes[0].location() - Vector3 { x: 2.0 / 3.0, y: -4.0 / 3.0, z: -10.0 }).length() < 1e-4);
+        assert!((scene.shapes[0].color().g - 0.5).abs() < 1e-6);
+        assert!((scene.shapes[0].refractive_index() - 1.5).abs() < 1e-4);
+
+        assert_eq!(scene.lights.len(), 1);
+        assert!(scene.lights[0].light_type == LightType::Directional);
+        assert!((scene.lights[0].direction.y + 1.0).abs() < 1e-5);
+        assert!((scene.lights[0].diffuse_color.r - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn embedded_buffer() {
+        // a strip of two triangles, colors as normalized bytes, no camera
+        let mut binary = pack(&[0.0, 0.0, -2.0, 1.0, 0.0, -2.0, 0.0, 1.0, -2.0, 1.0, 1.0, -2.0], &[]);
+        binary.extend_from_slice(&[255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255]);
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let encoded: String = binary.chunks(3).flat_map(|c| {
+            let n = (u32::from(c[0]) << 16) | (u32::from(*c.get(1).unwrap_or(&0)) << 8) | u32::from(*c.get(2).unwrap_or(&0));
+            (0..4).map(move |k| if k <= c.len() { ALPHABET[(n >> (18 - 6 * k) & 63) as usize] as char } else { '=' })
+        }).collect();
+        assert_eq!(decode_base64(&encoded).unwrap(), binary);
+
+        let json = format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "nodes": [{{"mesh": 0}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0, "COLOR_0": 1}}, "mode": 5}}]}}],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3"}},
+                {{"bufferView": 0, "byteOffset": 48, "componentType": 5121, "normalized": true, "count": 4, "type": "VEC4"}}
+            ],
+            "bufferViews": [{{"buffer": 0, "byteLength": 64}}],
+            "buffers": [{{"byteLength": 64, "uri": "data:application/octet-stream;base64,{}"}}]
+        }}"#, encoded);
+        assert!(external_files(json.as_bytes(), Path::new("models")).unwrap().is_empty());
+        let external = json.replace(&format!("data:application/octet-stream;base64,{}", encoded), "strip%20data.bin");
+        assert_eq!(external_files(external.as_bytes(), Path::new("models")).unwrap(), [Path::new("models").join("strip data.bin")]);
+        let import = parse_gltf(json.as_bytes(), Path::new(""), 160, 90).unwrap();
+        assert_eq!(import.scene.width, 160);
+        assert!(import.warnings.iter().any(|w| w.contains("no camera")));
+
+        let mut hit = crate::shape::Hit::new();
+        let ray = crate::ray::Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.05, y: 0.05, z: -1.0 }.normalize() };
+        assert_eq!(import.scene.shapes[0].intersect(&ray, &mut hit), 2);
+        assert!(hit.color.unwrap().r > 0.5);
+        let ray = crate::ray::Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.45, y: 0.4, z: -1.0 }.normalize() };
+        assert_eq!(import.scene.shapes[0].intersect(&ray, &mut hit), 2);
+    }
+
+    #[test]
+    fn mismatched_attributes() {
+        // a triangle with one extra float after its positions
+        let binary = pack(&[0.0, 0.0, -2.0, 1.0, 0.0, -2.0, 0.0, 1.0, -2.0, 0.5], &[]);
+        let gltf = |attribute: &str, accessor: &str| format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "nodes": [{{"mesh": 0}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0, "{}": 1}}}}]}}],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                {}
+            ],
+            "bufferViews": [{{"buffer": 0, "byteLength": 40}}],
+            "buffers": [{{"byteLength": 40}}]
+        }}"#, attribute, accessor);
+        let import = |attribute: &str, accessor: &str| parse_gltf(&glb(&gltf(attribute, accessor), &binary), Path::new(""), 100, 100).map(|_| ());
+
+        assert!(import("NORMAL", r#"{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}"#).is_ok());
+        let error = import("NORMAL", r#"{"bufferView": 0, "componentType": 5126, "count": 2, "type": "VEC3"}"#).unwrap_err();
+        assert!(error.contains("NORMAL has 2 values for 3 positions"));
+        let error = import("COLOR_0", r#"{"bufferView": 0, "componentType": 5126, "count": 10, "type": "SCALAR"}"#).unwrap_err();
+        assert!(error.contains("RGB or RGBA"));
+        let error = import("TEXCOORD_0", r#"{"bufferView": 0, "componentType": 5126, "count": 10, "type": "SCALAR"}"#).unwrap_err();
+        assert!(error.contains("2D texture coordinates"));
+        assert!(import("TEXCOORD_0", r#"{"bufferView": 0, "componentType": 5126, "count": 5, "type": "VEC2"}"#).is_err());
+
+        // counts and strides are checked against the data before anything
+        // is allocated; the first primitive has no indices, so its count
+        // comes from the POSITION values read
+        let error = import("NORMAL", r#"{"bufferView": 0, "componentType": 5126, "count": 4611686018427387904, "type": "VEC3"}"#).unwrap_err();
+        assert!(error.contains("more than its buffer view holds"), "{}", error);
+        let error = import("NORMAL", r#"{"componentType": 5126, "count": 3, "type": "VEC3"}"#).unwrap_err();
+        assert!(error.contains("no buffer view"), "{}", error);
+        let normals = gltf("NORMAL", r#"{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}"#);
+        let parse = |json: String| parse_gltf(&glb(&json, &binary), Path::new(""), 100, 100).map(|_| ()).unwrap_err();
+        let error = parse(normals.replacen(r#""count": 3"#, r#""count": 18446744073709551615"#, 1));
+        assert!(error.contains("accessor 0 has 18446744073709551615 values"), "{}", error);
+        let error = parse(normals.replace(r#""byteLength": 40}],"#, r#""byteLength": 40, "byteStride": 0}],"#));
+        assert!(error.contains("in a stride of 0"), "{}", error);
+    }
+
+    #[test]
+    fn metallic_reflectance() {
+        let white = Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+        assert!((refractive_index(1.5, 0.0, white) - 1.5).abs() < 1e-4);
+        assert!(refractive_index(1.5, 1.0, white) > 10.0);
+        assert!(parse_gltf(b"glTF\x01\0\0\0", Path::new(""), 1, 1).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, Cube, nearest_boundary, intersect_triangle};
+use crate::math::clamp;
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::quaternion::Quat;
+use crate::math::noise::fbm;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use image::DynamicImage;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde_derive::Deserialize;
+
+/// Regular grid of heights in [0, 1], `width` samples along x by `depth`
+/// along z.
+pub struct HeightGrid {
+    width: usize,
+    depth: usize,
+    heights: Vec<f32>,
+}
+
+impl HeightGrid {
+    pub fn new(width: usize, depth: usize, heights: Vec<f32>) -> HeightGrid {
+        assert!(width >= 2 && depth >= 2, "a height grid needs at least 2x2 samples");
+        assert_eq!(heights.len(), width * depth);
+        HeightGrid { width, depth, heights }
+    }
+
+    /// Heights from the image's luminance. The top row of the image lies
+    /// along the far (-z) edge, so textures line up with the image.
+    pub fn from_image(image: &DynamicImage) -> HeightGrid {
+        let luma = image.to_luma16();
+        let heights = luma.pixels().map(|p| f32::from(p[0]) / 65535.0).collect();
+        HeightGrid::new(luma.width() as usize, luma.height() as usize, heights)
+    }
+
+    pub fn open(path: &str) -> Result<HeightGrid, String> {
+        let image = image::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        if image.width() < 2 || image.height() < 2 {
+            return Err(format!("{}: a height image needs at least 2x2 pixels, got {}x{}", path, image.width(), image.height()));
+        }
+        Ok(HeightGrid::from_image(&image))
+    }
+
+    /// Square grid of fractal noise with `frequency` features across it.
+    pub fn from_noise(resolution: usize, frequency: f32, octaves: u32, seed: u32) -> HeightGrid {
+        let scale = frequency / (resolution - 1) as f32;
+        let mut heights = Vec::with_capacity(resolution * resolution);
+        for j in 0..resolution {
+            for i in 0..resolution {
+                // off the lattice plane, where every octave would vanish
+                let p = Vector3 { x: i as f32 * scale, y: 0.5, z: j as f32 * scale };
+                heights.push(clamp(0.5 + 0.5 * fbm(p, octaves, seed), 0.0, 1.0));
+            }
+        }
+        HeightGrid::new(resolution, resolution, heights)
+    }
+
+    pub fn height(&self, i: usize, j: usize) -> f32 {
+        self.heights[j * self.width + i]
+    }
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+// Samples along each side of a noise grid; past this the grid alone
+// outgrows what a scene can spend on one shape.
+const MAX_RESOLUTION: usize = 4096;
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum HeightSource {
+    Image {
+        path: String,
+    },
+    Noise {
+        resolution: usize,
+        frequency: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u32,
+    },
+}
+
+impl<'de> Deserialize<'de> for HeightGrid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HeightGrid, D::Error> {
+        match HeightSource::deserialize(deserializer)? {
+            HeightSource::Image { path } => HeightGrid::open(&path).map_err(D::Error::custom),
+            HeightSource::Noise { resolution, .. } if resolution < 2 => Err(D::Error::custom("noise resolution must be at least 2")),
+            HeightSource::Noise { resolution, .. } if resolution > MAX_RESOLUTION => {
+                Err(D::Error::custom(format!("noise resolution must be at most {}, got {}", MAX_RESOLUTION, resolution)))
+            }
+            HeightSource::Noise { resolution, frequency, octaves, seed } => Ok(HeightGrid::from_noise(resolution, frequency, octaves, seed)),
+        }
+    }
+}
+
+/// Terrain over the box from `location` to `location + size`, with the grid
+/// stretched across x and z and heights scaled by `size.y`. It is solid
+/// down to the bottom of the box.
+#[derive(Deserialize)]
+pub struct Heightfield {
+    pub location: Vector3,
+    pub size: Vector3,
+    pub grid: HeightGrid,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Heightfield {
+    fn cell_size(&self) -> (f32, f32) {
+        (self.size.x / (self.grid.width - 1) as f32, self.size.z / (self.grid.depth - 1) as f32)
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Vector3 {
+        let (dx, dz) = self.cell_size();
+        self.location + Vector3 { x: i as f32 * dx, y: self.grid.height(i, j) * self.size.y, z: j as f32 * dz }
+    }
+
+    // Smooth normal from central differences, one-sided at the edges.
+    fn vertex_normal(&self, i: usize, j: usize) -> Vector3 {
+        let (dx, dz) = self.cell_size();
+        let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.grid.width - 1));
+        let (j0, j1) = (j.saturating_sub(1), (j + 1).min(self.grid.depth - 1));
+        let slope_x = (self.grid.height(i1, j) - self.grid.height(i0, j)) * self.size.y / ((i1 - i0) as f32 * dx);
+        let slope_z = (self.grid.height(i, j1) - self.grid.height(i, j0)) * self.size.y / ((j1 - j0) as f32 * dz);
+        Vector3 { x: -slope_x, y: 1.0, z: -slope_z }.normalize()
+    }
+
+    fn uv(&self, p: Vector3) -> Vector2 {
+        Vector2 { x: (p.x - self.location.x) / self.size.x, y: 1.0 - (p.z - self.location.z) / self.size.z }
+    }
+
+    // Terrain height at a point of the footprint.
+    fn height_at(&self, x: f32, z: f32) -> f32 {
+        let (dx, dz) = self.cell_size();
+        let gx = clamp((x - self.location.x) / dx, 0.0, (self.grid.width - 1) as f32);
+        let gz = clamp((z - self.location.z) / dz, 0.0, (self.grid.depth - 1) as f32);
+        let i = (gx.floor() as usize).min(self.grid.width - 2);
+        let j = (gz.floor() as usize).min(self.grid.depth - 2);
+        let (fx, fz) = (gx - i as f32, gz - j as f32);
+        let h = |di: usize, dj: usize| self.grid.height(i + di, j + dj);
+        // the same split as the triangles of `cell_crossings`
+        let height = if fz >= fx {
+            h(0, 0) + fx * (h(1, 1) - h(0, 1)) + fz * (h(0, 1) - h(0, 0))
+        } else {
+            h(0, 0) + fx * (h(1, 0) - h(0, 0)) + fz * (h(1, 1) - h(1, 0))
+        };
+        self.location.y + height * self.size.y
+    }
+
+    // Surface crossings inside cell (i, j) between `t_enter` and `t_exit`.
+    fn cell_crossings(&self, ray: &Ray, i: usize, j: usize, t_enter: f32, t_exit: f32, crossings: &mut Vec<(f32, Hit)>) {
+        let corners = [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)];
+        let (mut low, mut high) = (f32::INFINITY, f32::NEG_INFINITY);
+        for &(ci, cj) in &corners {
+            let y = self.location.y + self.grid.height(ci, cj) * self.size.y;
+            low = low.min(y);
+            high = high.max(y);
+        }
+        let y0 = ray.origin.y + t_enter * ray.direction.y;
+        let y1 = ray.origin.y + t_exit * ray.direction.y;
+        if y0.min(y1) > high || y0.max(y1) < low {
+            return;
+        }
+
+        // counter-clockwise seen from above, so the faces point up
+        for tri in &[[(i, j), (i, j + 1), (i + 1, j + 1)], [(i, j), (i + 1, j + 1), (i + 1, j)]] {
+            let v = [self.vertex(tri[0].0, tri[0].1), self.vertex(tri[1].0, tri[1].1), self.vertex(tri[2].0, tri[2].1)];
+            if let Some((t, b1, b2)) = intersect_triangle(ray, v[0], v[1], v[2]) {
+                if t >= t_enter && t < t_exit {
+                    crossings.push((t, self.surface(ray, t, tri, v, b1, b2)));
+                }
+            }
+        }
+    }
+
+    fn surface(&self, ray: &Ray, t: f32, tri: &[(usize, usize); 3], v: [Vector3; 3], b1: f32, b2: f32) -> Hit {
+        let b0 = 1.0 - b1 - b2;
+        let normal = (b0 * self.vertex_normal(tri[0].0, tri[0].1)
+            + b1 * self.vertex_normal(tri[1].0, tri[1].1)
+            + b2 * self.vertex_normal(tri[2].0, tri[2].1)).normalize();
+
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = normal;
+        hit.geometric_normal = (v[1] - v[0]).cross(&(v[2] - v[0])).normalize();
+        // u runs along +x and v along -z
+        let along_x = Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        hit.tangent = (along_x - normal * normal.dot(&along_x)).normalize();
+        hit.bitangent = normal.cross(&hit.tangent);
+        hit.uv = self.uv(hit.point);
+        hit
+    }
+
+    // Sorted surface crossings between `t0` and `t1`, walking the cells the
+    // line passes over with a 2D DDA.
+    fn crossings(&self, ray: &Ray, t0: f32, t1: f32) -> Vec<(f32, Hit)> {
+        let (dx, dz) = self.cell_size();
+        let (last_i, last_j) = (self.grid.width - 2, self.grid.depth - 2);
+        let mid = ray.origin + (0.5 * (t0 + t1)) * ray.direction;
+        let start = ray.origin + t0 * ray.direction;
+        // the cell the line is in just after entering the box
+        let cell = |p: f32, q: f32, size: f32, last: usize| clamp(((p - q) / size).floor(), 0.0, last as f32) as usize;
+        let pick = |p: f32, q: f32, d: f32| if d == 0.0 { q } else { p };
+        let mut i = cell(pick(start.x, mid.x, ray.direction.x), self.location.x, dx, last_i);
+        let mut j = cell(pick(start.z, mid.z, ray.direction.z), self.location.z, dz, last_j);
+
+        let axis = |o: f32, d: f32, base: f32, size: f32, index: usize| -> (f32, f32) {
+            if d > 0.0 {
+                ((base + (index + 1) as f32 * size - o) / d, size / d)
+            } else if d < 0.0 {
+                ((base + index as f32 * size - o) / d, -size / d)
+            } else {
+                (f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (mut next_x, step_x) = axis(ray.origin.x, ray.direction.x, self.location.x, dx, i);
+        let (mut next_z, step_z) = axis(ray.origin.z, ray.direction.z, self.location.z, dz, j);
+
+        // moves to the neighbouring cell, unless that leaves the grid
+        let step = |index: &mut usize, d: f32, last: usize| {
+            if d > 0.0 && *index < last {
+                *index += 1;
+                true
+            } else if d < 0.0 && *index > 0 {
+                *index -= 1;
+                true
+            } else {
+                false
+            }
+        };
+        let mut crossings = Vec::new();
+        let mut t = t0;
+        while t < t1 {
+            let t_exit = next_x.min(next_z).min(t1);
+            // the last cell takes the crossings on the far boundary too
+            let end = if t_exit >= t1 { f32::INFINITY } else { t_exit };
+            self.cell_crossings(ray, i, j, if t == t0 { f32::NEG_INFINITY } else { t }, end, &mut crossings);
+            t = t_exit;
+            let stepped = if next_x <= next_z {
+                next_x += step_x;
+                step(&mut i, ray.direction.x, last_i)
+            } else {
+                next_z += step_z;
+                step(&mut j, ray.direction.z, last_j)
+            };
+            if !stepped {
+                break;
+            }
+        }
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        crossings
+    }
+}
+
+impl Shape for Heightfield {
+    fn location(&self) -> Vector3 {
+        self.location + self.size / 2.0
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Heightfield {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::new();
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let bounds = Cube {
+            location: self.location(),
+            color: self.color,
+            extent: self.size / 2.0,
+            rotation: Quat::identity(),
+            refractive_index: self.refractive_index,
+            detail_map: None,
+        };
+        let mut clipped = Vec::with_capacity(1);
+        bounds.intervals(ray, &mut clipped);
+        let Interval { t_in: t0, t_out: t1, enter, exit } = match clipped.pop() {
+            Some(box_interval) if box_interval.t_in.is_finite() && box_interval.t_out.is_finite() => box_interval,
+            // a line that never leaves the box runs parallel to the ground
+            _ => return,
+        };
+
+        let crossings = self.crossings(ray, t0, t1);
+
+        // whether the line starts under the terrain, tested away from edges
+        let probe_t = 0.5 * (t0 + crossings.first().map_or(t1, |c| c.0));
+        let probe = ray.origin + probe_t * ray.direction;
+        let under = probe.y < self.height_at(probe.x, probe.z);
+
+        let with_uv = |mut hit: Hit| {
+            hit.uv = self.uv(hit.point);
+            hit
+        };
+        let mut open = if under { Some((t0, with_uv(enter))) } else { None };
+        for (t, hit) in crossings {
+            let entering = ray.direction.dot(&hit.geometric_normal) < 0.0;
+            match open {
+                None if entering => open = Some((t, hit)),
+                Some((t_in, enter)) if !entering => {
+                    if t > t_in {
+                        intervals.push(Interval { t_in, t_out: t, enter, exit: hit });
+                    }
+                    open = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some((t_in, enter)) = open {
+            intervals.push(Interval { t_in, t_out: t1, enter, exit: with_uv(exit) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ridge along z: heights 0, 1, 0 across x.
+    fn ridge() -> Heightfield {
+        Heightfield {
+            location: Vector3 { x: -2.0, y: -1.0, z: -10.0 },
+            size: Vector3 { x: 4.0, y: 2.0, z: 4.0 },
+            grid: HeightGrid::new(3, 3, vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0]),
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        }
+    }
+
+    fn down(x: f32, z: f32) -> Ray {
+        Ray { origin: Vector3 { x, y: 5.0, z }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } }
+    }
+
+    #[test]
+    fn from_above() {
+        let terrain = ridge();
+        let mut hit = Hit::new();
+        assert_eq!(terrain.intersect(&down(1.0, -8.5), &mut hit), 2);
+        assert!(hit.point.y.abs() < 1e-4);
+        assert!((hit.uv.x - 0.75).abs() < 1e-4 && (hit.uv.y - 0.625).abs() < 1e-4);
+        // halfway down the slope the normal leans between the face and the
+        // vertical crest normal
+        let face = Vector3 { x: 1.0, y: 1.0, z: 0.0 }.normalize();
+        assert!((hit.geometric_normal - face).length() < 1e-4);
+        assert!(hit.normal.x > 0.0 && hit.normal.x < face.x);
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+
+        let mut intervals = Vec::new();
+        terrain.intervals(&down(1.0, -8.5), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_out - 6.0).abs() < 1e-4);
+        assert!((intervals[0].exit.normal.y + 1.0).abs() < 1e-4);
+
+        assert_eq!(terrain.intersect(&down(3.0, -8.0), &mut hit), 0);
+    }
+
+    #[test]
+    fn across_the_ridge() {
+        let terrain = ridge();
+        let mut intervals = Vec::new();
+        // skims over the valleys, through the ridge and out the other side
+        let ray = Ray { origin: Vector3 { x: -5.0, y: 0.0, z: -7.5 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+        terrain.intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_in - 4.0).abs() < 1e-4);
+        assert!((intervals[0].t_out - 6.0).abs() < 1e-4);
+
+        // inside the hill, the line leaves through the slope in front of it
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 0.0, z: -8.0 }, direction: Vector3 { x: -1.0, y: 0.0, z: 0.0 } };
+        assert_eq!(terrain.intersect(&ray, &mut hit), 1);
+        assert!((hit.point.x + 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn noise_grid() {
+        let grid = HeightGrid::from_noise(17, 3.0, 4, 7);
+        assert_eq!((grid.width, grid.depth), (17, 17));
+        let mut distinct = false;
+        for j in 0..17 {
+            for i in 0..17 {
+                assert!((0.0..=1.0).contains(&grid.height(i, j)));
+                distinct |= grid.height(i, j) != grid.height(0, 0);
+            }
+        }
+        assert!(distinct);
+
+        let terrain = Heightfield { grid, ..ridge() };
+        let mut hit = Hit::new();
+        for k in 0..20 {
+            let (x, z) = (-1.9 + 0.19 * k as f32, -9.9 + 0.19 * k as f32);
+            assert_eq!(terrain.intersect(&down(x, z), &mut hit), 2);
+            assert!((hit.point.y - terrain.height_at(x, z)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn image_too_small() {
+        let path = std::env::temp_dir().join(format!("ray-tracer-heightfield-{}.png", std::process::id()));
+        image::GrayImage::new(1, 4).save(&path).unwrap();
+        let json = format!(r#"{{"type": "Image", "path": {:?}}}"#, path.to_str().unwrap());
+        let error = serde_json::from_str::<HeightGrid>(&json).err().unwrap().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.contains("at least 2x2 pixels, got 1x4"));
+        assert!(serde_json::from_str::<HeightGrid>(r#"{"type": "Noise", "resolution": 1, "frequency": 1}"#).is_err());
+        let error = serde_json::from_str::<HeightGrid>(r#"{"type": "Noise", "resolution": 18446744073709551615, "frequency": 1}"#).err().unwrap();
+        assert!(error.to_string().contains("at most 4096"), "{}", error);
+    }
+}
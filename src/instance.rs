@@ -0,0 +1,172 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval};
+use crate::math::matrix::Matrix;
+use crate::math::vector::Vector3;
+use crate::math::quaternion::Quat;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use crate::light::Hair;
+
+use std::sync::Arc;
+
+/// A shared shape placed in the world by an object-to-world transform.
+/// Matrices use the row-vector convention of `Matrix`, so
+/// `Matrix::scale(..) * Matrix::translate(..)` scales first.
+pub struct Instance {
+    shape: Arc<dyn Shape>,
+    object_to_world: Matrix,
+    world_to_object: Matrix,
+    normal_to_world: Matrix,
+}
+
+impl Instance {
+    pub fn new(shape: Arc<dyn Shape>, object_to_world: Matrix) -> Instance {
+        let world_to_object = object_to_world.inverse();
+        Instance {
+            shape,
+            object_to_world,
+            world_to_object,
+            normal_to_world: world_to_object.transpose(),
+        }
+    }
+
+    /// Scales, then rotates, then translates `shape`.
+    pub fn from_trs(shape: Arc<dyn Shape>, translation: Vector3, rotation: Quat, scale: Vector3) -> Instance {
+        let transform = Matrix::scale(scale.x, scale.y, scale.z)
+            * rotation.to_matrix()
+            * Matrix::translate(translation.x, translation.y, translation.z);
+        Instance::new(shape, transform)
+    }
+}
+
+impl Shape for Instance {
+    fn location(&self) -> Vector3 {
+        self.object_to_world.transform_point(self.shape.location())
+    }
+    fn color(&self) -> Color {
+        self.shape.color()
+    }
+    fn refractive_index(&self) -> f32 {
+        self.shape.refractive_index()
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.shape.detail_map()
+    }
+    fn hair(&self) -> Option<&Hair> {
+        self.shape.hair()
+    }
+}
+
+impl Instance {
+    fn to_object(&self, ray: &Ray) -> (Ray, f32) {
+        let direction = self.world_to_object.transform_vector(ray.direction);
+        let scale = direction.length();
+        let local_ray = Ray {
+            origin: self.world_to_object.transform_point(ray.origin),
+            direction: direction / scale,
+        };
+        (local_ray, scale)
+    }
+
+    fn to_world(&self, local: &Hit) -> Hit {
+        let normal = self.normal_to_world.transform_vector(local.normal).normalize();
+        let tangent = self.object_to_world.transform_vector(local.tangent);
+        let bitangent = self.object_to_world.transform_vector(local.bitangent);
+        let tangent = (tangent - normal * normal.dot(&tangent)).normalize();
+        let mut orthogonal = normal.cross(&tangent);
+        // mirroring transforms flip the handedness of the frame
+        if orthogonal.dot(&bitangent) < 0.0 {
+            orthogonal = -orthogonal;
+        }
+
+        Hit {
+            point: self.object_to_world.transform_point(local.point),
+            normal,
+            geometric_normal: self.normal_to_world.transform_vector(local.geometric_normal).normalize(),
+            uv: local.uv,
+            tangent,
+            bitangent: orthogonal,
+            color: local.color,
+        }
+    }
+}
+
+impl Intersectable for Instance {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let (local_ray, _) = self.to_object(ray);
+        let mut local = Hit::new();
+        let count = self.shape.intersect(&local_ray, &mut local);
+        if count > 0 {
+            *hit = self.to_world(&local);
+        }
+        count
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let (local_ray, scale) = self.to_object(ray);
+        let mut local = Vec::new();
+        self.shape.intervals(&local_ray, &mut local);
+        // distances along the normalized object-space ray shrink by `scale`
+        intervals.extend(local.iter().map(|i| Interval {
+            t_in: i.t_in / scale,
+            t_out: i.t_out / scale,
+            enter: self.to_world(&i.enter),
+            exit: self.to_world(&i.exit),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Sphere;
+    use crate::curve::{Curve, CurveBasis, CurveType};
+
+    #[test]
+    fn scaled_sphere() {
+        let sphere: Arc<dyn Shape> = Arc::new(Sphere {
+            center: Vector3::zero(),
+            radius: 1.0,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        });
+        let instance = Instance::from_trs(
+            sphere.clone(),
+            Vector3 { x: 0.0, y: 0.0, z: -10.0 },
+            Quat::new(0.0, 0.0, 0.0, 1.0),
+            Vector3 { x: 2.0, y: 1.0, z: 1.0 });
+        let mut hit = Hit::new();
+
+        let ray = Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(instance.intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 9.0).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+
+        // the ellipsoid is 2 wide along x, so a ray at x = 1.5 still hits it
+        let ray = Ray { origin: Vector3 { x: 1.5, y: 0.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(instance.intersect(&ray, &mut hit), 2);
+        let expected = Vector3 { x: 1.5 / 4.0, y: 0.0, z: (1.0f32 - 0.75 * 0.75).sqrt() }.normalize();
+        assert!((hit.normal - expected).length() < 1e-3);
+
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 1.5, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(instance.intersect(&ray, &mut hit), 0);
+    }
+
+    #[test]
+    fn instanced_hair() {
+        let curve: Arc<dyn Shape> = Arc::new(Curve {
+            control_points: vec![Vector3::zero(), Vector3 { x: 1.0, y: 0.0, z: 0.0 }, Vector3 { x: 2.0, y: 0.0, z: 0.0 }, Vector3 { x: 3.0, y: 0.0, z: 0.0 }],
+            basis: CurveBasis::Bezier,
+            curve_type: CurveType::Ribbon,
+            start_width: 0.1,
+            end_width: 0.1,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            hair: Some(Hair { exponent: 40.0, specular: 0.5, shift: 0.0 }),
+            detail_map: None,
+        });
+        let instance = Instance::new(curve, Matrix::translate(0.0, 0.0, -5.0));
+        assert_eq!(instance.hair().map(|h| h.exponent), Some(40.0));
+    }
+}
@@ -1,45 +1,87 @@
 use crate::math::vector::*;
 use crate::color::Color;
 use crate::math::*;
-use crate::shape::Shape;
+use serde_derive::Deserialize;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Deserialize)]
 pub enum LightType {
     Point,
     Directional,
 }
 
+/// Point lights shine from `location`, directional ones along `direction`;
+/// each ignores the other field.
+#[derive(Deserialize)]
 pub struct Light {
+    #[serde(default = "Vector3::zero")]
     pub location: Vector3,
+    #[serde(default = "Vector3::zero")]
     pub direction: Vector3,
     pub light_type: LightType,
     pub diffuse_color: Color,
     pub specular_color: Color,
 }
 
-pub fn blinn_phong(shape: &dyn Shape, light: &Light, pos: Vector3, view: Vector3, normal: Vector3) -> (Color, Color) {    
-    let mut light_dir;
-    let mut distance;
+// Unit direction towards the light and the falloff of its intensity.
+fn incidence(light: &Light, pos: Vector3) -> (Vector3, f32) {
     if light.light_type == LightType::Directional {
-        light_dir = -light.direction;
-        distance = 1.0;
+        (-light.direction.normalize(), 1.0)
     } else {
-        light_dir = light.location - pos;
-        distance = light_dir.length();
-        light_dir = light_dir / distance;
-        distance = distance * distance;
+        let light_dir = light.location - pos;
+        let distance = light_dir.length();
+        (light_dir / distance, distance * distance)
     }
+}
+
+pub fn blinn_phong(color: Color, light: &Light, pos: Vector3, view: Vector3, normal: Vector3) -> (Color, Color) {    
+    let (light_dir, distance) = incidence(light, pos);
 
     let n_dot_l = normal.dot(&light_dir);
     let diffuse_intensity = clamp(n_dot_l, 0.0, 1.0);
 
-    let diffuse = shape.color() * diffuse_intensity as f32 * light.diffuse_color * (1.0/* diffuse power */ / distance) as f32;
+    let diffuse = color * diffuse_intensity * light.diffuse_color * (1.0/* diffuse power */ / distance);
 
     let half = (light_dir + view.normalize()).normalize();
     let n_dot_h = half.dot(&normal);
     let specular_intensity = clamp(n_dot_h, 0.0, 1.0).powf(4.0/* specular hardness */);
 
-    let specular = shape.color() * specular_intensity as f32 * light.specular_color * (1.0/* specular power */ / distance) as f32;
+    let specular = color * specular_intensity * light.specular_color * (1.0/* specular power */ / distance);
+
+    (diffuse, specular)
+}
+
+fn default_hair_exponent() -> f32 {
+    80.0
+}
+
+fn default_hair_specular() -> f32 {
+    0.5
+}
+
+/// Kajiya-Kay fiber shading, for hair, fur and grass. A strand scatters
+/// light in a cone around its tangent rather than about a normal.
+/// `shift` tilts the highlight cone towards the normal, as the scales on
+/// a real hair do.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Hair {
+    #[serde(default = "default_hair_exponent")]
+    pub exponent: f32,
+    #[serde(default = "default_hair_specular")]
+    pub specular: f32,
+    #[serde(default)]
+    pub shift: f32,
+}
+
+pub fn kajiya_kay(color: Color, light: &Light, hair: &Hair, pos: Vector3, view: Vector3, tangent: Vector3, normal: Vector3) -> (Color, Color) {
+    let (light_dir, distance) = incidence(light, pos);
+    let sine = |a: Vector3, b: Vector3| (1.0 - a.dot(&b).powi(2)).max(0.0).sqrt();
+
+    let diffuse = color * sine(tangent, light_dir) * light.diffuse_color * (1.0 / distance);
+
+    let shifted = (tangent + normal * hair.shift).normalize();
+    let half = (light_dir + view.normalize()).normalize();
+    let specular_intensity = sine(shifted, half).powf(hair.exponent) * hair.specular;
+    let specular = color * specular_intensity * light.specular_color * (1.0 / distance);
 
     (diffuse, specular)
-}
\ No newline at end of file
+}
@@ -4,35 +4,60 @@ mod ray;
 mod shape;
 mod light;
 mod color;
+mod texture;
+mod instance;
+mod csg;
+mod quadric;
+mod torus;
+mod sdf;
+mod blob;
+mod heightfield;
+mod bezier;
+mod curve;
+mod subdivision;
+mod displacement;
+mod voxel;
+mod meshfile;
+mod gltf;
+mod scenefile;
+mod validate;
+mod progressive;
+mod checkpoint;
+mod distributed;
+mod server;
+mod renderer;
 
 use crate::scene::*;
 use crate::shape::*;
 use crate::color::Color;
 use crate::light::*;
 use crate::math::vector::Vector3;
+use crate::math::quaternion::Quat;
 
-use std::vec::Vec;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-fn main() {
-    let mut lights = Vec::<Light>::new();
-    lights.push(Light {
+fn demo_scene() -> Scene {
+    let lights = vec![
+        Light {
             location: Vector3::zero(),
             direction: Vector3{ x: -1.0, y: -1.0, z: 0.0 },
             light_type: LightType::Directional,
             diffuse_color: Color { r: 0.4, g: 0.4, b: 0.1, a: 1.0 },
             specular_color: Color { r: 0.8, g: 0.8, b: 0.0, a: 1.0 },
-    });
-
-    lights.push(Light {
+        },
+        Light {
             location: Vector3::zero(),
             direction: Vector3{ x: 1.0, y: -2.0, z: 0.0 },
             light_type: LightType::Directional,
             diffuse_color: Color { r: 0.4, g: 0.3, b: 0.7, a: 1.0 },
             specular_color: Color { r: 0.8, g: 0.1, b: 1.0, a: 1.0 },
-    });
+        },
+    ];
 
-    let mut shapes = Vec::<&dyn Shape>::new();
-    shapes.push(&Sphere{
+    let shapes: Vec<Box<dyn Shape>> = vec![
+        Box::new(Sphere{
             center: Vector3{
                 x: 0.0,
                 y: 0.0,
@@ -45,10 +70,10 @@ fn main() {
                 b: 0.4,
                 a: 1.0
             },
-            refractive_index: 1.0
-    });
-
-    shapes.push(&Sphere{
+            refractive_index: 1.0,
+            detail_map: None,
+        }),
+        Box::new(Sphere{
             center: Vector3{
                 x: -4.0,
                 y: 2.0,
@@ -61,10 +86,10 @@ fn main() {
                 b: 8.0,
                 a: 1.0
             },
-            refractive_index: 1.2
-    });
-
-    shapes.push(&Sphere{
+            refractive_index: 1.2,
+            detail_map: None,
+        }),
+        Box::new(Sphere{
             center: Vector3{
                 x: -1.0,
                 y: -1.0,
@@ -77,10 +102,10 @@ fn main() {
                 b: 0.0,
                 a: 1.0
             },
-            refractive_index: 1.4
-    });
-
-    shapes.push(&Sphere{
+            refractive_index: 1.4,
+            detail_map: None,
+        }),
+        Box::new(Sphere{
             center: Vector3{
                 x: 2.0,
                 y: 1.0,
@@ -93,10 +118,10 @@ fn main() {
                 b: 1.0,
                 a: 1.0
             },
-            refractive_index: 1.5
-    });
-
-    shapes.push(&Sphere{
+            refractive_index: 1.5,
+            detail_map: None,
+        }),
+        Box::new(Sphere{
             center: Vector3{
                 x: -8.5,
                 y: 5.0,
@@ -109,19 +134,386 @@ fn main() {
                 b: 0.3,
                 a: 1.0
             },
-            refractive_index: 1.0
-    });
+            refractive_index: 1.0,
+            detail_map: None,
+        }),
+        Box::new(Cube{
+            location: Vector3{
+                x: 3.0,
+                y: -1.5,
+                z: -6.0,
+            },
+            color: Color{
+                r: 0.8,
+                g: 0.3,
+                b: 0.3,
+                a: 1.0
+            },
+            extent: Vector3::from_one(0.5),
+            rotation: Quat::new(0.0, 0.382_683_4, 0.0, 0.923_879_5),
+            refractive_index: 1.0,
+            detail_map: None,
+        }),
+        Box::new(Plane{
+            location: Vector3{
+                x: 0.0,
+                y: -2.0,
+                z: 0.0,
+            },
+            normal: Vector3{
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            color: Color{
+                r: 0.5,
+                g: 0.5,
+                b: 0.5,
+                a: 1.0
+            },
+            uv_scale: 1.0,
+            refractive_index: 1.0,
+            detail_map: None,
+        }),
+    ];
 
-    let scene = Scene {
+    Scene {
         width: 1280,
         height: 720,
         fov: 90.0,
         lights,
         shapes,
+    }
+}
+
+// Reads the scene named on the command line, or the demo scene, printing
+// any problems found in it.
+fn load(path: Option<&str>) -> Option<Scene> {
+    let (scene, mut diagnostics) = match path {
+        Some(path) if path.ends_with(".gltf") || path.ends_with(".glb") => match gltf::load_gltf(path, 1280, 720) {
+            Ok(import) => {
+                let diagnostics = import.warnings.into_iter()
+                    .map(|message| validate::Diagnostic { severity: validate::Severity::Warning, location: path.to_string(), message })
+                    .collect();
+                (Some(import.scene), diagnostics)
+            }
+            Err(message) => (None, vec![validate::Diagnostic { severity: validate::Severity::Error, location: path.to_string(), message }]),
+        },
+        Some(path) => scenefile::read_scene(path),
+        None => (Some(demo_scene()), Vec::new()),
     };
-    let image = render(&scene);
-    let r = image.save(std::path::Path::new(r#"./Render.png"#));
-    if r.is_err(){
-        println!("last OS error: {:?}", std::io::Error::last_os_error());
+    // scene files check their camera as they load; the others are checked here
+    let scene_file = path.is_some_and(|p| !p.ends_with(".gltf") && !p.ends_with(".glb"));
+    if let (Some(scene), false) = (&scene, scene_file) {
+        let locate = |name: &str| format!("{}: {}", path.unwrap_or("demo scene"), name);
+        validate::check_camera(f64::from(scene.width), f64::from(scene.height), f64::from(scene.fov), &locate, &mut diagnostics);
+    }
+    let scene = scene.filter(|_| !validate::has_errors(&diagnostics));
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    scene
+}
+
+fn parent(path: &str) -> String {
+    std::path::Path::new(path).parent().map_or_else(String::new, |p| p.to_string_lossy().into_owned())
+}
+
+// A glTF or GLB file's bytes, and the other files it reads.
+fn read_gltf(path: &str) -> Result<(Vec<u8>, Vec<std::path::PathBuf>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let files = gltf::external_files(&bytes, std::path::Path::new(&parent(path)))?;
+    Ok((bytes, files))
+}
+
+// Builds the scene a coordinator sends a worker.
+fn build(source: &distributed::Source) -> Result<Scene, String> {
+    match source {
+        distributed::Source::Demo => Ok(demo_scene()),
+        distributed::Source::Json(text) => match scenefile::read_scene_text(text, "scene.json") {
+            (Some(scene), _) => Ok(scene),
+            (None, diagnostics) => Err(diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n")),
+        },
+        distributed::Source::Gltf { base, bytes } => gltf::parse_gltf(bytes, std::path::Path::new(base), 1280, 720).map(|import| import.scene),
+    }
+}
+
+struct Options {
+    path: Option<String>,
+    output: String,
+    sample_map: Option<String>,
+    checkpoint: Option<String>,
+    checkpoint_interval: Duration,
+    resume: bool,
+    worker: Option<String>,
+    workers: Vec<String>,
+    farm: distributed::Settings,
+    serve: Option<String>,
+    service: server::Settings,
+    check: bool,
+    progressive: bool,
+    settings: progressive::Settings,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { path: None, output: "./Render.png".to_string(), sample_map: None,
+        checkpoint: None, checkpoint_interval: Duration::from_secs(60), resume: false,
+        worker: None, workers: Vec::new(), farm: distributed::Settings::default(),
+        serve: None, service: server::Settings::default(),
+        check: false, progressive: false, settings: progressive::Settings::default() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        fn number<T: std::str::FromStr>(name: &str, text: &str) -> Result<T, String> {
+            text.parse().map_err(|_| format!("{} expects a number, got {}", name, text))
+        }
+        let settings = &mut options.settings;
+        match arg.as_str() {
+            "--check" => options.check = true,
+            "--output" => options.output = value(arg)?.clone(),
+            "--progressive" => {}
+            "--samples" => {
+                let samples = number(arg, value(arg)?)?;
+                if samples == 0 {
+                    return Err("--samples must be at least 1".to_string());
+                }
+                settings.samples = samples;
+            }
+            "--time" => settings.time_limit = Some(Duration::from_secs_f64(number(arg, value(arg)?)?)),
+            "--adaptive" => settings.adaptive_threshold = Some(number(arg, value(arg)?)?),
+            "--min-samples" => settings.min_samples = number(arg, value(arg)?)?,
+            "--sample-map" => options.sample_map = Some(value(arg)?.clone()),
+            "--noise" => settings.noise_threshold = Some(number(arg, value(arg)?)?),
+            "--interval" => settings.update_interval = Duration::from_secs_f64(number(arg, value(arg)?)?),
+            "--seed" => settings.seed = number(arg, value(arg)?)?,
+            "--checkpoint" => options.checkpoint = Some(value(arg)?.clone()),
+            "--checkpoint-interval" => options.checkpoint_interval = Duration::from_secs_f64(number(arg, value(arg)?)?),
+            "--resume" => options.resume = true,
+            "--worker" => options.worker = Some(value(arg)?.clone()),
+            "--workers" => options.workers = value(arg)?.split(',').map(str::to_string).collect(),
+            "--tile" => options.farm.tile_size = number(arg, value(arg)?)?,
+            "--serve" => options.serve = Some(value(arg)?.clone()),
+            "--queue" => options.service.queue_limit = number(arg, value(arg)?)?,
+            "--root" => options.service.root = std::path::PathBuf::from(value(arg)?),
+            "--worker-timeout" => options.farm.timeout = Duration::from_secs_f64(number(arg, value(arg)?)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            path if options.path.is_none() => options.path = Some(path.to_string()),
+            extra => return Err(format!("unexpected argument {}", extra)),
+        }
+        // any progressive setting turns progressive rendering on
+        if ["--progressive", "--samples", "--adaptive", "--min-samples", "--sample-map", "--time", "--noise", "--interval", "--seed",
+            "--checkpoint", "--checkpoint-interval", "--resume"].contains(&arg.as_str()) {
+            options.progressive = true;
+        }
+    }
+    if options.resume && options.checkpoint.is_none() {
+        return Err("--resume needs --checkpoint".to_string());
+    }
+    if !options.workers.is_empty() && options.progressive {
+        return Err("--workers renders one sample per pixel and does not take progressive options".to_string());
+    }
+    if options.farm.tile_size == 0 {
+        return Err("--tile must be at least 1".to_string());
+    }
+    Ok(options)
+}
+
+fn save(image: &image::DynamicImage, output: &str) {
+    if let Err(e) = image.save(std::path::Path::new(output)) {
+        eprintln!("{}: {}", output, e);
+    }
+}
+
+static INTERRUPT: OnceLock<renderer::CancelToken> = OnceLock::new();
+
+// Cancels `token` on the first Ctrl-C; a second one stops the process at once.
+#[cfg(unix)]
+fn cancel_on_interrupt(token: &renderer::CancelToken) {
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    extern "C" fn interrupted(_: i32) {
+        if let Some(token) = INTERRUPT.get() {
+            token.cancel();
+        }
+        unsafe { signal(SIGINT, SIG_DFL) };
+    }
+    if INTERRUPT.set(token.clone()).is_ok() {
+        unsafe { signal(SIGINT, interrupted as extern "C" fn(i32) as usize) };
+    }
+}
+
+#[cfg(not(unix))]
+fn cancel_on_interrupt(_: &renderer::CancelToken) {}
+
+// Redraws a progress bar on the terminal; `unit` names what `done` counts.
+fn show_progress(report: &renderer::Report, unit: &str) {
+    const WIDTH: usize = 30;
+    if !std::io::stderr().is_terminal() {
+        return;
+    }
+    let filled = ((report.fraction() * WIDTH as f64) as usize).min(WIDTH);
+    let eta = report.eta.map_or_else(|| "--:--".to_string(), |eta| format!("{}:{:02}", eta.as_secs() / 60, eta.as_secs() % 60));
+    eprint!("\r[{}{}] {:3.0}% {}/{} {}, eta {}  ", "#".repeat(filled), "-".repeat(WIDTH - filled),
+            report.fraction() * 100.0, report.done, report.total, unit, eta);
+}
+
+fn end_progress() {
+    if std::io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+fn main() {
+    // `--check` validates the scene without rendering it; `--progressive`
+    // refines the output image pass by pass, and `--checkpoint` lets a
+    // progressive render be resumed
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("usage: Ray-Tracer [scene] [--check] [--output image] [--progressive] [--samples n] [--adaptive threshold] [--min-samples n] [--sample-map image] [--time seconds] [--noise threshold] [--interval seconds] [--seed n]");
+            eprintln!("       [--checkpoint file] [--checkpoint-interval seconds] [--resume]");
+            eprintln!("       [--workers host:port,...] [--tile pixels] [--worker-timeout seconds]");
+            eprintln!("       Ray-Tracer --worker host:port");
+            eprintln!("       Ray-Tracer --serve host:port [--queue jobs] [--root directory]");
+            std::process::exit(2);
+        }
+    };
+    let path = options.path.as_deref();
+
+    if let Some(address) = &options.worker {
+        match std::net::TcpListener::bind(address) {
+            Ok(listener) => {
+                eprintln!("worker listening on {}", listener.local_addr().map_or_else(|_| address.clone(), |a| a.to_string()));
+                distributed::serve(listener, build);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(address) = &options.serve {
+        match std::net::TcpListener::bind(address) {
+            Ok(listener) => {
+                eprintln!("render service listening on {}", listener.local_addr().map_or_else(|_| address.clone(), |a| a.to_string()));
+                server::serve(listener, options.service);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let scene = match load(path) {
+        Some(scene) => scene,
+        None => std::process::exit(1),
+    };
+    if options.check {
+        println!("{}: ok", path.unwrap_or("demo scene"));
+        return;
+    }
+    // Ctrl-C stops a render and keeps what it has so far
+    let cancel = renderer::CancelToken::new();
+    cancel_on_interrupt(&cancel);
+    if options.progressive {
+        let settings = &options.settings;
+        let files = match path {
+            Some(path) if path.ends_with(".gltf") || path.ends_with(".glb") => {
+                // the buffers and images it names are part of the scene too
+                let named = read_gltf(path).map(|(_, files)| files).unwrap_or_default();
+                std::iter::once(std::path::PathBuf::from(path)).chain(named).collect()
+            }
+            Some(path) => scenefile::sources(path),
+            None => Vec::new(),
+        };
+        let hash = checkpoint::scene_hash(&files, &scene, settings);
+        let checkpoint_path = options.checkpoint.as_deref().map(std::path::Path::new);
+        let progress = match checkpoint_path.filter(|_| options.resume) {
+            Some(file) => match checkpoint::load(file, hash) {
+                Ok(progress) => progress,
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            },
+            None => progressive::Progress::new(&scene, settings),
+        };
+        let write_checkpoint = |progress: &progressive::Progress| {
+            if let Some(file) = checkpoint_path {
+                if let Err(message) = checkpoint::save(file, hash, progress) {
+                    eprintln!("{}", message);
+                }
+            }
+        };
+        let start = std::time::Instant::now();
+        let mut last_checkpoint = start;
+        let first_pass = progress.pass;
+        let (progress, stop) = progressive::resume(&scene, settings, progress, &cancel, &mut |progress| {
+            show_progress(&progress.report(settings, first_pass, start.elapsed()), "passes");
+            save(&progress.accumulator.to_image(), &options.output);
+            if last_checkpoint.elapsed() >= options.checkpoint_interval {
+                write_checkpoint(progress);
+                last_checkpoint = std::time::Instant::now();
+            }
+        });
+        end_progress();
+        let accumulator = &progress.accumulator;
+        if stop == progressive::Stop::Cancelled {
+            // the last pass is incomplete, so the last checkpoint stands
+            save(&accumulator.to_image(), &options.output);
+            eprintln!("interrupted after {} passes; saved the image so far", progress.pass);
+            std::process::exit(130);
+        }
+        // a finished render can still be resumed with a higher sample count
+        write_checkpoint(&progress);
+        let reason = match stop {
+            progressive::Stop::Samples => "reached the sample count",
+            progressive::Stop::Time => "ran out of time",
+            progressive::Stop::Noise => "reached the noise threshold",
+            progressive::Stop::Converged => "every pixel is within the adaptive threshold",
+            progressive::Stop::Cancelled => unreachable!(),
+        };
+        let average = accumulator.total() as f64 / f64::from(scene.width * scene.height);
+        eprintln!("{:.1} samples per pixel on average, noise {:.5}: {}", average, accumulator.noise(), reason);
+        if let Some(sample_map) = &options.sample_map {
+            save(&accumulator.count_image(), sample_map);
+        }
+    } else if !options.workers.is_empty() {
+        // with the files the scene reads, which go to the workers with it
+        let source = match path {
+            None => Ok((distributed::Source::Demo, Vec::new())),
+            Some(path) if path.ends_with(".gltf") || path.ends_with(".glb") => {
+                read_gltf(path).map(|(bytes, files)| (distributed::Source::Gltf { base: parent(path), bytes }, files))
+            }
+            Some(path) => scenefile::flatten(path).map(|(text, files)| (distributed::Source::Json(text), files)),
+        };
+        let rendered = match source.and_then(|(source, files)| distributed::coordinate(&source, &files, scene.width, scene.height, &options.workers, &options.farm, &cancel)) {
+            Ok(rendered) => rendered,
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+        };
+        save(&rendered.image, &options.output);
+        if rendered.cancelled {
+            eprintln!("interrupted after {} of {} tiles; saved the partial image", rendered.report.done, rendered.report.total);
+            std::process::exit(130);
+        }
+    } else {
+        let rendered = renderer::render(&scene, options.farm.tile_size, &cancel, &mut |report| show_progress(report, "tiles"));
+        end_progress();
+        save(&rendered.image, &options.output);
+        if rendered.cancelled {
+            eprintln!("interrupted after {} of {} tiles; saved the partial image", rendered.report.done, rendered.report.total);
+            std::process::exit(130);
+        }
     }
 }
@@ -74,13 +74,81 @@ impl Matrix {
 
     pub fn translate(tx: f32, ty:f32, tz: f32) -> Matrix {
         Matrix {
-            elements: [[0.0, 0.0, 0.0,  tx],
-                       [0.0, 0.0, 0.0,  ty],
-                       [0.0, 0.0, 0.0,  tz],
-                       [0.0, 0.0, 0.0, 1.0]],
+            elements: [[1.0, 0.0, 0.0, 0.0],
+                       [0.0, 1.0, 0.0, 0.0],
+                       [0.0, 0.0, 1.0, 0.0],
+                       [ tx,  ty,  tz, 1.0]],
+        }
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let mut result = *self;
+        for i in 0..4 {
+            for j in 0..4 {
+                result[i][j] = self[j][i];
+            }
+        }
+        result
+    }
+
+    /// Transforms a position, applying translation.
+    pub fn transform_point(&self, p: Vector3) -> Vector3 {
+        let w = p.x * self[0][3] + p.y * self[1][3] + p.z * self[2][3] + self[3][3];
+        Vector3 {
+            x: p.x * self[0][0] + p.y * self[1][0] + p.z * self[2][0] + self[3][0],
+            y: p.x * self[0][1] + p.y * self[1][1] + p.z * self[2][1] + self[3][1],
+            z: p.x * self[0][2] + p.y * self[1][2] + p.z * self[2][2] + self[3][2],
+        } / w
+    }
+
+    /// Transforms a direction, ignoring translation.
+    pub fn transform_vector(&self, v: Vector3) -> Vector3 {
+        Vector3 {
+            x: v.x * self[0][0] + v.y * self[1][0] + v.z * self[2][0],
+            y: v.x * self[0][1] + v.y * self[1][1] + v.z * self[2][1],
+            z: v.x * self[0][2] + v.y * self[1][2] + v.z * self[2][2],
+        }
+    }
+
+    /// View matrix for a camera at `eye` looking at `target`, with the
+    /// camera looking down its -z axis like `Ray::create_primary_ray`.
+    pub fn look_at(eye: Vector3, target: Vector3, up: Vector3) -> Matrix {
+        let f = (target - eye).normalize();
+        let r = f.cross(&up).normalize();
+        let u = r.cross(&f);
+        Matrix {
+            elements: [[r.x, u.x, -f.x, 0.0],
+                       [r.y, u.y, -f.y, 0.0],
+                       [r.z, u.z, -f.z, 0.0],
+                       [-r.dot(&eye), -u.dot(&eye), f.dot(&eye), 1.0]],
         }
     }
 
+    /// Right-handed projection mapping view depths [-near, -far] to [-1, 1].
+    /// `fov_y` is in radians.
+    pub fn perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> Matrix {
+        let f = 1.0 / (fov_y / 2.0).tan();
+        Matrix {
+            elements: [[f / aspect, 0.0, 0.0, 0.0],
+                       [0.0, f, 0.0, 0.0],
+                       [0.0, 0.0, (far + near) / (near - far), -1.0],
+                       [0.0, 0.0, 2.0 * far * near / (near - far), 0.0]],
+        }
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let m = &self.elements;
+        let minor = |r0: usize, r1: usize, r2: usize, c0: usize, c1: usize, c2: usize| {
+            m[r0][c0] * (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1])
+                - m[r0][c1] * (m[r1][c0] * m[r2][c2] - m[r1][c2] * m[r2][c0])
+                + m[r0][c2] * (m[r1][c0] * m[r2][c1] - m[r1][c1] * m[r2][c0])
+        };
+        m[0][0] * minor(1, 2, 3, 1, 2, 3)
+            - m[0][1] * minor(1, 2, 3, 0, 2, 3)
+            + m[0][2] * minor(1, 2, 3, 0, 1, 3)
+            - m[0][3] * minor(1, 2, 3, 0, 1, 2)
+    }
+
     pub fn inverse(&self) -> Matrix {
         let mut s = Matrix::identity();
         let mut t = *self;
@@ -173,59 +241,63 @@ impl PartialEq for Matrix {
         true
     }
 }
-impl Mul for Matrix {
-    type Output = Matrix;
+impl Matrix {
+    fn mul_scalar(&self, other: &Matrix) -> Matrix {
+        let mut result = Matrix::identity();
+        for i in 0..4 {
+            for j in 0..4 {
+                result[i][j] = self[i][0] * other[0][j] +
+                            self[i][1] * other[1][j] +
+                            self[i][2] * other[2][j] +
+                            self[i][3] * other[3][j];
+            }
+        }
+        result
+    }
 
     #[cfg(target_arch = "x86_64")]
-    fn mul(self, other: Matrix) -> Matrix {
+    #[target_feature(enable = "avx")]
+    unsafe fn mul_avx(&self, other: &Matrix) -> Matrix {
         use std::arch::x86_64::*;
 
         let mut result = Matrix::identity();
+        for i in 0..4 {
+            let mut r = _mm_mul_ps(_mm_broadcast_ss(&self[i][0]), _mm_loadu_ps(&other[0] as *const f32));
+            r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][1]), _mm_loadu_ps(&other[1] as *const f32)));
+            r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][2]), _mm_loadu_ps(&other[2] as *const f32)));
+            r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][3]), _mm_loadu_ps(&other[3] as *const f32)));
 
-        if is_x86_feature_detected!("avx2")
-        {
-            unsafe {
-                for i in 0..4 {
-                    let mut r = _mm_mul_ps(_mm_broadcast_ss(&self[i][0]), _mm_loadu_ps(&other[0] as *const f32));
-                    r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][1]), _mm_loadu_ps(&other[1] as *const f32)));
-                    r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][2]), _mm_loadu_ps(&other[2] as *const f32)));
-                    r = _mm_add_ps(r, _mm_mul_ps(_mm_broadcast_ss(&self[i][3]), _mm_loadu_ps(&other[3] as *const f32)));
+            _mm_storeu_ps(&mut result[i][0] as *mut f32, r);
+        }
+        result
+    }
+}
 
-                    _mm_storeu_ps(&mut result[i][0] as *mut f32, r);    
-                }
-            }
+impl Mul for Matrix {
+    type Output = Matrix;
+
+    #[cfg(target_arch = "x86_64")]
+    fn mul(self, other: Matrix) -> Matrix {
+        if is_x86_feature_detected!("avx") {
+            unsafe { self.mul_avx(&other) }
         }
         else {
-            panic!("avx2 is disabled");
+            self.mul_scalar(&other)
         }
-
-        result
     }
 
     #[cfg(not(target_arch = "x86_64"))]
     fn mul(self, other: Matrix) -> Matrix {
-        let mut result = Matrix::identity();
-        for i in 0..4 {
-            for j in 0..4 {
-                result[i][j] = self[i][0] * other[0][j] +
-                            self[i][1] * other[1][j] +
-                            self[i][2] * other[2][j] +
-                            self[i][3] * other[3][j];
-            }
-        }
-        result
+        self.mul_scalar(&other)
     }
 }
 
+/// Treats the vector as a point; use `transform_vector` for directions.
 impl Mul<Vector3> for Matrix {
     type Output = Vector3;
 
     fn mul(self, other: Vector3) -> Vector3 {
-        Vector3 {
-            x: other.x * self[0][0] + other.y * self[1][0] + other.z * self[2][0],
-            y: other.x * self[0][1] + other.y * self[1][1] + other.z * self[2][1],
-            z: other.x * self[0][2] + other.y * self[1][2] + other.z * self[2][2],
-        }
+        self.transform_point(other)
     }
 }
 
@@ -246,4 +318,57 @@ mod tests {
         println!("{:?}", m3);
         assert_eq!(m3, Matrix::identity());
     }
+
+    fn nearly_equal(a: &Matrix, b: &Matrix) -> bool {
+        (0..4).all(|i| (0..4).all(|j| (a[i][j] - b[i][j]).abs() < 1e-5))
+    }
+
+    fn nearly(a: Vector3, b: Vector3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    #[test]
+    fn transforms() {
+        let p = Vector3 { x: 1.0, y: 2.0, z: 3.0 };
+        let t = Matrix::translate(1.0, -1.0, 2.0);
+        assert!(nearly(t.transform_point(p), Vector3 { x: 2.0, y: 1.0, z: 5.0 }));
+        assert!(nearly(t * p, Vector3 { x: 2.0, y: 1.0, z: 5.0 }));
+        assert!(nearly(t.transform_vector(p), p));
+
+        // scale first, then rotate a quarter turn about z, then translate
+        let m = Matrix::scale_uniform(2.0) * Matrix::rotate_z(std::f32::consts::FRAC_PI_2) * t;
+        assert!(nearly(m.transform_point(Vector3 { x: 1.0, y: 0.0, z: 0.0 }), Vector3 { x: 1.0, y: 1.0, z: 2.0 }));
+        assert!(nearly_equal(&(m * m.inverse()), &Matrix::identity()));
+    }
+
+    #[test]
+    fn transpose_and_determinant() {
+        let m = Matrix::rotate_x(0.3) * Matrix::scale(2.0, 3.0, 4.0) * Matrix::translate(5.0, 6.0, 7.0);
+        assert!((m.determinant() - 24.0).abs() < 1e-3);
+        assert!((Matrix::identity().determinant() - 1.0).abs() < 1e-6);
+        assert_eq!(m.transpose().transpose(), m);
+        assert_eq!(m.transpose()[3][0], m[0][3]);
+        assert!(nearly_equal(&Matrix::rotate_y(0.7).transpose(), &Matrix::rotate_y(0.7).inverse()));
+    }
+
+    #[test]
+    fn scalar_fallback() {
+        let a = Matrix::rotate_x(0.3) * Matrix::translate(1.0, 2.0, 3.0);
+        let b = Matrix::scale(2.0, 3.0, 4.0) * Matrix::rotate_z(1.1);
+        assert!(nearly_equal(&(a * b), &a.mul_scalar(&b)));
+    }
+
+    #[test]
+    fn camera() {
+        let eye = Vector3 { x: 0.0, y: 0.0, z: 5.0 };
+        let view = Matrix::look_at(eye, Vector3::zero(), Vector3 { x: 0.0, y: 1.0, z: 0.0 });
+        assert!(nearly(view.transform_point(Vector3::zero()), Vector3 { x: 0.0, y: 0.0, z: -5.0 }));
+        assert!(nearly(view.transform_point(Vector3 { x: 1.0, y: 0.0, z: 5.0 }), Vector3 { x: 1.0, y: 0.0, z: 0.0 }));
+
+        let projection = Matrix::perspective(std::f32::consts::FRAC_PI_2, 2.0, 1.0, 10.0);
+        let near = projection.transform_point(Vector3 { x: 0.0, y: 1.0, z: -1.0 });
+        let far = projection.transform_point(Vector3 { x: 20.0, y: 0.0, z: -10.0 });
+        assert!(nearly(near, Vector3 { x: 0.0, y: 1.0, z: -1.0 }));
+        assert!(nearly(far, Vector3 { x: 1.0, y: 0.0, z: 1.0 }));
+    }
 }
\ No newline at end of file
@@ -1,6 +1,9 @@
 pub mod matrix;
 pub mod vector;
 pub mod quaternion;
+pub mod polynomial;
+pub mod noise;
+pub mod random;
 
 pub fn clamp<T: PartialOrd>(v: T, min: T, max: T) -> T {
     if v < min {
@@ -12,18 +15,19 @@ pub fn clamp<T: PartialOrd>(v: T, min: T, max: T) -> T {
     }
 }
 
+#[allow(dead_code)]
 pub trait Math {
     fn is_nearly_zero(&self) -> bool;
 }
 
 impl Math for f64 {
     fn is_nearly_zero(&self) -> bool {
-        -std::f64::EPSILON < *self || *self < std::f64::EPSILON
+        -f64::EPSILON < *self && *self < f64::EPSILON
     }
 }
 
 impl Math for f32 {
     fn is_nearly_zero(&self) -> bool {
-        -std::f32::EPSILON < *self || *self < std::f32::EPSILON
+        -f32::EPSILON < *self && *self < f32::EPSILON
     }
 }
\ No newline at end of file
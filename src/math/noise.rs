@@ -0,0 +1,97 @@
+use crate::math::vector::Vector3;
+
+// Integer hash (lowbias32) so the noise needs no permutation table.
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f)
+        ^ seed;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    h
+}
+
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    // the twelve edge directions of a cube, as in improved Perlin noise
+    match h % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Gradient noise in about [-1, 1], zero at integer lattice points.
+/// Different seeds give uncorrelated patterns.
+pub fn perlin(p: Vector3, seed: u32) -> f32 {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+    let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        gradient(hash(ix + dx, iy + dy, iz + dz, seed), x - dx as f32, y - dy as f32, z - dz as f32)
+    };
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w)
+}
+
+/// Fractal sum of `octaves` layers of `perlin`, each at twice the
+/// frequency and half the amplitude of the last, normalized to about [-1, 1].
+pub fn fbm(p: Vector3, octaves: u32, seed: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut frequency = 1.0;
+    for octave in 0..octaves.max(1) {
+        sum += amplitude * perlin(p * frequency, seed.wrapping_add(octave));
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lattice_and_range() {
+        assert_eq!(perlin(Vector3 { x: 3.0, y: -2.0, z: 7.0 }, 0), 0.0);
+        for i in 0..1000 {
+            let p = Vector3 { x: i as f32 * 0.173, y: i as f32 * 0.071, z: i as f32 * -0.029 };
+            let n = fbm(p, 4, 1);
+            assert!((-1.0..=1.0).contains(&n));
+        }
+    }
+
+    #[test]
+    fn continuous() {
+        let p = Vector3 { x: 0.4, y: 1.3, z: -2.2 };
+        let q = p + Vector3::from_one(1e-4);
+        assert!((perlin(p, 5) - perlin(q, 5)).abs() < 1e-3);
+        assert!(perlin(p, 5) != perlin(p, 6));
+    }
+}
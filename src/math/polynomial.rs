@@ -0,0 +1,186 @@
+/// Real roots of `a t^2 + b t + c`, smallest first. Uses the cancellation
+/// free form so that nearly linear equations keep their small root.
+pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 || (a == 0.0 && b == 0.0) {
+        return None;
+    }
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (t0, t1) = if a == 0.0 {
+        let t = -c / b;
+        (t, t)
+    } else if q == 0.0 {
+        let t = -0.5 * b / a;
+        (t, t)
+    } else {
+        (q / a, c / q)
+    };
+    if t0 <= t1 { Some((t0, t1)) } else { Some((t1, t0)) }
+}
+
+const EPSILON: f64 = 1e-12;
+
+fn is_zero(v: f64) -> bool {
+    v.abs() < EPSILON
+}
+
+/// Real roots of `x^3 + a x^2 + b x + c`, unsorted. Repeated roots may be
+/// reported once.
+pub fn solve_normalized_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // substitute x = y - a/3 to eliminate the quadratic term
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let mut roots = if is_zero(d) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if d < 0.0 {
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        vec![t * phi.cos(), -t * (phi + std::f64::consts::FRAC_PI_3).cos(), -t * (phi - std::f64::consts::FRAC_PI_3).cos()]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 3.0;
+    }
+    roots
+}
+
+/// Real roots of `x^4 + a x^3 + b x^2 + c x + d` by Ferrari's method,
+/// unsorted and unpolished.
+pub fn solve_normalized_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // substitute x = y - a/4 to eliminate the cubic term
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c / 4.0 + d;
+
+    let mut roots = if is_zero(r) {
+        let mut roots = solve_normalized_cubic(0.0, p, q);
+        roots.push(0.0);
+        roots
+    } else {
+        // one real root of the resolvent cubic splits the quartic into two quadratics
+        let z = solve_normalized_cubic(-p / 2.0, -r, r * p / 2.0 - q * q / 8.0)[0];
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) { 0.0 } else if u > 0.0 { u.sqrt() } else { return Vec::new() };
+        let v = if is_zero(v) { 0.0 } else if v > 0.0 { v.sqrt() } else { return Vec::new() };
+        let v = if q < 0.0 { -v } else { v };
+
+        let mut roots = Vec::with_capacity(4);
+        for (linear, constant) in [(v, z - u), (-v, z + u)] {
+            let discriminant = linear * linear - 4.0 * constant;
+            if discriminant >= 0.0 {
+                let sqrt_d = discriminant.sqrt();
+                roots.push((-linear - sqrt_d) / 2.0);
+                roots.push((-linear + sqrt_d) / 2.0);
+            }
+        }
+        roots
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+    }
+    roots
+}
+
+/// Value of the polynomial with `coefficients` from the highest degree down.
+pub fn evaluate(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().fold(0.0, |acc, c| acc * x + c)
+}
+
+/// Refines `root` with a few Newton steps, keeping the original estimate
+/// if an iteration diverges.
+pub fn polish_root(coefficients: &[f64], root: f64, iterations: usize) -> f64 {
+    let degree = coefficients.len() - 1;
+    let derivative: Vec<f64> = coefficients[..degree].iter().enumerate()
+        .map(|(i, c)| c * (degree - i) as f64)
+        .collect();
+
+    let mut x = root;
+    for _ in 0..iterations {
+        let slope = evaluate(&derivative, x);
+        if slope == 0.0 {
+            break;
+        }
+        let next = x - evaluate(coefficients, x) / slope;
+        if !next.is_finite() || evaluate(coefficients, next).abs() > evaluate(coefficients, x).abs() {
+            break;
+        }
+        x = next;
+    }
+    x
+}
+
+/// Sorted real roots of `a x^4 + b x^3 + c x^2 + d x + e`, solved in f64
+/// and polished with Newton iterations.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return Vec::new();
+    }
+    let coefficients = [a, b, c, d, e];
+    let mut roots: Vec<f64> = solve_normalized_quartic(b / a, c / a, d / a, e / a)
+        .into_iter()
+        .map(|root| polish_root(&coefficients, root, 4))
+        .collect();
+    roots.sort_by(|x, y| x.total_cmp(y));
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quadratic() {
+        let (t0, t1) = solve_quadratic(1.0, -3.0, 2.0).unwrap();
+        assert!((t0 - 1.0).abs() < 1e-6 && (t1 - 2.0).abs() < 1e-6);
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_none());
+        let (t0, t1) = solve_quadratic(0.0, 2.0, -4.0).unwrap();
+        assert!((t0 - 2.0).abs() < 1e-6 && (t1 - 2.0).abs() < 1e-6);
+        // tiny leading coefficient must not lose the small root
+        let (_, t1) = solve_quadratic(1e-7, 1.0, -1.0).unwrap();
+        assert!((t1 - 1.0).abs() < 1e-4);
+    }
+
+    fn assert_roots(mut roots: Vec<f64>, expected: &[f64]) {
+        roots.sort_by(|x, y| x.total_cmp(y));
+        assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+        for (root, e) in roots.iter().zip(expected) {
+            assert!((root - e).abs() < 1e-6, "{:?}", roots);
+        }
+    }
+
+    #[test]
+    fn cubic() {
+        // (x - 1)(x - 2)(x + 3)
+        assert_roots(solve_normalized_cubic(0.0, -7.0, 6.0), &[-3.0, 1.0, 2.0]);
+        // (x - 2)(x^2 + 1)
+        assert_roots(solve_normalized_cubic(-2.0, 1.0, -2.0), &[2.0]);
+    }
+
+    #[test]
+    fn quartic() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0), &[1.0, 2.0, 3.0, 4.0]);
+        // (x^2 - 4)(x^2 + 1)
+        assert_roots(solve_quartic(2.0, 0.0, -6.0, 0.0, -8.0), &[-2.0, 2.0]);
+        assert!(solve_quartic(1.0, 0.0, 2.0, 0.0, 1.0).is_empty());
+
+        // widely spread roots: (x - 0.001)(x - 1)(x - 100)(x - 1000)
+        let roots = solve_quartic(1.0, -1101.001, 101101.101, -100101.1, 100.0);
+        assert_roots(roots, &[0.001, 1.0, 100.0, 1000.0]);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::math::vector::Vector3;
+use crate::math::vector::{Vector3, Vector4};
+use crate::math::clamp;
+use std::ops::Mul;
+use crate::math::matrix::Matrix;
 use serde_derive::Deserialize;
 
 #[derive(Copy, Clone, Debug, Deserialize)]
@@ -17,7 +20,75 @@ impl Quat {
     }
 
     pub fn identity() -> Quat {
-        Quat { x: 1.0, y: 0.0 , z: 0.0 , w: 0.0 }
+        Quat { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }
+    }
+
+    /// Rotation of `angle` radians about `axis`.
+    pub fn from_axis_angle(axis: Vector3, angle: f32) -> Quat {
+        let axis = axis.normalize();
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Quat { x: axis.x * sin, y: axis.y * sin, z: axis.z * sin, w: cos }
+    }
+
+    /// Rotation about x, then y, then z (fixed axes), angles in radians.
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Quat {
+        let unit_x = Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        let unit_y = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        let unit_z = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        Quat::from_axis_angle(unit_z, z) * Quat::from_axis_angle(unit_y, y) * Quat::from_axis_angle(unit_x, x)
+    }
+
+    /// Inverse of `from_axis_angle` for unit quaternions. The identity
+    /// returns the x axis with a zero angle.
+    pub fn to_axis_angle(self) -> (Vector3, f32) {
+        let w = clamp(self.w, -1.0, 1.0);
+        let angle = 2.0 * w.acos();
+        let sin = (1.0 - w * w).sqrt();
+        if sin < 1e-6 {
+            (Vector3 { x: 1.0, y: 0.0, z: 0.0 }, 0.0)
+        } else {
+            (Vector3 { x: self.x / sin, y: self.y / sin, z: self.z / sin }, angle)
+        }
+    }
+
+    /// Inverse of `from_euler` for unit quaternions, returned as (x, y, z).
+    pub fn to_euler(self) -> Vector3 {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        Vector3 {
+            x: (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y)),
+            y: clamp(2.0 * (w * y - z * x), -1.0, 1.0).asin(),
+            z: (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z)),
+        }
+    }
+
+    pub fn dot(&self, other: &Quat) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    /// Spherical interpolation between unit quaternions along the shorter arc.
+    pub fn slerp(a: Quat, b: Quat, t: f32) -> Quat {
+        let mut b = b;
+        let mut cos = a.dot(&b);
+        if cos < 0.0 {
+            b = Quat { x: -b.x, y: -b.y, z: -b.z, w: -b.w };
+            cos = -cos;
+        }
+
+        let (wa, wb) = if cos > 0.9995 {
+            // nearly parallel, fall back to a linear blend
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        let mut q = Quat {
+            x: wa * a.x + wb * b.x,
+            y: wa * a.y + wb * b.y,
+            z: wa * a.z + wb * b.z,
+            w: wa * a.w + wb * b.w,
+        };
+        *q.normalize()
     }
 
     pub fn normalize(&mut self) -> &mut Self {
@@ -46,4 +117,88 @@ impl Quat {
         let r = vec + (self.w * t) + q.cross(&t);
         r
     }
-}
\ No newline at end of file
+
+    /// Rotation matrix for row vectors, matching `rotate` for unit quaternions.
+    pub fn to_matrix(self) -> Matrix {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        Matrix::from_vector(
+            Vector4 { x: 1.0 - 2.0 * (y * y + z * z), y: 2.0 * (x * y + z * w), z: 2.0 * (x * z - y * w), w: 0.0 },
+            Vector4 { x: 2.0 * (x * y - z * w), y: 1.0 - 2.0 * (x * x + z * z), z: 2.0 * (y * z + x * w), w: 0.0 },
+            Vector4 { x: 2.0 * (x * z + y * w), y: 2.0 * (y * z - x * w), z: 1.0 - 2.0 * (x * x + y * y), w: 0.0 },
+            Vector4 { x: 0.0, y: 0.0, z: 0.0, w: 1.0 })
+    }
+}
+
+/// Hamilton product; `a * b` rotates by `b` first, then by `a`.
+impl Mul for Quat {
+    type Output = Quat;
+
+    fn mul(self, other: Quat) -> Quat {
+        Quat {
+            x: self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            y: self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            z: self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+            w: self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn nearly(a: Vector3, b: Vector3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    #[test]
+    fn identity() {
+        let v = Vector3 { x: 1.0, y: 2.0, z: 3.0 };
+        assert!(nearly(Quat::identity().rotate(v), v));
+        assert!(nearly(Quat::identity().to_matrix().transform_point(v), v));
+    }
+
+    #[test]
+    fn rotation() {
+        let x = Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        let y = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        let z = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let qz = Quat::from_axis_angle(z, FRAC_PI_2);
+        let qx = Quat::from_axis_angle(x, FRAC_PI_2);
+        assert!(nearly(qz.rotate(x), y));
+
+        // x then z: y -> z -> z
+        assert!(nearly((qz * qx).rotate(y), z));
+        // z then x: y -> -x -> -x
+        assert!(nearly((qx * qz).rotate(y), -x));
+
+        let q = Quat::from_euler(0.3, -0.7, 1.2);
+        let v = Vector3 { x: 0.2, y: -1.5, z: 0.8 };
+        assert!(nearly(q.rotate(v), q.to_matrix().transform_vector(v)));
+        assert!(nearly(q.rotate(v), (Matrix::rotate_x(0.3) * Matrix::rotate_y(-0.7) * Matrix::rotate_z(1.2)).transform_vector(v)));
+    }
+
+    #[test]
+    fn conversions() {
+        let euler = Quat::from_euler(0.3, -0.7, 1.2).to_euler();
+        assert!(nearly(euler, Vector3 { x: 0.3, y: -0.7, z: 1.2 }));
+
+        let axis = Vector3 { x: 1.0, y: 2.0, z: -1.0 }.normalize();
+        let (a, angle) = Quat::from_axis_angle(axis, 0.9).to_axis_angle();
+        assert!(nearly(a, axis));
+        assert!((angle - 0.9).abs() < 1e-5);
+    }
+
+    #[test]
+    fn slerp() {
+        let z = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let a = Quat::identity();
+        let b = Quat::from_axis_angle(z, FRAC_PI_2);
+        let half = Quat::slerp(a, b, 0.5);
+        let (_, angle) = half.to_axis_angle();
+        assert!((angle - FRAC_PI_2 / 2.0).abs() < 1e-5);
+        assert!(nearly(Quat::slerp(a, b, 1.0).rotate(z), z));
+        assert!((Quat::slerp(a, b, 0.0).w - 1.0).abs() < 1e-6);
+    }
+}
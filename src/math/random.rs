@@ -0,0 +1,49 @@
+/// A small seeded generator (PCG-XSH-RR) whose whole state is one number,
+/// so a render can be repeated or resumed exactly.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rng {
+    pub state: u64,
+}
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+const INCREMENT: u64 = 1_442_695_040_888_963_407;
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng { state: seed.wrapping_add(INCREMENT) };
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        f64::from(self.next_u32() >> 8) / f64::from(1u32 << 24)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeatable() {
+        let mut a = Rng::new(7);
+        let mut b = Rng::new(7);
+        let first: Vec<u32> = (0..8).map(|_| a.next_u32()).collect();
+        assert_eq!(first, (0..8).map(|_| b.next_u32()).collect::<Vec<_>>());
+        // resuming from a copied state continues the same sequence
+        let mut c = Rng { state: a.state };
+        assert_eq!(a.next_u32(), c.next_u32());
+        assert_ne!(Rng::new(8).next_u32(), Rng::new(7).next_u32());
+
+        let mean = (0..10_000).map(|_| a.next_f64()).inspect(|&v| assert!((0.0..1.0).contains(&v))).sum::<f64>() / 10_000.0;
+        assert!((mean - 0.5).abs() < 0.02);
+    }
+}
@@ -23,7 +23,7 @@ impl Vector3 {
     }
 
     pub fn size_squared(&self) -> f32 {
-        (self.x * self.x + self.y * self.y + self.z * self.z)
+        self.x * self.x + self.y * self.y + self.z * self.z
     }
 
     pub fn normalize(&self) -> Vector3 {
@@ -141,4 +141,56 @@ pub struct Vector4 {
     pub y: f32,
     pub z: f32,
     pub w: f32,
-}
\ No newline at end of file
+}
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[repr(C)]
+pub struct Vector2 {
+    pub x: f32,
+    pub y: f32,
+}
+impl Vector2 {
+    pub fn zero() -> Vector2 {
+        Vector2 { x: 0.0, y: 0.0 }
+    }
+}
+
+impl Add for Vector2 {
+    type Output = Vector2;
+
+    fn add(self, other: Vector2) -> Vector2 {
+        Vector2 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
+    }
+}
+
+impl Sub for Vector2 {
+    type Output = Vector2;
+
+    fn sub(self, other: Vector2) -> Vector2 {
+        Vector2 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+        }
+    }
+}
+
+impl Mul<f32> for Vector2 {
+    type Output = Vector2;
+
+    fn mul(self, other: f32) -> Vector2 {
+        Vector2 {
+            x: self.x * other,
+            y: self.y * other,
+        }
+    }
+}
+
+impl Mul<Vector2> for f32 {
+    type Output = Vector2;
+
+    fn mul(self, other: Vector2) -> Vector2 {
+        other * self
+    }
+}
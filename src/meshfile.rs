@@ -0,0 +1,409 @@
+use crate::math::vector::{Vector2, Vector3};
+use crate::color::Color;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Triangles and per-vertex attributes read from a mesh file. Attribute
+/// lists are either empty or as long as `positions`.
+#[derive(Default)]
+pub struct MeshData {
+    pub positions: Vec<Vector3>,
+    pub normals: Vec<Vector3>,
+    pub uvs: Vec<Vector2>,
+    pub colors: Vec<Color>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+/// Loads a PLY or STL file, chosen by extension. STL vertices within
+/// `weld_tolerance` of each other are merged.
+pub fn load_mesh(path: &str, weld_tolerance: f32) -> Result<MeshData, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("ply") => parse_ply(&bytes),
+        Some("stl") => parse_stl(&bytes, weld_tolerance),
+        _ => Err("unknown mesh format; expected .ply or .stl".to_string()),
+    }.map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Merges points closer than `tolerance`, or bit-identical ones for a
+/// tolerance of zero. Returns the merged points and, for every input point,
+/// the index of the point it became.
+pub fn weld(points: &[Vector3], tolerance: f32) -> (Vec<Vector3>, Vec<u32>) {
+    // -0.0 + 0.0 is 0.0, so signed zeros land in the same cell
+    let cell = |p: Vector3| -> [i64; 3] {
+        if tolerance > 0.0 {
+            [(p.x / tolerance).floor() as i64, (p.y / tolerance).floor() as i64, (p.z / tolerance).floor() as i64]
+        } else {
+            [i64::from((p.x + 0.0).to_bits()), i64::from((p.y + 0.0).to_bits()), i64::from((p.z + 0.0).to_bits())]
+        }
+    };
+    let reach = if tolerance > 0.0 { 1 } else { 0 };
+
+    let mut welded: Vec<Vector3> = Vec::new();
+    let mut grid: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+    let mut remap = Vec::with_capacity(points.len());
+    for &p in points {
+        let c = cell(p);
+        let mut found = None;
+        'search: for dx in -reach..=reach {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    let near = grid.get(&[c[0] + dx, c[1] + dy, c[2] + dz]).into_iter().flatten();
+                    if let Some(&i) = near.into_iter().find(|&&i| (welded[i as usize] - p).length() <= tolerance) {
+                        found = Some(i);
+                        break 'search;
+                    }
+                }
+            }
+        }
+        let index = found.unwrap_or_else(|| {
+            welded.push(p);
+            let i = welded.len() as u32 - 1;
+            grid.entry(c).or_default().push(i);
+            i
+        });
+        remap.push(index);
+    }
+    (welded, remap)
+}
+
+/// Reads ASCII or binary STL. Facet normals are dropped in favour of the
+/// winding, and the unindexed triangles are welded into a shared-vertex
+/// mesh, leaving out any that collapse.
+pub fn parse_stl(bytes: &[u8], weld_tolerance: f32) -> Result<MeshData, String> {
+    let mut soup = Vec::new();
+    let binary_count = bytes.get(80..84).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    // ASCII files start with "solid", but so do the headers of some binary ones
+    if let Some(count) = binary_count.filter(|&n| bytes.len() == 84 + 50 * n) {
+        for facet in bytes[84..].chunks(50).take(count) {
+            let float = |k: usize| f32::from_le_bytes([facet[k], facet[k + 1], facet[k + 2], facet[k + 3]]);
+            for v in 1..4 {
+                soup.push(Vector3 { x: float(12 * v), y: float(12 * v + 4), z: float(12 * v + 8) });
+            }
+        }
+    } else {
+        let text = std::str::from_utf8(bytes).map_err(|_| "neither a binary nor an ASCII STL file".to_string())?;
+        if !text.trim_start().starts_with("solid") {
+            return Err("neither a binary nor an ASCII STL file".to_string());
+        }
+        let mut tokens = text.split_whitespace();
+        while let Some(token) = tokens.next() {
+            if token == "vertex" {
+                let mut coordinate = || -> Result<f32, String> {
+                    tokens.next().ok_or("unexpected end of file reading a vertex")?.parse().map_err(|e| format!("vertex: {}", e))
+                };
+                soup.push(Vector3 { x: coordinate()?, y: coordinate()?, z: coordinate()? });
+            }
+        }
+        if soup.len() % 3 != 0 {
+            return Err(format!("{} vertices do not make whole triangles", soup.len()));
+        }
+    }
+
+    let (positions, remap) = weld(&soup, weld_tolerance);
+    let indices = remap.chunks(3)
+        .map(|t| [t[0], t[1], t[2]])
+        .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+        .collect();
+    Ok(MeshData { positions, indices, ..MeshData::default() })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("unknown property type {}", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // Value read as full intensity for a color channel.
+    fn full_scale(self) -> f64 {
+        match self {
+            Scalar::I8 => 127.0,
+            Scalar::U8 => 255.0,
+            Scalar::I16 => 32767.0,
+            Scalar::U16 => 65535.0,
+            Scalar::I32 => 2147483647.0,
+            Scalar::U32 => 4294967295.0,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+enum Property {
+    Scalar { name: String, kind: Scalar },
+    List { name: String, count: Scalar, item: Scalar },
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar { name, .. } | Property::List { name, .. } => name,
+        }
+    }
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// Source of the values in a PLY body, in file order.
+enum Values<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary { bytes: &'a [u8], at: usize, big_endian: bool },
+}
+
+impl Values<'_> {
+    fn read(&mut self, kind: Scalar) -> Result<f64, String> {
+        match self {
+            Values::Ascii(tokens) => {
+                let token = tokens.next().ok_or("unexpected end of file")?;
+                token.parse().map_err(|_| format!("bad number {}", token))
+            }
+            Values::Binary { bytes, at, big_endian } => {
+                let b = bytes.get(*at..*at + kind.size()).ok_or("unexpected end of file")?;
+                *at += kind.size();
+                let mut raw = [0u8; 8];
+                raw[..b.len()].copy_from_slice(b);
+                if *big_endian {
+                    raw[..b.len()].reverse();
+                }
+                let [b0, b1, b2, b3, ..] = raw;
+                Ok(match kind {
+                    Scalar::I8 => f64::from(b0 as i8),
+                    Scalar::U8 => f64::from(b0),
+                    Scalar::I16 => f64::from(i16::from_le_bytes([b0, b1])),
+                    Scalar::U16 => f64::from(u16::from_le_bytes([b0, b1])),
+                    Scalar::I32 => f64::from(i32::from_le_bytes([b0, b1, b2, b3])),
+                    Scalar::U32 => f64::from(u32::from_le_bytes([b0, b1, b2, b3])),
+                    Scalar::F32 => f64::from(f32::from_le_bytes([b0, b1, b2, b3])),
+                    Scalar::F64 => f64::from_le_bytes(raw),
+                })
+            }
+        }
+    }
+
+    // One element's scalar properties, and the items of its lists.
+    fn record(&mut self, element: &Element) -> Result<(Vec<f64>, Vec<Vec<f64>>), String> {
+        let mut scalars = Vec::new();
+        let mut lists = Vec::new();
+        for property in &element.properties {
+            match property {
+                Property::Scalar { kind, .. } => scalars.push(self.read(*kind)?),
+                Property::List { count, item, .. } => {
+                    let n = self.read(*count)?;
+                    if !(0.0..=1e6).contains(&n) {
+                        return Err(format!("bad list length {}", n));
+                    }
+                    lists.push((0..n as usize).map(|_| self.read(*item)).collect::<Result<_, _>>()?);
+                }
+            }
+        }
+        Ok((scalars, lists))
+    }
+}
+
+/// Reads ASCII or binary PLY. Vertices need `x`, `y` and `z`, and may carry
+/// `nx`/`ny`/`nz` normals, `u`/`v` (or `s`/`t`) texture coordinates and
+/// `red`/`green`/`blue`/`alpha` colors. Faces list their corners in
+/// `vertex_indices` and are split into fans. Other elements are skipped.
+pub fn parse_ply(bytes: &[u8]) -> Result<MeshData, String> {
+    if !bytes.starts_with(b"ply") {
+        return Err("not a PLY file".to_string());
+    }
+    let end = bytes.windows(10).position(|w| w == b"end_header").ok_or("missing end_header")?;
+    let body_start = bytes[end..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |n| end + n + 1);
+    let header = std::str::from_utf8(&bytes[..end]).map_err(|_| "header is not text".to_string())?;
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in header.lines().skip(1) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", kind, _] => format = Some(*kind),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("bad element count {}", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements.last_mut().ok_or("property before any element")?
+                .properties.push(Property::List { name: name.to_string(), count: Scalar::parse(count)?, item: Scalar::parse(item)? }),
+            ["property", kind, name] => elements.last_mut().ok_or("property before any element")?
+                .properties.push(Property::Scalar { name: name.to_string(), kind: Scalar::parse(kind)? }),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(format!("unrecognised header line: {}", line)),
+        }
+    }
+
+    let mut values = match format {
+        Some("ascii") => Values::Ascii(std::str::from_utf8(&bytes[body_start..]).map_err(|_| "body is not text".to_string())?.split_whitespace()),
+        Some("binary_little_endian") => Values::Binary { bytes: &bytes[body_start..], at: 0, big_endian: false },
+        Some("binary_big_endian") => Values::Binary { bytes: &bytes[body_start..], at: 0, big_endian: true },
+        Some(other) => return Err(format!("unknown format {}", other)),
+        None => return Err("missing format line".to_string()),
+    };
+
+    let mut mesh = MeshData::default();
+    let mut faces: Vec<Vec<f64>> = Vec::new();
+    for element in &elements {
+        // position of each named scalar among the element's scalars
+        let scalars: Vec<&Property> = element.properties.iter().filter(|p| matches!(p, Property::Scalar { .. })).collect();
+        let find = |names: &[&str]| scalars.iter().position(|p| names.contains(&p.name()));
+        let scale = |i: usize| match scalars[i] {
+            Property::Scalar { kind, .. } => kind.full_scale(),
+            Property::List { .. } => 1.0,
+        };
+        let lists: Vec<&Property> = element.properties.iter().filter(|p| matches!(p, Property::List { .. })).collect();
+        let corners = lists.iter().position(|p| p.name() == "vertex_indices" || p.name() == "vertex_index");
+
+        let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+        let uv = [find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"])];
+        let color = [find(&["red", "diffuse_red"]), find(&["green", "diffuse_green"]), find(&["blue", "diffuse_blue"])];
+        let alpha = find(&["alpha", "diffuse_alpha"]);
+        if element.name == "vertex" && position.contains(&None) {
+            return Err("vertices need x, y and z".to_string());
+        }
+
+        for _ in 0..element.count {
+            let (s, l) = values.record(element)?;
+            if element.name == "vertex" {
+                let vector = |[x, y, z]: [Option<usize>; 3]| x.zip(y).zip(z).map(|((x, y), z)| Vector3 { x: s[x] as f32, y: s[y] as f32, z: s[z] as f32 });
+                mesh.positions.extend(vector(position));
+                mesh.normals.extend(vector(normal));
+                if let [Some(u), Some(v)] = uv {
+                    mesh.uvs.push(Vector2 { x: s[u] as f32, y: s[v] as f32 });
+                }
+                if let [Some(r), Some(g), Some(b)] = color {
+                    let channel = |i: usize| (s[i] / scale(i)) as f32;
+                    mesh.colors.push(Color { r: channel(r), g: channel(g), b: channel(b), a: alpha.map_or(1.0, channel) });
+                }
+            } else if element.name == "face" {
+                faces.push(l.into_iter().nth(corners.ok_or("faces need vertex_indices")?).unwrap_or_default());
+            }
+        }
+    }
+
+    let count = mesh.positions.len();
+    for face in faces {
+        if let Some(&bad) = face.iter().find(|&&i| i < 0.0 || i as usize >= count) {
+            return Err(format!("face refers to missing vertex {}", bad));
+        }
+        for k in 1..face.len().saturating_sub(1) {
+            mesh.indices.push([face[0] as u32, face[k] as u32, face[k + 1] as u32]);
+        }
+    }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_ply() {
+        let text = "ply\nformat ascii 1.0\ncomment a colored quad\nelement vertex 4\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 255 0 0\n1 0 0 0 255 0\n1 1 0 0 0 255\n0 1 0 255 255 255\n4 0 1 2 3\n";
+        let mesh = parse_ply(text.as_bytes()).unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_empty() && mesh.uvs.is_empty());
+        assert_eq!((mesh.colors[1].g, mesh.colors[1].r, mesh.colors[1].a), (1.0, 0.0, 1.0));
+
+        assert!(parse_ply(text.replace("4 0 1 2 3", "3 0 1 7").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn binary_ply() {
+        let mut file = b"ply\nformat binary_big_endian 1.0\nelement vertex 3\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property float nx\nproperty float ny\nproperty float nz\n\
+            element edge 1\nproperty int vertex1\nproperty int vertex2\n\
+            element face 1\nproperty uchar flags\nproperty list uchar uint vertex_indices\nend_header\n".to_vec();
+        for v in [[0.0f32, 0.0, -1.0], [1.0, 0.0, -1.0], [0.0, 1.0, -1.0]] {
+            for x in v.iter().chain(&[0.0, 0.0, 1.0]) {
+                file.extend_from_slice(&x.to_be_bytes());
+            }
+        }
+        file.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        file.extend_from_slice(&[9, 3]);
+        for i in [2u32, 1, 0] {
+            file.extend_from_slice(&i.to_be_bytes());
+        }
+        let mesh = parse_ply(&file).unwrap();
+        assert_eq!(mesh.indices, vec![[2, 1, 0]]);
+        assert_eq!(mesh.positions[1].x, 1.0);
+        assert_eq!(mesh.normals[2].z, 1.0);
+
+        assert!(parse_ply(&file[..file.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn stl_welding() {
+        // a square as two triangles sharing an edge, once in each format
+        let triangles = [
+            [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [-0.0, 1.0, 0.0]],
+        ];
+        let mut text = "solid square\n".to_string();
+        let mut binary = vec![0u8; 80];
+        binary.extend_from_slice(&2u32.to_le_bytes());
+        for t in &triangles {
+            text += "facet normal 0 0 1\nouter loop\n";
+            binary.extend_from_slice(&[0; 12]);
+            for v in t {
+                text += &format!("vertex {} {} {}\n", v[0], v[1], v[2]);
+                binary.extend(v.iter().flat_map(|x| x.to_le_bytes()));
+            }
+            text += "endloop\nendfacet\n";
+            binary.extend_from_slice(&[0, 0]);
+        }
+        text += "endsolid square\n";
+
+        for mesh in [parse_stl(text.as_bytes(), 0.0).unwrap(), parse_stl(&binary, 0.0).unwrap()] {
+            assert_eq!(mesh.positions.len(), 4);
+            assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        }
+
+        // points within the tolerance merge, and the triangle they collapse goes
+        let (points, remap) = weld(&[Vector3::zero(), Vector3 { x: 0.05, y: 0.0, z: 0.0 }, Vector3 { x: 0.0, y: 0.5, z: 0.0 }], 0.1);
+        assert_eq!((points.len(), remap), (2, vec![0, 0, 1]));
+        assert!(parse_stl(b"garbage", 0.0).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::scene::{Scene, trace};
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::math::random::Rng;
+use crate::renderer::{CancelToken, Report};
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, GenericImage, GrayImage, Luma};
+
+/// Running per-pixel sums of samples in linear color, from which the current
+/// image and its noise are estimated.
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub sums: Vec<[f64; 3]>,
+    // sums of squared luminance, for the variance
+    pub squares: Vec<f64>,
+    pub counts: Vec<u32>,
+}
+
+fn luminance(sample: [f64; 3]) -> f64 {
+    0.2126 * sample[0] + 0.7152 * sample[1] + 0.0722 * sample[2]
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Accumulator {
+        let pixels = (width * height) as usize;
+        Accumulator { width, height, sums: vec![[0.0; 3]; pixels], squares: vec![0.0; pixels], counts: vec![0; pixels] }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    pub fn add(&mut self, x: u32, y: u32, color: Color) {
+        let i = self.index(x, y);
+        let sample = [f64::from(color.r), f64::from(color.g), f64::from(color.b)];
+        for (sum, s) in self.sums[i].iter_mut().zip(&sample) {
+            *sum += s;
+        }
+        self.squares[i] += luminance(sample).powi(2);
+        self.counts[i] += 1;
+    }
+
+    pub fn count(&self, x: u32, y: u32) -> u32 {
+        self.counts[self.index(x, y)]
+    }
+
+    pub fn mean(&self, x: u32, y: u32) -> Color {
+        let i = self.index(x, y);
+        let n = f64::from(self.counts[i].max(1));
+        let [r, g, b] = self.sums[i];
+        Color { r: (r / n) as f32, g: (g / n) as f32, b: (b / n) as f32, a: 1.0 }
+    }
+
+    /// Standard error of the pixel's mean luminance; infinite until it has
+    /// two samples.
+    pub fn error(&self, x: u32, y: u32) -> f64 {
+        let i = self.index(x, y);
+        let n = f64::from(self.counts[i]);
+        if n < 2.0 {
+            return f64::INFINITY;
+        }
+        let mean = luminance(self.sums[i]) / n;
+        let variance = ((self.squares[i] - n * mean * mean) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt()
+    }
+
+    /// The error averaged over the image.
+    pub fn noise(&self) -> f64 {
+        let total: f64 = (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.error(x, y))
+            .sum();
+        total / f64::from(self.width * self.height)
+    }
+
+    /// Samples taken over the whole image.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|&c| u64::from(c)).sum()
+    }
+
+    /// Grey levels proportional to each pixel's sample count, white at the
+    /// largest count.
+    pub fn count_image(&self) -> DynamicImage {
+        let most = f64::from(self.counts.iter().copied().max().unwrap_or(0).max(1));
+        let mut image = GrayImage::new(self.width, self.height);
+        for (pixel, &count) in image.pixels_mut().zip(&self.counts) {
+            *pixel = Luma([(f64::from(count) / most * 255.0).round() as u8]);
+        }
+        DynamicImage::ImageLuma8(image)
+    }
+
+    pub fn to_image(&self) -> DynamicImage {
+        let mut image = DynamicImage::new_rgb8(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                image.put_pixel(x, y, self.mean(x, y).to_rgba());
+            }
+        }
+        image
+    }
+}
+
+/// When a progressive render stops and how often it reports the image so far.
+/// With an adaptive threshold, a pixel stops being sampled once it has
+/// `min_samples` and its error is within the threshold; `samples` is then
+/// the most any pixel gets.
+pub struct Settings {
+    pub samples: u32,
+    pub adaptive_threshold: Option<f64>,
+    pub min_samples: u32,
+    pub time_limit: Option<Duration>,
+    pub noise_threshold: Option<f64>,
+    pub update_interval: Duration,
+    pub seed: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { samples: 256, adaptive_threshold: None, min_samples: 8, time_limit: None, noise_threshold: None, update_interval: Duration::from_secs(2), seed: 0 }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stop {
+    Samples,
+    Time,
+    Noise,
+    // every pixel is within the adaptive threshold
+    Converged,
+    // stopped part way through a pass
+    Cancelled,
+}
+
+/// One sample of a pixel. The first goes through the pixel center, so a
+/// single pass matches `renderer::render`; later ones are jittered.
+pub fn sample(scene: &Scene, x: u32, y: u32, first: bool, rng: &mut Rng) -> Color {
+    let offset = if first { (0.5, 0.5) } else { (rng.next_f64(), rng.next_f64()) };
+    trace(scene, Ray::create_sample_ray(x, y, offset, scene), 0)
+}
+
+fn needs_sample(accumulator: &Accumulator, settings: &Settings, x: u32, y: u32) -> bool {
+    let count = accumulator.count(x, y);
+    count < settings.samples && (count < settings.min_samples
+        || settings.adaptive_threshold.is_none_or(|threshold| accumulator.error(x, y) > threshold))
+}
+
+/// How far a progressive render has got: the samples so far, the passes
+/// made and the generator state for the next sample.
+pub struct Progress {
+    pub accumulator: Accumulator,
+    pub pass: u32,
+    pub rng: Rng,
+}
+
+impl Progress {
+    pub fn new(scene: &Scene, settings: &Settings) -> Progress {
+        Progress { accumulator: Accumulator::new(scene.width, scene.height), pass: 0, rng: Rng::new(settings.seed) }
+    }
+
+    /// Passes made towards the sample count, with the time left estimated
+    /// from those made in `elapsed`, since `first_pass`.
+    pub fn report(&self, settings: &Settings, first_pass: u32, elapsed: Duration) -> Report {
+        let mut report = Report::new(self.pass - first_pass, settings.samples.saturating_sub(first_pass), self.accumulator.total(), elapsed);
+        report.done = self.pass;
+        report.total = settings.samples;
+        if let Some(limit) = settings.time_limit {
+            let left = limit.saturating_sub(elapsed);
+            report.eta = Some(report.eta.map_or(left, |eta| eta.min(left)));
+        }
+        report
+    }
+}
+
+/// Renders from the first pass, as `resume` does.
+#[cfg(test)]
+pub fn render(scene: &Scene, settings: &Settings, cancel: &CancelToken, update: &mut dyn FnMut(&Progress)) -> (Progress, Stop) {
+    resume(scene, settings, Progress::new(scene, settings), cancel, update)
+}
+
+/// Carries a render on from `progress`, giving what it would have given had
+/// it never stopped: one sample per pixel per pass until a limit in
+/// `settings` is met. `update` sees the progress after the first pass, then
+/// at most once per update interval, and once more at the end. Limits are
+/// checked between passes, and `cancel` between rows; a cancelled render
+/// gives no final update, as its last pass is incomplete.
+pub fn resume(scene: &Scene, settings: &Settings, mut progress: Progress, cancel: &CancelToken, update: &mut dyn FnMut(&Progress)) -> (Progress, Stop) {
+    let start = Instant::now();
+    let mut last_update = start;
+    let stop = loop {
+        // a resumed render may already be done
+        if progress.pass >= settings.samples {
+            break Stop::Samples;
+        }
+        let Progress { accumulator, pass, rng } = &mut progress;
+        let mut active = false;
+        for y in 0..scene.height {
+            if cancel.is_cancelled() {
+                return (progress, Stop::Cancelled);
+            }
+            for x in 0..scene.width {
+                if needs_sample(accumulator, settings, x, y) {
+                    accumulator.add(x, y, sample(scene, x, y, *pass == 0, rng));
+                    active |= needs_sample(accumulator, settings, x, y);
+                }
+            }
+        }
+        *pass += 1;
+
+        if *pass >= settings.samples {
+            break Stop::Samples;
+        }
+        if !active {
+            break Stop::Converged;
+        }
+        if settings.time_limit.is_some_and(|limit| start.elapsed() >= limit) {
+            break Stop::Time;
+        }
+        if settings.noise_threshold.is_some_and(|threshold| accumulator.noise() <= threshold) {
+            break Stop::Noise;
+        }
+        if *pass == 1 || last_update.elapsed() >= settings.update_interval {
+            update(&progress);
+            last_update = Instant::now();
+        }
+    };
+    update(&progress);
+    (progress, stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::{Shape, Sphere};
+
+    fn scene(shapes: Vec<Box<dyn Shape>>) -> Scene {
+        Scene { width: 8, height: 6, fov: 90.0, lights: Vec::new(), shapes }
+    }
+
+    #[test]
+    fn accumulator() {
+        let mut accumulator = Accumulator::new(2, 1);
+        let grey = |v: f32| Color { r: v, g: v, b: v, a: 1.0 };
+        accumulator.add(0, 0, grey(0.2));
+        accumulator.add(0, 0, grey(0.6));
+        accumulator.add(1, 0, grey(0.5));
+        assert!((accumulator.mean(0, 0).g - 0.4).abs() < 1e-6);
+        assert_eq!(accumulator.count(0, 0), 2);
+        // two samples 0.4 apart: variance 0.08, standard error 0.2
+        assert!((accumulator.error(0, 0) - 0.2).abs() < 1e-6);
+        assert_eq!(accumulator.error(1, 0), f64::INFINITY);
+    }
+
+    #[test]
+    fn limits() {
+        // the first pass is the plain render
+        let empty = scene(Vec::new());
+        let settings = Settings { samples: 1, ..Settings::default() };
+        let (Progress { accumulator, .. }, stop) = render(&empty, &settings, &CancelToken::new(), &mut |_| ());
+        assert_eq!(stop, Stop::Samples);
+        assert_eq!(accumulator.to_image().as_bytes(), crate::renderer::render(&empty, 16, &CancelToken::new(), &mut |_| ()).image.as_bytes());
+
+        // a flat background has no noise, so it stops once that can be measured
+        let mut updates = 0;
+        let settings = Settings { noise_threshold: Some(1e-3), ..Settings::default() };
+        let (Progress { accumulator, .. }, stop) = render(&empty, &settings, &CancelToken::new(), &mut |_| updates += 1);
+        assert_eq!((stop, accumulator.count(3, 3)), (Stop::Noise, 2));
+        assert_eq!(updates, 2);
+
+        // a sphere's edge stays noisy, so only the sample count ends it
+        let sphere = scene(vec![Box::new(Sphere {
+            center: crate::math::vector::Vector3 { x: 0.0, y: 0.0, z: -3.0 },
+            radius: 2.0,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        })]);
+        let settings = Settings { samples: 12, noise_threshold: Some(1e-6), ..Settings::default() };
+        let (Progress { accumulator, .. }, stop) = render(&sphere, &settings, &CancelToken::new(), &mut |_| ());
+        assert_eq!((stop, accumulator.count(0, 0)), (Stop::Samples, 12));
+        assert!(accumulator.noise() > 1e-6);
+
+        // cancelling stops before the next row, with no final update
+        let cancel = CancelToken::new();
+        let mut updates = 0;
+        let settings = Settings { update_interval: Duration::from_secs(0), ..Settings::default() };
+        let (progress, stop) = render(&sphere, &settings, &cancel, &mut |progress| {
+            updates += 1;
+            if progress.pass == 3 {
+                cancel.cancel();
+            }
+        });
+        assert_eq!((stop, progress.pass, progress.accumulator.count(7, 5), updates), (Stop::Cancelled, 3, 3, 3));
+        let report = progress.report(&settings, 0, Duration::from_secs(3));
+        assert_eq!((report.done, report.total, report.eta), (3, 256, Some(Duration::from_secs(253))));
+    }
+
+    #[test]
+    fn adaptive() {
+        let sphere = scene(vec![Box::new(Sphere {
+            center: crate::math::vector::Vector3 { x: 0.0, y: 0.0, z: -3.0 },
+            radius: 2.0,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        })]);
+        let settings = Settings { samples: 64, adaptive_threshold: Some(1e-3), min_samples: 4, ..Settings::default() };
+        let (Progress { accumulator, .. }, stop) = render(&sphere, &settings, &CancelToken::new(), &mut |_| ());
+        assert_eq!(stop, Stop::Samples);
+        // flat background stops at the minimum; the sphere's edge gets the most
+        assert_eq!(accumulator.count(0, 0), 4);
+        let counts: Vec<u32> = (0..6).flat_map(|y| (0..8).map(move |x| (x, y))).map(|(x, y)| accumulator.count(x, y)).collect();
+        assert_eq!(counts.iter().max(), Some(&64));
+        assert!(accumulator.total() < 64 * 48 / 2);
+
+        let map = accumulator.count_image().to_luma8();
+        assert_eq!(map.get_pixel(0, 0)[0], 16);
+        assert!(map.pixels().any(|p| p[0] == 255));
+
+        // a flat image converges everywhere at the minimum
+        let settings = Settings { adaptive_threshold: Some(1e-3), min_samples: 3, ..Settings::default() };
+        let (Progress { accumulator, .. }, stop) = render(&scene(Vec::new()), &settings, &CancelToken::new(), &mut |_| ());
+        assert_eq!((stop, accumulator.total()), (Stop::Converged, 3 * 48));
+    }
+}
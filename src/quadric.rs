@@ -0,0 +1,508 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, RAY_EPSILON, basis};
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::quaternion::Quat;
+use crate::math::polynomial::solve_quadratic;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+use std::f32::consts::PI;
+
+// Surface x^2 + z^2 + b y^2 + d y + e = 0 in the shape's own frame, with the
+// inside where the left-hand side is negative, clipped to |y| <= half_height.
+struct Profile {
+    b: f32,
+    d: f32,
+    e: f32,
+    half_height: f32,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Boundary {
+    Side,
+    Bottom,
+    Top,
+    // the interval runs off to infinity
+    Open,
+}
+
+impl Profile {
+    fn radius_at(&self, y: f32) -> f32 {
+        (-(self.b * y * y + self.d * y + self.e)).max(0.0).sqrt()
+    }
+
+    // Stretches of the unclipped quadric's inside along the line.
+    fn side_spans(&self, o: Vector3, d: Vector3) -> Vec<(f32, f32)> {
+        let qa = d.x * d.x + d.z * d.z + self.b * d.y * d.y;
+        let qb = 2.0 * (o.x * d.x + o.z * d.z + self.b * o.y * d.y) + self.d * d.y;
+        let qc = o.x * o.x + o.z * o.z + self.b * o.y * o.y + self.d * o.y + self.e;
+
+        if qa.abs() < 1e-9 {
+            if qb.abs() < 1e-9 {
+                return if qc < 0.0 { vec![(f32::NEG_INFINITY, f32::INFINITY)] } else { Vec::new() };
+            }
+            let t = -qc / qb;
+            return if qb > 0.0 { vec![(f32::NEG_INFINITY, t)] } else { vec![(t, f32::INFINITY)] };
+        }
+
+        match solve_quadratic(qa, qb, qc) {
+            Some((t0, t1)) if qa > 0.0 => vec![(t0, t1)],
+            Some((t0, t1)) => vec![(f32::NEG_INFINITY, t0), (t1, f32::INFINITY)],
+            None if qa > 0.0 => Vec::new(),
+            None => vec![(f32::NEG_INFINITY, f32::INFINITY)],
+        }
+    }
+
+    // Inside intervals of the clipped solid with the boundary at each end.
+    fn spans(&self, o: Vector3, d: Vector3) -> Vec<(f32, Boundary, f32, Boundary)> {
+        let h = self.half_height;
+        let (slab_in, bottom_in, slab_out, bottom_out) = if d.y.abs() < 1e-9 {
+            if o.y.abs() > h {
+                return Vec::new();
+            }
+            (f32::NEG_INFINITY, Boundary::Open, f32::INFINITY, Boundary::Open)
+        } else {
+            let ta = (-h - o.y) / d.y;
+            let tb = (h - o.y) / d.y;
+            if d.y > 0.0 { (ta, Boundary::Bottom, tb, Boundary::Top) } else { (tb, Boundary::Top, ta, Boundary::Bottom) }
+        };
+
+        let boundary = |t: f32| if t.is_finite() { Boundary::Side } else { Boundary::Open };
+        let mut spans = Vec::with_capacity(2);
+        for (side_in, side_out) in self.side_spans(o, d) {
+            let (t_in, b_in) = if side_in >= slab_in { (side_in, boundary(side_in)) } else { (slab_in, bottom_in) };
+            let (t_out, b_out) = if side_out <= slab_out { (side_out, boundary(side_out)) } else { (slab_out, bottom_out) };
+            if t_in < t_out {
+                spans.push((t_in, b_in, t_out, b_out));
+            }
+        }
+        spans
+    }
+
+    // Local-space normal, tangent and uv of a boundary point.
+    fn surface(&self, p: Vector3, boundary: Boundary) -> (Vector3, Vector3, Vector2) {
+        let h = self.half_height;
+        match boundary {
+            Boundary::Top | Boundary::Bottom => {
+                let (sign, y) = if boundary == Boundary::Top { (1.0, h) } else { (-1.0, -h) };
+                let radius = self.radius_at(y).max(1e-6);
+                let uv = Vector2 { x: 0.5 + p.x / (2.0 * radius), y: 0.5 - sign * p.z / (2.0 * radius) };
+                (Vector3 { x: 0.0, y: sign, z: 0.0 }, Vector3 { x: 1.0, y: 0.0, z: 0.0 }, uv)
+            }
+            _ => {
+                let n = Vector3 { x: 2.0 * p.x, y: 2.0 * self.b * p.y + self.d, z: 2.0 * p.z };
+                let n = if n.size_squared() > 0.0 { n.normalize() } else { Vector3 { x: 0.0, y: 1.0, z: 0.0 } };
+                let tangent = Vector3 { x: p.z, y: 0.0, z: -p.x };
+                let tangent = if tangent.size_squared() > 1e-12 {
+                    (tangent - n * n.dot(&tangent)).normalize()
+                } else {
+                    basis(n).0
+                };
+                // longitude around +y starting at +z, height from the bottom
+                let uv = Vector2 { x: 0.5 + p.x.atan2(p.z) / (2.0 * PI), y: (p.y + h) / (2.0 * h) };
+                (n, tangent, uv)
+            }
+        }
+    }
+}
+
+// A profile placed in the world like `Cube`: rotated about its center and
+// moved to `location`.
+struct Solid {
+    profile: Profile,
+    location: Vector3,
+    rotation: Quat,
+    capped: bool,
+}
+
+impl Solid {
+    fn local_ray(&self, ray: &Ray) -> (Vector3, Vector3) {
+        let mut inverse = self.rotation;
+        inverse.conjugate();
+        (inverse.rotate(ray.origin - self.location), inverse.rotate(ray.direction))
+    }
+
+    fn hit(&self, ray: &Ray, o: Vector3, d: Vector3, t: f32, boundary: Boundary) -> Hit {
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        if boundary == Boundary::Open {
+            return hit;
+        }
+        let (n, tangent, uv) = self.profile.surface(o + t * d, boundary);
+        hit.normal = self.rotation.rotate(n);
+        hit.geometric_normal = hit.normal;
+        hit.tangent = self.rotation.rotate(tangent);
+        hit.bitangent = hit.normal.cross(&hit.tangent);
+        hit.uv = uv;
+        hit
+    }
+
+    // An open end lets the line into the hollow of the tube, so where it
+    // then meets the side it strikes the inner face of the wall: that hit
+    // enters the wall, with its normal turned to face into the hollow.
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let (o, d) = self.local_ray(ray);
+        let mut nearest: Option<(f32, Boundary, u8, bool)> = None;
+        for (t_in, b_in, t_out, b_out) in self.profile.spans(o, d) {
+            let through_end = !self.capped && matches!(b_in, Boundary::Top | Boundary::Bottom);
+            let exit = if through_end { (t_out, b_out, 2, true) } else { (t_out, b_out, 1, false) };
+            for (t, boundary, count, inner) in [(t_in, b_in, 2, false), exit] {
+                let visible = boundary == Boundary::Side || (self.capped && boundary != Boundary::Open);
+                if visible && t > RAY_EPSILON && nearest.is_none_or(|n| t < n.0) {
+                    nearest = Some((t, boundary, count, inner));
+                }
+            }
+        }
+        match nearest {
+            Some((t, boundary, count, inner)) => {
+                *hit = self.hit(ray, o, d, t, boundary);
+                if inner {
+                    hit.normal = -hit.normal;
+                    hit.geometric_normal = -hit.geometric_normal;
+                }
+                count
+            }
+            None => 0,
+        }
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let (o, d) = self.local_ray(ray);
+        for (t_in, b_in, t_out, b_out) in self.profile.spans(o, d) {
+            intervals.push(Interval {
+                t_in,
+                t_out,
+                enter: self.hit(ray, o, d, t_in, b_in),
+                exit: self.hit(ray, o, d, t_out, b_out),
+            });
+        }
+    }
+}
+
+fn default_capped() -> bool {
+    true
+}
+
+/// Cylinder around its local y axis, centered on `location`. Uncapped
+/// shapes are open tubes that rays pass through at the ends, meeting the
+/// inside of the wall as a surface facing into the tube; CSG still treats
+/// them as closed.
+#[derive(Deserialize)]
+pub struct Cylinder {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub radius: f32,
+    pub height: f32,
+    #[serde(default = "default_capped")]
+    pub capped: bool,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Cylinder {
+    fn solid(&self) -> Solid {
+        Solid {
+            profile: Profile { b: 0.0, d: 0.0, e: -self.radius * self.radius, half_height: self.height / 2.0 },
+            location: self.location,
+            rotation: self.rotation,
+            capped: self.capped,
+        }
+    }
+}
+
+impl Shape for Cylinder {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Cylinder {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        self.solid().intersect(ray, hit)
+    }
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        self.solid().intervals(ray, intervals)
+    }
+}
+
+/// Cone around its local y axis with a base of `radius` at `-height / 2`
+/// and the apex at `height / 2`.
+#[derive(Deserialize)]
+pub struct Cone {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub radius: f32,
+    pub height: f32,
+    #[serde(default = "default_capped")]
+    pub capped: bool,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Cone {
+    fn solid(&self) -> Solid {
+        // x^2 + z^2 = k^2 (h / 2 - y)^2
+        let k2 = (self.radius / self.height).powi(2);
+        Solid {
+            profile: Profile { b: -k2, d: k2 * self.height, e: -k2 * self.height * self.height / 4.0, half_height: self.height / 2.0 },
+            location: self.location,
+            rotation: self.rotation,
+            capped: self.capped,
+        }
+    }
+}
+
+impl Shape for Cone {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Cone {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        self.solid().intersect(ray, hit)
+    }
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        self.solid().intervals(ray, intervals)
+    }
+}
+
+/// Paraboloid opening along local +y, with its vertex at `-height / 2` and
+/// a rim of `radius` at `height / 2`.
+#[derive(Deserialize)]
+pub struct Paraboloid {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub radius: f32,
+    pub height: f32,
+    #[serde(default = "default_capped")]
+    pub capped: bool,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Paraboloid {
+    fn solid(&self) -> Solid {
+        // x^2 + z^2 = k (y + h / 2)
+        let k = self.radius * self.radius / self.height;
+        Solid {
+            profile: Profile { b: 0.0, d: -k, e: -k * self.height / 2.0, half_height: self.height / 2.0 },
+            location: self.location,
+            rotation: self.rotation,
+            capped: self.capped,
+        }
+    }
+}
+
+impl Shape for Paraboloid {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Paraboloid {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        self.solid().intersect(ray, hit)
+    }
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        self.solid().intervals(ray, intervals)
+    }
+}
+
+/// Hyperboloid of one sheet around local y, with a waist of `radius` at the
+/// center widening to `top_radius` at both ends.
+#[derive(Deserialize)]
+pub struct Hyperboloid {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub radius: f32,
+    pub top_radius: f32,
+    pub height: f32,
+    #[serde(default = "default_capped")]
+    pub capped: bool,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Hyperboloid {
+    fn solid(&self) -> Solid {
+        // x^2 + z^2 = r^2 + s y^2
+        let half_height = self.height / 2.0;
+        let s = (self.top_radius * self.top_radius - self.radius * self.radius) / (half_height * half_height);
+        Solid {
+            profile: Profile { b: -s, d: 0.0, e: -self.radius * self.radius, half_height },
+            location: self.location,
+            rotation: self.rotation,
+            capped: self.capped,
+        }
+    }
+}
+
+impl Shape for Hyperboloid {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for Hyperboloid {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        self.solid().intersect(ray, hit)
+    }
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        self.solid().intervals(ray, intervals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Color = Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+
+    fn ray(origin: Vector3, direction: Vector3) -> Ray {
+        Ray { origin, direction: direction.normalize() }
+    }
+
+    fn v(x: f32, y: f32, z: f32) -> Vector3 {
+        Vector3 { x, y, z }
+    }
+
+    fn nearly(a: Vector3, b: Vector3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    fn cylinder(capped: bool) -> Cylinder {
+        Cylinder { location: v(0.0, 0.0, -5.0), rotation: Quat::identity(), radius: 1.0, height: 2.0, capped, color: WHITE, refractive_index: 1.5, detail_map: None }
+    }
+
+    #[test]
+    fn cylinder_side_and_caps() {
+        let capped = cylinder(true);
+        let mut hit = Hit::new();
+        assert_eq!(capped.intersect(&ray(v(0.0, 0.5, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(0.0, 0.5, -4.0)));
+        assert!(nearly(hit.normal, v(0.0, 0.0, 1.0)));
+        assert!((hit.uv.y - 0.75).abs() < 1e-4);
+        assert!(nearly(hit.normal.cross(&hit.tangent), hit.bitangent));
+
+        assert_eq!(capped.intersect(&ray(v(0.0, 5.0, -5.0), v(0.0, -1.0, 0.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(0.0, 1.0, -5.0)));
+        assert!(nearly(hit.normal, v(0.0, 1.0, 0.0)));
+
+        // from the center the ray leaves through the side
+        assert_eq!(capped.intersect(&ray(v(0.0, 0.0, -5.0), v(1.0, 0.0, 0.0)), &mut hit), 1);
+        assert!(nearly(hit.normal, v(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn uncapped_cylinder_is_open() {
+        let tube = cylinder(false);
+        let mut hit = Hit::new();
+        assert_eq!(tube.intersect(&ray(v(0.0, 5.0, -5.0), v(0.0, -1.0, 0.0)), &mut hit), 0);
+
+        // through the open top onto the inner wall, which faces into the tube
+        assert_eq!(tube.intersect(&ray(v(0.0, 1.5, -5.0), v(1.0, -1.0, 0.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(1.0, 0.5, -5.0)));
+        assert!(nearly(hit.normal, v(-1.0, 0.0, 0.0)) && nearly(hit.geometric_normal, v(-1.0, 0.0, 0.0)));
+        // through the side from outside the wall still faces out
+        assert_eq!(tube.intersect(&ray(v(-3.0, 0.5, -5.0), v(1.0, 0.0, 0.0)), &mut hit), 2);
+        assert!(nearly(hit.normal, v(-1.0, 0.0, 0.0)) && nearly(hit.point, v(-1.0, 0.5, -5.0)));
+
+        let mut intervals = Vec::new();
+        tube.intervals(&ray(v(0.0, 5.0, -5.0), v(0.0, -1.0, 0.0)), &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].t_in - 4.0).abs() < 1e-4 && (intervals[0].t_out - 6.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn cone() {
+        let cone = Cone { location: v(0.0, 0.0, -5.0), rotation: Quat::identity(), radius: 1.0, height: 2.0, capped: true, color: WHITE, refractive_index: 1.0, detail_map: None };
+        let mut hit = Hit::new();
+        // halfway up the radius is 0.5
+        assert_eq!(cone.intersect(&ray(v(0.0, 0.0, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(0.0, 0.0, -4.5)));
+        assert!(nearly(hit.normal, v(0.0, 1.0, 2.0).normalize()));
+
+        assert_eq!(cone.intersect(&ray(v(0.0, -5.0, -5.0), v(0.0, 1.0, 0.0)), &mut hit), 2);
+        assert!(nearly(hit.normal, v(0.0, -1.0, 0.0)));
+
+        // above the apex the second nappe is clipped away
+        assert_eq!(cone.intersect(&ray(v(0.0, 1.5, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 0);
+    }
+
+    #[test]
+    fn rotated_paraboloid() {
+        // rotated a quarter turn about z, the paraboloid opens along -x
+        let paraboloid = Paraboloid {
+            location: v(0.0, 0.0, -5.0),
+            rotation: Quat::from_axis_angle(v(0.0, 0.0, 1.0), PI / 2.0),
+            radius: 1.0,
+            height: 1.0,
+            capped: false,
+            color: WHITE,
+            refractive_index: 1.0,
+            detail_map: None,
+        };
+        let mut hit = Hit::new();
+        assert_eq!(paraboloid.intersect(&ray(v(5.0, 0.0, -5.0), v(-1.0, 0.0, 0.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(0.5, 0.0, -5.0)));
+        assert!(nearly(hit.normal, v(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn hyperboloid() {
+        let hyperboloid = Hyperboloid { location: v(0.0, 0.0, -5.0), rotation: Quat::identity(), radius: 0.5, top_radius: 1.0, height: 2.0, capped: true, color: WHITE, refractive_index: 1.0, detail_map: None };
+        let mut hit = Hit::new();
+        assert_eq!(hyperboloid.intersect(&ray(v(0.0, 0.0, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 2);
+        assert!(nearly(hit.point, v(0.0, 0.0, -4.5)));
+
+        // a ray through the waist misses the wider rim at x = 0.75 only near the middle
+        assert_eq!(hyperboloid.intersect(&ray(v(0.75, 0.0, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 0);
+        assert_eq!(hyperboloid.intersect(&ray(v(0.75, 0.9, 0.0), v(0.0, 0.0, -1.0)), &mut hit), 2);
+    }
+}
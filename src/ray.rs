@@ -11,11 +11,16 @@ pub struct Ray{
 
 impl Ray{
     pub fn create_primary_ray(x: u32, y: u32, scene: &Scene) -> Ray{
+        Ray::create_sample_ray(x, y, (0.5, 0.5), scene)
+    }
+
+    /// A primary ray through the point `offset` (each in [0, 1)) within the pixel.
+    pub fn create_sample_ray(x: u32, y: u32, offset: (f64, f64), scene: &Scene) -> Ray{
         assert!(scene.width > scene.height);
         let fov_adjustment = f64::from(scene.fov.to_radians() / 2.0).tan();
         let aspect_ratio = f64::from(scene.width) / f64::from(scene.height);
-        let sensor_x = ((f64::from(x) + 0.5) / f64::from(scene.width) * 2.0 - 1.0) * aspect_ratio * fov_adjustment;
-        let sensor_y = (1.0 - (f64::from(y) + 0.5) / f64::from(scene.height) * 2.0) * fov_adjustment;
+        let sensor_x = ((f64::from(x) + offset.0) / f64::from(scene.width) * 2.0 - 1.0) * aspect_ratio * fov_adjustment;
+        let sensor_y = (1.0 - (f64::from(y) + offset.1) / f64::from(scene.height) * 2.0) * fov_adjustment;
 
         Ray{
             origin: Vector3::zero(),
@@ -0,0 +1,176 @@
+use crate::scene::{Scene, trace};
+use crate::ray::Ray;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Asks a render to stop. Clones share the request, so one can be kept to
+/// cancel a render running elsewhere.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a render has got.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Work finished and in all: tiles, or passes of a progressive render.
+    pub done: u32,
+    pub total: u32,
+    /// Samples traced so far.
+    pub samples: u64,
+    pub elapsed: Duration,
+    /// Time left at the pace so far, once anything is done.
+    pub eta: Option<Duration>,
+}
+
+impl Report {
+    pub fn new(done: u32, total: u32, samples: u64, elapsed: Duration) -> Report {
+        let eta = if done == 0 { None } else { Some(elapsed.mul_f64(f64::from(total.saturating_sub(done)) / f64::from(done))) };
+        Report { done, total, samples, elapsed, eta }
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 { 1.0 } else { f64::from(self.done) / f64::from(self.total) }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Covers the image in tiles of at most `size` pixels square, row by row.
+pub fn tiles(width: u32, height: u32, size: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(size as usize) {
+        for x in (0..width).step_by(size as usize) {
+            tiles.push(Tile { x, y, width: size.min(width - x), height: size.min(height - y) });
+        }
+    }
+    tiles
+}
+
+/// The tile's pixels as RGB rows, each traced through the pixel center.
+pub fn render_tile(scene: &Scene, tile: Tile) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((tile.width * tile.height * 3) as usize);
+    for y in tile.y..tile.y + tile.height {
+        for x in tile.x..tile.x + tile.width {
+            let color = trace(scene, Ray::create_primary_ray(x, y, scene), 0).to_rgba();
+            pixels.extend_from_slice(&color.0[..3]);
+        }
+    }
+    pixels
+}
+
+/// Copies a tile's pixels, as `render_tile` gives them, into `image`.
+pub fn place_tile(image: &mut RgbImage, tile: Tile, pixels: &[u8]) {
+    for (row, line) in pixels.chunks_exact((tile.width * 3) as usize).enumerate() {
+        for (column, rgb) in line.chunks_exact(3).enumerate() {
+            image.put_pixel(tile.x + column as u32, tile.y + row as u32, Rgb([rgb[0], rgb[1], rgb[2]]));
+        }
+    }
+}
+
+pub struct Rendered {
+    pub image: DynamicImage,
+    /// Whether the render stopped early; tiles not reached are left black.
+    pub cancelled: bool,
+    pub report: Report,
+}
+
+/// Renders `scene` a tile at a time, giving `progress` a report after each
+/// tile, and stopping before the next tile once `cancel` is set. Reports
+/// can go to a channel with `&mut |report| { let _ = sender.send(*report); }`.
+pub fn render(scene: &Scene, tile_size: u32, cancel: &CancelToken, progress: &mut dyn FnMut(&Report)) -> Rendered {
+    let start = Instant::now();
+    let tiles = tiles(scene.width, scene.height, tile_size.max(1));
+    let total = tiles.len() as u32;
+    let mut image = RgbImage::new(scene.width, scene.height);
+    let mut report = Report::new(0, total, 0, Duration::from_secs(0));
+    for (done, &tile) in tiles.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Rendered { image: DynamicImage::ImageRgb8(image), cancelled: true, report };
+        }
+        place_tile(&mut image, tile, &render_tile(scene, tile));
+        report = Report::new(done as u32 + 1, total, report.samples + u64::from(tile.width * tile.height), start.elapsed());
+        progress(&report);
+    }
+    Rendered { image: DynamicImage::ImageRgb8(image), cancelled: false, report }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::math::vector::Vector3;
+    use crate::shape::Sphere;
+
+    fn scene() -> Scene {
+        Scene { width: 40, height: 24, fov: 70.0, lights: Vec::new(), shapes: vec![Box::new(Sphere {
+            center: Vector3 { x: 0.0, y: 0.0, z: -4.0 },
+            radius: 1.5,
+            color: Color { r: 0.9, g: 0.4, b: 0.2, a: 1.0 },
+            refractive_index: 1.3,
+            detail_map: None,
+        })] }
+    }
+
+    // The scene traced a pixel at a time, with no tiles.
+    fn traced(scene: &Scene) -> RgbImage {
+        RgbImage::from_fn(scene.width, scene.height, |x, y| {
+            let color = trace(scene, Ray::create_primary_ray(x, y, scene), 0).to_rgba();
+            Rgb([color.0[0], color.0[1], color.0[2]])
+        })
+    }
+
+    #[test]
+    fn reports_progress() {
+        let scene = scene();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let rendered = render(&scene, 16, &CancelToken::new(), &mut |report| { let _ = sender.send(*report); });
+        assert!(!rendered.cancelled);
+        assert_eq!(rendered.image.as_bytes(), traced(&scene).as_raw().as_slice());
+
+        let reports: Vec<Report> = receiver.try_iter().collect();
+        assert_eq!(reports.iter().map(|r| r.done).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!((reports[5].total, reports[5].samples, reports[5].eta), (6, 40 * 24, Some(Duration::from_secs(0))));
+        assert_eq!(tiles(40, 24, 16)[2], Tile { x: 32, y: 0, width: 8, height: 16 });
+    }
+
+    #[test]
+    fn cancels() {
+        let scene = scene();
+        let cancel = CancelToken::new();
+        let watcher = cancel.clone();
+        let rendered = render(&scene, 8, &cancel, &mut |report| if report.done == 4 { watcher.cancel() });
+        assert!(rendered.cancelled);
+        assert_eq!((rendered.report.done, rendered.report.total), (4, 15));
+        // the first row of tiles is there; the rest is black
+        let expected = traced(&scene);
+        let image = rendered.image.to_rgb8();
+        assert_eq!(image.get_pixel(20, 4), expected.get_pixel(20, 4));
+        assert_eq!(image.get_pixel(20, 12), &Rgb([0, 0, 0]));
+
+        let estimate = Report::new(1, 4, 10, Duration::from_secs(2));
+        assert_eq!((estimate.eta, estimate.fraction()), (Some(Duration::from_secs(6)), 0.25));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::shape::Shape;
+use crate::shape::{Shape, Hit};
 use crate::math::vector::Vector3;
 use crate::light::*;
 use crate::color::Color;
@@ -6,7 +6,6 @@ use crate::math::clamp;
 
 use std::vec::Vec;
 
-use image::{DynamicImage, GenericImage};
 use crate::ray::Ray;
 
 pub struct Scene {
@@ -14,46 +13,43 @@ pub struct Scene {
     pub height: u32,
     pub fov: f32,
     pub lights: Vec<Light>,
-    pub shapes: Vec<&'static dyn Shape>,
+    pub shapes: Vec<Box<dyn Shape>>,
 }
 
-pub fn render(scene: &Scene) -> DynamicImage{
-    let mut image = DynamicImage::new_rgb8(scene.width, scene.height);
-    for x in 0..scene.width{
-        for y in 0..scene.height{
-            let ray = Ray::create_primary_ray(x, y, scene);
-            let color = trace(scene, ray, 0);
-            image.put_pixel(x, y, color.to_rgba());
-        }
-    }
-    image
-}
-
-pub fn ray_casting(scene: &Scene, ray: Ray) -> (Option<&dyn Shape>, Vector3, Vector3, u8) {
-    let mut hit_normal = Vector3::zero();
-    let mut hit_point = Vector3::zero();
+pub fn ray_casting(scene: &Scene, ray: Ray) -> (Option<&dyn Shape>, Hit, u8) {
+    let mut closest_hit = Hit::new();
 
-    let mut min_distance = std::f32::INFINITY;
+    let mut min_distance = f32::INFINITY;
     let mut closest_shape: Option<&dyn Shape> = None;
 
     let mut intersect_count = 0;
 
     for shape in scene.shapes.iter() {
-        let mut normal = Vector3::zero();
-        let mut point = Vector3::zero();
-        intersect_count = shape.intersect(&ray, &mut normal, &mut point);
-        if intersect_count > 0 {
-            let distance = point.size_squared();
+        let mut hit = Hit::new();
+        let count = shape.intersect(&ray, &mut hit);
+        if count > 0 {
+            let distance = (hit.point - ray.origin).size_squared();
             if distance < min_distance {
                 min_distance = distance;
-                closest_shape = Some(*shape);
-                hit_normal = normal;
-                hit_point = point;
+                closest_shape = Some(shape.as_ref());
+                closest_hit = hit;
+                intersect_count = count;
             }
         }
     }
 
-    (closest_shape, hit_point, hit_normal, intersect_count)
+    (closest_shape, closest_hit, intersect_count)
+}
+
+// Moves a secondary ray's origin off the surface along the geometric normal,
+// on the side the ray leaves towards, so it cannot hit the surface it starts on.
+fn offset_origin(point: Vector3, geometric_normal: Vector3, direction: Vector3) -> Vector3 {
+    const RAY_OFFSET: f32 = 1e-3;
+    if direction.dot(&geometric_normal) >= 0.0 {
+        point + geometric_normal * RAY_OFFSET
+    } else {
+        point - geometric_normal * RAY_OFFSET
+    }
 }
 
 pub fn trace(scene: &Scene, ray: Ray, order: u8) -> Color {
@@ -66,9 +62,13 @@ pub fn trace(scene: &Scene, ray: Ray, order: u8) -> Color {
     }
 
     // find intersect
-    let (closest_shape, hit_point, hit_normal, intersect_count) = ray_casting(scene, ray);
+    let (closest_shape, hit, intersect_count) = ray_casting(scene, ray);
+    let hit_point = hit.point;
+    let geometric_normal = hit.geometric_normal;
 
     if let Some(shape) = closest_shape {
+        let hit_normal = shape.detail_map().map_or(hit.normal, |map| map.perturb(&hit));
+        let surface_color = hit.color.unwrap_or_else(|| shape.color());
         for light in scene.lights.iter() {
             let light_direction = 
                 if light.light_type == LightType::Directional {
@@ -77,28 +77,35 @@ pub fn trace(scene: &Scene, ray: Ray, order: u8) -> Color {
                     (light.location - hit_point).normalize()
                 };
             
-            let sray = Ray { origin: hit_point + light_direction * 0.1 , direction: light_direction };
+            let sray = Ray { origin: offset_origin(hit_point, geometric_normal, light_direction), direction: light_direction };
 
             let shadow_ray_result = ray_casting(scene, sray);
 
             if shadow_ray_result.0.is_none() {
-                let (diffuse, specular) = blinn_phong(shape, light, hit_point, Vector3::zero() - hit_point, hit_normal); 
+                let view = Vector3::zero() - hit_point;
+                let (diffuse, specular) = match shape.hair() {
+                    Some(hair) => kajiya_kay(surface_color, light, hair, hit_point, view, hit.tangent, hit_normal),
+                    None => blinn_phong(surface_color, light, hit_point, view, hit_normal),
+                };
                 color = color + diffuse + specular;
             }
         }
 
         let mut n1 = 1.0;
         let mut n2 = shape.refractive_index();
+        // normals point out of the shape; leaving it, face them against the ray
+        let mut facing_normal = hit_normal;
         if intersect_count == 1 {
             n1 = n2;
             n2 = 1.0;
+            facing_normal = -hit_normal;
         }
-        let (reflectance, reflection_ray, refraction_ray) = light_calculation(ray.direction, hit_normal, n1, n2);
+        let (reflectance, reflection_ray, refraction_ray) = light_calculation(ray.direction, facing_normal, n1, n2);
         // reflection
-        color = color + reflectance * trace(scene, Ray { origin: hit_point, direction: reflection_ray }, order + 1);
+        color = color + reflectance * trace(scene, Ray { origin: offset_origin(hit_point, geometric_normal, reflection_ray), direction: reflection_ray }, order + 1);
         // refraction
         if reflectance < 1.0 {
-            color = color + (1.0 - reflectance) * trace(scene, Ray { origin: hit_point, direction: refraction_ray }, order + 1);
+            color = color + (1.0 - reflectance) * trace(scene, Ray { origin: offset_origin(hit_point, geometric_normal, refraction_ray), direction: refraction_ray }, order + 1);
         }
     }
     else if order == 0 {
@@ -146,7 +153,7 @@ fn fresnel(incident: Vector3, normal: Vector3, n1: f32, n2: f32) -> f32 {
     let rs = ((n2 * cos_i) - (n1 * cos_t)) / ((n2 * cos_i) + (n1 * cos_t));
     let rp = ((n1 * cos_i) - (n2 * cos_t)) / ((n1 * cos_i) + (n2 * cos_t));
 
-    ((rs * rs + rp * rp) / 2.0) as f32
+    (rs * rs + rp * rp) / 2.0
 }
 
 fn light_calculation(incident: Vector3, normal: Vector3, n1: f32, n2: f32) -> (f32, Vector3, Vector3) {
@@ -167,7 +174,7 @@ fn light_calculation(incident: Vector3, normal: Vector3, n1: f32, n2: f32) -> (f
         let ortho = (n1 * cos_i_abs - n2 * cos_t) / (n1 * cos_i_abs + n2 * cos_t);
         let parallel = (n2 * cos_i_abs - n1 * cos_t) / (n2 * cos_i_abs + n1 * cos_t);
 
-        reflectance = clamp(((ortho * ortho + parallel * parallel) / 2.0) as f32, 0.0, 1.0);
+        reflectance = clamp((ortho * ortho + parallel * parallel) / 2.0, 0.0, 1.0);
         refraction_ray = n * incident + (n * cos_i - cos_t) * normal;
     }
     
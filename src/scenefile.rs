@@ -0,0 +1,801 @@
+use crate::scene::Scene;
+use crate::shape::{Shape, Sphere, Cube, Plane, Mesh};
+use crate::color::Color;
+use crate::csg::{Csg, CsgOperation};
+use crate::quadric::{Cylinder, Cone, Paraboloid, Hyperboloid};
+use crate::torus::Torus;
+use crate::sdf::Sdf;
+use crate::blob::Blob;
+use crate::heightfield::Heightfield;
+use crate::bezier::BezierPatch;
+use crate::curve::Curve;
+use crate::subdivision::SubdivisionSurface;
+use crate::voxel::VoxelGrid;
+use crate::instance::Instance;
+use crate::displacement::ScreenSpace;
+use crate::math::vector::Vector3;
+use crate::math::quaternion::Quat;
+use crate::validate::{self, Diagnostic, Severity};
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn unit_scale() -> Vector3 {
+    Vector3::from_one(1.0)
+}
+
+/// Every shape a scene file can name in its `type` field.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ShapeDescription {
+    Sphere(Sphere),
+    Cube(Cube),
+    Plane(Plane),
+    Mesh(Mesh),
+    Torus(Torus),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Paraboloid(Paraboloid),
+    Hyperboloid(Hyperboloid),
+    Sdf(Sdf),
+    Blob(Blob),
+    Heightfield(Heightfield),
+    BezierPatch(BezierPatch),
+    Curve(Curve),
+    SubdivisionSurface(SubdivisionSurface),
+    VoxelGrid(VoxelGrid),
+    Csg {
+        operation: CsgOperation,
+        left: Box<ShapeDescription>,
+        right: Box<ShapeDescription>,
+        color: Color,
+        refractive_index: f32,
+    },
+    /// The shape defined under `shape` in `define.shapes`, built once and
+    /// shared by every instance, then scaled, rotated and translated.
+    Instance {
+        shape: String,
+        #[serde(default = "Vector3::zero")]
+        translation: Vector3,
+        #[serde(default = "Quat::identity")]
+        rotation: Quat,
+        #[serde(default = "unit_scale")]
+        scale: Vector3,
+    },
+}
+
+impl ShapeDescription {
+    fn into_shape(self, definitions: &mut Definitions) -> Result<Box<dyn Shape>, String> {
+        Ok(match self {
+            ShapeDescription::Sphere(s) => Box::new(s),
+            ShapeDescription::Cube(s) => Box::new(s),
+            ShapeDescription::Plane(s) => Box::new(s),
+            ShapeDescription::Mesh(mut s) => match s.displacement.take() {
+                Some(displacement) => Box::new(displacement.apply(s, &definitions.screen)),
+                None => Box::new(s),
+            },
+            ShapeDescription::Torus(s) => Box::new(s),
+            ShapeDescription::Cylinder(s) => Box::new(s),
+            ShapeDescription::Cone(s) => Box::new(s),
+            ShapeDescription::Paraboloid(s) => Box::new(s),
+            ShapeDescription::Hyperboloid(s) => Box::new(s),
+            ShapeDescription::Sdf(s) => Box::new(s),
+            ShapeDescription::Blob(s) => Box::new(s),
+            ShapeDescription::Heightfield(s) => Box::new(s),
+            ShapeDescription::BezierPatch(s) => Box::new(s),
+            ShapeDescription::Curve(s) => Box::new(s),
+            ShapeDescription::SubdivisionSurface(s) => Box::new(s.to_mesh()),
+            ShapeDescription::VoxelGrid(s) => Box::new(s),
+            ShapeDescription::Csg { operation, left, right, color, refractive_index } => {
+                Box::new(Csg::new(operation, left.into_shape(definitions)?, right.into_shape(definitions)?, color, refractive_index))
+            }
+            ShapeDescription::Instance { shape, translation, rotation, scale } => {
+                let placed = definitions.get(&shape)?;
+                // its edges were measured on screen where the definition stands
+                if definitions.displaced.contains(&shape) {
+                    return Err(format!("shape definition {} is displaced for the camera, so it cannot be placed by an Instance; use it directly", shape));
+                }
+                Box::new(Instance::from_trs(placed, translation, rotation, scale))
+            }
+        })
+    }
+}
+
+// Shapes built from `define.shapes` for instances, each built once, and
+// the camera that displaced meshes are tessellated for.
+struct Definitions<'a> {
+    document: &'a Document,
+    screen: ScreenSpace,
+    built: HashMap<String, Arc<dyn Shape>>,
+    // names being built, to catch definitions that instance themselves
+    building: Vec<String>,
+    // definitions holding a displacement, tessellated for the camera
+    displaced: HashSet<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Definitions<'_> {
+    fn new(document: &Document, screen: ScreenSpace) -> Definitions<'_> {
+        Definitions { document, screen, built: HashMap::new(), building: Vec::new(), displaced: HashSet::new(), diagnostics: Vec::new() }
+    }
+
+    fn get(&mut self, name: &str) -> Result<Arc<dyn Shape>, String> {
+        if let Some(shape) = self.built.get(name) {
+            return Ok(shape.clone());
+        }
+        if self.building.iter().any(|n| n == name) {
+            return Err(format!("shape definition {} instances itself: {} -> {}", name, self.building.join(" -> "), name));
+        }
+        let document = self.document;
+        let definition = document.shapes.get(name).ok_or_else(|| format!("unknown shape definition {}", name))?;
+        let location = format!("{}: define.shapes.{}", document.shape_files.get(name).map_or("", String::as_str), name);
+        let value = expand_shape(definition, document, &mut vec![name.to_string()]).map_err(|e| format!("{}: {}", location, e))?;
+        if let Value::Object(fields) = &value {
+            validate::check_shape(fields, &location, &mut self.diagnostics);
+        }
+        if holds(&value, "displacement") {
+            self.displaced.insert(name.to_string());
+        }
+        let description: ShapeDescription = serde_json::from_value(value).map_err(|e| format!("{}: {}", location, e))?;
+        self.building.push(name.to_string());
+        let shape = description.into_shape(self);
+        self.building.pop();
+        let shape: Arc<dyn Shape> = Arc::from(shape?);
+        self.built.insert(name.to_string(), shape.clone());
+        Ok(shape)
+    }
+}
+
+// An entry of a `shapes` or `lights` list, and where it was written.
+struct Entry {
+    value: Value,
+    location: String,
+}
+
+// Settings, definitions and contents gathered from a file and everything
+// it includes, before any references are expanded.
+#[derive(Default)]
+struct Document {
+    settings: Map<String, Value>,
+    setting_files: HashMap<String, String>,
+    // the file each shape definition is read from
+    shape_files: HashMap<String, String>,
+    materials: Map<String, Value>,
+    shapes: Map<String, Value>,
+    lights: Map<String, Value>,
+    shape_list: Vec<Entry>,
+    light_list: Vec<Entry>,
+    // every file read, and every mesh or texture named
+    files: Vec<PathBuf>,
+}
+
+/// Reads a scene file, failing with every error found.
+#[cfg(test)]
+pub fn load_scene(path: &str) -> Result<Scene, String> {
+    match read_scene(path) {
+        (Some(scene), _) => Ok(scene),
+        (None, diagnostics) => Err(diagnostics.iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+/// Every file a scene file reads: itself, its includes, and the meshes and
+/// textures it names. Empty if the scene file cannot be read.
+pub fn sources(path: &str) -> Vec<PathBuf> {
+    load_document(Path::new(path), &mut Vec::new()).map(|document| document.files).unwrap_or_default()
+}
+
+/// Reads and validates a JSON scene file, reporting every problem found
+/// with its location. The scene is only built when there are no errors.
+///
+/// `width`, `height` and `fov` set up the camera, and `shapes` and `lights`
+/// list what is in the scene. `define` holds named `materials`, `shapes`
+/// and `lights`; an entry `{"use": "name", ...}` stands for the definition
+/// with the other fields laid over it, objects merging field by field; a
+/// shape entry that is nothing but `{"use": "name"}` shares one copy of
+/// the definition with every other such entry. A shape's `material` names
+/// a material, or gives one inline, whose fields fill in those the shape
+/// leaves out. A shape of type `Instance` places the shape definition it
+/// names instead; its instances share one copy. A mesh's `displacement` is
+/// tessellated for the scene's camera, so a definition holding one cannot
+/// be placed by an `Instance`.
+///
+/// `include` names further files, relative to the including one. Their
+/// definitions, shapes and lights come first, so the including file can
+/// redefine names and override settings. Relative `path` and `texture`
+/// fields are resolved against the file they are written in.
+pub fn read_scene(path: &str) -> (Option<Scene>, Vec<Diagnostic>) {
+    match load_document(Path::new(path), &mut Vec::new()) {
+        Ok(document) => build_scene(&document, path),
+        Err(diagnostic) => (None, vec![diagnostic]),
+    }
+}
+
+/// Reads a scene from text, as though from a file called `name`.
+pub fn read_scene_text(text: &str, name: &str) -> (Option<Scene>, Vec<Diagnostic>) {
+    match parse_document(text, Path::new(name), &mut Vec::new()) {
+        Ok(document) => build_scene(&document, name),
+        Err(diagnostic) => (None, vec![diagnostic]),
+    }
+}
+
+/// Writes a scene file out as one document, with its includes merged,
+/// references to definitions and materials expanded and file names made
+/// absolute, so it can be read elsewhere. Shape definitions are kept for
+/// instances and bare uses to name. Meshes, textures and other files are
+/// still named, not copied; they are listed alongside the text.
+pub fn flatten(path: &str) -> Result<(String, Vec<PathBuf>), String> {
+    let path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+    let document = load_document(&path, &mut Vec::new()).map_err(|d| d.to_string())?;
+    let at = |location: &str, message: String| format!("{}: {}", location, message);
+    let shapes = document.shape_list.iter()
+        .map(|entry| match bare_use(&entry.value) {
+            Some(_) => Ok(entry.value.clone()),
+            None => expand_shape(&entry.value, &document, &mut Vec::new()).map_err(|e| at(&entry.location, e)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let lights = document.light_list.iter()
+        .map(|entry| expand(&entry.value, &document.lights, "light", &mut Vec::new()).map_err(|e| at(&entry.location, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut root = document.settings.clone();
+    root.insert("define".to_string(), json!({ "materials": document.materials, "shapes": document.shapes }));
+    root.insert("shapes".to_string(), Value::Array(shapes));
+    root.insert("lights".to_string(), Value::Array(lights));
+    let mut root = Value::Object(root);
+    // every name is absolute by now, so this only gathers them
+    let mut files = Vec::new();
+    resolve_paths(&mut root, Path::new(""), &mut files);
+    files.sort();
+    files.dedup();
+    Ok((root.to_string(), files))
+}
+
+fn build_scene(document: &Document, path: &str) -> (Option<Scene>, Vec<Diagnostic>) {
+    let mut diagnostics = Vec::new();
+    let locate = |name: &str| format!("{}: {}", document.setting_files.get(name).map_or(path, String::as_str), name);
+
+    let mut setting = |name: &str, default: f64| match document.settings.get(name) {
+        None => default,
+        Some(v) => v.as_f64().unwrap_or_else(|| {
+            diagnostics.push(Diagnostic { severity: Severity::Error, location: locate(name), message: format!("{} must be a number", name) });
+            default
+        }),
+    };
+    let (width, height, fov) = (setting("width", 1280.0), setting("height", 720.0), setting("fov", 90.0));
+    validate::check_camera(width, height, fov, &locate, &mut diagnostics);
+
+    let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
+    let mut definitions = Definitions::new(document, ScreenSpace::new(fov as f32, height as u32));
+    for entry in &document.shape_list {
+        if let Some(name) = bare_use(&entry.value) {
+            match definitions.get(name) {
+                Ok(shape) => shapes.push(Box::new(shape)),
+                Err(message) => diagnostics.push(Diagnostic { severity: Severity::Error, location: entry.location.clone(), message }),
+            }
+            continue;
+        }
+        let value = match expand_shape(&entry.value, document, &mut Vec::new()) {
+            Ok(value) => value,
+            Err(message) => {
+                diagnostics.push(Diagnostic { severity: Severity::Error, location: entry.location.clone(), message });
+                continue;
+            }
+        };
+        if let Value::Object(fields) = &value {
+            validate::check_shape(fields, &entry.location, &mut diagnostics);
+        }
+        match serde_json::from_value::<ShapeDescription>(value).map_err(|e| e.to_string()).and_then(|d| d.into_shape(&mut definitions)) {
+            Ok(shape) => shapes.push(shape),
+            Err(message) => diagnostics.push(Diagnostic { severity: Severity::Error, location: entry.location.clone(), message }),
+        }
+    }
+    diagnostics.append(&mut definitions.diagnostics);
+    let mut lights = Vec::new();
+    for entry in &document.light_list {
+        let value = match expand(&entry.value, &document.lights, "light", &mut Vec::new()) {
+            Ok(value) => value,
+            Err(message) => {
+                diagnostics.push(Diagnostic { severity: Severity::Error, location: entry.location.clone(), message });
+                continue;
+            }
+        };
+        if let Value::Object(fields) = &value {
+            validate::check_light(fields, &entry.location, &mut diagnostics);
+        }
+        match serde_json::from_value(value) {
+            Ok(light) => lights.push(light),
+            Err(e) => diagnostics.push(Diagnostic { severity: Severity::Error, location: entry.location.clone(), message: e.to_string() }),
+        }
+    }
+
+    if validate::has_errors(&diagnostics) {
+        return (None, diagnostics);
+    }
+    (Some(Scene { width: width as u32, height: height as u32, fov: fov as f32, lights, shapes }), diagnostics)
+}
+
+fn load_document(path: &Path, chain: &mut Vec<(PathBuf, PathBuf)>) -> Result<Document, Diagnostic> {
+    let fail = |message: String| Diagnostic { severity: Severity::Error, location: path.display().to_string(), message };
+    let canonical = fs::canonicalize(path).map_err(|e| fail(e.to_string()))?;
+    if chain.iter().any(|(c, _)| *c == canonical) {
+        let names: Vec<String> = chain.iter().map(|(_, p)| p.display().to_string()).chain(Some(path.display().to_string())).collect();
+        let including = chain.last().map_or_else(String::new, |(_, p)| p.display().to_string());
+        return Err(Diagnostic { severity: Severity::Error, location: including, message: format!("include cycle: {}", names.join(" -> ")) });
+    }
+    chain.push((canonical, path.to_path_buf()));
+    let text = fs::read_to_string(path).map_err(|e| fail(e.to_string()))?;
+    let document = parse_document(&text, path, chain);
+    chain.pop();
+    document
+}
+
+// Reads a scene document from `text`; `chain` holds the files including it.
+fn parse_document(text: &str, path: &Path, chain: &mut Vec<(PathBuf, PathBuf)>) -> Result<Document, Diagnostic> {
+    let fail = |message: String| Diagnostic { severity: Severity::Error, location: path.display().to_string(), message };
+    let mut root: Value = serde_json::from_str(text).map_err(|e| fail(e.to_string()))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut document = Document { files: vec![path.to_path_buf()], ..Document::default() };
+    resolve_paths(&mut root, directory, &mut document.files);
+    let mut root = match root {
+        Value::Object(root) => root,
+        _ => return Err(fail("a scene file holds a JSON object".to_string())),
+    };
+
+    let includes = match root.remove("include") {
+        Some(Value::String(file)) => vec![file],
+        Some(Value::Array(files)) => files.into_iter().map(|f| match f {
+            Value::String(file) => Ok(file),
+            _ => Err(fail("include lists file names".to_string())),
+        }).collect::<Result<_, _>>()?,
+        Some(_) => return Err(fail("include lists file names".to_string())),
+        None => Vec::new(),
+    };
+    for file in includes {
+        let included = load_document(&directory.join(file), chain)?;
+        document.settings.extend(included.settings);
+        document.setting_files.extend(included.setting_files);
+        document.shape_files.extend(included.shape_files);
+        document.materials.extend(included.materials);
+        document.shapes.extend(included.shapes);
+        document.lights.extend(included.lights);
+        document.shape_list.extend(included.shape_list);
+        document.light_list.extend(included.light_list);
+        document.files.extend(included.files);
+    }
+
+    let list = |value: Option<Value>, name: &str| match value {
+        Some(Value::Array(items)) => Ok(items.into_iter().enumerate().map(|(i, value)| Entry {
+            value,
+            location: format!("{}: {}[{}]", path.display(), name, i),
+        }).collect()),
+        None => Ok(Vec::new()),
+        Some(_) => Err(fail(format!("{} must be a list", name))),
+    };
+    document.shape_list.extend(list(root.remove("shapes"), "shapes")?);
+    document.light_list.extend(list(root.remove("lights"), "lights")?);
+    match root.remove("define") {
+        Some(Value::Object(mut define)) => {
+            for (kind, map) in [("materials", &mut document.materials), ("shapes", &mut document.shapes), ("lights", &mut document.lights)] {
+                match define.remove(kind) {
+                    Some(Value::Object(definitions)) => {
+                        if kind == "shapes" {
+                            document.shape_files.extend(definitions.keys().map(|name| (name.clone(), path.display().to_string())));
+                        }
+                        map.extend(definitions);
+                    }
+                    None => {}
+                    Some(_) => return Err(fail(format!("define.{} must map names to definitions", kind))),
+                }
+            }
+            if let Some(other) = define.keys().next() {
+                return Err(fail(format!("unknown definition kind {}", other)));
+            }
+        }
+        None => {}
+        Some(_) => return Err(fail("define must be an object".to_string())),
+    }
+    for key in root.keys() {
+        document.setting_files.insert(key.clone(), path.display().to_string());
+    }
+    document.settings.extend(root);
+    Ok(document)
+}
+
+// Makes relative `path` and `texture` strings relative to `directory`
+// instead, wherever they appear, collecting them in `files`.
+fn resolve_paths(value: &mut Value, directory: &Path, files: &mut Vec<PathBuf>) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                match field {
+                    Value::String(file) if key == "path" || key == "texture" => {
+                        if Path::new(file.as_str()).is_relative() {
+                            *file = directory.join(&*file).to_string_lossy().into_owned();
+                        }
+                        files.push(PathBuf::from(&*file));
+                    }
+                    _ => resolve_paths(field, directory, files),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| resolve_paths(item, directory, files)),
+        _ => {}
+    }
+}
+
+// Whether `key` is a field of `value` or of anything inside it.
+fn holds(value: &Value, key: &str) -> bool {
+    match value {
+        Value::Object(map) => map.contains_key(key) || map.values().any(|field| holds(field, key)),
+        Value::Array(items) => items.iter().any(|item| holds(item, key)),
+        _ => false,
+    }
+}
+
+// Lays `overrides` over `base`, merging objects field by field.
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+// The shape definition named by an entry that is only `{"use": name}`.
+fn bare_use(value: &Value) -> Option<&str> {
+    match value {
+        Value::Object(fields) if fields.len() == 1 => fields.get("use").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+// Replaces `{"use": name, ...}` with the named definition, overridden by
+// the other fields. `stack` holds the names being expanded, to catch
+// definitions that use themselves.
+fn expand(value: &Value, definitions: &Map<String, Value>, kind: &str, stack: &mut Vec<String>) -> Result<Value, String> {
+    let mut fields = match value {
+        Value::Object(fields) => fields.clone(),
+        _ => return Ok(value.clone()),
+    };
+    let name = match fields.remove("use") {
+        Some(Value::String(name)) => name,
+        Some(_) => return Err("use takes a definition name".to_string()),
+        None => return Ok(value.clone()),
+    };
+    if stack.contains(&name) {
+        return Err(format!("{} definition {} uses itself: {} -> {}", kind, name, stack.join(" -> "), name));
+    }
+    let definition = definitions.get(&name).ok_or_else(|| format!("unknown {} definition {}", kind, name))?;
+    stack.push(name);
+    let mut result = expand(definition, definitions, kind, stack)?;
+    stack.pop();
+    merge(&mut result, Value::Object(fields));
+    Ok(result)
+}
+
+// Expands a shape, its material and any shapes nested in it.
+fn expand_shape(value: &Value, document: &Document, stack: &mut Vec<String>) -> Result<Value, String> {
+    let mut shape = expand(value, &document.shapes, "shape", stack)?;
+    let fields = match &mut shape {
+        Value::Object(fields) => fields,
+        _ => return Ok(shape),
+    };
+    if let Some(material) = fields.remove("material") {
+        let reference = match material {
+            Value::String(name) => json!({ "use": name }),
+            inline => inline,
+        };
+        let mut material = expand(&reference, &document.materials, "material", &mut Vec::new())?;
+        merge(&mut material, Value::Object(std::mem::take(fields)));
+        *fields = match material {
+            Value::Object(merged) => merged,
+            _ => return Err("a material must be an object".to_string()),
+        };
+    }
+    for operand in ["left", "right"] {
+        if let Some(nested) = fields.get(operand) {
+            let nested = expand_shape(nested, document, stack)?;
+            fields.insert(operand.to_string(), nested);
+        }
+    }
+    Ok(shape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Hit;
+    use crate::ray::Ray;
+    use crate::math::vector::Vector3;
+
+    // A fresh directory of scene files for one test.
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("ray-tracer-scenefile-{}-{}", test, std::process::id()));
+        for (name, text) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        directory
+    }
+
+    const LIBRARY: &str = r#"{
+        "fov": 60,
+        "define": {
+            "materials": {
+                "glass": {"color": {"r": 0.9, "g": 0.9, "b": 1.0, "a": 1.0}, "refractive_index": 1.5},
+                "red_glass": {"use": "glass", "color": {"g": 0.1, "b": 0.1}}
+            },
+            "shapes": {
+                "ball": {"type": "Sphere", "center": {"x": 0, "y": 0, "z": -5}, "radius": 1, "material": "glass"}
+            },
+            "lights": {
+                "sun": {"light_type": "Directional", "direction": {"x": 0, "y": -1, "z": 0},
+                        "diffuse_color": {"r": 1, "g": 1, "b": 1, "a": 1}, "specular_color": {"r": 1, "g": 1, "b": 1, "a": 1}}
+            }
+        },
+        "lights": [{"use": "sun"}]
+    }"#;
+
+    #[test]
+    fn definitions_and_overrides() {
+        let directory = files("definitions", &[
+            ("lib/common.json", LIBRARY),
+            ("scene.json", r#"{
+                "include": "lib/common.json",
+                "width": 320,
+                "height": 200,
+                "shapes": [
+                    {"use": "ball"},
+                    {"use": "ball", "center": {"x": 3}, "material": "red_glass"},
+                    {"use": "ball", "radius": 0.5, "refractive_index": 1.2},
+                    {"type": "Csg", "operation": "Difference", "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1,
+                     "left": {"use": "ball"}, "right": {"use": "ball", "center": {"z": -4}}}
+                ],
+                "lights": [{"use": "sun", "direction": {"x": 1}}]
+            }"#),
+        ]);
+        let scene = load_scene(directory.join("scene.json").to_str().unwrap()).unwrap();
+        assert_eq!((scene.width, scene.height, scene.fov), (320, 200, 60.0));
+        assert_eq!((scene.shapes.len(), scene.lights.len()), (4, 2));
+
+        assert_eq!(scene.shapes[0].refractive_index(), 1.5);
+        // only the overridden coordinate moves
+        let moved = scene.shapes[1].location();
+        assert_eq!((moved.x, moved.y, moved.z), (3.0, 0.0, -5.0));
+        let red = scene.shapes[1].color();
+        assert_eq!((red.r, red.g, red.b), (0.9, 0.1, 0.1));
+        assert_eq!(scene.shapes[2].refractive_index(), 1.2);
+
+        // the ball with its front hollowed out is first hit at its back half
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(scene.shapes[3].intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 5.0).abs() < 1e-4);
+        assert_eq!(scene.lights[1].direction.x, 1.0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn relative_paths() {
+        let directory = files("paths", &[
+            ("models/tri.stl", "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 -2\nvertex 1 0 -2\nvertex 0 1 -2\nendloop\nendfacet\nendsolid t\n"),
+            ("models/defs.json", r#"{"define": {"shapes": {"tri": {"type": "Mesh", "path": "tri.stl", "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1}}}}"#),
+            ("scene.json", r#"{"include": ["models/defs.json"], "shapes": [{"use": "tri"}]}"#),
+        ]);
+        let scene = load_scene(directory.join("scene.json").to_str().unwrap()).unwrap();
+        assert!((scene.shapes[0].location().z + 2.0).abs() < 1e-6);
+        assert!(load_scene(directory.join("missing.json").to_str().unwrap()).is_err());
+
+        let sources = sources(directory.join("scene.json").to_str().unwrap());
+        assert_eq!(sources, [directory.join("scene.json"), directory.join("models/defs.json"), directory.join("models/tri.stl")]);
+
+        // flattened, the scene reads from anywhere
+        let (flat, named) = flatten(directory.join("scene.json").to_str().unwrap()).unwrap();
+        assert_eq!(named, [directory.join("models/tri.stl")]);
+        let (scene, diagnostics) = read_scene_text(&flat, "elsewhere/scene.json");
+        assert!(diagnostics.is_empty());
+        assert!((scene.unwrap().shapes[0].location().z + 2.0).abs() < 1e-6);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn instances() {
+        let directory = files("instances", &[
+            ("tri.stl", "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid t\n"),
+            ("scene.json", r#"{
+                "define": {"shapes": {
+                    "tri": {"type": "Mesh", "path": "tri.stl", "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1},
+                    "big_tri": {"type": "Instance", "shape": "tri", "scale": {"x": 4, "y": 4, "z": 1}},
+                    "loop": {"type": "Instance", "shape": "loop"}
+                }},
+                "shapes": [
+                    {"type": "Instance", "shape": "tri", "translation": {"x": 0, "y": 0, "z": -2}},
+                    {"type": "Instance", "shape": "big_tri", "translation": {"x": 10, "y": 0, "z": -3}}
+                ]
+            }"#),
+        ]);
+        let path = directory.join("scene.json");
+        let scene = load_scene(path.to_str().unwrap()).unwrap();
+        let mut hit = Hit::new();
+        let ray = |x: f32, y: f32| Ray { origin: Vector3 { x, y, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(scene.shapes[0].intersect(&ray(0.25, 0.25), &mut hit), 2);
+        assert!((hit.point.z + 2.0).abs() < 1e-5);
+        assert_eq!(scene.shapes[0].intersect(&ray(10.5, 0.25), &mut hit), 0);
+        // scaled up, the second triangle reaches 4 along x
+        assert_eq!(scene.shapes[1].intersect(&ray(13.0, 0.5), &mut hit), 2);
+        assert!((hit.point.z + 3.0).abs() < 1e-5);
+
+        // every instance shares the one mesh read from tri.stl
+        let document = load_document(&path, &mut Vec::new()).unwrap();
+        let mut definitions = Definitions::new(&document, ScreenSpace::new(90.0, 720));
+        let tri = definitions.get("tri").unwrap();
+        assert!(Arc::ptr_eq(&tri, &definitions.get("tri").unwrap()));
+        assert!(definitions.get("loop").err().unwrap().contains("loop instances itself"));
+        assert!(definitions.get("missing").err().unwrap().contains("unknown shape definition missing"));
+
+        let (flat, _) = flatten(path.to_str().unwrap()).unwrap();
+        let (scene, diagnostics) = read_scene_text(&flat, "elsewhere/scene.json");
+        assert!(diagnostics.is_empty());
+        assert_eq!(scene.unwrap().shapes[1].intersect(&ray(13.0, 0.5), &mut hit), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn shared_uses() {
+        let directory = files("shared", &[
+            ("tri.stl", "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 -2\nvertex 1 0 -2\nvertex 0 1 -2\nendloop\nendfacet\nendsolid t\n"),
+            ("scene.json", r#"{
+                "define": {"shapes": {
+                    "tri": {"type": "Mesh", "path": "tri.stl", "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1},
+                    "tilted": {"type": "Instance", "shape": "tri", "rotation": {"x": 0, "y": 0, "z": 0, "w": 2}}
+                }},
+                "shapes": [{"use": "tri"}, {"use": "tri"}, {"use": "tilted"}, {"use": "tilted"}, {"use": "tri", "color": {"g": 0}}]
+            }"#),
+        ]);
+        let path = directory.join("scene.json");
+        let (scene, diagnostics) = read_scene(path.to_str().unwrap());
+        let scene = scene.unwrap();
+        assert_eq!(scene.shapes.len(), 5);
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.25, y: 0.25, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(scene.shapes[1].intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 2.0).abs() < 1e-5);
+        assert_eq!(scene.shapes[4].color().g, 0.0);
+        // built once for both uses, so its warning is given once
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].location.ends_with("define.shapes.tilted.rotation"));
+
+        // flattened, bare uses still name the definition
+        let flat: Value = serde_json::from_str(&flatten(path.to_str().unwrap()).unwrap().0).unwrap();
+        assert_eq!(flat["shapes"][0], json!({"use": "tri"}));
+        assert_eq!(flat["shapes"][4]["type"], "Mesh");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn displaced_mesh() {
+        let text = |max_edge_pixels: f32| format!(r#"{{
+            "width": 320, "height": 200, "fov": 60,
+            "shapes": [{{
+                "type": "Mesh",
+                "positions": [{{"x": -1, "y": -1, "z": -5}}, {{"x": 1, "y": -1, "z": -5}}, {{"x": 1, "y": 1, "z": -5}}, {{"x": -1, "y": 1, "z": -5}}],
+                "indices": [[0, 1, 2], [0, 2, 3]],
+                "color": {{"r": 1, "g": 1, "b": 1, "a": 1}},
+                "refractive_index": 1,
+                "displacement": {{
+                    "source": {{"type": "Noise", "frequency": 3, "seed": 1}},
+                    "scale": 0.2,
+                    "midlevel": 0.5,
+                    "max_edge_pixels": {}
+                }}
+            }}]
+        }}"#, max_edge_pixels);
+        let (scene, diagnostics) = read_scene_text(&text(4.0), "scene.json");
+        assert!(diagnostics.is_empty());
+        let scene = scene.unwrap();
+        let mut depths = Vec::new();
+        for i in 0..10 {
+            for j in 0..10 {
+                let (x, y) = (-0.9 + 0.2 * i as f32, -0.9 + 0.2 * j as f32);
+                let ray = Ray { origin: Vector3::zero(), direction: Vector3 { x, y, z: -5.0 }.normalize() };
+                let mut hit = Hit::new();
+                assert_eq!(scene.shapes[0].intersect(&ray, &mut hit), 2);
+                depths.push(hit.point.z + 5.0);
+            }
+        }
+        // moved along the normal by at most scale * (1 - midlevel)
+        assert!(depths.iter().all(|d| d.abs() <= 0.1 + 1e-4));
+        assert!(depths.iter().any(|d| d.abs() > 0.01));
+
+        let (scene, diagnostics) = read_scene_text(&text(0.0), "scene.json");
+        assert!(scene.is_none());
+        assert_eq!(diagnostics[0].location, "scene.json: shapes[0].displacement.max_edge_pixels");
+
+        // a displaced definition may be used as it stands, but not instanced
+        let mut document: Value = serde_json::from_str(&text(4.0)).unwrap();
+        let bumpy = document["shapes"][0].take();
+        document["define"] = json!({ "shapes": { "bumpy": bumpy, "pair": { "type": "Csg", "operation": "Union", "left": bumpy, "right": bumpy,
+                                                                            "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1 } } });
+        document["shapes"] = json!([{"use": "bumpy"}]);
+        assert!(read_scene_text(&document.to_string(), "scene.json").0.is_some());
+        for name in ["bumpy", "pair"] {
+            document["shapes"] = json!([{"type": "Instance", "shape": name, "scale": {"x": 10, "y": 10, "z": 10}}]);
+            let (scene, diagnostics) = read_scene_text(&document.to_string(), "scene.json");
+            assert!(scene.is_none());
+            assert!(diagnostics[0].message.contains(&format!("{} is displaced for the camera", name)), "{}", diagnostics[0]);
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let directory = files("diagnostics", &[
+            ("lib.json", r#"{
+                "define": {"shapes": {"dot": {"type": "Sphere", "center": {"x": 0, "y": 0, "z": -5}, "radius": 0,
+                                              "color": {"r": 1, "g": 1, "b": 1, "a": 1}, "refractive_index": 1}}},
+                "shapes": [{"use": "dot", "radius": 1, "refractive_index": -1.5}]
+            }"#),
+            ("scene.json", r#"{
+                "include": "lib.json",
+                "fov": 180,
+                "shapes": [{"use": "dot"}, {"use": "missing"}, {"type": "Sphere", "radius": 1}],
+                "lights": [{"light_type": "Directional", "direction": {"x": 0, "y": -2, "z": 0},
+                            "diffuse_color": {"r": 1, "g": 1, "b": 1, "a": 1}, "specular_color": {"r": 1, "g": 1, "b": 1, "a": 1}}]
+            }"#),
+        ]);
+        let (scene, diagnostics) = read_scene(directory.join("scene.json").to_str().unwrap());
+        assert!(scene.is_none());
+        let found: Vec<(Severity, String)> = diagnostics.iter()
+            .map(|d| (d.severity, d.location.rsplit(std::path::MAIN_SEPARATOR).next().unwrap().to_string()))
+            .collect();
+        assert_eq!(found, vec![
+            (Severity::Error, "scene.json: fov".to_string()),
+            (Severity::Error, "lib.json: shapes[0].refractive_index".to_string()),
+            (Severity::Error, "scene.json: shapes[1]".to_string()),
+            (Severity::Error, "scene.json: shapes[2]".to_string()),
+            // a bare use shares the definition, so it is checked where it is written
+            (Severity::Error, "lib.json: define.shapes.dot.radius".to_string()),
+            (Severity::Warning, "scene.json: lights[0].direction".to_string()),
+        ]);
+        assert!(diagnostics[2].message.contains("unknown shape definition missing"));
+        assert!(diagnostics[3].message.contains("missing field"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn cycles() {
+        let directory = files("cycles", &[
+            ("a.json", r#"{"include": "sub/b.json"}"#),
+            ("sub/b.json", r#"{"include": "c.json"}"#),
+            ("sub/c.json", r#"{"include": "../a.json"}"#),
+            ("self.json", r#"{"define": {"shapes": {"loop": {"use": "loop"}}}, "shapes": [{"use": "loop"}]}"#),
+        ]);
+        let (scene, diagnostics) = read_scene(directory.join("a.json").to_str().unwrap());
+        assert!(scene.is_none() && diagnostics.len() == 1);
+        assert!(diagnostics[0].location.ends_with("c.json"));
+        let error = &diagnostics[0].message;
+        assert!(error.starts_with("include cycle: "));
+        let chain: Vec<&str> = error["include cycle: ".len()..].split(" -> ").collect();
+        assert_eq!(chain.len(), 4);
+        assert!(chain[0].ends_with("a.json") && chain[1].ends_with("b.json") && chain[2].ends_with("c.json") && chain[3].ends_with("a.json"));
+
+        let error = load_scene(directory.join("self.json").to_str().unwrap()).err().unwrap();
+        assert!(error.contains("loop uses itself"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, RAY_EPSILON, basis};
+use crate::math::clamp;
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::quaternion::Quat;
+use crate::math::noise::fbm;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+use std::f32::consts::PI;
+
+fn abs(v: Vector3) -> Vector3 {
+    Vector3 { x: v.x.abs(), y: v.y.abs(), z: v.z.abs() }
+}
+
+fn max(v: Vector3, m: f32) -> Vector3 {
+    Vector3 { x: v.x.max(m), y: v.y.max(m), z: v.z.max(m) }
+}
+
+fn box_distance(p: Vector3, extent: Vector3) -> f32 {
+    let q = abs(p) - extent;
+    max(q, 0.0).length() + q.x.max(q.y.max(q.z)).min(0.0)
+}
+
+fn repeat(v: f32, period: f32) -> f32 {
+    if period > 0.0 { v - period * (v / period).round() } else { v }
+}
+
+/// Node of a signed distance tree. Primitives are centered on the origin of
+/// the space their parent hands them.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum SdfNode {
+    Sphere { radius: f32 },
+    Box { extent: Vector3 },
+    /// Box of half size `extent` with edges rounded by `radius`.
+    RoundBox { extent: Vector3, radius: f32 },
+    /// Torus around the y axis.
+    Torus { major_radius: f32, minor_radius: f32 },
+    Capsule { a: Vector3, b: Vector3, radius: f32 },
+    Translate { offset: Vector3, node: Box<SdfNode> },
+    Scale { factor: f32, node: Box<SdfNode> },
+    Union { left: Box<SdfNode>, right: Box<SdfNode> },
+    /// Union blending the surfaces over a distance of about `k`.
+    SmoothUnion { left: Box<SdfNode>, right: Box<SdfNode>, k: f32 },
+    /// `left` with `right` carved out of it.
+    Subtraction { left: Box<SdfNode>, right: Box<SdfNode> },
+    Intersection { left: Box<SdfNode>, right: Box<SdfNode> },
+    /// Infinite copies every `period` along each axis; 0 disables an axis.
+    Repeat { period: Vector3, node: Box<SdfNode> },
+    /// Rotates about the y axis by `rate` radians per unit of height.
+    Twist { rate: f32, node: Box<SdfNode> },
+    /// Pushes the surface out by fractal noise.
+    Displace { amplitude: f32, frequency: f32, node: Box<SdfNode> },
+}
+
+impl SdfNode {
+    pub fn distance(&self, p: Vector3) -> f32 {
+        match self {
+            SdfNode::Sphere { radius } => p.length() - radius,
+            SdfNode::Box { extent } => box_distance(p, *extent),
+            SdfNode::RoundBox { extent, radius } => box_distance(p, *extent - Vector3::from_one(*radius)) - radius,
+            SdfNode::Torus { major_radius, minor_radius } => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major_radius;
+                (ring * ring + p.y * p.y).sqrt() - minor_radius
+            }
+            SdfNode::Capsule { a, b, radius } => {
+                let pa = p - *a;
+                let ba = *b - *a;
+                let h = clamp(pa.dot(&ba) / ba.dot(&ba), 0.0, 1.0);
+                (pa - ba * h).length() - radius
+            }
+            SdfNode::Translate { offset, node } => node.distance(p - *offset),
+            SdfNode::Scale { factor, node } => node.distance(p / *factor) * factor,
+            SdfNode::Union { left, right } => left.distance(p).min(right.distance(p)),
+            SdfNode::SmoothUnion { left, right, k } => {
+                let d1 = left.distance(p);
+                let d2 = right.distance(p);
+                let h = clamp(0.5 + 0.5 * (d2 - d1) / k, 0.0, 1.0);
+                d2 + (d1 - d2) * h - k * h * (1.0 - h)
+            }
+            SdfNode::Subtraction { left, right } => left.distance(p).max(-right.distance(p)),
+            SdfNode::Intersection { left, right } => left.distance(p).max(right.distance(p)),
+            SdfNode::Repeat { period, node } => node.distance(Vector3 {
+                x: repeat(p.x, period.x),
+                y: repeat(p.y, period.y),
+                z: repeat(p.z, period.z),
+            }),
+            SdfNode::Twist { rate, node } => {
+                let (s, c) = (rate * p.y).sin_cos();
+                node.distance(Vector3 { x: c * p.x - s * p.z, y: p.y, z: s * p.x + c * p.z })
+            }
+            SdfNode::Displace { amplitude, frequency, node } => {
+                node.distance(p) + amplitude * fbm(p * *frequency, 3, 0)
+            }
+        }
+    }
+}
+
+fn default_max_distance() -> f32 {
+    100.0
+}
+
+fn default_max_steps() -> u32 {
+    256
+}
+
+fn default_step_scale() -> f32 {
+    1.0
+}
+
+/// Surface of a signed distance tree, found by sphere tracing. Twisted or
+/// displaced trees overestimate distances and need a `step_scale` below 1.
+#[derive(Deserialize)]
+pub struct Sdf {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub root: SdfNode,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default = "default_max_distance")]
+    pub max_distance: f32,
+    #[serde(default = "default_max_steps")]
+    pub max_steps: u32,
+    #[serde(default = "default_step_scale")]
+    pub step_scale: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+const SURFACE_DISTANCE: f32 = 1e-4;
+
+// How close counts as on the surface at distance `t` along the ray; it grows
+// with distance, as does the rounding error in the points traced.
+fn tolerance(t: f32) -> f32 {
+    SURFACE_DISTANCE * t.abs().max(1.0)
+}
+
+impl Sdf {
+    fn local_ray(&self, ray: &Ray) -> (Vector3, Vector3) {
+        let mut inverse = self.rotation;
+        inverse.conjugate();
+        (inverse.rotate(ray.origin - self.location), inverse.rotate(ray.direction))
+    }
+
+    // Sphere traces from `from` until the distance changes sign relative to
+    // `inside`, returning where it does. Each step is taken from `steps`,
+    // and the search gives up once they run out.
+    fn march(&self, o: Vector3, d: Vector3, from: f32, to: f32, inside: bool, steps: &mut u32) -> Option<f32> {
+        let sign = if inside { -1.0 } else { 1.0 };
+        let mut t = from;
+        while *steps > 0 {
+            *steps -= 1;
+            let distance = sign * self.root.distance(o + t * d);
+            if distance < tolerance(t) {
+                return Some(t);
+            }
+            t += distance * self.step_scale;
+            if t > to {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn surface(&self, ray: &Ray, p: Vector3, t: f32) -> Hit {
+        const H: f32 = 1e-3;
+        let dx = Vector3 { x: H, y: 0.0, z: 0.0 };
+        let dy = Vector3 { x: 0.0, y: H, z: 0.0 };
+        let dz = Vector3 { x: 0.0, y: 0.0, z: H };
+        let gradient = Vector3 {
+            x: self.root.distance(p + dx) - self.root.distance(p - dx),
+            y: self.root.distance(p + dy) - self.root.distance(p - dy),
+            z: self.root.distance(p + dz) - self.root.distance(p - dz),
+        };
+        let n = if gradient.size_squared() > 0.0 { gradient.normalize() } else { Vector3 { x: 0.0, y: 1.0, z: 0.0 } };
+        let (tangent, _) = basis(n);
+
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = self.rotation.rotate(n);
+        hit.geometric_normal = hit.normal;
+        hit.tangent = self.rotation.rotate(tangent);
+        hit.bitangent = hit.normal.cross(&hit.tangent);
+        // spherical projection of the normal, as there is no parameterization
+        hit.uv = Vector2 {
+            x: 0.5 + n.x.atan2(n.z) / (2.0 * PI),
+            y: 0.5 + clamp(n.y, -1.0, 1.0).asin() / PI,
+        };
+        hit
+    }
+}
+
+impl Shape for Sdf {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+// Steps `intervals` may take, as a multiple of `max_steps`, since it
+// searches the whole line and every crossing along it.
+const INTERVAL_STEPS: u32 = 4;
+
+impl Intersectable for Sdf {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let (o, d) = self.local_ray(ray);
+        let inside = self.root.distance(o + RAY_EPSILON * d) < 0.0;
+        let mut steps = self.max_steps;
+        match self.march(o, d, RAY_EPSILON, self.max_distance, inside, &mut steps) {
+            Some(t) => {
+                *hit = self.surface(ray, o + t * d, t);
+                if inside { 1 } else { 2 }
+            }
+            None => 0,
+        }
+    }
+
+    /// Only the stretch of the line within `max_distance` of the origin is
+    /// searched, in at most `INTERVAL_STEPS` times the steps `intersect`
+    /// takes; past that the last interval is left open.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let (o, d) = self.local_ray(ray);
+        let mut steps = self.max_steps.saturating_mul(INTERVAL_STEPS);
+        let mut t = -self.max_distance;
+        let mut inside = self.root.distance(o + t * d) < 0.0;
+        let mut open: Option<(f32, Hit)> = if inside { Some((f32::NEG_INFINITY, Hit::new())) } else { None };
+
+        while let Some(crossing) = self.march(o, d, t, self.max_distance, inside, &mut steps) {
+            let hit = self.surface(ray, o + crossing * d, crossing);
+            match open.take() {
+                Some((t_in, enter)) => intervals.push(Interval { t_in, t_out: crossing, enter, exit: hit }),
+                None => open = Some((crossing, hit)),
+            }
+            inside = !inside;
+            // step clear of the surface before tracing the other side; a
+            // grazing ray may take a while, so this draws on `steps` too
+            t = crossing + 2.0 * tolerance(crossing);
+            while t < self.max_distance && steps > 0 {
+                let distance = self.root.distance(o + t * d);
+                if (distance < 0.0) == inside {
+                    break;
+                }
+                steps -= 1;
+                t += (distance.abs() * self.step_scale).max(tolerance(t));
+            }
+        }
+        if let Some((t_in, enter)) = open {
+            intervals.push(Interval { t_in, t_out: f32::INFINITY, enter, exit: enter });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sdf(root: SdfNode) -> Sdf {
+        Sdf {
+            location: Vector3 { x: 0.0, y: 0.0, z: -5.0 },
+            rotation: Quat::identity(),
+            root,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            max_distance: default_max_distance(),
+            max_steps: default_max_steps(),
+            step_scale: 1.0,
+            detail_map: None,
+        }
+    }
+
+    fn forward(x: f32) -> Ray {
+        Ray { origin: Vector3 { x, y: 0.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } }
+    }
+
+    #[test]
+    fn sphere_matches_analytic() {
+        let shape = sdf(SdfNode::Sphere { radius: 1.0 });
+        let mut hit = Hit::new();
+        assert_eq!(shape.intersect(&forward(0.0), &mut hit), 2);
+        assert!((hit.point.z + 4.0).abs() < 1e-3);
+        assert!((hit.normal.z - 1.0).abs() < 1e-3);
+
+        let inside = Ray { origin: Vector3 { x: 0.0, y: 0.0, z: -5.0 }, direction: Vector3 { x: 0.0, y: 1.0, z: 0.0 } };
+        assert_eq!(shape.intersect(&inside, &mut hit), 1);
+        assert!((hit.point.y - 1.0).abs() < 1e-3);
+
+        assert_eq!(shape.intersect(&forward(1.5), &mut hit), 0);
+    }
+
+    #[test]
+    fn subtraction_intervals() {
+        // a box with a spherical bite taken out of its middle
+        let shape = sdf(SdfNode::Subtraction {
+            left: Box::new(SdfNode::Box { extent: Vector3::from_one(1.0) }),
+            right: Box::new(SdfNode::Sphere { radius: 0.5 }),
+        });
+        let mut intervals = Vec::new();
+        shape.intervals(&forward(0.0), &mut intervals);
+        assert_eq!(intervals.len(), 2);
+        assert!((intervals[0].t_in - 4.0).abs() < 1e-3);
+        assert!((intervals[0].t_out - 4.5).abs() < 1e-3);
+        assert!((intervals[1].t_in - 5.5).abs() < 1e-3);
+        assert!((intervals[1].t_out - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn grazing_intervals() {
+        // a ray running along a long face, just outside it, is not walked in
+        // tiny steps from one end of the face to the other
+        let shape = sdf(SdfNode::Box { extent: Vector3 { x: 1.0, y: 1.0, z: 60.0 } });
+        let mut intervals = Vec::new();
+        shape.intervals(&forward(1.0 + 5e-5), &mut intervals);
+        assert!(intervals.len() <= 1);
+
+        // far along the ray the surface tolerance loosens with the distance
+        let mut far = sdf(SdfNode::Sphere { radius: 1.0 });
+        far.location.z = -90.0;
+        let mut hit = Hit::new();
+        assert_eq!(far.intersect(&forward(0.0), &mut hit), 2);
+        assert!((hit.point.z + 89.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn operators() {
+        let sphere = |x: f32| Box::new(SdfNode::Translate { offset: Vector3 { x, y: 0.0, z: 0.0 }, node: Box::new(SdfNode::Sphere { radius: 1.0 }) });
+        let union = SdfNode::Union { left: sphere(-1.0), right: sphere(1.0) };
+        let smooth = SdfNode::SmoothUnion { left: sphere(-1.0), right: sphere(1.0), k: 0.5 };
+        // blending fills in the crease between the two spheres
+        let crease = Vector3 { x: 0.0, y: 0.0, z: 0.1 };
+        assert!(smooth.distance(crease) < union.distance(crease));
+
+        let repeated = SdfNode::Repeat { period: Vector3 { x: 4.0, y: 0.0, z: 0.0 }, node: Box::new(SdfNode::Sphere { radius: 1.0 }) };
+        assert!(repeated.distance(Vector3 { x: 8.0, y: 0.0, z: 0.0 }) < 0.0);
+
+        let capsule = SdfNode::Capsule { a: Vector3::zero(), b: Vector3 { x: 0.0, y: 2.0, z: 0.0 }, radius: 0.5 };
+        assert!((capsule.distance(Vector3 { x: 1.0, y: 1.0, z: 0.0 }) - 0.5).abs() < 1e-5);
+        let torus = SdfNode::Torus { major_radius: 2.0, minor_radius: 0.5 };
+        assert!((torus.distance(Vector3 { x: 0.0, y: 0.0, z: 3.0 }) - 0.5).abs() < 1e-5);
+        let round = SdfNode::RoundBox { extent: Vector3::from_one(1.0), radius: 0.25 };
+        assert!((round.distance(Vector3 { x: 2.0, y: 0.0, z: 0.0 }) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn twisted_and_displaced() {
+        let mut shape = sdf(SdfNode::Displace {
+            amplitude: 0.05,
+            frequency: 4.0,
+            node: Box::new(SdfNode::Twist { rate: 1.0, node: Box::new(SdfNode::Box { extent: Vector3::from_one(0.8) }) }),
+        });
+        shape.step_scale = 0.5;
+        let mut hit = Hit::new();
+        assert_eq!(shape.intersect(&forward(0.0), &mut hit), 2);
+        assert!(shape.root.distance(hit.point - shape.location).abs() < 1e-3);
+    }
+}
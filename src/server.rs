@@ -0,0 +1,697 @@
+use crate::scene::Scene;
+use crate::renderer::{self, CancelToken, Report};
+use crate::scenefile::read_scene_text;
+use crate::validate::Severity;
+
+use serde_json::{json, Value};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use image::{DynamicImage, ImageFormat};
+
+const LARGEST_BODY: usize = 16 << 20;
+// The request line and headers together, and how many headers.
+const LARGEST_HEAD: u64 = 16 << 10;
+const MOST_HEADERS: usize = 64;
+const LARGEST_IMAGE: u64 = 8192 * 8192;
+const TILE_SIZE: u32 = 32;
+
+pub struct Settings {
+    /// Jobs that may wait for the renderer; more are turned away.
+    pub queue_limit: usize,
+    /// Finished jobs kept for their results; the oldest are forgotten first.
+    pub keep_finished: usize,
+    /// Connections served at once; more wait to be accepted.
+    pub connections: usize,
+    /// Directory the files a scene names are read from.
+    pub root: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { queue_limit: 8, keep_finished: 64, connections: 32, root: PathBuf::from(".") }
+    }
+}
+
+enum State {
+    Queued,
+    Running,
+    Done(Arc<Vec<u8>>),
+    Failed(String),
+    Cancelled,
+}
+
+struct Job {
+    state: State,
+    report: Arc<Mutex<Option<Report>>>,
+    cancel: CancelToken,
+    warnings: Vec<String>,
+}
+
+impl Job {
+    fn finished(&self) -> bool {
+        !matches!(self.state, State::Queued | State::Running)
+    }
+}
+
+struct Jobs {
+    next: u64,
+    jobs: HashMap<u64, Job>,
+    queue: VecDeque<(u64, Scene)>,
+    // scenes being built for the queue, each holding a place in it
+    building: usize,
+    // finished jobs, oldest first
+    finished: VecDeque<u64>,
+}
+
+struct Service {
+    settings: Settings,
+    jobs: Mutex<Jobs>,
+    changed: Condvar,
+}
+
+impl Service {
+    fn new(settings: Settings) -> Service {
+        Service {
+            settings,
+            jobs: Mutex::new(Jobs { next: 1, jobs: HashMap::new(), queue: VecDeque::new(), building: 0, finished: VecDeque::new() }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn enqueue(&self, jobs: &mut Jobs, scene: Scene, warnings: Vec<String>) -> u64 {
+        let id = jobs.next;
+        jobs.next += 1;
+        jobs.jobs.insert(id, Job {
+            state: State::Queued,
+            report: Arc::new(Mutex::new(None)),
+            cancel: CancelToken::new(),
+            warnings,
+        });
+        jobs.queue.push_back((id, scene));
+        self.changed.notify_all();
+        id
+    }
+
+    fn finish(&self, jobs: &mut Jobs, id: u64, state: State) {
+        if let Some(job) = jobs.jobs.get_mut(&id) {
+            job.state = state;
+        }
+        jobs.finished.push_back(id);
+        while jobs.finished.len() > self.settings.keep_finished {
+            let old = jobs.finished.pop_front().unwrap();
+            jobs.jobs.remove(&old);
+        }
+        self.changed.notify_all();
+    }
+}
+
+fn png(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+// Text of a panic's payload, when it has any.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+// Takes jobs off the queue one at a time, for as long as the service runs.
+// A job whose render panics fails, and the renderer goes on to the next.
+fn renderer(service: Arc<Service>) {
+    loop {
+        let (id, scene, report, cancel) = {
+            let mut jobs = service.jobs.lock().unwrap();
+            while jobs.queue.is_empty() {
+                jobs = service.changed.wait(jobs).unwrap();
+            }
+            let (id, scene) = jobs.queue.pop_front().unwrap();
+            let job = jobs.jobs.get_mut(&id).unwrap();
+            job.state = State::Running;
+            (id, scene, job.report.clone(), job.cancel.clone())
+        };
+        service.changed.notify_all();
+        let rendered = panic::catch_unwind(AssertUnwindSafe(|| {
+            renderer::render(&scene, TILE_SIZE, &cancel, &mut |progress| *report.lock().unwrap() = Some(*progress))
+        }));
+        let state = match rendered {
+            Err(payload) => State::Failed(format!("rendering crashed: {}", panic_message(payload.as_ref()))),
+            Ok(rendered) if rendered.cancelled => State::Cancelled,
+            Ok(rendered) => png(&rendered.image).map_or_else(State::Failed, |bytes| State::Done(Arc::new(bytes))),
+        };
+        let mut jobs = service.jobs.lock().unwrap();
+        service.finish(&mut jobs, id, state);
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+fn json_response(status: u16, value: Value) -> Response {
+    Response { status, content_type: "application/json", body: value.to_string().into_bytes() }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    json_response(status, json!({ "error": message }))
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+    let bad = |message: &str| error(400, message);
+    let mut reader = BufReader::new(stream);
+    // the head is read through a limit, so no line can grow without end
+    let mut head = reader.by_ref().take(LARGEST_HEAD);
+    let mut read_line = |what: &str| {
+        let mut line = String::new();
+        head.read_line(&mut line).map_err(|_| bad(&format!("could not read the {}", what)))?;
+        match (line.ends_with('\n'), head.limit()) {
+            (false, 0) => Err(error(431, "the request headers are too large")),
+            _ => Ok(line),
+        }
+    };
+    let line = read_line("request")?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(bad("malformed request line")),
+    };
+    let mut length = 0;
+    let mut expect_continue = false;
+    for count in 0.. {
+        let header = read_line("headers")?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if count == MOST_HEADERS {
+            return Err(error(431, "the request has too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| bad("malformed Content-Length"))?;
+            }
+            if name.eq_ignore_ascii_case("expect") && value.trim().eq_ignore_ascii_case("100-continue") {
+                expect_continue = true;
+            }
+        }
+    }
+    if length > LARGEST_BODY {
+        return Err(error(413, "the scene is too large"));
+    }
+    if expect_continue {
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").map_err(|_| bad("could not continue the request"))?;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|_| bad("the body is shorter than its Content-Length"))?;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    Ok(Request { method, path: path.to_string(), query: query.to_string(), body })
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       response.status, reason, response.content_type, response.body.len());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)
+}
+
+fn status(id: u64, job: &Job, jobs: &Jobs) -> Value {
+    let report = *job.report.lock().unwrap();
+    let state = match &job.state {
+        State::Queued => "queued",
+        State::Running => "running",
+        State::Done(_) => "done",
+        State::Failed(_) => "failed",
+        State::Cancelled => "cancelled",
+    };
+    let progress = report.map_or(0.0, |r| r.fraction());
+    let mut value = json!({ "id": id, "state": state, "progress": progress, "warnings": job.warnings });
+    if let (State::Running, Some(eta)) = (&job.state, report.and_then(|r| r.eta)) {
+        value["eta_seconds"] = json!(eta.as_secs_f64());
+    }
+    if let State::Failed(message) = &job.state {
+        value["error"] = json!(message);
+    }
+    if let Some(position) = jobs.queue.iter().position(|(queued, _)| *queued == id) {
+        value["position"] = json!(position);
+    }
+    value
+}
+
+// Whether `file` stays inside the directory it is read from: relative,
+// and never climbing out with `..`.
+fn confined(file: &str) -> bool {
+    Path::new(file).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+// Reports the files a posted scene names outside the service's root,
+// wherever they appear in `value`.
+fn check_files(value: &Value, location: &str, errors: &mut Vec<String>) {
+    let at = |key: &str| if location.is_empty() { key.to_string() } else { format!("{}.{}", location, key) };
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map {
+                let files: Vec<&str> = match field {
+                    Value::String(file) if key == "path" || key == "texture" || key == "include" => vec![file],
+                    Value::Array(files) if key == "include" => files.iter().filter_map(Value::as_str).collect(),
+                    _ => {
+                        check_files(field, &at(key), errors);
+                        continue;
+                    }
+                };
+                for file in files.into_iter().filter(|file| !confined(file)) {
+                    errors.push(format!("request.json: {}: {} must be a relative path without ..", at(key), file));
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_files(item, &format!("{}[{}]", location, i), errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+// A place in the queue held while a scene is built, given back if the
+// build fails.
+struct Reservation<'a>(Option<&'a Service>);
+
+impl<'a> Reservation<'a> {
+    // Hands the place to a job: the jobs, locked, with room for one more.
+    fn fill(mut self) -> MutexGuard<'a, Jobs> {
+        let mut jobs = self.0.take().unwrap().jobs.lock().unwrap();
+        jobs.building -= 1;
+        jobs
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(service) = self.0 {
+            service.jobs.lock().unwrap().building -= 1;
+        }
+    }
+}
+
+fn submit(service: &Service, request: &Request) -> Response {
+    let text = match std::str::from_utf8(&request.body) {
+        Ok(text) => text,
+        Err(_) => return error(400, "the scene is not UTF-8"),
+    };
+    // text that is not JSON is reported when the scene is read
+    if let Ok(value) = serde_json::from_str::<Value>(text) {
+        let mut errors = Vec::new();
+        check_files(&value, "", &mut errors);
+        if !errors.is_empty() {
+            return json_response(400, json!({ "errors": errors }));
+        }
+    }
+    // turned away before the scene is built, which may take a while
+    let reservation = {
+        let mut jobs = service.jobs.lock().unwrap();
+        if jobs.queue.len() + jobs.building >= service.settings.queue_limit {
+            return error(503, "the job queue is full");
+        }
+        jobs.building += 1;
+        Reservation(Some(service))
+    };
+    let (scene, diagnostics) = read_scene_text(text, &service.settings.root.join("request.json").to_string_lossy());
+    let scene = match scene {
+        Some(scene) => scene,
+        None => {
+            let errors: Vec<String> = diagnostics.iter().filter(|d| d.severity == Severity::Error).map(|d| d.to_string()).collect();
+            return json_response(400, json!({ "errors": errors }));
+        }
+    };
+    if u64::from(scene.width) * u64::from(scene.height) > LARGEST_IMAGE {
+        return error(400, "the image is too large");
+    }
+    let wait = request.query.split('&').any(|p| p == "wait" || p == "wait=true" || p == "wait=1");
+
+    let mut jobs = reservation.fill();
+    let id = service.enqueue(&mut jobs, scene, diagnostics.iter().map(|d| d.to_string()).collect());
+    if !wait {
+        return json_response(202, json!({ "id": id, "status": format!("/jobs/{}", id) }));
+    }
+    loop {
+        match jobs.jobs.get(&id) {
+            None => return error(410, "the job finished and was forgotten"),
+            Some(job) if job.finished() => return image(id, job),
+            Some(_) => jobs = service.changed.wait(jobs).unwrap(),
+        }
+    }
+}
+
+fn image(id: u64, job: &Job) -> Response {
+    match &job.state {
+        State::Done(bytes) => Response { status: 200, content_type: "image/png", body: bytes.to_vec() },
+        State::Failed(message) => error(500, message),
+        State::Cancelled => error(409, &format!("job {} was cancelled", id)),
+        _ => error(409, &format!("job {} is not finished", id)),
+    }
+}
+
+fn cancel(service: &Service, id: u64) -> Response {
+    let mut jobs = service.jobs.lock().unwrap();
+    let state = match jobs.jobs.get(&id) {
+        None => return error(404, "no such job"),
+        Some(job) => match job.state {
+            State::Queued => "queued",
+            State::Running => "running",
+            _ => return error(409, &format!("job {} has already finished", id)),
+        },
+    };
+    if state == "queued" {
+        jobs.queue.retain(|(queued, _)| *queued != id);
+        service.finish(&mut jobs, id, State::Cancelled);
+        json_response(200, json!({ "id": id, "state": "cancelled" }))
+    } else {
+        // the renderer notices between tiles
+        jobs.jobs[&id].cancel.cancel();
+        json_response(202, json!({ "id": id, "state": "cancelling" }))
+    }
+}
+
+fn route(service: &Service, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let job = |id: &str| id.parse::<u64>().ok();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["render"]) => submit(service, request),
+        (_, ["render"]) => error(405, "scenes are sent with POST"),
+        (method, ["jobs", id, rest @ ..]) => {
+            let id = match job(id) {
+                Some(id) => id,
+                None => return error(404, "no such job"),
+            };
+            match (method, rest) {
+                ("GET", []) => {
+                    let jobs = service.jobs.lock().unwrap();
+                    jobs.jobs.get(&id).map_or_else(|| error(404, "no such job"), |job| json_response(200, status(id, job, &jobs)))
+                }
+                ("GET", ["image"]) => {
+                    let jobs = service.jobs.lock().unwrap();
+                    jobs.jobs.get(&id).map_or_else(|| error(404, "no such job"), |job| image(id, job))
+                }
+                ("DELETE", []) | ("POST", ["cancel"]) => cancel(service, id),
+                (_, []) | (_, ["image"]) | (_, ["cancel"]) => error(405, "method not allowed"),
+                _ => error(404, "not found"),
+            }
+        }
+        _ => error(404, "not found"),
+    }
+}
+
+// One of the connections being served, counted until dropped.
+struct Connection(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let (count, changed) = &*self.0;
+        *count.lock().unwrap() -= 1;
+        changed.notify_all();
+    }
+}
+
+/// Runs the render service on `listener`:
+///
+/// - `POST /render` takes a scene document and answers with a job id, or
+///   with the PNG image once rendered given `?wait=true`;
+/// - `GET /jobs/{id}` reports a job's state and progress;
+/// - `GET /jobs/{id}/image` gives a finished job's image;
+/// - `DELETE /jobs/{id}` (or `POST /jobs/{id}/cancel`) cancels a job.
+///
+/// Jobs render one at a time in the order they come. Files a scene names
+/// are read from `Settings::root`, and must be relative paths that stay
+/// inside it.
+pub fn serve(listener: TcpListener, settings: Settings) {
+    let service = Arc::new(Service::new(settings));
+    let renderer_service = service.clone();
+    thread::spawn(move || renderer(renderer_service));
+    let open = Arc::new((Mutex::new(0), Condvar::new()));
+    loop {
+        // past the limit, connections wait in the listener's backlog
+        let connection = {
+            let (count, changed) = &*open;
+            let mut count = count.lock().unwrap();
+            while *count >= service.settings.connections {
+                count = changed.wait(count).unwrap();
+            }
+            *count += 1;
+            Connection(open.clone())
+        };
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("accepting a connection: {}", e);
+                continue;
+            }
+        };
+        let service = service.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(30)));
+            let response = match read_request(&mut stream) {
+                Ok(request) => route(&service, &request),
+                Err(response) => response,
+            };
+            let _ = write_response(&mut stream, &response);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn scene(width: u32, height: u32) -> String {
+        format!(r#"{{
+            "width": {}, "height": {}, "fov": 70,
+            "shapes": [{{"type": "Sphere", "center": {{"x": 0, "y": 0, "z": -4}}, "radius": 1.5,
+                         "color": {{"r": 0.9, "g": 0.4, "b": 0.2, "a": 1}}, "refractive_index": 1.3}}],
+            "lights": [{{"light_type": "Directional", "direction": {{"x": -1, "y": -1, "z": -1}},
+                         "diffuse_color": {{"r": 1, "g": 1, "b": 1, "a": 1}}, "specular_color": {{"r": 1, "g": 1, "b": 1, "a": 1}}}}]
+        }}"#, width, height)
+    }
+
+    fn start(settings: Settings) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, settings));
+        address
+    }
+
+    fn call(address: &str, method: &str, path: &str, body: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn json(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    // Polls a job until its state is `state`.
+    fn await_state(address: &str, id: u64, state: &str) -> Value {
+        let start = Instant::now();
+        loop {
+            let (code, body) = call(address, "GET", &format!("/jobs/{}", id), "");
+            assert_eq!(code, 200);
+            let status = json(&body);
+            if status["state"] == state {
+                return status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(30), "job {} stuck at {}", id, status);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn render_and_poll() {
+        let address = start(Settings::default());
+        let text = scene(64, 36);
+        let expected = renderer::render(&read_scene_text(&text, "scene.json").0.unwrap(), 16, &CancelToken::new(), &mut |_| ()).image;
+
+        let (code, body) = call(&address, "POST", "/render?wait=true", &text);
+        assert_eq!(code, 200);
+        assert_eq!(image::load_from_memory(&body).unwrap().to_rgb8().as_raw(), expected.as_bytes());
+
+        let (code, body) = call(&address, "POST", "/render", &text);
+        assert_eq!(code, 202);
+        let id = json(&body)["id"].as_u64().unwrap();
+        assert_eq!(await_state(&address, id, "done")["progress"], 1.0);
+        let (code, body) = call(&address, "GET", &format!("/jobs/{}/image", id), "");
+        assert_eq!((code, &body[1..4]), (200, &b"PNG"[..]));
+
+        let (code, body) = call(&address, "POST", "/render", &text.replace("\"fov\": 70", "\"fov\": 200"));
+        assert_eq!(code, 400);
+        assert!(json(&body)["errors"][0].as_str().unwrap().contains("fov"));
+        assert_eq!(call(&address, "GET", "/jobs/99", "").0, 404);
+        assert_eq!(call(&address, "GET", "/render", "").0, 405);
+    }
+
+    // A shape that brings the renderer down.
+    struct Broken;
+
+    impl crate::shape::Intersectable for Broken {
+        fn intersect(&self, _: &crate::ray::Ray, _: &mut crate::shape::Hit) -> u8 {
+            panic!("broken shape")
+        }
+        fn intervals(&self, _: &crate::ray::Ray, _: &mut Vec<crate::shape::Interval>) {
+            panic!("broken shape")
+        }
+    }
+
+    impl crate::shape::Shape for Broken {
+        fn location(&self) -> crate::math::vector::Vector3 {
+            crate::math::vector::Vector3::zero()
+        }
+        fn color(&self) -> crate::color::Color {
+            crate::color::Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }
+        }
+        fn refractive_index(&self) -> f32 {
+            1.0
+        }
+    }
+
+    #[test]
+    fn render_panics() {
+        let service = Arc::new(Service::new(Settings::default()));
+        let (broken, fine) = {
+            let mut jobs = service.jobs.lock().unwrap();
+            let broken = Scene { width: 8, height: 4, fov: 70.0, lights: Vec::new(), shapes: vec![Box::new(Broken)] };
+            let fine = read_scene_text(&scene(8, 4), "scene.json").0.unwrap();
+            (service.enqueue(&mut jobs, broken, Vec::new()), service.enqueue(&mut jobs, fine, Vec::new()))
+        };
+        let renderer_service = service.clone();
+        thread::spawn(move || renderer(renderer_service));
+
+        let mut jobs = service.jobs.lock().unwrap();
+        while !jobs.jobs[&fine].finished() {
+            jobs = service.changed.wait(jobs).unwrap();
+        }
+        match &jobs.jobs[&broken].state {
+            State::Failed(message) => assert!(message.contains("broken shape"), "{}", message),
+            _ => panic!("the broken job did not fail"),
+        }
+        // the renderer lives on for the next job
+        assert!(matches!(jobs.jobs[&fine].state, State::Done(_)));
+    }
+
+    #[test]
+    fn queue_and_cancel() {
+        let address = start(Settings { queue_limit: 1, ..Settings::default() });
+        let (_, body) = call(&address, "POST", "/render", &scene(1600, 900));
+        let slow = json(&body)["id"].as_u64().unwrap();
+        await_state(&address, slow, "running");
+
+        let (code, body) = call(&address, "POST", "/render", &scene(64, 36));
+        assert_eq!(code, 202);
+        let queued = json(&body)["id"].as_u64().unwrap();
+        assert_eq!(json(&call(&address, "GET", &format!("/jobs/{}", queued), "").1)["position"], 0);
+        // the queue holds one job
+        assert_eq!(call(&address, "POST", "/render", &scene(64, 36)).0, 503);
+
+        let (code, body) = call(&address, "DELETE", &format!("/jobs/{}", queued), "");
+        assert_eq!((code, json(&body)["state"].clone()), (200, json!("cancelled")));
+        // a scene that fails to build gives its place back
+        assert_eq!(call(&address, "POST", "/render", &scene(64, 36).replace("\"fov\": 70", "\"fov\": 200")).0, 400);
+        let (code, body) = call(&address, "POST", "/render", &scene(64, 36));
+        assert_eq!(code, 202);
+        let (code, _) = call(&address, "DELETE", &format!("/jobs/{}", json(&body)["id"]), "");
+        assert_eq!(code, 200);
+        assert_eq!(call(&address, "POST", &format!("/jobs/{}/cancel", slow), "").0, 202);
+        await_state(&address, slow, "cancelled");
+        assert_eq!(call(&address, "GET", &format!("/jobs/{}/image", slow), "").0, 409);
+        assert_eq!(call(&address, "DELETE", &format!("/jobs/{}", slow), "").0, 409);
+    }
+
+    // Sends `bytes` as they are and gives the status of the answer.
+    fn raw(address: &str, bytes: &[u8]) -> u16 {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(bytes).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response[9..12]).parse().unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        let address = start(Settings { connections: 1, ..Settings::default() });
+        // a header line with no end, cut off at the limit
+        let mut endless = b"GET /jobs/1 HTTP/1.1\r\nX-Padding: ".to_vec();
+        endless.resize(LARGEST_HEAD as usize, b'a');
+        assert_eq!(raw(&address, &endless), 431);
+        let crowded = format!("GET /jobs/1 HTTP/1.1\r\n{}", "X-Header: 1\r\n".repeat(MOST_HEADERS + 1));
+        assert_eq!(raw(&address, crowded.as_bytes()), 431);
+        let headers = format!("GET /jobs/1 HTTP/1.1\r\n{}\r\n", "X-Header: 1\r\n".repeat(MOST_HEADERS));
+        assert_eq!(raw(&address, headers.as_bytes()), 404);
+
+        // one connection at a time: a second waits while the first is open
+        let held = TcpStream::connect(&address).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiting = address.clone();
+        thread::spawn(move || sender.send(call(&waiting, "GET", "/jobs/1", "").0).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(held);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(30)).unwrap(), 404);
+    }
+
+    #[test]
+    fn scene_files() {
+        let root = std::env::temp_dir().join(format!("ray-tracer-server-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("tri.stl"), "solid t\nfacet normal 0 0 1\nouter loop\nvertex -1 -1 -3\nvertex 1 -1 -3\nvertex 0 1 -3\nendloop\nendfacet\nendsolid t\n").unwrap();
+        let address = start(Settings { root: root.clone(), ..Settings::default() });
+        let mesh = |path: &str| scene(16, 9).replacen(r#""shapes": ["#, &format!(r#""shapes": [{{"type": "Mesh", "path": "{}", "color": {{"r": 1, "g": 1, "b": 1, "a": 1}}, "refractive_index": 1}},"#, path), 1);
+
+        // files are read from the root, whatever the service's directory
+        assert_eq!(call(&address, "POST", "/render?wait=true", &mesh("tri.stl")).0, 200);
+        for outside in ["/etc/passwd", "../tri.stl", "models/../../tri.stl"] {
+            let (code, body) = call(&address, "POST", "/render", &mesh(outside));
+            assert_eq!(code, 400);
+            assert_eq!(json(&body)["errors"][0], format!("request.json: shapes[0].path: {} must be a relative path without ..", outside));
+        }
+        let (code, body) = call(&address, "POST", "/render", &scene(16, 9).replacen("{", r#"{"include": ["lib.json", "/lib.json"],"#, 1));
+        assert_eq!(code, 400);
+        assert_eq!(json(&body)["errors"].as_array().unwrap().len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,18 +1,175 @@
 use crate::math::*;
-use crate::math::vector::Vector3;
+use crate::math::vector::{Vector2, Vector3};
 use crate::math::quaternion::Quat;
 use crate::ray::Ray;
 use crate::color::Color;
+use crate::texture::{DetailMap, Texture};
+use crate::light::Hair;
+use crate::meshfile::load_mesh;
+use crate::displacement::Displacement;
 use serde_derive::Deserialize;
 
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::sync::{Arc, OnceLock};
+
+/// Surface information at a ray hit. `tangent` and `bitangent` are unit
+/// vectors perpendicular to `normal`, following the directions of
+/// increasing `uv.x` and `uv.y`. `bitangent` is `normal × tangent` or its
+/// negation, whichever points along increasing `uv.y`, so where the uv
+/// layout is mirrored (as a mesh's may be) the frame is left-handed.
+/// `normal` is the shading normal, `geometric_normal` the true surface
+/// orientation used to offset secondary rays. `color` overrides
+/// `Shape::color` where a shape varies its color over the surface.
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    pub point: Vector3,
+    pub normal: Vector3,
+    pub geometric_normal: Vector3,
+    pub uv: Vector2,
+    pub tangent: Vector3,
+    pub bitangent: Vector3,
+    pub color: Option<Color>,
+}
+
+impl Hit {
+    pub fn new() -> Hit {
+        Hit {
+            point: Vector3::zero(),
+            normal: Vector3::zero(),
+            geometric_normal: Vector3::zero(),
+            uv: Vector2::zero(),
+            tangent: Vector3::zero(),
+            bitangent: Vector3::zero(),
+            color: None,
+        }
+    }
+}
+
+/// Distance below which a crossing counts as the ray's own origin.
+pub const RAY_EPSILON: f32 = 1e-4;
+
+/// A stretch of the ray's line inside a solid, from `t_in` to `t_out`.
+/// Distances may be negative or infinite; `enter` and `exit` carry outward
+/// facing normals.
+#[derive(Copy, Clone, Debug)]
+pub struct Interval {
+    pub t_in: f32,
+    pub t_out: f32,
+    pub enter: Hit,
+    pub exit: Hit,
+}
+
 pub trait Intersectable{
-    fn intersect(&self, ray: &Ray, normal: &mut Vector3, point: &mut Vector3) -> u8;
+    /// Nearest hit in front of the ray. Returns 0 on a miss, 2 when the ray
+    /// enters the shape at `hit` and 1 when it leaves it.
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8;
+
+    /// Every interval the ray's whole line spends inside the shape, in any
+    /// order.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>);
+}
+
+/// Nearest boundary in front of the ray among `intervals`, in the form
+/// `Intersectable::intersect` reports it.
+pub fn nearest_boundary(intervals: &[Interval], hit: &mut Hit) -> u8 {
+    let mut nearest = f32::INFINITY;
+    let mut count = 0;
+    for interval in intervals {
+        if interval.t_in > RAY_EPSILON {
+            if interval.t_in < nearest {
+                nearest = interval.t_in;
+                *hit = interval.enter;
+                count = 2;
+            }
+        } else if interval.t_out > RAY_EPSILON && interval.t_out < nearest {
+            nearest = interval.t_out;
+            *hit = interval.exit;
+            count = 1;
+        }
+    }
+    count
 }
 
-pub trait Shape : Intersectable {
+pub trait Shape : Intersectable + Send + Sync {
+    #[allow(dead_code)]
     fn location(&self) -> Vector3;
     fn color(&self) -> Color;
     fn refractive_index(&self) -> f32;
+    fn detail_map(&self) -> Option<&DetailMap> {
+        None
+    }
+    /// Fiber shading in place of Blinn-Phong, for shapes whose tangent runs
+    /// along a strand.
+    fn hair(&self) -> Option<&Hair> {
+        None
+    }
+}
+
+// A shape built once and shared between several places in a scene.
+impl<S: Shape + ?Sized> Intersectable for Arc<S> {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        (**self).intersect(ray, hit)
+    }
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        (**self).intervals(ray, intervals)
+    }
+}
+
+impl<S: Shape + ?Sized> Shape for Arc<S> {
+    fn location(&self) -> Vector3 {
+        (**self).location()
+    }
+    fn color(&self) -> Color {
+        (**self).color()
+    }
+    fn refractive_index(&self) -> f32 {
+        (**self).refractive_index()
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        (**self).detail_map()
+    }
+    fn hair(&self) -> Option<&Hair> {
+        (**self).hair()
+    }
+}
+
+/// Intervals of a surface made of oriented faces, from the line's crossings
+/// through them. A crossing against `geometric_normal` enters the solid;
+/// unmatched crossings leave the interval open to infinity.
+pub fn pair_crossings(ray: &Ray, mut crossings: Vec<(f32, Hit)>, intervals: &mut Vec<Interval>) {
+    crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut open: Option<(f32, Hit)> = None;
+    for (t, hit) in crossings {
+        let entering = ray.direction.dot(&hit.geometric_normal) < 0.0;
+        match (entering, open) {
+            (true, None) => open = Some((t, hit)),
+            (false, Some((t_in, enter))) => {
+                intervals.push(Interval { t_in, t_out: t, enter, exit: hit });
+                open = None;
+            }
+            (false, None) => intervals.push(Interval { t_in: f32::NEG_INFINITY, t_out: t, enter: hit, exit: hit }),
+            // a second entry without an exit, e.g. an edge hit twice
+            (true, Some(_)) => {}
+        }
+    }
+    if let Some((t_in, enter)) = open {
+        intervals.push(Interval { t_in, t_out: f32::INFINITY, enter, exit: enter });
+    }
+}
+
+// Any tangent frame around `normal`, used where a surface has no natural
+// parameterization of its own.
+pub fn basis(normal: Vector3) -> (Vector3, Vector3) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vector3 { x: 0.0, y: 1.0, z: 0.0 }
+    } else {
+        Vector3 { x: 1.0, y: 0.0, z: 0.0 }
+    };
+    let tangent = (helper - normal * normal.dot(&helper)).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
 }
 
 #[derive(Deserialize)]
@@ -20,7 +177,9 @@ pub struct Sphere {
     pub center: Vector3,
     pub radius: f32,
     pub color: Color,
-    pub refractive_index: f32
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
 }
 
 impl Shape for Sphere{
@@ -33,46 +192,67 @@ impl Shape for Sphere{
     fn refractive_index(&self) -> f32 {
         self.refractive_index
     }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Sphere {
+    fn surface(&self, ray: &Ray, t: f32) -> Hit {
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+
+        let n = (hit.point - self.center).normalize();
+        hit.normal = n;
+        hit.geometric_normal = n;
+
+        // longitude around +y starting at +z, latitude from the south pole
+        hit.uv = Vector2 {
+            x: 0.5 + n.x.atan2(n.z) / (2.0 * std::f32::consts::PI),
+            y: 0.5 + clamp(n.y, -1.0, 1.0).asin() / std::f32::consts::PI,
+        };
+        let tangent = Vector3 { x: n.z, y: 0.0, z: -n.x };
+        if tangent.size_squared() > 1e-12 {
+            hit.tangent = tangent.normalize();
+            hit.bitangent = n.cross(&hit.tangent);
+        } else {
+            let (t, b) = basis(n);
+            hit.tangent = t;
+            hit.bitangent = b;
+        }
+        hit
+    }
 }
 
 impl Intersectable for Sphere{
-    fn intersect(&self, ray: &Ray, normal: &mut Vector3, point: &mut Vector3) -> u8 {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::with_capacity(1);
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
         let l = self.center - ray.origin;
         let tc = l.dot(&ray.direction);
-        if tc < 0.0 { return 0; }
-
         let sd = l.dot(&l) - tc * tc;
-        if sd >= 0.0 {
-            let td = (self.radius * self.radius - sd).sqrt();
-            let t0 = tc - td;
-            let t1 = tc + td;
-            if t0 > 0.0 || t1 > 0.0 {
-                if !t0.is_nearly_zero() { 
-                    *point = ray.origin + t0 * ray.direction; 
-                }
-                else { 
-                    *point = ray.origin + t1 * ray.direction; 
-                }
-
-                *normal = (*point - self.center).normalize();
-
-                if t0 > 0.0 && t1 > 0.0 { 2 } else { 1 }
-            }
-            else {
-                0
-            }
-        }
-        else {
-            0
+        let r2 = self.radius * self.radius;
+        if sd > r2 {
+            return;
         }
+
+        let td = (r2 - sd).sqrt();
+        let t0 = tc - td;
+        let t1 = tc + td;
+        intervals.push(Interval { t_in: t0, t_out: t1, enter: self.surface(ray, t0), exit: self.surface(ray, t1) });
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::shape::*;
+    use std::sync::OnceLock;
     use crate::color::Color;
-    use crate::math::vector::Vector3;
+    use crate::math::vector::{Vector2, Vector3};
     use crate::ray::Ray;
     #[test]
     fn goo() { 
@@ -89,34 +269,215 @@ mod tests {
                 b: 0.4,
                 a: 1.0
             },
-            refractive_index: 1.0
+            refractive_index: 1.0,
+            detail_map: None,
         };
         
-        let mut hit_normal = Vector3::zero();
-        let mut hit_point = Vector3::zero();
+        let mut hit = Hit::new();
 
         let mut ray = Ray { origin: Vector3::zero(), direction: Vector3 { x:0.0, y:1.0, z:0.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point), 0);
+        assert_eq!(sphere.intersect(&ray, &mut hit), 0);
 
         ray = Ray { origin: Vector3::zero(), direction: Vector3 { x:0.0, y:0.0, z:-1.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point) > 0, true);
+        assert!(sphere.intersect(&ray, &mut hit) > 0);
 
         ray = Ray { origin: Vector3 { x:0.0, y:0.0, z:-5.0 }, direction: Vector3 { x:0.0, y:1.0, z:0.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point) > 0, true);
+        assert!(sphere.intersect(&ray, &mut hit) > 0);
 
         ray = Ray { origin: Vector3 { x:0.0, y:-1.0, z:-5.0 }, direction: Vector3 { x:0.0, y:1.0, z:0.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point) > 0, true);
+        assert!(sphere.intersect(&ray, &mut hit) > 0);
 
         ray = Ray { origin: Vector3 { x:0.0, y:1.0, z:-5.0 }, direction: Vector3 { x:0.0, y:1.0, z:0.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point), 0);
+        assert_eq!(sphere.intersect(&ray, &mut hit), 0);
 
         ray = Ray { origin: Vector3 { x:0.0, y:0.0, z:0.0 }, direction: Vector3 { x:0.0, y:0.0, z:-1.0 }};
-        assert_eq!(sphere.intersect(&ray, &mut hit_normal, &mut hit_point) > 0, true);
+        assert!(sphere.intersect(&ray, &mut hit) > 0);
 
-        println!("{:?}, {:?}", hit_normal, hit_point);
+        println!("{:?}, {:?}", hit.normal, hit.point);
+    }
+
+    fn assert_frame(hit: &Hit) {
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+        assert!(hit.normal.dot(&hit.tangent).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sphere_uv() {
+        let sphere = Sphere{
+            center: Vector3{ x: 0.0, y: 0.0, z: -5.0 },
+            radius: 1.0,
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        };
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 0.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }};
+        assert_eq!(sphere.intersect(&ray, &mut hit), 2);
+        assert!((hit.uv.x - 0.5).abs() < 1e-4 && (hit.uv.y - 0.5).abs() < 1e-4);
+        assert!((hit.tangent.x - 1.0).abs() < 1e-4);
+        assert_frame(&hit);
+    }
+
+    #[test]
+    fn cube_uv() {
+        let cube = Cube{
+            location: Vector3{ x: 0.0, y: 0.0, z: -5.0 },
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            extent: Vector3{ x: 2.0, y: 1.0, z: 1.0 },
+            rotation: Quat::new(0.0, 0.0, 0.0, 1.0),
+            refractive_index: 1.0,
+            detail_map: None,
+        };
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 1.0, y: 0.5, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }};
+        assert_eq!(cube.intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 4.0).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+        assert!((hit.uv.x - 0.75).abs() < 1e-4 && (hit.uv.y - 0.75).abs() < 1e-4);
+        assert_frame(&hit);
+
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 0.0, z: -5.0 }, direction: Vector3 { x: 0.0, y: 1.0, z: 0.0 }};
+        assert_eq!(cube.intersect(&ray, &mut hit), 1);
+        assert!((hit.normal.y - 1.0).abs() < 1e-4);
+        assert_frame(&hit);
+    }
+
+    #[test]
+    fn plane_uv() {
+        let plane = Plane{
+            location: Vector3::zero(),
+            normal: Vector3{ x: 0.0, y: 1.0, z: 0.0 },
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            uv_scale: 2.0,
+            refractive_index: 1.0,
+            detail_map: None,
+        };
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 3.0, y: 1.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 }};
+        assert_eq!(plane.intersect(&ray, &mut hit), 2);
+        assert!((hit.uv.x - 1.5).abs() < 1e-4);
+        assert_frame(&hit);
+    }
+
+    #[test]
+    fn mesh_uv() {
+        let mesh = Mesh{
+            positions: vec![Vector3{ x: 0.0, y: 0.0, z: -1.0 }, Vector3{ x: 2.0, y: 0.0, z: -1.0 }, Vector3{ x: 0.0, y: 2.0, z: -1.0 }],
+            normals: Vec::new(),
+            uvs: vec![Vector2{ x: 0.0, y: 0.0 }, Vector2{ x: 1.0, y: 0.0 }, Vector2{ x: 0.0, y: 1.0 }],
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2]],
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            texture: None,
+            refractive_index: 1.0,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        };
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.5, y: 1.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }};
+        assert_eq!(mesh.intersect(&ray, &mut hit), 2);
+        assert!((hit.uv.x - 0.25).abs() < 1e-4 && (hit.uv.y - 0.5).abs() < 1e-4);
+        assert!((hit.tangent.x - 1.0).abs() < 1e-4 && (hit.bitangent.y - 1.0).abs() < 1e-4);
+        assert_frame(&hit);
+        assert!(hit.color.is_none());
+    }
+
+    #[test]
+    fn mesh_vertex_colors() {
+        let red = Color{ r: 1.0, g: 0.0, b: 0.0, a: 1.0 };
+        let blue = Color{ r: 0.0, g: 0.0, b: 1.0, a: 1.0 };
+        let mesh = Mesh{
+            positions: vec![Vector3{ x: 0.0, y: 0.0, z: -1.0 }, Vector3{ x: 2.0, y: 0.0, z: -1.0 }, Vector3{ x: 0.0, y: 2.0, z: -1.0 }],
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: vec![red, blue, blue],
+            indices: vec![[0, 1, 2]],
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            texture: None,
+            refractive_index: 1.0,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        };
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.5, y: 0.5, z: 0.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }};
+        assert_eq!(mesh.intersect(&ray, &mut hit), 2);
+        let color = hit.color.unwrap();
+        assert!((color.r - 0.5).abs() < 1e-4 && (color.b - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn mesh_attribute_lengths() {
+        let mesh = |field: &str, values: serde_json::Value| {
+            let mut description = serde_json::json!({
+                "positions": [{"x": 0, "y": 0, "z": -1}, {"x": 2, "y": 0, "z": -1}, {"x": 0, "y": 2, "z": -1}],
+                "indices": [[0, 1, 2]],
+                "color": {"r": 1, "g": 1, "b": 1, "a": 1},
+                "refractive_index": 1,
+            });
+            description[field] = values;
+            serde_json::from_value::<Mesh>(description).map(|_| ()).map_err(|e| e.to_string())
+        };
+        let white = serde_json::json!({"r": 1, "g": 1, "b": 1, "a": 1});
+        assert!(mesh("colors", serde_json::json!([white, white, white])).is_ok());
+        assert!(mesh("colors", serde_json::json!([])).is_ok());
+        assert!(mesh("colors", serde_json::json!([white])).unwrap_err().contains("colors has 1 entries for 3 vertices"));
+        assert!(mesh("normals", serde_json::json!([{"x": 0, "y": 0, "z": 1}, {"x": 0, "y": 0, "z": 1}])).is_err());
+        assert!(mesh("uvs", serde_json::json!([{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": 0, "y": 1}, {"x": 1, "y": 1}])).is_err());
+    }
+
+    #[test]
+    fn mesh_tree() {
+        // a bumpy 20 x 20 grid, traced through the tree and triangle by triangle
+        let n = 20;
+        let mut positions = Vec::new();
+        for j in 0..=n {
+            for i in 0..=n {
+                let (x, y) = (i as f32 / n as f32 * 2.0 - 1.0, j as f32 / n as f32 * 2.0 - 1.0);
+                positions.push(Vector3 { x, y, z: -5.0 + 0.3 * (3.0 * x).sin() * (2.0 * y).cos() });
+            }
+        }
+        let mut indices = Vec::new();
+        for j in 0..n {
+            for i in 0..n {
+                let v = j * (n + 1) + i;
+                indices.push([v, v + 1, v + n + 2]);
+                indices.push([v, v + n + 2, v + n + 1]);
+            }
+        }
+        let mesh = Mesh{
+            positions,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices,
+            color: Color{ r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            texture: None,
+            refractive_index: 1.0,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        };
+        for k in 0..50 {
+            let direction = Vector3 { x: (k as f32 * 0.37).sin() * 0.25, y: (k as f32 * 0.61).cos() * 0.25, z: -1.0 }.normalize();
+            let ray = Ray { origin: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, direction };
+            let closest = (0..mesh.indices.len())
+                .filter_map(|i| { let (v0, v1, v2) = mesh.triangle(i); intersect_triangle(&ray, v0, v1, v2) })
+                .map(|(t, _, _)| t)
+                .filter(|&t| t > RAY_EPSILON)
+                .fold(f32::INFINITY, f32::min);
+            let mut hit = Hit::new();
+            let count = mesh.intersect(&ray, &mut hit);
+            assert_eq!(count > 0, closest.is_finite());
+            if count > 0 {
+                assert!(((hit.point - ray.origin).length() - closest).abs() < 1e-4);
+            }
+        }
     }
 }
 
+
 #[derive(Deserialize)]
 pub struct Cube {
     pub location: Vector3,
@@ -124,6 +485,8 @@ pub struct Cube {
     pub extent: Vector3,
     pub rotation: Quat,
     pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
 }
 
 impl Shape for Cube{
@@ -136,12 +499,560 @@ impl Shape for Cube{
     fn refractive_index(&self) -> f32 {
         self.refractive_index
     }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Cube {
+    // Face frame in cube space: (normal, tangent, bitangent) with
+    // bitangent = normal x tangent.
+    fn face(axis: usize, positive: bool) -> (Vector3, Vector3, Vector3) {
+        let s = if positive { 1.0 } else { -1.0 };
+        match axis {
+            0 => (Vector3 { x: s, y: 0.0, z: 0.0 }, Vector3 { x: 0.0, y: 0.0, z: -s }, Vector3 { x: 0.0, y: 1.0, z: 0.0 }),
+            1 => (Vector3 { x: 0.0, y: s, z: 0.0 }, Vector3 { x: 1.0, y: 0.0, z: 0.0 }, Vector3 { x: 0.0, y: 0.0, z: -s }),
+            _ => (Vector3 { x: 0.0, y: 0.0, z: s }, Vector3 { x: s, y: 0.0, z: 0.0 }, Vector3 { x: 0.0, y: 1.0, z: 0.0 }),
+        }
+    }
+
+    fn half_extent_along(&self, axis: Vector3) -> f32 {
+        axis.x.abs() * self.extent.x + axis.y.abs() * self.extent.y + axis.z.abs() * self.extent.z
+    }
 }
 
 impl Intersectable for Cube{
-    fn intersect(&self, ray: &Ray, normal: &mut Vector3, point: &mut Vector3) -> u8 {
-        let l: Vector3 = self.location - ray.origin;
-        
-        0
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::with_capacity(1);
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let mut inverse = self.rotation;
+        inverse.conjugate();
+        let origin = inverse.rotate(ray.origin - self.location);
+        let direction = inverse.rotate(ray.direction);
+
+        let o = [origin.x, origin.y, origin.z];
+        let d = [direction.x, direction.y, direction.z];
+        let e = [self.extent.x, self.extent.y, self.extent.z];
+
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        let mut near_face = (0, false);
+        let mut far_face = (0, false);
+        for i in 0..3 {
+            if d[i] == 0.0 {
+                if o[i] < -e[i] || o[i] > e[i] { return; }
+                continue;
+            }
+            let t0 = (-e[i] - o[i]) / d[i];
+            let t1 = (e[i] - o[i]) / d[i];
+            let (t_in, t_out, entering_positive) = if t0 < t1 { (t0, t1, false) } else { (t1, t0, true) };
+            if t_in > t_near {
+                t_near = t_in;
+                near_face = (i, entering_positive);
+            }
+            if t_out < t_far {
+                t_far = t_out;
+                far_face = (i, !entering_positive);
+            }
+        }
+
+        if t_far < t_near {
+            return;
+        }
+
+        let surface = |t: f32, (axis, positive): (usize, bool)| {
+            let local = origin + t * direction;
+            let (n, tangent, bitangent) = Cube::face(axis, positive);
+            let mut hit = Hit::new();
+            hit.point = ray.origin + t * ray.direction;
+            hit.normal = self.rotation.rotate(n);
+            hit.geometric_normal = hit.normal;
+            hit.tangent = self.rotation.rotate(tangent);
+            hit.bitangent = self.rotation.rotate(bitangent);
+            hit.uv = Vector2 {
+                x: 0.5 + 0.5 * local.dot(&tangent) / self.half_extent_along(tangent),
+                y: 0.5 + 0.5 * local.dot(&bitangent) / self.half_extent_along(bitangent),
+            };
+            hit
+        };
+        intervals.push(Interval { t_in: t_near, t_out: t_far, enter: surface(t_near, near_face), exit: surface(t_far, far_face) });
     }
-}
\ No newline at end of file
+}
+
+fn default_uv_scale() -> f32 {
+    1.0
+}
+
+/// Infinite plane through `location`. Texture coordinates are a planar
+/// projection repeating every `uv_scale` units.
+#[derive(Deserialize)]
+pub struct Plane {
+    pub location: Vector3,
+    #[serde(deserialize_with = "Vector3::deserialize_normalized")]
+    pub normal: Vector3,
+    pub color: Color,
+    #[serde(default = "default_uv_scale")]
+    pub uv_scale: f32,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Shape for Plane{
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Plane {
+    fn surface(&self, ray: &Ray, t: f32) -> Hit {
+        let (tangent, bitangent) = basis(self.normal);
+        let offset = ray.origin + t * ray.direction - self.location;
+
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = self.normal;
+        hit.geometric_normal = self.normal;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit.uv = Vector2 {
+            x: offset.dot(&tangent) / self.uv_scale,
+            y: offset.dot(&bitangent) / self.uv_scale,
+        };
+        hit
+    }
+}
+
+/// The plane bounds the half-space behind its normal.
+impl Intersectable for Plane{
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::with_capacity(1);
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let denom = self.normal.dot(&ray.direction);
+        let distance = (self.location - ray.origin).dot(&self.normal);
+        if denom.abs() < 1e-6 {
+            // parallel: the line is either entirely inside or outside
+            if distance > 0.0 {
+                let hit = Hit::new();
+                intervals.push(Interval { t_in: f32::NEG_INFINITY, t_out: f32::INFINITY, enter: hit, exit: hit });
+            }
+            return;
+        }
+
+        let t = distance / denom;
+        let hit = self.surface(ray, t);
+        if denom < 0.0 {
+            intervals.push(Interval { t_in: t, t_out: f32::INFINITY, enter: hit, exit: hit });
+        } else {
+            intervals.push(Interval { t_in: f32::NEG_INFINITY, t_out: t, enter: hit, exit: hit });
+        }
+    }
+}
+
+/// Indexed triangle mesh. `normals`, `uvs` and `colors` are optional
+/// per-vertex attributes; leave them empty to fall back to face normals,
+/// barycentric coordinates and `color`. A `texture` multiplies the color
+/// at each hit. A `displacement` is waiting to be applied once the scene's
+/// camera is known. `tree` is built the first time a ray is traced, so the
+/// vertices must not move after that.
+#[derive(Deserialize)]
+#[serde(try_from = "MeshDescription")]
+pub struct Mesh {
+    pub positions: Vec<Vector3>,
+    pub normals: Vec<Vector3>,
+    pub uvs: Vec<Vector2>,
+    pub colors: Vec<Color>,
+    pub indices: Vec<[u32; 3]>,
+    pub color: Color,
+    pub texture: Option<Texture>,
+    pub refractive_index: f32,
+    pub detail_map: Option<DetailMap>,
+    pub displacement: Option<Displacement>,
+    pub tree: OnceLock<TriangleTree>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A mesh as read from a scene: given inline, or loaded from the PLY or
+/// STL file at `path`. Inline `normals`, `uvs` and `colors` are empty or
+/// hold one entry per position. STL vertices closer than `weld_tolerance`
+/// are merged. `smooth` replaces the normals with averaged ones,
+/// `vertex_colors` can turn off a PLY file's colors in favour of `color`,
+/// and a `displacement` moves the surface along its normals.
+#[derive(Deserialize)]
+struct MeshDescription {
+    #[serde(default)]
+    positions: Vec<Vector3>,
+    #[serde(default)]
+    normals: Vec<Vector3>,
+    #[serde(default)]
+    uvs: Vec<Vector2>,
+    #[serde(default)]
+    colors: Vec<Color>,
+    #[serde(default)]
+    indices: Vec<[u32; 3]>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    weld_tolerance: f32,
+    #[serde(default)]
+    smooth: bool,
+    #[serde(default = "default_true")]
+    vertex_colors: bool,
+    color: Color,
+    #[serde(default)]
+    texture: Option<Texture>,
+    refractive_index: f32,
+    #[serde(default)]
+    detail_map: Option<DetailMap>,
+    #[serde(default)]
+    displacement: Option<Displacement>,
+}
+
+impl TryFrom<MeshDescription> for Mesh {
+    type Error = String;
+
+    fn try_from(description: MeshDescription) -> Result<Mesh, String> {
+        let mut mesh = Mesh {
+            positions: description.positions,
+            normals: description.normals,
+            uvs: description.uvs,
+            colors: description.colors,
+            indices: description.indices,
+            color: description.color,
+            texture: description.texture,
+            refractive_index: description.refractive_index,
+            detail_map: description.detail_map,
+            displacement: description.displacement,
+            tree: OnceLock::new(),
+        };
+        if let Some(path) = description.path {
+            if !mesh.positions.is_empty() {
+                return Err(format!("{}: a mesh loaded from a file cannot also list positions", path));
+            }
+            let data = load_mesh(&path, description.weld_tolerance)?;
+            mesh.positions = data.positions;
+            mesh.normals = data.normals;
+            mesh.uvs = data.uvs;
+            mesh.colors = data.colors;
+            mesh.indices = data.indices;
+        }
+        if let Some(&[a, b, c]) = mesh.indices.iter().find(|t| t.iter().any(|&i| i as usize >= mesh.positions.len())) {
+            return Err(format!("triangle [{}, {}, {}] refers to a missing vertex", a, b, c));
+        }
+        let vertices = mesh.positions.len();
+        for (name, length) in [("normals", mesh.normals.len()), ("uvs", mesh.uvs.len()), ("colors", mesh.colors.len())] {
+            if length != 0 && length != vertices {
+                return Err(format!("{} has {} entries for {} vertices; give one per vertex or none", name, length, vertices));
+            }
+        }
+        if !description.vertex_colors {
+            mesh.colors.clear();
+        }
+        if description.smooth {
+            mesh.smooth_normals();
+        }
+        Ok(mesh)
+    }
+}
+
+impl Shape for Mesh{
+    fn location(&self) -> Vector3 {
+        let sum = self.positions.iter().fold(Vector3::zero(), |acc, p| acc + *p);
+        sum / self.positions.len().max(1) as f32
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+// Moller-Trumbore; returns the signed distance along the ray's line and the
+// barycentrics of v1 and v2.
+pub fn intersect_triangle(ray: &Ray, v0: Vector3, v1: Vector3, v2: Vector3) -> Option<(f32, f32, f32)> {
+    let e1 = v1 - v0;
+    let e2 = v2 - v0;
+    let p = ray.direction.cross(&e2);
+    let det = e1.dot(&p);
+    if det.abs() < 1e-8 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - v0;
+    let b1 = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let q = s.cross(&e1);
+    let b2 = ray.direction.dot(&q) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    Some((e2.dot(&q) * inv_det, b1, b2))
+}
+
+impl Mesh {
+    fn surface(&self, ray: &Ray, triangle: usize, t: f32, b1: f32, b2: f32) -> Hit {
+        let mut hit = Hit::new();
+        let tri = self.indices[triangle];
+        let (i0, i1, i2) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+        let b0 = 1.0 - b1 - b2;
+        let e1 = self.positions[i1] - self.positions[i0];
+        let e2 = self.positions[i2] - self.positions[i0];
+        let geometric_normal = e1.cross(&e2).normalize();
+
+        let normal = if self.normals.len() == self.positions.len() {
+            (b0 * self.normals[i0] + b1 * self.normals[i1] + b2 * self.normals[i2]).normalize()
+        } else {
+            geometric_normal
+        };
+
+        let (tangent, bitangent) = if self.uvs.len() == self.positions.len() {
+            let uv0 = self.uvs[i0];
+            let duv1 = self.uvs[i1] - uv0;
+            let duv2 = self.uvs[i2] - uv0;
+            hit.uv = b0 * uv0 + b1 * self.uvs[i1] + b2 * self.uvs[i2];
+
+            let r = duv1.x * duv2.y - duv1.y * duv2.x;
+            if r.abs() > 1e-12 {
+                let dpdu = (e1 * duv2.y - e2 * duv1.y) / r;
+                let dpdv = (e2 * duv1.x - e1 * duv2.x) / r;
+                let tangent = dpdu - normal * normal.dot(&dpdu);
+                if tangent.size_squared() > 1e-12 {
+                    let tangent = tangent.normalize();
+                    let bitangent = normal.cross(&tangent);
+                    if bitangent.dot(&dpdv) < 0.0 { (tangent, -bitangent) } else { (tangent, bitangent) }
+                } else {
+                    basis(normal)
+                }
+            } else {
+                basis(normal)
+            }
+        } else {
+            hit.uv = Vector2 { x: b1, y: b2 };
+            basis(normal)
+        };
+
+        if self.colors.len() == self.positions.len() {
+            hit.color = Some(b0 * self.colors[i0] + b1 * self.colors[i1] + b2 * self.colors[i2]);
+        }
+        if let Some(texture) = &self.texture {
+            hit.color = Some(hit.color.unwrap_or(self.color) * texture.sample_color(hit.uv));
+        }
+
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = normal;
+        hit.geometric_normal = geometric_normal;
+        hit.tangent = tangent;
+        hit.bitangent = bitangent;
+        hit
+    }
+
+    /// Replaces `normals` with area-weighted averages of the face normals
+    /// around each vertex.
+    pub fn smooth_normals(&mut self) {
+        let mut normals = vec![Vector3::zero(); self.positions.len()];
+        for i in 0..self.indices.len() {
+            let (p0, p1, p2) = self.triangle(i);
+            // twice the area, pointing out of the front face
+            let n = (p1 - p0).cross(&(p2 - p0));
+            for &v in &self.indices[i] {
+                normals[v as usize] = normals[v as usize] + n;
+            }
+        }
+        self.normals = normals.into_iter()
+            .map(|n| if n.size_squared() > 0.0 { n.normalize() } else { n })
+            .collect();
+    }
+
+    fn tree(&self) -> &TriangleTree {
+        self.tree.get_or_init(|| TriangleTree::build(self))
+    }
+
+    fn triangle(&self, i: usize) -> (Vector3, Vector3, Vector3) {
+        let tri = self.indices[i];
+        (self.positions[tri[0] as usize], self.positions[tri[1] as usize], self.positions[tri[2] as usize])
+    }
+}
+
+impl Intersectable for Mesh{
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut closest: Option<(usize, f32, f32, f32)> = None;
+        self.tree().visit(ray, RAY_EPSILON, &mut |i| {
+            let (v0, v1, v2) = self.triangle(i);
+            if let Some((t, b1, b2)) = intersect_triangle(ray, v0, v1, v2) {
+                if t > RAY_EPSILON && closest.is_none_or(|c| t < c.1) {
+                    closest = Some((i, t, b1, b2));
+                }
+            }
+            closest.map_or(f32::INFINITY, |c| c.1)
+        });
+
+        let (i, t, b1, b2) = match closest {
+            Some(c) => c,
+            None => return 0,
+        };
+        *hit = self.surface(ray, i, t, b1, b2);
+
+        if ray.direction.dot(&hit.geometric_normal) < 0.0 { 2 } else { 1 }
+    }
+
+    /// Treats the mesh as a closed solid with counter-clockwise front faces.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let mut crossings = Vec::new();
+        self.tree().visit(ray, f32::NEG_INFINITY, &mut |i| {
+            let (v0, v1, v2) = self.triangle(i);
+            if let Some((t, b1, b2)) = intersect_triangle(ray, v0, v1, v2) {
+                crossings.push((t, self.surface(ray, i, t, b1, b2)));
+            }
+            f32::INFINITY
+        });
+        pair_crossings(ray, crossings, intervals);
+    }
+}
+
+/// Whole-line slab test, returning the span of the line inside the box.
+pub fn line_box(ray: &Ray, min: Vector3, max: Vector3) -> Option<(f32, f32)> {
+    let o = [ray.origin.x, ray.origin.y, ray.origin.z];
+    let d = [ray.direction.x, ray.direction.y, ray.direction.z];
+    let (lo, hi) = ([min.x, min.y, min.z], [max.x, max.y, max.z]);
+    let mut t_near = f32::NEG_INFINITY;
+    let mut t_far = f32::INFINITY;
+    for i in 0..3 {
+        if d[i] == 0.0 {
+            if o[i] < lo[i] || o[i] > hi[i] { return None; }
+            continue;
+        }
+        let t0 = (lo[i] - o[i]) / d[i];
+        let t1 = (hi[i] - o[i]) / d[i];
+        t_near = t_near.max(t0.min(t1));
+        t_far = t_far.min(t0.max(t1));
+    }
+    if t_near <= t_far { Some((t_near, t_far)) } else { None }
+}
+
+// Most triangles a leaf of a mesh's tree holds.
+const LEAF_TRIANGLES: usize = 4;
+
+// Node of a mesh's bounding hierarchy over the triangles `order[start..end]`,
+// with no children or two.
+struct TreeNode {
+    min: Vector3,
+    max: Vector3,
+    start: usize,
+    end: usize,
+    children: Vec<TreeNode>,
+}
+
+/// Bounding hierarchy over a mesh's triangles, split at the median along
+/// the longest axis. The boxes are taken from the vertices as they are
+/// when it is built, displaced or not, so they always hold the surface.
+pub struct TriangleTree {
+    order: Vec<u32>,
+    root: TreeNode,
+}
+
+impl TriangleTree {
+    fn build(mesh: &Mesh) -> TriangleTree {
+        let centroids: Vec<Vector3> = (0..mesh.indices.len()).map(|i| {
+            let (v0, v1, v2) = mesh.triangle(i);
+            (v0 + v1 + v2) / 3.0
+        }).collect();
+        let mut order: Vec<u32> = (0..mesh.indices.len() as u32).collect();
+        let root = TreeNode::build(mesh, &centroids, &mut order, 0);
+        TriangleTree { order, root }
+    }
+
+    // Calls `visit` with every triangle in a box the ray's line crosses
+    // between `near` and the distance `visit` last returned.
+    fn visit(&self, ray: &Ray, near: f32, visit: &mut dyn FnMut(usize) -> f32) {
+        let mut far = f32::INFINITY;
+        self.walk(&self.root, ray, near, &mut far, visit);
+    }
+
+    fn walk(&self, node: &TreeNode, ray: &Ray, near: f32, far: &mut f32, visit: &mut dyn FnMut(usize) -> f32) {
+        if node.start == node.end {
+            return;
+        }
+        match line_box(ray, node.min, node.max) {
+            Some((t0, t1)) if t1 >= near && t0 <= *far => {}
+            _ => return,
+        }
+        if node.children.is_empty() {
+            for &i in &self.order[node.start..node.end] {
+                *far = far.min(visit(i as usize));
+            }
+        }
+        for child in &node.children {
+            self.walk(child, ray, near, far, visit);
+        }
+    }
+}
+
+impl TreeNode {
+    fn build(mesh: &Mesh, centroids: &[Vector3], order: &mut [u32], start: usize) -> TreeNode {
+        let mut min = Vector3::from_one(f32::INFINITY);
+        let mut max = Vector3::from_one(f32::NEG_INFINITY);
+        for &i in order.iter() {
+            for &v in &mesh.indices[i as usize] {
+                let p = mesh.positions[v as usize];
+                min = Vector3 { x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z) };
+                max = Vector3 { x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z) };
+            }
+        }
+        // widened a little, so rounding in the slab test loses no triangle
+        let size = [min.x, min.y, min.z, max.x, max.y, max.z].iter().fold(0.0f32, |m, c| m.max(c.abs()));
+        let pad = Vector3::from_one(1e-5 * (1.0 + size));
+        let (min, max) = (min - pad, max + pad);
+        let end = start + order.len();
+
+        if order.len() <= LEAF_TRIANGLES {
+            return TreeNode { min, max, start, end, children: Vec::new() };
+        }
+        let mut lo = Vector3::from_one(f32::INFINITY);
+        let mut hi = Vector3::from_one(f32::NEG_INFINITY);
+        for &i in order.iter() {
+            let c = centroids[i as usize];
+            lo = Vector3 { x: lo.x.min(c.x), y: lo.y.min(c.y), z: lo.z.min(c.z) };
+            hi = Vector3 { x: hi.x.max(c.x), y: hi.y.max(c.y), z: hi.z.max(c.z) };
+        }
+        let extent = hi - lo;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let key = |i: &u32| {
+            let c = centroids[*i as usize];
+            [c.x, c.y, c.z][axis]
+        };
+        let middle = order.len() / 2;
+        order.select_nth_unstable_by(middle, |a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+        let (left, right) = order.split_at_mut(middle);
+        let children = vec![
+            TreeNode::build(mesh, centroids, left, start),
+            TreeNode::build(mesh, centroids, right, start + middle),
+        ];
+        TreeNode { min, max, start, end, children }
+    }
+}
@@ -0,0 +1,498 @@
+use crate::shape::Mesh;
+use crate::math::vector::Vector3;
+use crate::color::Color;
+use serde_derive::Deserialize;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+/// Edge between two cage vertices that resists smoothing. A sharpness of
+/// n keeps the edge sharp for n levels; fractions blend towards smooth.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Crease {
+    pub edge: [u32; 2],
+    pub sharpness: f32,
+}
+
+/// Polygonal control mesh. Faces list their vertices counter-clockwise as
+/// seen from outside; edges used by a single face form boundaries, which
+/// are kept sharp.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "CageDescription")]
+pub struct Cage {
+    pub positions: Vec<Vector3>,
+    pub faces: Vec<Vec<u32>>,
+    pub creases: Vec<Crease>,
+}
+
+/// A cage as read from a scene, checked before it is used: every face has
+/// at least three distinct vertices, and faces and creases name only
+/// vertices there are.
+#[derive(Deserialize)]
+struct CageDescription {
+    positions: Vec<Vector3>,
+    faces: Vec<Vec<u32>>,
+    #[serde(default)]
+    creases: Vec<Crease>,
+}
+
+impl TryFrom<CageDescription> for Cage {
+    type Error = String;
+
+    fn try_from(description: CageDescription) -> Result<Cage, String> {
+        let count = description.positions.len();
+        for (f, face) in description.faces.iter().enumerate() {
+            if face.len() < 3 {
+                return Err(format!("face {} has {} vertices; a face needs at least 3", f, face.len()));
+            }
+            if let Some(v) = face.iter().find(|&&v| v as usize >= count) {
+                return Err(format!("face {} refers to missing vertex {}", f, v));
+            }
+            if face.iter().enumerate().any(|(i, v)| face[i + 1..].contains(v)) {
+                return Err(format!("face {} lists a vertex more than once", f));
+            }
+        }
+        if let Some(crease) = description.creases.iter().find(|c| c.edge.iter().any(|&v| v as usize >= count)) {
+            return Err(format!("crease [{}, {}] refers to a missing vertex", crease.edge[0], crease.edge[1]));
+        }
+        Ok(Cage { positions: description.positions, faces: description.faces, creases: description.creases })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum Scheme {
+    /// Loop subdivision; faces that are not triangles are split into fans.
+    Loop,
+    CatmullClark,
+}
+
+fn key(a: u32, b: u32) -> (u32, u32) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+struct Edge {
+    faces: Vec<usize>,
+    sharpness: f32,
+}
+
+impl Edge {
+    // Boundary and non-manifold edges are infinitely sharp.
+    fn sharpness(&self) -> f32 {
+        if self.faces.len() == 2 { self.sharpness } else { f32::INFINITY }
+    }
+}
+
+// Edges of the cage with their faces and creases, and the edges around
+// each vertex.
+struct Topology {
+    edges: HashMap<(u32, u32), Edge>,
+    vertex_edges: Vec<Vec<(u32, u32)>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(cage: &Cage) -> Topology {
+        let mut edges: HashMap<(u32, u32), Edge> = HashMap::new();
+        let mut vertex_faces = vec![Vec::new(); cage.positions.len()];
+        for (f, face) in cage.faces.iter().enumerate() {
+            for (i, &v) in face.iter().enumerate() {
+                let next = face[(i + 1) % face.len()];
+                edges.entry(key(v, next)).or_insert(Edge { faces: Vec::new(), sharpness: 0.0 }).faces.push(f);
+                vertex_faces[v as usize].push(f);
+            }
+        }
+        for crease in &cage.creases {
+            if let Some(edge) = edges.get_mut(&key(crease.edge[0], crease.edge[1])) {
+                edge.sharpness = crease.sharpness.max(0.0);
+            }
+        }
+        let mut vertex_edges = vec![Vec::new(); cage.positions.len()];
+        for &(a, b) in edges.keys() {
+            vertex_edges[a as usize].push((a, b));
+            vertex_edges[b as usize].push((a, b));
+        }
+        // a fixed order keeps the result independent of hashing
+        for list in vertex_edges.iter_mut() {
+            list.sort_unstable();
+        }
+        Topology { edges, vertex_edges, vertex_faces }
+    }
+
+    // Vertex rule shared by both schemes: `smooth` is the scheme's interior
+    // position. Two sharp edges make a crease, more make a corner; fractional
+    // sharpness blends with the smooth position.
+    fn vertex(&self, cage: &Cage, v: usize, smooth: Vector3) -> Vector3 {
+        let sharp: Vec<(&(u32, u32), f32)> = self.vertex_edges[v].iter()
+            .map(|e| (e, self.edges[e].sharpness()))
+            .filter(|(_, s)| *s > 0.0)
+            .collect();
+        let p = cage.positions[v];
+        let sharp_position = match sharp.len() {
+            0 | 1 => return smooth,
+            2 => {
+                let other = |&(a, b): &(u32, u32)| cage.positions[if a as usize == v { b } else { a } as usize];
+                p * 0.75 + (other(sharp[0].0) + other(sharp[1].0)) * 0.125
+            }
+            _ => p,
+        };
+        let sharpness = sharp.iter().map(|(_, s)| *s).sum::<f32>() / sharp.len() as f32;
+        if sharpness >= 1.0 { sharp_position } else { smooth + (sharp_position - smooth) * sharpness }
+    }
+
+    // Sharpness of the halves of a split edge.
+    fn child_creases(&self, edge_points: &HashMap<(u32, u32), u32>) -> Vec<Crease> {
+        let mut creases = Vec::new();
+        for (&(a, b), edge) in &self.edges {
+            let s = edge.sharpness - 1.0;
+            if edge.faces.len() == 2 && s > 0.0 {
+                let mid = edge_points[&(a, b)];
+                creases.push(Crease { edge: [a, mid], sharpness: s });
+                creases.push(Crease { edge: [mid, b], sharpness: s });
+            }
+        }
+        creases.sort_by_key(|c| c.edge);
+        creases
+    }
+}
+
+fn centroid(cage: &Cage, face: &[u32]) -> Vector3 {
+    face.iter().fold(Vector3::zero(), |acc, &v| acc + cage.positions[v as usize]) / face.len() as f32
+}
+
+// Sorted edge keys, so new vertices are numbered deterministically.
+fn sorted_edges(topology: &Topology) -> Vec<(u32, u32)> {
+    let mut keys: Vec<(u32, u32)> = topology.edges.keys().copied().collect();
+    keys.sort_unstable();
+    keys
+}
+
+fn blend(smooth: Vector3, sharp: Vector3, sharpness: f32) -> Vector3 {
+    if sharpness >= 1.0 { sharp } else { smooth + (sharp - smooth) * sharpness }
+}
+
+/// One level of Catmull-Clark subdivision; every face becomes quads.
+pub fn catmull_clark(cage: &Cage) -> Cage {
+    let topology = Topology::new(cage);
+    let face_points: Vec<Vector3> = cage.faces.iter().map(|f| centroid(cage, f)).collect();
+
+    // old vertices, then edge points, then face points
+    let mut positions = Vec::with_capacity(cage.positions.len() + topology.edges.len() + cage.faces.len());
+    for v in 0..cage.positions.len() {
+        let p = cage.positions[v];
+        let faces = &topology.vertex_faces[v];
+        let edges = &topology.vertex_edges[v];
+        let smooth = if faces.is_empty() || edges.is_empty() {
+            p
+        } else {
+            let n = edges.len() as f32;
+            let q = faces.iter().fold(Vector3::zero(), |acc, &f| acc + face_points[f]) / faces.len() as f32;
+            let r = edges.iter().fold(Vector3::zero(), |acc, &(a, b)| acc + (cage.positions[a as usize] + cage.positions[b as usize]) / 2.0) / n;
+            (q + r * 2.0 + p * (n - 3.0)) / n
+        };
+        positions.push(topology.vertex(cage, v, smooth));
+    }
+
+    let mut edge_points = HashMap::new();
+    for (a, b) in sorted_edges(&topology) {
+        let edge = &topology.edges[&(a, b)];
+        let mid = (cage.positions[a as usize] + cage.positions[b as usize]) / 2.0;
+        let point = if edge.faces.len() == 2 {
+            let smooth = (mid * 2.0 + face_points[edge.faces[0]] + face_points[edge.faces[1]]) / 4.0;
+            blend(smooth, mid, edge.sharpness())
+        } else {
+            mid
+        };
+        edge_points.insert((a, b), positions.len() as u32);
+        positions.push(point);
+    }
+
+    let mut faces = Vec::new();
+    for (f, face) in cage.faces.iter().enumerate() {
+        let center = positions.len() as u32;
+        positions.push(face_points[f]);
+        let k = face.len();
+        for i in 0..k {
+            let (previous, v, next) = (face[(i + k - 1) % k], face[i], face[(i + 1) % k]);
+            faces.push(vec![v, edge_points[&key(v, next)], center, edge_points[&key(previous, v)]]);
+        }
+    }
+
+    let creases = topology.child_creases(&edge_points);
+    Cage { positions, faces, creases }
+}
+
+/// One level of Loop subdivision; every triangle becomes four.
+pub fn loop_subdivide(cage: &Cage) -> Cage {
+    let cage = &triangulated(cage);
+    let topology = Topology::new(cage);
+
+    let mut positions = Vec::with_capacity(cage.positions.len() + topology.edges.len());
+    for v in 0..cage.positions.len() {
+        let p = cage.positions[v];
+        let edges = &topology.vertex_edges[v];
+        let smooth = if edges.is_empty() {
+            p
+        } else {
+            let n = edges.len();
+            // Warren's weights
+            let beta = if n == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n as f32) };
+            let sum = edges.iter().fold(Vector3::zero(), |acc, &(a, b)| acc + cage.positions[if a as usize == v { b } else { a } as usize]);
+            p * (1.0 - n as f32 * beta) + sum * beta
+        };
+        positions.push(topology.vertex(cage, v, smooth));
+    }
+
+    // the vertex across the edge in a triangle
+    let opposite = |f: usize, (a, b): (u32, u32)| *cage.faces[f].iter().find(|&&v| v != a && v != b).unwrap_or(&a);
+
+    let mut edge_points = HashMap::new();
+    for (a, b) in sorted_edges(&topology) {
+        let edge = &topology.edges[&(a, b)];
+        let (pa, pb) = (cage.positions[a as usize], cage.positions[b as usize]);
+        let mid = (pa + pb) / 2.0;
+        let point = if edge.faces.len() == 2 {
+            let (c, d) = (opposite(edge.faces[0], (a, b)), opposite(edge.faces[1], (a, b)));
+            let smooth = (pa + pb) * 0.375 + (cage.positions[c as usize] + cage.positions[d as usize]) * 0.125;
+            blend(smooth, mid, edge.sharpness())
+        } else {
+            mid
+        };
+        edge_points.insert((a, b), positions.len() as u32);
+        positions.push(point);
+    }
+
+    let mut faces = Vec::with_capacity(cage.faces.len() * 4);
+    for face in &cage.faces {
+        let (v0, v1, v2) = (face[0], face[1], face[2]);
+        let (e01, e12, e20) = (edge_points[&key(v0, v1)], edge_points[&key(v1, v2)], edge_points[&key(v2, v0)]);
+        faces.push(vec![v0, e01, e20]);
+        faces.push(vec![v1, e12, e01]);
+        faces.push(vec![v2, e20, e12]);
+        faces.push(vec![e01, e12, e20]);
+    }
+
+    let creases = topology.child_creases(&edge_points);
+    Cage { positions, faces, creases }
+}
+
+fn triangulated(cage: &Cage) -> Cage {
+    let mut faces = Vec::with_capacity(cage.faces.len());
+    for face in cage.faces.iter().filter(|f| f.len() >= 3) {
+        for i in 1..face.len() - 1 {
+            faces.push(vec![face[0], face[i], face[i + 1]]);
+        }
+    }
+    Cage { positions: cage.positions.clone(), faces, creases: cage.creases.clone() }
+}
+
+impl Cage {
+    /// `levels` rounds of `scheme`.
+    pub fn subdivide(&self, scheme: Scheme, levels: u32) -> Cage {
+        let mut cage = self.clone();
+        for _ in 0..levels {
+            cage = match scheme {
+                Scheme::Loop => loop_subdivide(&cage),
+                Scheme::CatmullClark => catmull_clark(&cage),
+            };
+        }
+        cage
+    }
+
+    /// Triangle mesh of the cage with smooth, area-weighted vertex normals.
+    pub fn to_mesh(&self, color: Color, refractive_index: f32) -> Mesh {
+        let mut mesh = Mesh {
+            positions: self.positions.clone(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: triangulated(self).faces.iter().map(|f| [f[0], f[1], f[2]]).collect(),
+            color,
+            texture: None,
+            refractive_index,
+            detail_map: None,
+            displacement: None,
+            tree: OnceLock::new(),
+        };
+        mesh.smooth_normals();
+        mesh
+    }
+}
+
+// Faces a subdivision surface may grow to, counting each n-sided cage face
+// as n quads or triangles after the first level.
+const MAX_FACES: usize = 1 << 18;
+
+/// Control cage, scheme and level together, as read from a scene. Levels
+/// that would make more than `MAX_FACES` faces are refused.
+#[derive(Deserialize)]
+#[serde(try_from = "SurfaceDescription")]
+pub struct SubdivisionSurface {
+    pub cage: Cage,
+    pub scheme: Scheme,
+    pub levels: u32,
+    pub color: Color,
+    pub refractive_index: f32,
+}
+
+#[derive(Deserialize)]
+struct SurfaceDescription {
+    cage: Cage,
+    scheme: Scheme,
+    levels: u32,
+    color: Color,
+    refractive_index: f32,
+}
+
+impl TryFrom<SurfaceDescription> for SubdivisionSurface {
+    type Error = String;
+
+    fn try_from(description: SurfaceDescription) -> Result<SubdivisionSurface, String> {
+        let cage = &description.cage;
+        let first = if description.levels == 0 { cage.faces.len() } else { cage.faces.iter().map(Vec::len).sum() };
+        // every level after the first quadruples the faces
+        let faces = (1..description.levels).try_fold(first, |faces, _| faces.checked_mul(4).filter(|&f| f <= MAX_FACES));
+        if faces.is_none_or(|f| f > MAX_FACES) {
+            return Err(format!("{} levels of subdivision would make more than {} faces", description.levels, MAX_FACES));
+        }
+        Ok(SubdivisionSurface {
+            cage: description.cage,
+            scheme: description.scheme,
+            levels: description.levels,
+            color: description.color,
+            refractive_index: description.refractive_index,
+        })
+    }
+}
+
+impl SubdivisionSurface {
+    pub fn to_mesh(&self) -> Mesh {
+        self.cage.subdivide(self.scheme, self.levels).to_mesh(self.color, self.refractive_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::{Hit, Intersectable};
+    use crate::ray::Ray;
+
+    fn cube() -> Cage {
+        let positions = (0..8).map(|i| Vector3 {
+            x: if i & 1 == 0 { -1.0 } else { 1.0 },
+            y: if i & 2 == 0 { -1.0 } else { 1.0 },
+            z: if i & 4 == 0 { -1.0 } else { 1.0 },
+        }).collect();
+        let faces = vec![
+            vec![0, 2, 3, 1], vec![4, 5, 7, 6],
+            vec![0, 1, 5, 4], vec![2, 6, 7, 3],
+            vec![0, 4, 6, 2], vec![1, 3, 7, 5],
+        ];
+        Cage { positions, faces, creases: Vec::new() }
+    }
+
+    fn radii(cage: &Cage) -> (f32, f32) {
+        cage.positions.iter().fold((f32::INFINITY, 0.0), |(lo, hi), p| (lo.min(p.length()), hi.max(p.length())))
+    }
+
+    #[test]
+    fn catmull_clark_cube() {
+        let once = cube().subdivide(Scheme::CatmullClark, 1);
+        assert_eq!((once.positions.len(), once.faces.len()), (26, 24));
+        // corners pull in to (5/9, 5/9, 5/9), face points stay put
+        assert!((once.positions[7] - Vector3::from_one(5.0 / 9.0)).length() < 1e-5);
+
+        // repeated subdivision rounds the cube off
+        let (lo, hi) = radii(&cube().subdivide(Scheme::CatmullClark, 3));
+        assert!(hi / lo < 1.15);
+
+        let mesh = cube().subdivide(Scheme::CatmullClark, 2).to_mesh(Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }, 1.0);
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.1, y: 0.2, z: 5.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(mesh.intersect(&ray, &mut hit), 2);
+        assert!(hit.normal.z > 0.95);
+    }
+
+    #[test]
+    fn creases_and_corners() {
+        // creasing every edge of the cube keeps it a cube
+        let mut sharp = cube();
+        for face in &cube().faces {
+            for i in 0..4 {
+                sharp.creases.push(Crease { edge: [face[i], face[(i + 1) % 4]], sharpness: 10.0 });
+            }
+        }
+        let result = sharp.subdivide(Scheme::CatmullClark, 2);
+        assert!((result.positions[7] - Vector3::from_one(1.0)).length() < 1e-6);
+        assert!(result.positions.iter().all(|p| p.x.abs().max(p.y.abs()).max(p.z.abs()) > 1.0 - 1e-5));
+
+        // a sharpness of 1 is used up after one level
+        for crease in sharp.creases.iter_mut() {
+            crease.sharpness = 1.0;
+        }
+        let once = sharp.subdivide(Scheme::CatmullClark, 1);
+        assert!(once.creases.is_empty());
+        assert!((once.positions[7] - Vector3::from_one(1.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn loop_tetrahedron() {
+        let cage = Cage {
+            positions: vec![
+                Vector3 { x: 1.0, y: 1.0, z: 1.0 },
+                Vector3 { x: 1.0, y: -1.0, z: -1.0 },
+                Vector3 { x: -1.0, y: 1.0, z: -1.0 },
+                Vector3 { x: -1.0, y: -1.0, z: 1.0 },
+            ],
+            faces: vec![vec![0, 1, 2], vec![0, 3, 1], vec![0, 2, 3], vec![1, 3, 2]],
+            creases: Vec::new(),
+        };
+        let once = cage.subdivide(Scheme::Loop, 1);
+        assert_eq!((once.positions.len(), once.faces.len()), (10, 16));
+        let (lo, hi) = radii(&cage.subdivide(Scheme::Loop, 3));
+        assert!(hi / lo < 1.3);
+
+        // quads are split into triangles first
+        assert_eq!(cube().subdivide(Scheme::Loop, 1).faces.len(), 48);
+
+        // normals face out of the solid
+        let mesh = cage.subdivide(Scheme::Loop, 2).to_mesh(Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 }, 1.0);
+        for (p, n) in mesh.positions.iter().zip(&mesh.normals) {
+            assert!(p.dot(n) > 0.0);
+        }
+    }
+
+    #[test]
+    fn boundary() {
+        // an open grid of quads stays flat and keeps its outline
+        let mut positions = Vec::new();
+        for j in 0..3 {
+            for i in 0..3 {
+                positions.push(Vector3 { x: i as f32, y: 0.0, z: -(j as f32) });
+            }
+        }
+        let faces = vec![vec![0, 1, 4, 3], vec![1, 2, 5, 4], vec![3, 4, 7, 6], vec![4, 5, 8, 7]];
+        let cage = Cage { positions, faces, creases: Vec::new() };
+        for scheme in [Scheme::CatmullClark, Scheme::Loop] {
+            let result = cage.subdivide(scheme, 2);
+            assert!(result.positions.iter().all(|p| p.y.abs() < 1e-6));
+            // the middle of the bottom edge stays on it
+            assert!(result.positions.iter().any(|p| (p.x - 1.0).abs() < 1e-6 && p.z.abs() < 1e-6));
+            assert!(result.positions.iter().all(|p| p.x >= -1e-6 && p.x <= 2.0 + 1e-6));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_cages() {
+        let surface = |faces: &str, levels: u32| serde_json::from_str::<SubdivisionSurface>(&format!(
+            r#"{{"cage": {{"positions": [{{"x": 0, "y": 0, "z": 0}}, {{"x": 1, "y": 0, "z": 0}}, {{"x": 0, "y": 1, "z": 0}}], "faces": {}}},
+                "scheme": "Loop", "levels": {}, "color": {{"r": 1, "g": 1, "b": 1, "a": 1}}, "refractive_index": 1}}"#,
+            faces, levels)).map(|_| ()).map_err(|e| e.to_string());
+        assert_eq!(surface("[[0, 1, 2]]", 3), Ok(()));
+        assert!(surface("[[0, 1, 3]]", 1).err().unwrap().contains("face 0 refers to missing vertex 3"));
+        assert!(surface("[[0, 1, 2], [0, 1]]", 1).err().unwrap().contains("face 1 has 2 vertices"));
+        assert!(surface("[[0, 1, 1]]", 1).err().unwrap().contains("more than once"));
+        assert!(surface("[[0, 1, 2]]", 12).err().unwrap().contains("12 levels"));
+        assert!(surface("[[0, 1, 2]]", u32::MAX).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::color::Color;
+use crate::math::vector::{Vector2, Vector3};
+use crate::shape::Hit;
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde_derive::Deserialize;
+
+/// Image addressed by texture coordinates, repeating outside [0, 1].
+/// `uv.y` points up, so `v = 0` is the bottom row of the image.
+#[derive(Clone)]
+pub struct Texture {
+    image: RgbaImage,
+}
+
+impl Texture {
+    pub fn from_image(image: DynamicImage) -> Texture {
+        Texture { image: image.to_rgba8() }
+    }
+
+    pub fn open(path: &str) -> Result<Texture, image::ImageError> {
+        Ok(Texture::from_image(image::open(path)?))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    fn texel(&self, x: i64, y: i64) -> [f32; 4] {
+        let w = i64::from(self.image.width());
+        let h = i64::from(self.image.height());
+        let p = self.image.get_pixel(x.rem_euclid(w) as u32, y.rem_euclid(h) as u32);
+        [f32::from(p[0]) / 255.0, f32::from(p[1]) / 255.0, f32::from(p[2]) / 255.0, f32::from(p[3]) / 255.0]
+    }
+
+    /// Bilinearly filtered lookup. Values are returned as stored, without
+    /// gamma decoding, since most maps hold data rather than colors.
+    pub fn sample(&self, uv: Vector2) -> Color {
+        let x = uv.x * self.image.width() as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.image.height() as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let t00 = self.texel(x0, y0);
+        let t10 = self.texel(x0 + 1, y0);
+        let t01 = self.texel(x0, y0 + 1);
+        let t11 = self.texel(x0 + 1, y0 + 1);
+        let mut c = [0.0; 4];
+        for i in 0..4 {
+            let top = t00[i] + (t10[i] - t00[i]) * fx;
+            let bottom = t01[i] + (t11[i] - t01[i]) * fx;
+            c[i] = top + (bottom - top) * fy;
+        }
+        Color { r: c[0], g: c[1], b: c[2], a: c[3] }
+    }
+
+    /// Lookup of a color map, whose texels are gamma encoded.
+    pub fn sample_color(&self, uv: Vector2) -> Color {
+        self.sample(uv).decode()
+    }
+}
+
+impl<'de> Deserialize<'de> for Texture {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Texture, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Texture::open(&path).map_err(|e| D::Error::custom(format!("{}: {}", path, e)))
+    }
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+/// Tangent-space normal map. Maps authored for DirectX store +y pointing
+/// down the texture and need `flip_green`.
+#[derive(Deserialize)]
+pub struct NormalMap {
+    pub texture: Texture,
+    #[serde(default = "default_strength")]
+    pub strength: f32,
+    #[serde(default)]
+    pub flip_green: bool,
+}
+
+/// Grayscale height map; `strength` scales the slope of the height field.
+#[derive(Deserialize)]
+pub struct BumpMap {
+    pub texture: Texture,
+    #[serde(default = "default_strength")]
+    pub strength: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum DetailMap {
+    Normal(NormalMap),
+    Bump(BumpMap),
+}
+
+impl NormalMap {
+    pub fn perturb(&self, hit: &Hit) -> Vector3 {
+        let c = self.texture.sample(hit.uv);
+        let green = if self.flip_green { 1.0 - c.g } else { c.g };
+        let x = (c.r * 2.0 - 1.0) * self.strength;
+        let y = (green * 2.0 - 1.0) * self.strength;
+        let z = c.b * 2.0 - 1.0;
+
+        let n = hit.tangent * x + hit.bitangent * y + hit.normal * z;
+        if n.size_squared() > 0.0 { n.normalize() } else { hit.normal }
+    }
+}
+
+impl BumpMap {
+    fn height(&self, uv: Vector2) -> f32 {
+        let c = self.texture.sample(uv);
+        (c.r + c.g + c.b) / 3.0
+    }
+
+    pub fn perturb(&self, hit: &Hit) -> Vector3 {
+        let du = 1.0 / self.texture.width() as f32;
+        let dv = 1.0 / self.texture.height() as f32;
+        let dhdu = (self.height(hit.uv + Vector2 { x: du, y: 0.0 }) - self.height(hit.uv - Vector2 { x: du, y: 0.0 })) / (2.0 * du);
+        let dhdv = (self.height(hit.uv + Vector2 { x: 0.0, y: dv }) - self.height(hit.uv - Vector2 { x: 0.0, y: dv })) / (2.0 * dv);
+
+        let n = hit.normal - self.strength * (dhdu * hit.tangent + dhdv * hit.bitangent);
+        if n.size_squared() > 0.0 { n.normalize() } else { hit.normal }
+    }
+}
+
+impl DetailMap {
+    /// Shading normal at `hit`; the geometric normal is left untouched.
+    pub fn perturb(&self, hit: &Hit) -> Vector3 {
+        match self {
+            DetailMap::Normal(map) => map.perturb(hit),
+            DetailMap::Bump(map) => map.perturb(hit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    fn flat_hit() -> Hit {
+        let mut hit = Hit::new();
+        hit.normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        hit.geometric_normal = hit.normal;
+        hit.tangent = Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        hit.bitangent = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        hit.uv = Vector2 { x: 0.5, y: 0.5 };
+        hit
+    }
+
+    fn solid(r: u8, g: u8, b: u8) -> Texture {
+        Texture::from_image(DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([r, g, b, 255]))))
+    }
+
+    #[test]
+    fn normal_map() {
+        let hit = flat_hit();
+        let flat = NormalMap { texture: solid(128, 128, 255), strength: 1.0, flip_green: false };
+        assert!((flat.perturb(&hit).z - 1.0).abs() < 1e-3);
+
+        let up = NormalMap { texture: solid(128, 255, 128), strength: 1.0, flip_green: false };
+        assert!(up.perturb(&hit).y > 0.99);
+
+        let down = NormalMap { texture: solid(128, 255, 128), strength: 1.0, flip_green: true };
+        assert!(down.perturb(&hit).y < -0.99);
+    }
+
+    #[test]
+    fn bump_map() {
+        let hit = flat_hit();
+        let flat = BumpMap { texture: solid(100, 100, 100), strength: 1.0 };
+        assert!((flat.perturb(&hit).z - 1.0).abs() < 1e-5);
+
+        // height rising along +u tilts the normal towards -u
+        let ramp = RgbaImage::from_fn(4, 1, |x, _| Rgba([(x * 60) as u8, (x * 60) as u8, (x * 60) as u8, 255]));
+        let ramp = BumpMap { texture: Texture::from_image(DynamicImage::ImageRgba8(ramp)), strength: 0.1 };
+        let n = ramp.perturb(&hit);
+        assert!(n.x < 0.0 && n.z > 0.0);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, nearest_boundary};
+use crate::math::vector::{Vector2, Vector3};
+use crate::math::quaternion::Quat;
+use crate::math::polynomial::solve_quartic;
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+use std::f32::consts::PI;
+
+/// Ring around the local y axis: a tube of `minor_radius` swept along a
+/// circle of `major_radius` centered on `location`.
+#[derive(Deserialize)]
+pub struct Torus {
+    pub location: Vector3,
+    pub rotation: Quat,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub color: Color,
+    pub refractive_index: f32,
+    #[serde(default)]
+    pub detail_map: Option<DetailMap>,
+}
+
+impl Shape for Torus {
+    fn location(&self) -> Vector3 {
+        self.location
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Torus {
+    // Signed distances along the ray's line where it crosses the surface.
+    fn roots(&self, o: Vector3, d: Vector3) -> Vec<f64> {
+        let outer = f64::from(self.major_radius + self.minor_radius);
+
+        // Solve from the point closest to the center; a distant origin would
+        // otherwise swamp the coefficients.
+        let shift = -f64::from(o.dot(&d));
+        let (ox, oy, oz) = (f64::from(o.x) + shift * f64::from(d.x), f64::from(o.y) + shift * f64::from(d.y), f64::from(o.z) + shift * f64::from(d.z));
+        let (dx, dy, dz) = (f64::from(d.x), f64::from(d.y), f64::from(d.z));
+        let m = ox * ox + oy * oy + oz * oz;
+        if m > outer * outer {
+            return Vec::new();
+        }
+
+        let r2 = f64::from(self.major_radius).powi(2);
+        let n = ox * dx + oy * dy + oz * dz;
+        let k = m + r2 - f64::from(self.minor_radius).powi(2);
+        let roots = solve_quartic(
+            1.0,
+            4.0 * n,
+            2.0 * k + 4.0 * n * n - 4.0 * r2 * (dx * dx + dz * dz),
+            4.0 * n * k - 8.0 * r2 * (ox * dx + oz * dz),
+            k * k - 4.0 * r2 * (ox * ox + oz * oz));
+        roots.into_iter().map(|t| t + shift).collect()
+    }
+
+    fn surface(&self, ray: &Ray, p: Vector3, t: f32) -> Hit {
+        let r2 = self.major_radius * self.major_radius;
+        let s = p.size_squared() + r2 - self.minor_radius * self.minor_radius;
+        let n = (s * p - 2.0 * r2 * Vector3 { x: p.x, y: 0.0, z: p.z }).normalize();
+        let ring = (p.x * p.x + p.z * p.z).sqrt();
+        let tangent = if ring > 0.0 { Vector3 { x: p.z / ring, y: 0.0, z: -p.x / ring } } else { Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = self.rotation.rotate(n);
+        hit.geometric_normal = hit.normal;
+        hit.tangent = self.rotation.rotate(tangent);
+        hit.bitangent = hit.normal.cross(&hit.tangent);
+        // around the ring from +z, then around the tube from its inner edge
+        hit.uv = Vector2 {
+            x: 0.5 + p.x.atan2(p.z) / (2.0 * PI),
+            y: 0.5 + p.y.atan2(ring - self.major_radius) / (2.0 * PI),
+        };
+        hit
+    }
+}
+
+impl Intersectable for Torus {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::with_capacity(2);
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let mut inverse = self.rotation;
+        inverse.conjugate();
+        let o = inverse.rotate(ray.origin - self.location);
+        let d = inverse.rotate(ray.direction);
+
+        // the line is inside the tube between consecutive pairs of roots;
+        // an odd count means a grazing double root was split by rounding
+        let roots = self.roots(o, d);
+        if !roots.len().is_multiple_of(2) {
+            return;
+        }
+        for pair in roots.chunks(2) {
+            let (t_in, t_out) = (pair[0] as f32, pair[1] as f32);
+            intervals.push(Interval {
+                t_in,
+                t_out,
+                enter: self.surface(ray, o + t_in * d, t_in),
+                exit: self.surface(ray, o + t_out * d, t_out),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torus() -> Torus {
+        Torus {
+            location: Vector3 { x: 0.0, y: 0.0, z: -10.0 },
+            rotation: Quat::identity(),
+            major_radius: 2.0,
+            minor_radius: 0.5,
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        }
+    }
+
+    #[test]
+    fn through_the_ring() {
+        let torus = torus();
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(torus.intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 7.5).abs() < 1e-4);
+        assert!((hit.normal.z - 1.0).abs() < 1e-4);
+
+        let mut intervals = Vec::new();
+        torus.intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 2);
+        assert!((intervals[1].t_out - 12.5).abs() < 1e-4);
+
+        // straight down the hole
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 5.0, z: -10.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        assert_eq!(torus.intersect(&ray, &mut hit), 0);
+    }
+
+    #[test]
+    fn tube_normal_and_uv() {
+        let torus = torus();
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 0.0, y: 5.0, z: -8.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+        assert_eq!(torus.intersect(&ray, &mut hit), 2);
+        assert!((hit.point.y - 0.5).abs() < 1e-4);
+        assert!((hit.normal.y - 1.0).abs() < 1e-4);
+        assert!((hit.uv.y - 0.75).abs() < 1e-4);
+        assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-4);
+    }
+
+    #[test]
+    fn distant_ray() {
+        let torus = torus();
+        let mut hit = Hit::new();
+        let ray = Ray { origin: Vector3 { x: 2.0, y: 0.0, z: 5000.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 } };
+        assert_eq!(torus.intersect(&ray, &mut hit), 2);
+        assert!((hit.point.z + 8.5).abs() < 2e-3);
+    }
+}
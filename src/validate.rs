@@ -0,0 +1,228 @@
+use serde_json::{Map, Value};
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    /// The scene cannot be rendered as written.
+    Error,
+    /// The scene renders, but probably not as intended.
+    Warning,
+}
+
+/// A problem found in a scene, and where: the file and the field within it.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.location, severity, self.message)
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+fn error(location: String, message: String) -> Diagnostic {
+    Diagnostic { severity: Severity::Error, location, message }
+}
+
+fn warning(location: String, message: String) -> Diagnostic {
+    Diagnostic { severity: Severity::Warning, location, message }
+}
+
+/// Checks the image size and field of view. `locate` gives the location of
+/// a setting by name.
+pub fn check_camera(width: f64, height: f64, fov: f64, locate: &dyn Fn(&str) -> String, out: &mut Vec<Diagnostic>) {
+    if width < 1.0 || width.fract() != 0.0 {
+        out.push(error(locate("width"), format!("width must be a whole number of pixels, got {}", width)));
+    }
+    if height < 1.0 || height.fract() != 0.0 {
+        out.push(error(locate("height"), format!("height must be a whole number of pixels, got {}", height)));
+    }
+    if width <= height {
+        // primary rays are laid out for a landscape image
+        out.push(error(locate("width"), format!("width must exceed height, got {} x {}", width, height)));
+    }
+    if fov <= 0.0 || fov >= 180.0 {
+        out.push(error(locate("fov"), format!("fov must lie strictly between 0 and 180 degrees, got {}", fov)));
+    }
+}
+
+fn vector(value: &Value, fields: &[&str]) -> Option<Vec<f64>> {
+    fields.iter().map(|f| value.get(*f).and_then(Value::as_f64)).collect()
+}
+
+// Fields that size a shape, or a part of one, and must be positive.
+const POSITIVE: [&str; 11] = ["radius", "top_radius", "major_radius", "minor_radius", "height", "voxel_size", "threshold", "tessellate", "max_edge_pixels",
+                              "factor", "k"];
+
+/// Checks a shape as written in a scene file, after definitions and
+/// materials are filled in, along with every object nested in it: CSG
+/// operands, blob sources, distance tree nodes and the like. `at` locates
+/// the shape itself.
+pub fn check_shape(shape: &Map<String, Value>, at: &str, out: &mut Vec<Diagnostic>) {
+    let field = |name: &str| format!("{}.{}", at, name);
+    for name in POSITIVE {
+        if let Some(v) = shape.get(name).and_then(Value::as_f64) {
+            if v <= 0.0 {
+                out.push(error(field(name), format!("{} must be positive, got {}", name.replace('_', " "), v)));
+            }
+        }
+    }
+    if let Some(n) = shape.get("refractive_index").and_then(Value::as_f64) {
+        if n <= 0.0 {
+            out.push(error(field("refractive_index"), format!("refractive index must be positive, got {}", n)));
+        }
+    }
+    for name in ["extent", "radii"] {
+        if let Some(extent) = shape.get(name).and_then(|e| vector(e, &["x", "y", "z"])) {
+            if extent.iter().any(|&e| e <= 0.0) {
+                out.push(error(field(name), format!("{} must be positive along every axis, got ({}, {}, {})", name, extent[0], extent[1], extent[2])));
+            }
+        }
+    }
+    if let Some(scale) = shape.get("scale").and_then(|s| vector(s, &["x", "y", "z"])) {
+        if scale.contains(&0.0) {
+            out.push(error(field("scale"), format!("scale must be nonzero along every axis, got ({}, {}, {})", scale[0], scale[1], scale[2])));
+        }
+    }
+    if let Some(normal) = shape.get("normal").and_then(|n| vector(n, &["x", "y", "z"])) {
+        if normal.iter().all(|&n| n == 0.0) {
+            out.push(error(field("normal"), "normal has zero length".to_string()));
+        }
+    }
+    if let Some(q) = shape.get("rotation").and_then(|r| vector(r, &["x", "y", "z", "w"])) {
+        let length = q.iter().map(|c| c * c).sum::<f64>().sqrt();
+        if length < 1e-6 {
+            out.push(error(field("rotation"), "rotation is a zero-length quaternion".to_string()));
+        } else if (length - 1.0).abs() > 1e-3 {
+            out.push(warning(field("rotation"), format!("rotation quaternion has length {:.4}, so it also scales the shape", length)));
+        }
+    }
+    if shape.get("type").and_then(Value::as_str) == Some("Curve") {
+        if let Some(Value::Array(points)) = shape.get("control_points") {
+            let n = points.len();
+            match shape.get("basis").and_then(Value::as_str) {
+                // any extra points would be dropped without a word
+                Some("Bezier") if n < 4 || (n - 1) % 3 != 0 => {
+                    out.push(error(field("control_points"), format!("a Bezier curve takes 3n + 1 control points, at least 4, got {}", n)));
+                }
+                Some("BSpline") if n < 4 => {
+                    out.push(error(field("control_points"), format!("a B-spline curve takes at least 4 control points, got {}", n)));
+                }
+                _ => {}
+            }
+        }
+    }
+    for (name, value) in shape {
+        check_nested(value, field(name), out);
+    }
+}
+
+// Checks every object inside `value`, at any depth, as a shape.
+fn check_nested(value: &Value, at: String, out: &mut Vec<Diagnostic>) {
+    match value {
+        Value::Object(nested) => check_shape(nested, &at, out),
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_nested(item, format!("{}[{}]", at, i), out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Checks a light as written in a scene file.
+pub fn check_light(light: &Map<String, Value>, at: &str, out: &mut Vec<Diagnostic>) {
+    if light.get("light_type").and_then(Value::as_str) != Some("Directional") {
+        return;
+    }
+    if let Some(d) = light.get("direction").and_then(|d| vector(d, &["x", "y", "z"])) {
+        let length = d.iter().map(|c| c * c).sum::<f64>().sqrt();
+        if length == 0.0 {
+            out.push(error(format!("{}.direction", at), "directional light has a zero-length direction".to_string()));
+        } else if (length - 1.0).abs() > 1e-3 {
+            out.push(warning(format!("{}.direction", at), format!("direction has length {:.4}; it is normalized before use", length)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shape(value: Value) -> Vec<Diagnostic> {
+        let mut out = Vec::new();
+        check_shape(value.as_object().unwrap(), "scene.json: shapes[0]", &mut out);
+        out
+    }
+
+    #[test]
+    fn shapes() {
+        let fine = json!({"type": "Sphere", "radius": 1, "refractive_index": 1.5, "rotation": {"x": 0, "y": 0, "z": 0, "w": 1}});
+        assert!(shape(fine).is_empty());
+
+        let bad = shape(json!({"type": "Sphere", "radius": 0, "refractive_index": -1.3}));
+        assert_eq!(bad.len(), 2);
+        assert_eq!(bad[0].to_string(), "scene.json: shapes[0].radius: error: radius must be positive, got 0");
+        assert_eq!(bad[1].location, "scene.json: shapes[0].refractive_index");
+
+        let nested = shape(json!({"type": "Csg", "left": {"rotation": {"x": 0, "y": 0, "z": 0, "w": 0}}, "right": {"rotation": {"x": 0, "y": 2, "z": 0, "w": 0}}}));
+        assert_eq!(nested.len(), 2);
+        assert_eq!((nested[0].severity, nested[0].location.as_str()), (Severity::Error, "scene.json: shapes[0].left.rotation"));
+        assert_eq!(nested[1].severity, Severity::Warning);
+
+        // inside blob sources and distance trees too
+        let blob = shape(json!({"type": "Blob", "threshold": 0.5, "sources": [
+            {"type": "Point", "radius": 1, "strength": 1},
+            {"type": "Ellipsoid", "radii": {"x": 1, "y": 0, "z": 1}, "rotation": {"x": 0, "y": 0, "z": 0, "w": 0}, "strength": 1}
+        ]}));
+        assert_eq!(blob.iter().map(|d| d.location.as_str()).collect::<Vec<_>>(),
+                   ["scene.json: shapes[0].sources[1].radii", "scene.json: shapes[0].sources[1].rotation"]);
+        assert!(blob.iter().all(|d| d.severity == Severity::Error));
+        let sdf = shape(json!({"type": "Sdf", "root": {"type": "Scale", "factor": 0, "node": {"type": "Sphere", "radius": -1}}}));
+        assert_eq!(sdf.iter().map(|d| d.location.as_str()).collect::<Vec<_>>(),
+                   ["scene.json: shapes[0].root.factor", "scene.json: shapes[0].root.node.radius"]);
+    }
+
+    #[test]
+    fn curve_points() {
+        let curve = |basis: &str, n: usize| shape(json!({"type": "Curve", "basis": basis, "control_points": vec![json!({"x": 0, "y": 0, "z": 0}); n]}));
+        assert!(curve("Bezier", 7).is_empty());
+        assert!(curve("BSpline", 5).is_empty());
+        let five = curve("Bezier", 5);
+        assert_eq!(five.len(), 1);
+        assert_eq!(five[0].to_string(), "scene.json: shapes[0].control_points: error: a Bezier curve takes 3n + 1 control points, at least 4, got 5");
+        assert_eq!(curve("Bezier", 1).len(), 1);
+        assert_eq!(curve("BSpline", 3).len(), 1);
+    }
+
+    #[test]
+    fn lights_and_camera() {
+        let mut out = Vec::new();
+        let light = json!({"light_type": "Directional", "direction": {"x": -1, "y": -1, "z": 0}});
+        check_light(light.as_object().unwrap(), "scene.json: lights[1]", &mut out);
+        let light = json!({"light_type": "Directional", "direction": {"x": 0, "y": 0, "z": 0}});
+        check_light(light.as_object().unwrap(), "scene.json: lights[2]", &mut out);
+        assert_eq!(out.len(), 2);
+        assert_eq!((out[0].severity, out[0].location.as_str()), (Severity::Warning, "scene.json: lights[1].direction"));
+        assert!(has_errors(&out));
+
+        let mut out = Vec::new();
+        check_camera(640.0, 480.0, 90.0, &|name| name.to_string(), &mut out);
+        assert!(out.is_empty());
+        check_camera(480.0, 640.0, 180.0, &|name| name.to_string(), &mut out);
+        assert_eq!(out.iter().map(|d| d.location.as_str()).collect::<Vec<_>>(), ["width", "fov"]);
+    }
+}
@@ -0,0 +1,442 @@
+use crate::shape::{Shape, Intersectable, Hit, Interval, nearest_boundary};
+use crate::math::vector::{Vector2, Vector3};
+use crate::ray::Ray;
+use crate::color::Color;
+use crate::texture::DetailMap;
+use serde_derive::Deserialize;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+
+/// Material index per cell of a grid, 0 meaning empty. Dense storage suits
+/// filled volumes, sparse storage scattered ones.
+pub enum Voxels {
+    Dense { size: [u32; 3], data: Vec<u8> },
+    Sparse { size: [u32; 3], cells: HashMap<[u32; 3], u8> },
+}
+
+impl Voxels {
+    pub fn dense(size: [u32; 3]) -> Voxels {
+        Voxels::Dense { size, data: vec![0; size[0] as usize * size[1] as usize * size[2] as usize] }
+    }
+
+    pub fn sparse(size: [u32; 3]) -> Voxels {
+        Voxels::Sparse { size, cells: HashMap::new() }
+    }
+
+    pub fn size(&self) -> [u32; 3] {
+        match self {
+            Voxels::Dense { size, .. } | Voxels::Sparse { size, .. } => *size,
+        }
+    }
+
+    fn index(size: [u32; 3], cell: [u32; 3]) -> usize {
+        (cell[2] as usize * size[1] as usize + cell[1] as usize) * size[0] as usize + cell[0] as usize
+    }
+
+    pub fn get(&self, cell: [u32; 3]) -> u8 {
+        match self {
+            Voxels::Dense { size, data } => data[Voxels::index(*size, cell)],
+            Voxels::Sparse { cells, .. } => cells.get(&cell).copied().unwrap_or(0),
+        }
+    }
+
+    pub fn set(&mut self, cell: [u32; 3], material: u8) {
+        match self {
+            Voxels::Dense { size, data } => data[Voxels::index(*size, cell)] = material,
+            Voxels::Sparse { cells, .. } if material == 0 => {
+                cells.remove(&cell);
+            }
+            Voxels::Sparse { cells, .. } => {
+                cells.insert(cell, material);
+            }
+        }
+    }
+}
+
+/// Voxels with the colors of their materials: `palette[m]` for material m.
+pub struct VoxelModel {
+    pub voxels: Voxels,
+    pub palette: Vec<Color>,
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, String> {
+    bytes.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "unexpected end of file".to_string())
+}
+
+/// Reads a MagicaVoxel `.vox` file. Only the first model of a file is
+/// used. MagicaVoxel's z axis points up; here it becomes y, with the
+/// model's +y running towards -z. Files without a palette chunk are given
+/// a gray ramp rather than MagicaVoxel's built-in palette.
+pub fn parse_vox(bytes: &[u8]) -> Result<VoxelModel, String> {
+    if bytes.get(0..4) != Some(b"VOX ") {
+        return Err("not a MagicaVoxel file".to_string());
+    }
+    if bytes.get(8..12) != Some(b"MAIN") {
+        return Err("missing MAIN chunk".to_string());
+    }
+    let main_content = read_u32(bytes, 12)? as usize;
+    let main_children = read_u32(bytes, 16)? as usize;
+    let end = (20 + main_content + main_children).min(bytes.len());
+
+    let mut size: Option<[u32; 3]> = None;
+    let mut points: Option<&[u8]> = None;
+    let mut palette: Option<Vec<Color>> = None;
+    let mut at = 20 + main_content;
+    while at + 12 <= end {
+        let id = &bytes[at..at + 4];
+        let content = read_u32(bytes, at + 4)? as usize;
+        let children = read_u32(bytes, at + 8)? as usize;
+        let body = bytes.get(at + 12..at + 12 + content).ok_or("chunk runs past the end of the file")?;
+        match id {
+            b"SIZE" if size.is_none() => size = Some([read_u32(body, 0)?, read_u32(body, 4)?, read_u32(body, 8)?]),
+            b"XYZI" if points.is_none() => {
+                let count = read_u32(body, 0)? as usize;
+                points = Some(body.get(4..4 + 4 * count).ok_or("XYZI chunk is shorter than its voxel count")?);
+            }
+            b"RGBA" => {
+                let rgba = body.get(0..1024).ok_or("RGBA chunk is too short")?;
+                // entry i of the chunk is the color of material i + 1
+                let mut colors = vec![Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }];
+                colors.extend(rgba.chunks(4).take(255).map(|c| Color {
+                    r: f32::from(c[0]) / 255.0,
+                    g: f32::from(c[1]) / 255.0,
+                    b: f32::from(c[2]) / 255.0,
+                    a: f32::from(c[3]) / 255.0,
+                }));
+                palette = Some(colors);
+            }
+            _ => {}
+        }
+        at += 12 + content + children;
+    }
+
+    let [sx, sy, sz] = size.ok_or("missing SIZE chunk")?;
+    let points = points.ok_or("missing XYZI chunk")?;
+    let grid_size = [sx, sz, sy];
+    let count = points.len() / 4;
+    let total = sx as usize * sy as usize * sz as usize;
+    let mut voxels = if count * 8 < total { Voxels::sparse(grid_size) } else { Voxels::dense(grid_size) };
+    for p in points.chunks(4) {
+        let (x, y, z) = (u32::from(p[0]), u32::from(p[1]), u32::from(p[2]));
+        if x >= sx || y >= sy || z >= sz {
+            return Err(format!("voxel ({}, {}, {}) lies outside the model", x, y, z));
+        }
+        voxels.set([x, z, sy - 1 - y], p[3]);
+    }
+
+    let palette = palette.unwrap_or_else(|| (0..256).map(|i| {
+        let v = i as f32 / 255.0;
+        Color { r: v, g: v, b: v, a: 1.0 }
+    }).collect());
+    Ok(VoxelModel { voxels, palette })
+}
+
+pub fn load_vox(path: &str) -> Result<VoxelModel, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_vox(&bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn default_voxel_size() -> f32 {
+    1.0
+}
+
+/// Grid of cubes `voxel_size` wide, from `location` along +x, +y and +z.
+/// Each voxel takes its color from `palette`, falling back to `color`.
+#[derive(Deserialize)]
+#[serde(try_from = "VoxelDescription")]
+pub struct VoxelGrid {
+    pub location: Vector3,
+    pub voxel_size: f32,
+    pub voxels: Voxels,
+    pub palette: Vec<Color>,
+    pub color: Color,
+    pub refractive_index: f32,
+    pub detail_map: Option<DetailMap>,
+}
+
+/// A grid as read from a scene, loaded from the `.vox` file at `path`.
+#[derive(Deserialize)]
+struct VoxelDescription {
+    path: String,
+    location: Vector3,
+    #[serde(default = "default_voxel_size")]
+    voxel_size: f32,
+    color: Color,
+    refractive_index: f32,
+    #[serde(default)]
+    detail_map: Option<DetailMap>,
+}
+
+impl TryFrom<VoxelDescription> for VoxelGrid {
+    type Error = String;
+
+    fn try_from(description: VoxelDescription) -> Result<VoxelGrid, String> {
+        let model = load_vox(&description.path)?;
+        Ok(VoxelGrid {
+            location: description.location,
+            voxel_size: description.voxel_size,
+            voxels: model.voxels,
+            palette: model.palette,
+            color: description.color,
+            refractive_index: description.refractive_index,
+            detail_map: description.detail_map,
+        })
+    }
+}
+
+fn axis(i: usize, sign: f32) -> Vector3 {
+    match i {
+        0 => Vector3 { x: sign, y: 0.0, z: 0.0 },
+        1 => Vector3 { x: 0.0, y: sign, z: 0.0 },
+        _ => Vector3 { x: 0.0, y: 0.0, z: sign },
+    }
+}
+
+impl VoxelGrid {
+    // Face of the voxel `cell` with outward `normal` where the line crosses
+    // it at `t`.
+    fn surface(&self, ray: &Ray, t: f32, normal: Vector3, cell: [u32; 3]) -> Hit {
+        let mut hit = Hit::new();
+        hit.point = ray.origin + t * ray.direction;
+        hit.normal = normal;
+        hit.geometric_normal = normal;
+        // the next axis round runs along u
+        let a = if normal.x != 0.0 { 0 } else if normal.y != 0.0 { 1 } else { 2 };
+        hit.tangent = axis((a + 1) % 3, 1.0);
+        hit.bitangent = normal.cross(&hit.tangent);
+        let g = (hit.point - self.location) / self.voxel_size;
+        hit.uv = Vector2 { x: g.dot(&hit.tangent).rem_euclid(1.0), y: g.dot(&hit.bitangent).rem_euclid(1.0) };
+        hit.color = self.palette.get(self.voxels.get(cell) as usize).copied();
+        hit
+    }
+}
+
+impl Shape for VoxelGrid {
+    fn location(&self) -> Vector3 {
+        let [x, y, z] = self.voxels.size();
+        self.location + Vector3 { x: x as f32, y: y as f32, z: z as f32 } * (self.voxel_size / 2.0)
+    }
+    fn color(&self) -> Color {
+        self.color
+    }
+    fn refractive_index(&self) -> f32 {
+        self.refractive_index
+    }
+    fn detail_map(&self) -> Option<&DetailMap> {
+        self.detail_map.as_ref()
+    }
+}
+
+impl Intersectable for VoxelGrid {
+    fn intersect(&self, ray: &Ray, hit: &mut Hit) -> u8 {
+        let mut intervals = Vec::new();
+        self.intervals(ray, &mut intervals);
+        nearest_boundary(&intervals, hit)
+    }
+
+    /// Walks the cells along the line with Amanatides and Woo's DDA,
+    /// reporting every run of filled voxels.
+    fn intervals(&self, ray: &Ray, intervals: &mut Vec<Interval>) {
+        let size = self.voxels.size();
+        if size.contains(&0) {
+            return;
+        }
+        let o = (ray.origin - self.location) / self.voxel_size;
+        let d = ray.direction / self.voxel_size;
+        let (o, d) = ([o.x, o.y, o.z], [d.x, d.y, d.z]);
+
+        // clip the line to the grid, noting the faces it crosses
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        let (mut near_axis, mut far_axis) = (0, 0);
+        for i in 0..3 {
+            if d[i] == 0.0 {
+                if o[i] < 0.0 || o[i] > size[i] as f32 { return; }
+                continue;
+            }
+            let t0 = -o[i] / d[i];
+            let t1 = (size[i] as f32 - o[i]) / d[i];
+            if t0.min(t1) > t_near {
+                t_near = t0.min(t1);
+                near_axis = i;
+            }
+            if t0.max(t1) < t_far {
+                t_far = t0.max(t1);
+                far_axis = i;
+            }
+        }
+        if t_near > t_far || !t_near.is_finite() || !t_far.is_finite() {
+            return;
+        }
+
+        let step: [i64; 3] = [0, 1, 2].map(|i| if d[i] > 0.0 { 1 } else if d[i] < 0.0 { -1 } else { 0 });
+        let mut cell = [0i64; 3];
+        for i in 0..3 {
+            cell[i] = if i == near_axis {
+                if step[i] > 0 { 0 } else { i64::from(size[i]) - 1 }
+            } else {
+                ((o[i] + t_near * d[i]).floor() as i64).clamp(0, i64::from(size[i]) - 1)
+            };
+        }
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for i in 0..3 {
+            if step[i] != 0 {
+                let boundary = (cell[i] + if step[i] > 0 { 1 } else { 0 }) as f32;
+                t_next[i] = (boundary - o[i]) / d[i];
+                t_delta[i] = (1.0 / d[i]).abs();
+            }
+        }
+        let as_cell = |c: [i64; 3]| [c[0] as u32, c[1] as u32, c[2] as u32];
+        let sign = |i: usize| step[i] as f32;
+
+        let mut open = if self.voxels.get(as_cell(cell)) != 0 {
+            Some((t_near, self.surface(ray, t_near, axis(near_axis, -sign(near_axis)), as_cell(cell))))
+        } else {
+            None
+        };
+        loop {
+            let a = if t_next[0] <= t_next[1] && t_next[0] <= t_next[2] { 0 } else if t_next[1] <= t_next[2] { 1 } else { 2 };
+            let t = t_next[a];
+            let previous = cell;
+            cell[a] += step[a];
+            if t >= t_far || cell[a] < 0 || cell[a] >= i64::from(size[a]) {
+                break;
+            }
+            t_next[a] += t_delta[a];
+
+            let solid = self.voxels.get(as_cell(cell)) != 0;
+            match open {
+                None if solid => open = Some((t, self.surface(ray, t, axis(a, -sign(a)), as_cell(cell)))),
+                Some((t_in, enter)) if !solid => {
+                    intervals.push(Interval { t_in, t_out: t, enter, exit: self.surface(ray, t, axis(a, sign(a)), as_cell(previous)) });
+                    open = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some((t_in, enter)) = open {
+            let last = [0, 1, 2].map(|i| cell[i].clamp(0, i64::from(size[i]) - 1));
+            intervals.push(Interval { t_in, t_out: t_far, enter, exit: self.surface(ray, t_far, axis(far_axis, sign(far_axis)), as_cell(last)) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Color {
+        Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 }
+    }
+
+    // A 4 x 1 x 1 row at z in [-6, -5] with cells 0, 1 and 3 filled, the
+    // last in a second material.
+    fn row(voxels: Voxels) -> VoxelGrid {
+        let mut voxels = voxels;
+        voxels.set([0, 0, 0], 1);
+        voxels.set([1, 0, 0], 1);
+        voxels.set([3, 0, 0], 2);
+        VoxelGrid {
+            location: Vector3 { x: -2.0, y: -0.5, z: -6.0 },
+            voxel_size: 1.0,
+            voxels,
+            palette: vec![Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }, red(), Color { r: 0.0, g: 0.0, b: 1.0, a: 1.0 }],
+            color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            refractive_index: 1.0,
+            detail_map: None,
+        }
+    }
+
+    #[test]
+    fn traversal() {
+        for grid in [row(Voxels::dense([4, 1, 1])), row(Voxels::sparse([4, 1, 1]))] {
+            let mut intervals = Vec::new();
+            let along = Ray { origin: Vector3 { x: -5.0, y: 0.0, z: -5.5 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+            grid.intervals(&along, &mut intervals);
+            assert_eq!(intervals.len(), 2);
+            assert!((intervals[0].t_in - 3.0).abs() < 1e-5 && (intervals[0].t_out - 5.0).abs() < 1e-5);
+            assert!((intervals[1].t_in - 6.0).abs() < 1e-5 && (intervals[1].t_out - 7.0).abs() < 1e-5);
+            assert!((intervals[0].exit.normal.x - 1.0).abs() < 1e-6);
+            assert!((intervals[1].enter.normal.x + 1.0).abs() < 1e-6);
+            assert_eq!(intervals[1].enter.color.map(|c| c.b), Some(1.0));
+
+            let mut hit = Hit::new();
+            let down = Ray { origin: Vector3 { x: -0.5, y: 3.0, z: -5.5 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+            assert_eq!(grid.intersect(&down, &mut hit), 2);
+            assert!((hit.point.y - 0.5).abs() < 1e-5);
+            assert!((hit.normal.y - 1.0).abs() < 1e-6);
+            assert_eq!(hit.color.map(|c| c.r), Some(1.0));
+            assert!((hit.normal.cross(&hit.tangent) - hit.bitangent).length() < 1e-6);
+
+            // the gap at cell 2
+            let gap = Ray { origin: Vector3 { x: 0.5, y: 3.0, z: -5.5 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 } };
+            assert_eq!(grid.intersect(&gap, &mut hit), 0);
+
+            // from inside the first run
+            let inside = Ray { origin: Vector3 { x: -1.5, y: 0.0, z: -5.5 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 } };
+            assert_eq!(grid.intersect(&inside, &mut hit), 1);
+            assert!((hit.point.x - 0.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn diagonal() {
+        // a solid 8^3 block hit at an angle enters through the nearest face
+        let mut voxels = Voxels::dense([8, 8, 8]);
+        for z in 0..8 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    voxels.set([x, y, z], 1);
+                }
+            }
+        }
+        let grid = VoxelGrid { location: Vector3 { x: -4.0, y: -4.0, z: -12.0 }, voxels, ..row(Voxels::sparse([1, 1, 1])) };
+        let ray = Ray { origin: Vector3::zero(), direction: Vector3 { x: 0.5, y: -0.2, z: -1.0 }.normalize() };
+        let mut intervals = Vec::new();
+        grid.intervals(&ray, &mut intervals);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].enter.point.z + 4.0).abs() < 1e-4);
+        assert!((intervals[0].enter.normal.z - 1.0).abs() < 1e-6);
+        assert!((intervals[0].exit.normal.x - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn vox_file() {
+        fn chunk(id: &[u8], content: &[u8]) -> Vec<u8> {
+            let mut bytes = id.to_vec();
+            bytes.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            bytes.extend_from_slice(content);
+            bytes
+        }
+        let size: Vec<u8> = [2u32, 3, 4].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut xyzi = 2u32.to_le_bytes().to_vec();
+        xyzi.extend_from_slice(&[0, 0, 0, 1, 1, 2, 3, 5]);
+        let mut rgba = vec![0u8; 1024];
+        rgba[16..20].copy_from_slice(&[255, 128, 0, 255]);
+
+        let children: Vec<u8> = [chunk(b"SIZE", &size), chunk(b"XYZI", &xyzi), chunk(b"RGBA", &rgba)].concat();
+        let mut file = b"VOX ".to_vec();
+        file.extend_from_slice(&150u32.to_le_bytes());
+        file.extend_from_slice(b"MAIN");
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        file.extend_from_slice(&children);
+
+        let model = parse_vox(&file).unwrap();
+        // z up becomes y up, with the model's y running towards -z
+        assert_eq!(model.voxels.size(), [2, 4, 3]);
+        assert_eq!(model.voxels.get([0, 0, 2]), 1);
+        assert_eq!(model.voxels.get([1, 3, 0]), 5);
+        assert_eq!(model.voxels.get([0, 0, 0]), 0);
+        assert!((model.palette[5].g - 128.0 / 255.0).abs() < 1e-6);
+
+        assert!(parse_vox(b"PLY ").is_err());
+        assert!(parse_vox(&file[..file.len() - 1100]).is_err());
+    }
+}